@@ -0,0 +1,47 @@
+//! Request context recorded in the audit log with every change.
+
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use chrono::Utc;
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use crate::storage::models::AuditContext;
+use crate::AppState;
+
+/// Header naming the authenticated caller, set by the gateway in front of the API
+pub const ACTOR_HEADER: &str = "x-actor";
+
+/// Header carrying the request ID. One is generated when it is missing.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Who is making a request, for the audit log
+pub struct RequestContext(pub AuditContext);
+
+#[axum::async_trait]
+impl FromRequestParts<Arc<AppState>> for RequestContext {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Infallible> {
+        let header = |name: &str| {
+            parts
+                .headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .map(str::to_string)
+        };
+        Ok(RequestContext(AuditContext {
+            actor: header(ACTOR_HEADER),
+            request_id: Some(
+                header(REQUEST_ID_HEADER).unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+            ),
+            source_node: Some(state.config.node.id.clone()),
+            timestamp: Utc::now(),
+        }))
+    }
+}
@@ -0,0 +1,104 @@
+//! ZIP archives of a subject's files, for access requests and account
+//! migrations. Each file's current content is stored under
+//! `files/<permalink>`, cleaned of `.` and `..` segments, followed by a
+//! `manifest.json` of the file records. Entries are written to the output as
+//! they are made, so only one file's content is held in memory at a time.
+
+use std::collections::HashSet;
+
+use async_zip::tokio::write::ZipFileWriter;
+use async_zip::{Compression, ZipEntryBuilder};
+use chrono::Utc;
+use serde::Serialize;
+use tokio::io::AsyncWrite;
+
+use crate::object_store::{ObjectStore, ObjectStoreError};
+use crate::storage::models::FileRecord;
+
+/// Name of the manifest entry
+pub const MANIFEST_PATH: &str = "manifest.json";
+
+#[derive(Debug, Serialize)]
+struct Manifest<'a> {
+    exported_at: String,
+    files: Vec<ExportedFile<'a>>,
+    namespace: &'a str,
+    subject_id: &'a str,
+}
+
+#[derive(Debug, Serialize)]
+struct ExportedFile<'a> {
+    file: &'a FileRecord,
+    /// Path of the content in the archive, or null if it was missing from
+    /// object storage
+    path: Option<String>,
+}
+
+/// Path of a file's content in the archive. Permalinks aren't restricted to
+/// clean paths, so empty, `.` and `..` segments are dropped to keep entries
+/// inside `files/`. A permalink left empty, or colliding with an earlier one
+/// once cleaned, is placed under the file's id instead.
+fn content_path(file: &FileRecord, taken: &mut HashSet<String>) -> String {
+    let segments: Vec<&str> = file
+        .permalink
+        .split(['/', '\\'])
+        .filter(|segment| !matches!(*segment, "" | "." | ".."))
+        .collect();
+    let cleaned = segments.join("/");
+    let mut path = format!("files/{cleaned}");
+    if cleaned.is_empty() {
+        path = format!("files/{}", file.id);
+    } else if taken.contains(&path) {
+        path = format!("files/{}/{cleaned}", file.id);
+    }
+    taken.insert(path.clone());
+    path
+}
+
+/// Write the export archive of a subject's files. Content missing from object
+/// storage is left out, with a null path in the manifest.
+pub async fn write_export<W>(
+    object_store: &dyn ObjectStore,
+    namespace: &str,
+    subject_id: &str,
+    files: &[FileRecord],
+    writer: W,
+) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut zip = ZipFileWriter::with_tokio(writer);
+    let mut exported = Vec::with_capacity(files.len());
+    let mut taken = HashSet::new();
+    for file in files {
+        let version = file.current_version();
+        let path = match object_store.get(&version.blob_key).await {
+            Ok(data) => {
+                let path = content_path(file, &mut taken);
+                // Content is mostly already compressed
+                let entry = ZipEntryBuilder::new(path.clone().into(), Compression::Stored)
+                    .last_modification_date((&version.created_at).into());
+                zip.write_entry_whole(entry, &data).await?;
+                Some(path)
+            }
+            Err(ObjectStoreError::NotFound(_)) => {
+                tracing::warn!(file_id = %file.id, "Exported file without its missing content");
+                None
+            }
+            Err(e) => return Err(e.into()),
+        };
+        exported.push(ExportedFile { file, path });
+    }
+
+    let manifest = Manifest {
+        exported_at: Utc::now().to_rfc3339(),
+        files: exported,
+        namespace,
+        subject_id,
+    };
+    let entry = ZipEntryBuilder::new(MANIFEST_PATH.to_string().into(), Compression::Deflate);
+    zip.write_entry_whole(entry, &serde_json::to_vec_pretty(&manifest)?)
+        .await?;
+    zip.close().await?;
+    Ok(())
+}
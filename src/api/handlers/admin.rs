@@ -1,9 +1,16 @@
-use axum::extract::State;
+use axum::extract::{Path, State};
+use axum::http::{header, HeaderMap};
 use axum::Json;
-use serde::Serialize;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
-use crate::api::response::{ApiError, JSend};
+use super::files::{file_to_response, get_namespaced_file, nullable, FileResponse};
+use super::replication_error;
+use crate::api::context::RequestContext;
+use crate::api::namespace::Namespace;
+use crate::api::response::{ApiError, AppJson, JSend};
+use crate::storage::models::{FileRecord, Patch, WriteOp};
 use crate::AppState;
 
 // ============================================================================
@@ -21,8 +28,17 @@ pub struct ClusterStatusResponse {
     pub cluster_info: serde_json::Value,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct RetentionRequest {
+    #[serde(default)]
+    pub legal_hold: Option<bool>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub retain_until: Option<Option<DateTime<Utc>>>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct PurgeResponse {
+    pub blobs_deleted: u64,
     pub files_deleted: u64,
 }
 
@@ -68,15 +84,142 @@ pub async fn cluster_status(
 
 pub async fn admin_purge(
     State(state): State<Arc<AppState>>,
+    RequestContext(context): RequestContext,
 ) -> Result<Json<JSend<PurgeResponse>>, ApiError> {
-    let stats = state
+    let files_deleted = state
         .db
-        .purge_all()
+        .count_files()
         .map_err(|e| ApiError::internal(e.to_string()))?;
 
-    tracing::warn!(files = stats.files, "Purged all data");
+    // Phase 1: Purge metadata on every node via muster
+    state
+        .node
+        .replicate(WriteOp::PurgeAll.audited(&context))
+        .await
+        .map_err(replication_error)?;
+
+    // Phase 2: Sweep every object store. Only the leader gets this far, since
+    // replicate() rejects writes on followers.
+    let mut blobs_deleted = 0;
+    let stores = std::iter::once(&state.object_store).chain(state.namespace_stores.values());
+    for store in stores {
+        let keys = store
+            .list()
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to list object storage: {e}")))?;
+
+        for key in keys {
+            match store.delete(&key).await {
+                Ok(()) => blobs_deleted += 1,
+                Err(e) => {
+                    tracing::warn!(key = %key, error = %e, "Failed to delete blob during purge");
+                }
+            }
+        }
+    }
+
+    tracing::warn!(
+        files = files_deleted,
+        blobs = blobs_deleted,
+        "Purged all data"
+    );
 
     Ok(JSend::success(PurgeResponse {
-        files_deleted: stats.files,
+        blobs_deleted,
+        files_deleted,
     }))
 }
+
+/// Place or release a legal hold and set the retention period of a file.
+/// Route: PUT /admin/files/:id/retention (only when ADMIN_TOKEN is set)
+pub async fn set_retention(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+    RequestContext(mut context): RequestContext,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    AppJson(req): AppJson<RetentionRequest>,
+) -> Result<Json<JSend<FileResponse>>, ApiError> {
+    require_admin(&state, &headers)?;
+    context.actor.get_or_insert_with(|| "admin".to_string());
+
+    if req.legal_hold.is_none() && req.retain_until.is_none() {
+        return Err(ApiError::bad_request(
+            "at least one field (legal_hold, retain_until) must be provided",
+        ));
+    }
+
+    let file = get_namespaced_file(&state, &namespace.name, &id)?;
+    ensure_retention_extended(&file, req.retain_until)?;
+
+    let operation = WriteOp::SetRetention {
+        id: id.clone(),
+        legal_hold: req.legal_hold,
+        retain_until: Patch::from(req.retain_until),
+    };
+    if let Err(e) = state.node.replicate(operation.audited(&context)).await {
+        // Rejected if the retention was extended past the request meanwhile
+        let current = get_namespaced_file(&state, &namespace.name, &id)?;
+        ensure_retention_extended(&current, req.retain_until)?;
+        return Err(replication_error(e));
+    }
+
+    let file = state
+        .db
+        .get_file(&id)
+        .map_err(|e| ApiError::internal(e.to_string()))?
+        .ok_or_else(|| ApiError::not_found("File not found"))?;
+
+    tracing::info!(
+        file_id = %id,
+        legal_hold = file.legal_hold,
+        retain_until = ?file.retain_until,
+        "Updated file retention"
+    );
+    Ok(JSend::success(file_to_response(&file)))
+}
+
+// ============================================================================
+// Helpers
+// ============================================================================
+
+/// Check the request's bearer token against `ADMIN_TOKEN`.
+fn require_admin(state: &AppState, headers: &HeaderMap) -> Result<(), ApiError> {
+    let expected = state
+        .config
+        .admin_token
+        .as_deref()
+        .ok_or_else(|| ApiError::unauthorized("Admin routes are disabled"))?;
+
+    let provided = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .ok_or_else(|| ApiError::unauthorized("Missing admin bearer token"))?;
+
+    if !constant_time_eq(provided.as_bytes(), expected.as_bytes()) {
+        return Err(ApiError::unauthorized("Invalid admin token"));
+    }
+    Ok(())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Retention in effect can be extended but never shortened or removed
+fn ensure_retention_extended(
+    file: &FileRecord,
+    retain_until: Option<Option<DateTime<Utc>>>,
+) -> Result<(), ApiError> {
+    let now = Utc::now();
+    if let (Some(current), Some(until)) = (file.retain_until.filter(|t| *t > now), retain_until) {
+        if until.is_none_or(|t| t < current) {
+            return Err(ApiError::conflict(format!(
+                "File is retained until {} and its retention can only be extended",
+                current.to_rfc3339()
+            )));
+        }
+    }
+    Ok(())
+}
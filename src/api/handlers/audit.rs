@@ -0,0 +1,170 @@
+use std::collections::BTreeMap;
+
+use axum::extract::{Path, State};
+use axum::Json;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::api::namespace::Namespace;
+use crate::api::response::{ApiError, AppQuery, JSend};
+use crate::storage::audit::{AuditEntry, AuditFilter, FieldChange};
+use crate::storage::models::WriteOp;
+use crate::storage::query::RangeFilter;
+use crate::AppState;
+
+// ============================================================================
+// Types
+// ============================================================================
+
+#[derive(Debug, Deserialize)]
+pub struct ListAuditParams {
+    #[serde(default)]
+    pub actor: Option<String>,
+    #[serde(default)]
+    pub file_id: Option<String>,
+    /// Operation name, e.g. `update_file`
+    #[serde(default)]
+    pub operation: Option<String>,
+    #[serde(default)]
+    pub request_id: Option<String>,
+    /// `timestamp[gte]=2024-01-01T00:00:00Z`
+    #[serde(default)]
+    pub timestamp: RangeFilter<DateTime<Utc>>,
+    #[serde(default = "default_limit")]
+    pub limit: u32,
+    /// Sequence number to resume after, from a previous page's `next_after`
+    #[serde(default)]
+    pub after: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FileAuditParams {
+    #[serde(default = "default_limit")]
+    pub limit: u32,
+    /// Sequence number to resume after, from a previous page's `next_after`
+    #[serde(default)]
+    pub after: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditEntryResponse {
+    pub actor: Option<String>,
+    pub changes: BTreeMap<String, FieldChange>,
+    pub file_id: Option<String>,
+    pub namespace: String,
+    pub op: WriteOp,
+    pub operation: &'static str,
+    pub request_id: Option<String>,
+    pub seq: u64,
+    pub source_node: Option<String>,
+    pub timestamp: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditLogResponse {
+    pub entries: Vec<AuditEntryResponse>,
+    pub next_after: Option<u64>,
+}
+
+fn default_limit() -> u32 {
+    100
+}
+
+// ============================================================================
+// Handlers
+// ============================================================================
+
+/// List the namespace's audit log, newest first.
+pub async fn list_audit(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+    AppQuery(params): AppQuery<ListAuditParams>,
+) -> Result<Json<JSend<AuditLogResponse>>, ApiError> {
+    let filter = AuditFilter {
+        namespace: namespace.name,
+        actor: params.actor,
+        file_id: params.file_id,
+        operation: params.operation,
+        request_id: params.request_id,
+        timestamp: params.timestamp,
+    };
+    audit_page(&state, &filter, params.after, params.limit)
+}
+
+/// List the changes made to a file, newest first. Available after the file
+/// is deleted, until its entries pass the audit retention period.
+pub async fn list_file_audit(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+    Path(id): Path<String>,
+    AppQuery(params): AppQuery<FileAuditParams>,
+) -> Result<Json<JSend<AuditLogResponse>>, ApiError> {
+    let file = state
+        .db
+        .get_file(&id)
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    if file.as_ref().is_some_and(|f| f.namespace != namespace.name) {
+        return Err(ApiError::not_found("File not found"));
+    }
+
+    let filter = AuditFilter {
+        namespace: namespace.name,
+        file_id: Some(id),
+        ..Default::default()
+    };
+    let page = audit_page(&state, &filter, params.after, params.limit)?;
+    if file.is_none() && page.0.data.entries.is_empty() && params.after.is_none() {
+        return Err(ApiError::not_found("File not found"));
+    }
+    Ok(page)
+}
+
+// ============================================================================
+// Helpers
+// ============================================================================
+
+fn audit_page(
+    state: &AppState,
+    filter: &AuditFilter,
+    after: Option<u64>,
+    limit: u32,
+) -> Result<Json<JSend<AuditLogResponse>>, ApiError> {
+    if limit == 0 {
+        return Err(ApiError::bad_request("limit must be greater than 0"));
+    }
+    let limit = limit as usize;
+
+    // Fetch one extra to tell whether another page follows
+    let mut entries = state
+        .db
+        .list_audit(filter, after, limit + 1)
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+
+    let next_after = if entries.len() > limit {
+        entries.truncate(limit);
+        entries.last().map(|e| e.seq)
+    } else {
+        None
+    };
+
+    Ok(JSend::success(AuditLogResponse {
+        entries: entries.into_iter().map(entry_to_response).collect(),
+        next_after,
+    }))
+}
+
+fn entry_to_response(entry: AuditEntry) -> AuditEntryResponse {
+    AuditEntryResponse {
+        actor: entry.actor,
+        changes: entry.changes,
+        file_id: entry.file_id,
+        namespace: entry.namespace,
+        operation: entry.op.name(),
+        op: entry.op,
+        request_id: entry.request_id,
+        seq: entry.seq,
+        source_node: entry.source_node,
+        timestamp: entry.timestamp.to_rfc3339(),
+    }
+}
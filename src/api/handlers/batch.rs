@@ -0,0 +1,317 @@
+use std::collections::HashSet;
+
+use axum::extract::State;
+use axum::Json;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use super::files::{
+    ensure_unlocked, file_to_response, get_live_file, get_namespaced_file, normalize_tags,
+    update_operation, validate_update, FileResponse, UpdateFileRequest,
+};
+use super::replication_error;
+use crate::api::context::RequestContext;
+use crate::api::namespace::Namespace;
+use crate::api::response::{ApiError, AppJson, JSend};
+use crate::storage::models::{FileRecord, WriteOp};
+use crate::AppState;
+
+/// Most operations or IDs accepted in one batch request
+const MAX_BATCH_SIZE: usize = 1000;
+
+// ============================================================================
+// Types
+// ============================================================================
+
+#[derive(Debug, Deserialize)]
+pub struct BatchRequest {
+    pub operations: Vec<BatchOperation>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum BatchOperation {
+    /// Same as `DELETE /files/:id`
+    Delete {
+        id: String,
+    },
+    /// Same as `PUT /files/:id`
+    Update {
+        id: String,
+        #[serde(flatten)]
+        fields: UpdateFileRequest,
+    },
+    AddTags {
+        id: String,
+        tags: Vec<String>,
+    },
+    RemoveTags {
+        id: String,
+        tags: Vec<String>,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchResult {
+    /// The file after the operation, or null once permanently deleted
+    pub file: Option<FileResponse>,
+    pub id: String,
+    pub op: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchResponse {
+    pub results: Vec<BatchResult>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchGetRequest {
+    pub ids: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchGetResponse {
+    pub files: Vec<FileResponse>,
+    /// Requested IDs with no file in the namespace
+    pub missing: Vec<String>,
+}
+
+impl BatchOperation {
+    fn id(&self) -> &str {
+        match self {
+            BatchOperation::Delete { id }
+            | BatchOperation::Update { id, .. }
+            | BatchOperation::AddTags { id, .. }
+            | BatchOperation::RemoveTags { id, .. } => id,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            BatchOperation::Delete { .. } => "delete",
+            BatchOperation::Update { .. } => "update",
+            BatchOperation::AddTags { .. } => "add_tags",
+            BatchOperation::RemoveTags { .. } => "remove_tags",
+        }
+    }
+}
+
+// ============================================================================
+// Handlers
+// ============================================================================
+
+/// Delete, update and tag many files in one replicated transaction. Every
+/// operation is checked first; if any fails, nothing is applied.
+pub async fn batch_files(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+    RequestContext(context): RequestContext,
+    AppJson(req): AppJson<BatchRequest>,
+) -> Result<Json<JSend<BatchResponse>>, ApiError> {
+    if req.operations.is_empty() {
+        return Err(ApiError::bad_request("operations must not be empty"));
+    }
+    if req.operations.len() > MAX_BATCH_SIZE {
+        return Err(ApiError::bad_request(format!(
+            "at most {MAX_BATCH_SIZE} operations can be batched"
+        )));
+    }
+    let mut ids = HashSet::new();
+    if let Some(op) = req.operations.iter().find(|op| !ids.insert(op.id())) {
+        return Err(ApiError::bad_request(format!(
+            "file {} appears more than once in the batch",
+            op.id()
+        )));
+    }
+
+    let plan = plan_batch(&state, &namespace.name, &req.operations)?;
+    let operation = WriteOp::Batch {
+        ops: plan.iter().map(|(op, _)| op.clone()).collect(),
+    };
+    if let Err(e) = state.node.replicate(operation.audited(&context)).await {
+        // Rejected if a concurrent change invalidated an operation
+        plan_batch(&state, &namespace.name, &req.operations)?;
+        return Err(replication_error(e));
+    }
+
+    // Permanently deleted files' blobs go after the records (best-effort)
+    let object_store = state.object_store_for(&namespace.name);
+    for (op, file) in &plan {
+        if !matches!(op, WriteOp::DeleteFile { .. }) {
+            continue;
+        }
+        for key in file.blob_keys() {
+            if let Err(e) = object_store.delete(&key).await {
+                tracing::warn!(file_id = %file.id, key = %key, error = %e, "Failed to delete file from object storage");
+            }
+        }
+    }
+
+    let ids: Vec<String> = req
+        .operations
+        .iter()
+        .map(|op| op.id().to_string())
+        .collect();
+    let files = state
+        .db
+        .get_files(&ids)
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    let results = req
+        .operations
+        .iter()
+        .zip(files)
+        .map(|(op, file)| BatchResult {
+            file: file.as_ref().map(file_to_response),
+            id: op.id().to_string(),
+            op: op.name(),
+        })
+        .collect();
+
+    tracing::debug!(namespace = %namespace.name, operations = ids.len(), "Applied batch");
+    Ok(JSend::success(BatchResponse { results }))
+}
+
+/// Get many files by ID in one call, in the order asked for.
+pub async fn batch_get_files(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+    AppJson(req): AppJson<BatchGetRequest>,
+) -> Result<Json<JSend<BatchGetResponse>>, ApiError> {
+    if req.ids.len() > MAX_BATCH_SIZE {
+        return Err(ApiError::bad_request(format!(
+            "at most {MAX_BATCH_SIZE} ids can be fetched at once"
+        )));
+    }
+    let mut seen = HashSet::new();
+    let ids: Vec<String> = req
+        .ids
+        .into_iter()
+        .filter(|id| seen.insert(id.clone()))
+        .collect();
+
+    let records = state
+        .db
+        .get_files(&ids)
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    let mut response = BatchGetResponse {
+        files: Vec::new(),
+        missing: Vec::new(),
+    };
+    for (id, file) in ids.into_iter().zip(records) {
+        match file.filter(|f| f.namespace == namespace.name) {
+            Some(file) => response.files.push(file_to_response(&file)),
+            None => response.missing.push(id),
+        }
+    }
+    Ok(JSend::success(response))
+}
+
+// ============================================================================
+// Helpers
+// ============================================================================
+
+/// Check every operation against the current state, returning the write
+/// operation for each with the file it applies to. Fails with the status of
+/// the first failing operation, listing every failure.
+fn plan_batch(
+    state: &AppState,
+    namespace: &str,
+    operations: &[BatchOperation],
+) -> Result<Vec<(WriteOp, FileRecord)>, ApiError> {
+    let mut plan = Vec::with_capacity(operations.len());
+    let mut failures: Vec<(usize, ApiError)> = Vec::new();
+    let mut permalinks = HashSet::new();
+    for (i, operation) in operations.iter().enumerate() {
+        match plan_operation(state, namespace, operation) {
+            Ok((op, file)) => {
+                // Two files can't move to the same permalink
+                if let WriteOp::UpdateFile {
+                    permalink: Some(permalink),
+                    ..
+                } = &op
+                {
+                    if *permalink != file.permalink && !permalinks.insert(permalink.clone()) {
+                        failures.push((
+                            i,
+                            ApiError::conflict(format!(
+                                "permalink '{permalink}' is already in use"
+                            )),
+                        ));
+                        continue;
+                    }
+                }
+                plan.push((op, file));
+            }
+            Err(e @ ApiError::Error(..)) => return Err(e),
+            Err(e) => failures.push((i, e)),
+        }
+    }
+
+    let Some((_, ApiError::Fail(status, _))) = failures.first() else {
+        return Ok(plan);
+    };
+    let status = *status;
+    let message = failures
+        .iter()
+        .map(|(i, e)| match e {
+            ApiError::Fail(_, message) | ApiError::Error(_, message) => {
+                format!("operations[{i}]: {message}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("; ");
+    Err(ApiError::Fail(
+        status,
+        format!("nothing was applied: {message}"),
+    ))
+}
+
+fn plan_operation(
+    state: &AppState,
+    namespace: &str,
+    operation: &BatchOperation,
+) -> Result<(WriteOp, FileRecord), ApiError> {
+    match operation {
+        BatchOperation::Delete { id } => {
+            let file = get_namespaced_file(state, namespace, id)?;
+            ensure_unlocked(&file, "deleted")?;
+            // Live files go to the trash, trashed ones are deleted for good
+            let op = match file.deleted_at {
+                None => WriteOp::TrashFile {
+                    id: id.clone(),
+                    deleted_at: Utc::now(),
+                },
+                Some(_) => WriteOp::DeleteFile { id: id.clone() },
+            };
+            Ok((op, file))
+        }
+        BatchOperation::Update { id, fields } => {
+            validate_update(fields)?;
+            let file = get_live_file(state, namespace, id)?;
+            Ok((update_operation(state, &file, fields)?, file))
+        }
+        BatchOperation::AddTags { id, tags } => {
+            let tags = normalize_tags(tags)?;
+            let file = get_live_file(state, namespace, id)?;
+            Ok((
+                WriteOp::AddTags {
+                    id: id.clone(),
+                    tags,
+                },
+                file,
+            ))
+        }
+        BatchOperation::RemoveTags { id, tags } => {
+            let tags = normalize_tags(tags)?;
+            let file = get_live_file(state, namespace, id)?;
+            Ok((
+                WriteOp::RemoveTags {
+                    id: id.clone(),
+                    tags,
+                },
+                file,
+            ))
+        }
+    }
+}
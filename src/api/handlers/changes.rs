@@ -0,0 +1,201 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::Json;
+use futures_util::Stream;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::api::namespace::Namespace;
+use crate::api::response::{ApiError, AppQuery, JSend};
+use crate::storage::changes::{Change, ChangeEvent, ChangePage};
+use crate::AppState;
+
+/// Number of changes read from the database at a time while streaming
+const STREAM_BATCH_SIZE: usize = 100;
+
+// ============================================================================
+// Types
+// ============================================================================
+
+#[derive(Debug, Deserialize)]
+pub struct ListChangesParams {
+    /// Sequence number to resume after, from a previous page's `next_since`
+    #[serde(default)]
+    pub since: u64,
+    #[serde(default = "default_limit")]
+    pub limit: u32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StreamChangesParams {
+    /// Sequence number to resume after. `Last-Event-ID` takes precedence.
+    #[serde(default)]
+    pub since: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChangeResponse {
+    pub events: Vec<ChangeEvent>,
+    pub operation: String,
+    pub seq: u64,
+    pub timestamp: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ListChangesResponse {
+    pub changes: Vec<ChangeResponse>,
+    /// Sequence number of the last operation applied on this node
+    pub last_seq: u64,
+    /// Pass as `since` to fetch the next page. It can be short of `last_seq`
+    /// even on a page with fewer changes than `limit`.
+    pub next_since: u64,
+}
+
+fn default_limit() -> u32 {
+    100
+}
+
+// ============================================================================
+// Handlers
+// ============================================================================
+
+/// List changes applied after a sequence number, oldest first.
+pub async fn list_changes(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+    AppQuery(params): AppQuery<ListChangesParams>,
+) -> Result<Json<JSend<ListChangesResponse>>, ApiError> {
+    if params.limit == 0 {
+        return Err(ApiError::bad_request("limit must be greater than 0"));
+    }
+    let limit = params.limit as usize;
+
+    let page = read_changes(&state, &namespace.name, params.since, limit)?;
+    Ok(JSend::success(ListChangesResponse {
+        changes: page.changes.into_iter().map(change_to_response).collect(),
+        last_seq: page.last_seq,
+        next_since: page.next_since,
+    }))
+}
+
+/// Stream changes applied after a sequence number as server-sent events,
+/// then each new change as it is applied on this node.
+pub async fn stream_changes(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+    headers: HeaderMap,
+    AppQuery(params): AppQuery<StreamChangesParams>,
+) -> Result<Sse<impl Stream<Item = Result<Event, axum::Error>>>, ApiError> {
+    // Reconnecting EventSource clients send the ID of the last event they saw
+    let since = match headers.get("last-event-id") {
+        Some(value) => value
+            .to_str()
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+            .ok_or_else(|| ApiError::bad_request("Invalid Last-Event-ID header"))?,
+        None => params.since.unwrap_or(0),
+    };
+
+    // Fail before streaming if the consumer has already missed changes
+    read_changes(&state, &namespace.name, since, 1)?;
+
+    let tail = Tail {
+        changes: state.changes.clone(),
+        state,
+        namespace: namespace.name,
+        since,
+        scanned: since,
+        pending: VecDeque::new(),
+    };
+    let stream = futures_util::stream::unfold(tail, next_event);
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+// ============================================================================
+// Helpers
+// ============================================================================
+
+fn read_changes(
+    state: &AppState,
+    namespace: &str,
+    since: u64,
+    limit: usize,
+) -> Result<ChangePage, ApiError> {
+    let page = state
+        .db
+        .list_changes(namespace, since, limit)
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    if since < page.pruned_seq {
+        return Err(ApiError::gone(format!(
+            "Changes up to {} have been pruned. Resync from GET /files, then resume from {}.",
+            page.pruned_seq, page.last_seq
+        )));
+    }
+    Ok(page)
+}
+
+fn change_to_response(change: Change) -> ChangeResponse {
+    ChangeResponse {
+        events: change.events,
+        operation: change.operation,
+        seq: change.seq,
+        timestamp: change.timestamp.to_rfc3339(),
+    }
+}
+
+/// A consumer tailing the change feed
+struct Tail {
+    state: Arc<AppState>,
+    namespace: String,
+    /// Sequence number of the last change sent
+    since: u64,
+    /// Sequence number the changes read so far reach, once they are sent
+    scanned: u64,
+    changes: watch::Receiver<u64>,
+    /// Changes read but not sent yet
+    pending: VecDeque<Change>,
+}
+
+/// Send the next change, waiting for one to be applied if the consumer is
+/// caught up. Ends the stream when the node shuts down.
+async fn next_event(mut tail: Tail) -> Option<(Result<Event, axum::Error>, Tail)> {
+    loop {
+        if let Some(change) = tail.pending.pop_front() {
+            tail.since = change.seq;
+            let event = Event::default()
+                .id(change.seq.to_string())
+                .event("change")
+                .json_data(change_to_response(change));
+            return Some((event, tail));
+        }
+
+        // Changes read past the last one sent were in other namespaces
+        tail.since = tail.since.max(tail.scanned);
+
+        // Mark the current sequence number seen before reading, so a change
+        // applied after the read wakes us up
+        tail.changes.borrow_and_update();
+        let page = match tail
+            .state
+            .db
+            .list_changes(&tail.namespace, tail.since, STREAM_BATCH_SIZE)
+        {
+            Ok(page) => page,
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to read change feed");
+                return None;
+            }
+        };
+        tail.scanned = page.next_since;
+        if page.changes.is_empty() && page.next_since >= page.last_seq {
+            tail.since = tail.since.max(page.next_since);
+            tail.changes.changed().await.ok()?;
+        } else {
+            tail.pending.extend(page.changes);
+        }
+    }
+}
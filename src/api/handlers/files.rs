@@ -1,16 +1,30 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
+use axum::extract::multipart::Field;
 use axum::extract::{Multipart, Path, State};
+use axum::http::header::{HeaderName, ETAG};
 use axum::Json;
-use bytes::BytesMut;
-use chrono::Utc;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Deserializer, Serialize};
 use std::sync::Arc;
 
-use super::replication_error;
+use super::{lock_or_replication_error, replication_error};
+use crate::api::context::RequestContext;
+use crate::api::namespace::Namespace;
+use crate::api::precondition::{etag, IfMatch};
 use crate::api::response::{ApiError, AppJson, AppQuery, JSend, JSendPaginated, Pagination};
-use crate::storage::models::{FileRecord, FileType, Patch, WriteOp};
+use crate::config::NamespaceConfig;
+use crate::storage::models::{
+    normalize_tag, AuditContext, FileRecord, FileType, FileVersion, Patch, PermalinkConflict,
+    PermalinkRename, RenamePlan, Visibility, WriteOp,
+};
+use crate::storage::query::{
+    Cursor, FileFilter, MetadataCondition, MetadataFilter, MimeFilter, RangeFilter, Sort,
+    SortField, TagMatch,
+};
+use crate::storage::search::{tokenize, SearchQuery};
 use crate::AppState;
 
 // ============================================================================
@@ -22,15 +36,27 @@ pub struct FileResponse {
     pub alt: Option<String>,
     pub byte_size: u64,
     pub created_at: String,
+    /// When the file was moved to the trash
+    pub deleted_at: Option<String>,
     pub description: Option<String>,
+    pub expires_at: Option<String>,
     pub file_type: FileType,
     pub id: String,
+    pub legal_hold: bool,
     pub metadata: Option<HashMap<String, serde_json::Value>>,
     pub mime_type: String,
     pub name: Option<String>,
+    pub namespace: String,
     pub permalink: String,
+    pub retain_until: Option<String>,
+    /// Bumped on every change, served as the `ETag`
+    pub revision: u64,
     pub subject_id: Option<String>,
+    pub tags: Vec<String>,
     pub updated_at: String,
+    /// Current content version
+    pub version: u32,
+    pub visibility: Visibility,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -40,6 +66,8 @@ pub struct UpdateFileRequest {
     #[serde(default, deserialize_with = "nullable")]
     pub description: Option<Option<String>>,
     #[serde(default, deserialize_with = "nullable")]
+    pub expires_at: Option<Option<DateTime<Utc>>>,
+    #[serde(default, deserialize_with = "nullable")]
     pub metadata: Option<Option<HashMap<String, serde_json::Value>>>,
     #[serde(default, deserialize_with = "nullable")]
     pub name: Option<Option<String>>,
@@ -47,6 +75,8 @@ pub struct UpdateFileRequest {
     pub permalink: Option<String>,
     #[serde(default, deserialize_with = "nullable")]
     pub subject_id: Option<Option<String>>,
+    #[serde(default)]
+    pub visibility: Option<Visibility>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -55,10 +85,157 @@ pub struct ListFilesParams {
     pub limit: u32,
     #[serde(default)]
     pub offset: u32,
+    /// Opaque cursor from a previous page's `next_cursor`
+    #[serde(default)]
+    pub after: Option<String>,
+    /// `byte_size[gte]=1024&byte_size[lt]=1048576`
+    #[serde(default)]
+    pub byte_size: RangeFilter<u64>,
+    /// `created_at[gte]=2024-01-01T00:00:00Z`
+    #[serde(default)]
+    pub created_at: RangeFilter<DateTime<Utc>>,
+    /// One type, a comma-separated list, or `file_type=...` / `file_type[]=...` repeated
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub file_type: Vec<FileType>,
+    /// Exact MIME type, or a `type/*` wildcard
+    #[serde(default)]
+    pub mime_type: Option<String>,
+    /// Case-insensitive substring of the file name
     #[serde(default)]
-    pub file_type: Option<String>,
+    pub name: Option<String>,
+    /// `created_at`, `byte_size`, `name` or `updated_at`, prefixed with `-` for descending
+    #[serde(default)]
+    pub sort: Sort,
     #[serde(default)]
     pub subject_id: Option<String>,
+    /// One tag, a comma-separated list, or `tag=...` repeated
+    #[serde(default, deserialize_with = "tag_list")]
+    pub tag: Vec<String>,
+    /// Whether files need `all` of the tags (default) or `any` of them
+    #[serde(default)]
+    pub tag_match: TagMatch,
+    #[serde(default)]
+    pub updated_at: RangeFilter<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TagsRequest {
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RenamePrefixRequest {
+    pub from: String,
+    pub to: String,
+    /// Report what would change without renaming anything
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RenamePrefixResponse {
+    pub conflicts: Vec<PermalinkConflict>,
+    pub dry_run: bool,
+    pub renamed: Vec<PermalinkRename>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListTrashParams {
+    #[serde(default = "default_limit")]
+    pub limit: u32,
+    #[serde(default)]
+    pub offset: u32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchFilesParams {
+    /// Words to search for. Each word also matches terms it is a prefix of.
+    pub q: String,
+    #[serde(default = "default_limit")]
+    pub limit: u32,
+    #[serde(default)]
+    pub offset: u32,
+    /// One type, a comma-separated list, or `file_type=...` / `file_type[]=...` repeated
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub file_type: Vec<FileType>,
+    #[serde(default)]
+    pub subject_id: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchResultResponse {
+    #[serde(flatten)]
+    pub file: FileResponse,
+    /// Relevance score. Only meaningful relative to other results of the same search.
+    pub score: f64,
+}
+
+/// `metadata.<key>` filters, collected from the whole query string:
+/// `metadata.<key>=<value>`, `metadata.<key>[in]=a,b` and
+/// `metadata.<key>[exists]=true|false`.
+#[derive(Debug, Default)]
+pub struct MetadataParams(pub Vec<MetadataFilter>);
+
+impl<'de> Deserialize<'de> for MetadataParams {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::{Error, IgnoredAny};
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Param {
+            Value(String),
+            Operators(BTreeMap<String, String>),
+            Other(IgnoredAny),
+        }
+
+        let mut filters = Vec::new();
+        for (name, param) in BTreeMap::<String, Param>::deserialize(deserializer)? {
+            let Some(key) = name.strip_prefix("metadata.") else {
+                continue;
+            };
+            if key.is_empty() {
+                return Err(D::Error::custom("metadata filters need a key"));
+            }
+
+            let mut push = |condition| {
+                filters.push(MetadataFilter {
+                    key: key.to_string(),
+                    condition,
+                })
+            };
+            match param {
+                Param::Value(value) => push(MetadataCondition::Equals(value)),
+                Param::Operators(operators) => {
+                    for (operator, value) in operators {
+                        let condition = match operator.as_str() {
+                            "eq" => MetadataCondition::Equals(value),
+                            "in" => MetadataCondition::In(
+                                value.split(',').map(|v| v.trim().to_string()).collect(),
+                            ),
+                            "exists" => MetadataCondition::Exists(value.parse().map_err(|_| {
+                                D::Error::custom(format!(
+                                    "metadata.{key}[exists] must be true or false"
+                                ))
+                            })?),
+                            _ => {
+                                return Err(D::Error::custom(format!(
+                                    "unknown metadata operator '{operator}', \
+                                     expected one of eq, exists, in"
+                                )))
+                            }
+                        };
+                        push(condition);
+                    }
+                }
+                Param::Other(_) => {
+                    return Err(D::Error::custom(format!(
+                        "invalid filter for metadata.{key}"
+                    )))
+                }
+            }
+        }
+        Ok(MetadataParams(filters))
+    }
 }
 
 fn default_limit() -> u32 {
@@ -66,7 +243,7 @@ fn default_limit() -> u32 {
 }
 
 /// Distinguishes between a missing field (`None`) and an explicit `null` (`Some(None)`).
-fn nullable<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+pub(super) fn nullable<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
 where
     T: DeserializeOwned,
     D: Deserializer<'de>,
@@ -74,23 +251,61 @@ where
     Ok(Some(Option::deserialize(deserializer)?))
 }
 
+/// Accepts a single value, a comma-separated list, or a sequence of values.
+fn one_or_many<'de, T, D>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    let values = match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    };
+    values
+        .iter()
+        .flat_map(|value| value.split(','))
+        .map(|value| value.trim().parse().map_err(serde::de::Error::custom))
+        .collect()
+}
+
+/// Like [`one_or_many`], normalizing each value as a tag.
+fn tag_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    one_or_many::<String, D>(deserializer)?
+        .iter()
+        .map(|tag| normalize_tag(tag).map_err(serde::de::Error::custom))
+        .collect()
+}
+
 // ============================================================================
 // Handlers
 // ============================================================================
 
 pub async fn create_file(
     State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+    RequestContext(context): RequestContext,
     mut multipart: Multipart,
 ) -> Result<Json<JSend<FileResponse>>, ApiError> {
-    let mut file_data: Option<BytesMut> = None;
+    let mut file_data: Option<Bytes> = None;
     let mut file_name: Option<String> = None;
     let mut file_content_type: Option<String> = None;
     let mut permalink: Option<String> = None;
     let mut name: Option<String> = None;
     let mut alt: Option<String> = None;
     let mut description: Option<String> = None;
+    let mut expires_at: Option<DateTime<Utc>> = None;
     let mut metadata: Option<HashMap<String, serde_json::Value>> = None;
     let mut subject_id: Option<String> = None;
+    let mut tags: Vec<String> = Vec::new();
+    let mut visibility = namespace.default_visibility;
 
     while let Some(field) = multipart
         .next_field()
@@ -103,22 +318,7 @@ pub async fn create_file(
             "file" => {
                 file_name = field.file_name().map(|s| s.to_string());
                 file_content_type = field.content_type().map(|s| s.to_string());
-
-                let data = field
-                    .bytes()
-                    .await
-                    .map_err(|e| ApiError::bad_request(format!("Failed to read file: {e}")))?;
-
-                if data.len() as u64 > state.config.max_upload_size {
-                    return Err(ApiError::payload_too_large(format!(
-                        "File exceeds maximum upload size of {} bytes",
-                        state.config.max_upload_size
-                    )));
-                }
-
-                let mut buf = BytesMut::with_capacity(data.len());
-                buf.extend_from_slice(&data);
-                file_data = Some(buf);
+                file_data = Some(read_upload(field, namespace.max_upload_size).await?);
             }
             "permalink" => {
                 permalink = Some(
@@ -152,6 +352,13 @@ pub async fn create_file(
                         .map_err(|e| ApiError::bad_request(format!("Invalid description: {e}")))?,
                 );
             }
+            "expires_at" => {
+                let text = field
+                    .text()
+                    .await
+                    .map_err(|e| ApiError::bad_request(format!("Invalid expires_at: {e}")))?;
+                expires_at = Some(parse_expires_at(&text)?);
+            }
             "subject_id" => {
                 subject_id = Some(
                     field
@@ -160,6 +367,22 @@ pub async fn create_file(
                         .map_err(|e| ApiError::bad_request(format!("Invalid subject_id: {e}")))?,
                 );
             }
+            "tags" => {
+                let text = field
+                    .text()
+                    .await
+                    .map_err(|e| ApiError::bad_request(format!("Invalid tags: {e}")))?;
+                for tag in text.split(',') {
+                    tags.push(normalize_tag(tag).map_err(ApiError::bad_request)?);
+                }
+            }
+            "visibility" => {
+                let text = field
+                    .text()
+                    .await
+                    .map_err(|e| ApiError::bad_request(format!("Invalid visibility: {e}")))?;
+                visibility = text.trim().parse().map_err(ApiError::bad_request)?;
+            }
             "metadata" => {
                 let text = field
                     .text()
@@ -177,18 +400,497 @@ pub async fn create_file(
         }
     }
 
-    let file_data = file_data.ok_or_else(|| ApiError::bad_request("file field is required"))?;
+    let data = file_data.ok_or_else(|| ApiError::bad_request("file field is required"))?;
     let permalink =
         permalink.ok_or_else(|| ApiError::bad_request("permalink field is required"))?;
 
+    let file_record = store_new_file(
+        &state,
+        &namespace,
+        &context,
+        NewFile {
+            data,
+            file_name,
+            content_type: file_content_type,
+            permalink,
+            alt,
+            description,
+            expires_at,
+            metadata,
+            name,
+            subject_id,
+            tags,
+            visibility,
+        },
+    )
+    .await?;
+
+    Ok(JSend::success(file_to_response(&file_record)))
+}
+
+pub async fn get_file(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+    Path(id): Path<String>,
+) -> Result<([(HeaderName, String); 1], Json<JSend<FileResponse>>), ApiError> {
+    let file = get_namespaced_file(&state, &namespace.name, &id)?;
+
+    Ok((
+        [(ETAG, etag(&file))],
+        JSend::success(file_to_response(&file)),
+    ))
+}
+
+pub async fn update_file(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+    RequestContext(context): RequestContext,
+    if_match: IfMatch,
+    Path(id): Path<String>,
+    AppJson(req): AppJson<UpdateFileRequest>,
+) -> Result<([(HeaderName, String); 1], Json<JSend<FileResponse>>), ApiError> {
+    validate_update(&req)?;
+
+    // Verify the file exists and isn't trashed
+    let existing = get_live_file(&state, &namespace.name, &id)?;
+    if_match.check(Some(&existing))?;
+
+    let operation = update_operation(&state, &existing, &req)?;
+    let moved = req
+        .permalink
+        .as_ref()
+        .is_some_and(|p| *p != existing.permalink);
+    let lock_action = moved.then_some("moved");
+    replicate_if_match(&state, &context, &if_match, &id, operation, lock_action).await?;
+
+    let file = state
+        .db
+        .get_file(&id)
+        .map_err(|e| ApiError::internal(e.to_string()))?
+        .ok_or_else(|| ApiError::internal("File not found after update"))?;
+
+    tracing::debug!(file_id = %id, revision = file.revision, "Updated file");
+    Ok((
+        [(ETAG, etag(&file))],
+        JSend::success(file_to_response(&file)),
+    ))
+}
+
+pub async fn add_tags(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+    RequestContext(context): RequestContext,
+    Path(id): Path<String>,
+    AppJson(req): AppJson<TagsRequest>,
+) -> Result<Json<JSend<FileResponse>>, ApiError> {
+    let tags = normalize_tags(&req.tags)?;
+    update_tags(
+        &state,
+        &context,
+        &namespace.name,
+        &id,
+        WriteOp::AddTags {
+            id: id.clone(),
+            tags,
+        },
+    )
+    .await
+}
+
+pub async fn remove_tag(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+    RequestContext(context): RequestContext,
+    Path((id, tag)): Path<(String, String)>,
+) -> Result<Json<JSend<FileResponse>>, ApiError> {
+    let tag = normalize_tag(&tag).map_err(ApiError::bad_request)?;
+    let operation = WriteOp::RemoveTags {
+        id: id.clone(),
+        tags: vec![tag],
+    };
+    update_tags(&state, &context, &namespace.name, &id, operation).await
+}
+
+/// Replicate a change to a file conditional on `If-Match`. A change rejected
+/// because another write got to the file first fails with 412, and one
+/// rejected because the file was locked for `lock_action` meanwhile with 423.
+async fn replicate_if_match(
+    state: &AppState,
+    context: &AuditContext,
+    if_match: &IfMatch,
+    id: &str,
+    operation: WriteOp,
+    lock_action: Option<&str>,
+) -> Result<(), ApiError> {
+    let operation = if_match.wrap(id, operation).audited(context);
+    if let Err(e) = state.node.replicate(operation).await {
+        let current = state
+            .db
+            .get_file(id)
+            .map_err(|e| ApiError::internal(e.to_string()))?;
+        if_match.check(current.as_ref())?;
+        return Err(match lock_action {
+            Some(action) => lock_or_replication_error(state, &[id], action, e),
+            None => replication_error(e),
+        });
+    }
+    Ok(())
+}
+
+/// Replicate a tag change and return the updated file.
+async fn update_tags(
+    state: &AppState,
+    context: &AuditContext,
+    namespace: &str,
+    id: &str,
+    operation: WriteOp,
+) -> Result<Json<JSend<FileResponse>>, ApiError> {
+    // Verify the file exists and isn't trashed
+    get_live_file(state, namespace, id)?;
+
+    state
+        .node
+        .replicate(operation.audited(context))
+        .await
+        .map_err(replication_error)?;
+
+    let file = state
+        .db
+        .get_file(id)
+        .map_err(|e| ApiError::internal(e.to_string()))?
+        .ok_or_else(|| ApiError::internal("File not found after update"))?;
+
+    tracing::debug!(file_id = %id, tags = ?file.tags, "Updated file tags");
+    Ok(JSend::success(file_to_response(&file)))
+}
+
+pub async fn rename_prefix(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+    RequestContext(context): RequestContext,
+    AppJson(req): AppJson<RenamePrefixRequest>,
+) -> Result<Json<JSend<RenamePrefixResponse>>, ApiError> {
+    if req.from.is_empty() || req.to.is_empty() {
+        return Err(ApiError::bad_request("from and to must not be empty"));
+    }
+    if req.from == req.to {
+        return Err(ApiError::bad_request("from and to must differ"));
+    }
+
+    let plan = state
+        .db
+        .plan_rename_prefix(&namespace.name, &req.from, &req.to)
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+
+    if !req.dry_run && !plan.renames.is_empty() {
+        for rename in &plan.renames {
+            let file = state
+                .db
+                .get_file(&rename.id)
+                .map_err(|e| ApiError::internal(e.to_string()))?;
+            if let Some(ref file) = file {
+                ensure_unlocked(file, "moved")?;
+            }
+        }
+        ensure_no_rename_conflicts(&plan)?;
+
+        let operation = WriteOp::RenamePrefix {
+            namespace: namespace.name.clone(),
+            from: req.from.clone(),
+            to: req.to.clone(),
+            renamed_at: Utc::now(),
+        };
+        if let Err(e) = state.node.replicate(operation.audited(&context)).await {
+            // Apply rejects the rename if a permalink was taken meanwhile
+            let current = state
+                .db
+                .plan_rename_prefix(&namespace.name, &req.from, &req.to)
+                .map_err(|e| ApiError::internal(e.to_string()))?;
+            ensure_no_rename_conflicts(&current)?;
+            let ids: Vec<&str> = current.renames.iter().map(|r| r.id.as_str()).collect();
+            return Err(lock_or_replication_error(&state, &ids, "moved", e));
+        }
+
+        tracing::debug!(namespace = %namespace.name, from = %req.from, to = %req.to, files = plan.renames.len(), "Renamed permalink prefix");
+    }
+
+    Ok(JSend::success(RenamePrefixResponse {
+        conflicts: plan.conflicts,
+        dry_run: req.dry_run,
+        renamed: plan.renames,
+    }))
+}
+
+/// Reject a rename whose new permalinks are already in use.
+fn ensure_no_rename_conflicts(plan: &RenamePlan) -> Result<(), ApiError> {
+    match plan.conflicts.first() {
+        Some(conflict) => Err(ApiError::conflict(format!(
+            "{} renamed permalinks are already in use, e.g. '{}'",
+            plan.conflicts.len(),
+            conflict.permalink
+        ))),
+        None => Ok(()),
+    }
+}
+
+pub async fn delete_file(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+    RequestContext(context): RequestContext,
+    if_match: IfMatch,
+    Path(id): Path<String>,
+) -> Result<Json<JSend<()>>, ApiError> {
+    // Verify the file exists
+    let file = get_namespaced_file(&state, &namespace.name, &id)?;
+    if_match.check(Some(&file))?;
+    ensure_unlocked(&file, "deleted")?;
+
+    // Live files go to the trash, keeping their permalink and content
+    if file.deleted_at.is_none() {
+        let operation = WriteOp::TrashFile {
+            id: id.clone(),
+            deleted_at: Utc::now(),
+        };
+        replicate_if_match(&state, &context, &if_match, &id, operation, Some("deleted")).await?;
+
+        tracing::debug!(file_id = %id, "Moved file to trash");
+        return Ok(JSend::success(()));
+    }
+
+    // Phase 1: Remove metadata via muster
+    let operation = WriteOp::DeleteFile { id: id.clone() };
+    replicate_if_match(&state, &context, &if_match, &id, operation, Some("deleted")).await?;
+
+    // Phase 2: Delete every version's blob from object storage (best-effort)
+    let object_store = state.object_store_for(&namespace.name);
+    for key in file.blob_keys() {
+        if let Err(e) = object_store.delete(&key).await {
+            tracing::warn!(file_id = %id, key = %key, error = %e, "Failed to delete file from object storage");
+        }
+    }
+
+    tracing::debug!(file_id = %id, "Deleted file");
+    Ok(JSend::success(()))
+}
+
+/// List files in the trash, most recently deleted first.
+pub async fn list_trash(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+    AppQuery(params): AppQuery<ListTrashParams>,
+) -> Result<Json<JSendPaginated<FileResponse>>, ApiError> {
+    if params.limit == 0 {
+        return Err(ApiError::bad_request("limit must be greater than 0"));
+    }
+
+    let page = state
+        .db
+        .list_trash(
+            &namespace.name,
+            params.offset as usize,
+            params.limit as usize,
+        )
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+
+    let items = page.files.iter().map(file_to_response).collect();
+    Ok(JSendPaginated::success(
+        items,
+        Pagination {
+            limit: params.limit,
+            next_cursor: None,
+            offset: params.offset,
+            total: page.total,
+        },
+    ))
+}
+
+/// Take a file back out of the trash.
+pub async fn restore_file(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+    RequestContext(context): RequestContext,
+    Path(id): Path<String>,
+) -> Result<Json<JSend<FileResponse>>, ApiError> {
+    let file = get_namespaced_file(&state, &namespace.name, &id)?;
+    if file.deleted_at.is_none() {
+        return Err(ApiError::conflict("File is not in the trash"));
+    }
+
+    let operation = WriteOp::RestoreFile { id: id.clone() };
+    state
+        .node
+        .replicate(operation.audited(&context))
+        .await
+        .map_err(replication_error)?;
+
+    let file = state
+        .db
+        .get_file(&id)
+        .map_err(|e| ApiError::internal(e.to_string()))?
+        .ok_or_else(|| ApiError::not_found("File not found"))?;
+
+    tracing::debug!(file_id = %id, "Restored file from trash");
+    Ok(JSend::success(file_to_response(&file)))
+}
+
+pub async fn list_files(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+    AppQuery(params): AppQuery<ListFilesParams>,
+    AppQuery(metadata): AppQuery<MetadataParams>,
+) -> Result<Json<JSendPaginated<FileResponse>>, ApiError> {
+    if params.limit == 0 {
+        return Err(ApiError::bad_request("limit must be greater than 0"));
+    }
+
+    let after = match params.after.as_deref() {
+        Some(_) if params.offset > 0 => {
+            return Err(ApiError::bad_request("after and offset cannot be combined"));
+        }
+        Some(_) if params.sort.field != SortField::CreatedAt => {
+            return Err(ApiError::bad_request(
+                "after is only supported when sorting by created_at",
+            ));
+        }
+        Some(token) => {
+            Some(Cursor::decode(token).ok_or_else(|| ApiError::bad_request("Invalid cursor"))?)
+        }
+        None => None,
+    };
+
+    let filter = FileFilter {
+        byte_size: params.byte_size,
+        created_at: params.created_at,
+        file_types: params.file_type,
+        metadata: metadata.0,
+        mime_type: params.mime_type.as_deref().map(MimeFilter::parse),
+        name_contains: params.name,
+        namespace: namespace.name,
+        subject_id: params.subject_id,
+        tags: params.tag,
+        tag_match: params.tag_match,
+        updated_at: params.updated_at,
+    };
+
+    let page = state
+        .db
+        .query_files(
+            &filter,
+            params.sort,
+            after.as_ref(),
+            params.offset as usize,
+            params.limit as usize,
+        )
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+
+    let items: Vec<FileResponse> = page.files.iter().map(file_to_response).collect();
+
+    Ok(JSendPaginated::success(
+        items,
+        Pagination {
+            limit: params.limit,
+            next_cursor: page.next_cursor.map(|c| c.encode()),
+            offset: params.offset,
+            total: page.total,
+        },
+    ))
+}
+
+pub async fn search_files(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+    AppQuery(params): AppQuery<SearchFilesParams>,
+) -> Result<Json<JSendPaginated<SearchResultResponse>>, ApiError> {
+    if params.limit == 0 {
+        return Err(ApiError::bad_request("limit must be greater than 0"));
+    }
+    if tokenize(&params.q).next().is_none() {
+        return Err(ApiError::bad_request("q must contain at least one word"));
+    }
+
+    let query = SearchQuery {
+        text: params.q,
+        namespace: namespace.name,
+        file_types: params.file_type,
+        subject_id: params.subject_id,
+    };
+    let page = state
+        .db
+        .search_files(&query, params.offset as usize, params.limit as usize)
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+
+    let items: Vec<SearchResultResponse> = page
+        .hits
+        .iter()
+        .map(|hit| SearchResultResponse {
+            file: file_to_response(&hit.file),
+            score: hit.score,
+        })
+        .collect();
+
+    Ok(JSendPaginated::success(
+        items,
+        Pagination {
+            limit: params.limit,
+            next_cursor: None,
+            offset: params.offset,
+            total: Some(page.total),
+        },
+    ))
+}
+
+// ============================================================================
+// Helpers
+// ============================================================================
+
+/// Content and fields of a file to create
+pub(super) struct NewFile {
+    pub data: Bytes,
+    /// Name the content was uploaded with, for guessing its MIME type
+    pub file_name: Option<String>,
+    pub content_type: Option<String>,
+    pub permalink: String,
+    pub alt: Option<String>,
+    pub description: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub metadata: Option<HashMap<String, serde_json::Value>>,
+    pub name: Option<String>,
+    pub subject_id: Option<String>,
+    pub tags: Vec<String>,
+    pub visibility: Visibility,
+}
+
+/// Create a file: check its permalink is free and its MIME type allowed,
+/// upload its content and replicate its record.
+pub(super) async fn store_new_file(
+    state: &AppState,
+    namespace: &NamespaceConfig,
+    context: &AuditContext,
+    new: NewFile,
+) -> Result<FileRecord, ApiError> {
+    let NewFile {
+        data,
+        file_name,
+        content_type,
+        permalink,
+        alt,
+        description,
+        expires_at,
+        metadata,
+        name,
+        subject_id,
+        mut tags,
+        visibility,
+    } = new;
+
     if permalink.trim().is_empty() {
         return Err(ApiError::bad_request("permalink must not be empty"));
     }
 
-    // Check permalink uniqueness
+    // Check permalink uniqueness, including redirects from former permalinks
     if state
         .db
-        .permalink_exists(&permalink)
+        .permalink_in_use(&namespace.name, &permalink, None)
         .map_err(|e| ApiError::internal(e.to_string()))?
     {
         return Err(ApiError::conflict(format!(
@@ -196,96 +898,112 @@ pub async fn create_file(
         )));
     }
 
-    // Determine MIME type: from multipart Content-Type, or guess from filename, or fallback
-    let mime_type = file_content_type
-        .filter(|ct| ct != "application/octet-stream")
-        .or_else(|| {
-            file_name
-                .as_deref()
-                .and_then(|n| mime_guess::from_path(n).first())
-                .map(|m| m.to_string())
-        })
+    let mime_type = upload_mime_type(content_type, file_name.as_deref())
         .unwrap_or_else(|| "application/octet-stream".to_string());
+    ensure_mime_type_allowed(namespace, &mime_type)?;
 
     let file_type = FileType::from_mime(&mime_type);
-    let byte_size = file_data.len() as u64;
+    let byte_size = data.len() as u64;
+    let sha256 = sha256_hex(&data);
     let id = uuid::Uuid::new_v4().to_string();
+    let blob_key = namespace.blob_key(&id);
     let now = Utc::now();
+    let object_store = state.object_store_for(&namespace.name);
 
     // Phase 1: Upload bytes to object storage (keyed by UUID)
-    state
-        .object_store
-        .put(&id, file_data.freeze())
+    object_store
+        .put(&blob_key, data)
         .await
         .map_err(|e| ApiError::internal(format!("Failed to store file: {e}")))?;
 
     // Phase 2: Write metadata to redb via muster
     let file_record = FileRecord {
         id: id.clone(),
-        mime_type,
+        namespace: namespace.name.clone(),
+        mime_type: mime_type.clone(),
         file_type,
         byte_size,
         permalink: permalink.clone(),
         created_at: now,
         updated_at: now,
-        alt: alt.clone(),
-        description: description.clone(),
-        metadata: metadata.clone(),
-        name: name.clone(),
-        subject_id: subject_id.clone(),
+        revision: 1,
+        alt,
+        description,
+        expires_at,
+        metadata,
+        name,
+        subject_id,
+        tags: {
+            tags.sort();
+            tags.dedup();
+            tags
+        },
+        visibility,
+        versions: vec![FileVersion {
+            version: 1,
+            blob_key: blob_key.clone(),
+            byte_size,
+            mime_type,
+            sha256: Some(sha256),
+            created_at: now,
+        }],
+        deleted_at: None,
+        legal_hold: false,
+        retain_until: None,
     };
 
     let operation = WriteOp::CreateFile(file_record.clone());
-    if let Err(e) = state.node.replicate(operation).await {
+    if let Err(e) = state.node.replicate(operation.audited(context)).await {
         // Best-effort cleanup of the uploaded blob
-        let _ = state.object_store.delete(&id).await;
+        let _ = object_store.delete(&blob_key).await;
         return Err(replication_error(e));
     }
 
-    tracing::debug!(file_id = %id, permalink = %permalink, "Created file");
-
-    Ok(JSend::success(file_to_response(&file_record)))
+    tracing::debug!(file_id = %id, namespace = %namespace.name, permalink = %permalink, "Created file");
+    Ok(file_record)
 }
 
-pub async fn get_file(
-    State(state): State<Arc<AppState>>,
-    Path(id): Path<String>,
-) -> Result<Json<JSend<FileResponse>>, ApiError> {
-    let file = state
-        .db
-        .get_file(&id)
-        .map_err(|e| ApiError::internal(e.to_string()))?
-        .ok_or_else(|| ApiError::not_found("File not found"))?;
-
-    Ok(JSend::success(file_to_response(&file)))
-}
-
-pub async fn update_file(
-    State(state): State<Arc<AppState>>,
-    Path(id): Path<String>,
-    AppJson(req): AppJson<UpdateFileRequest>,
-) -> Result<Json<JSend<FileResponse>>, ApiError> {
-    // Validate at least one field is provided
+/// Reject updates that change nothing or expire the file in the past.
+pub(super) fn validate_update(req: &UpdateFileRequest) -> Result<(), ApiError> {
     if req.alt.is_none()
         && req.description.is_none()
+        && req.expires_at.is_none()
         && req.metadata.is_none()
         && req.name.is_none()
         && req.permalink.is_none()
         && req.subject_id.is_none()
+        && req.visibility.is_none()
     {
         return Err(ApiError::bad_request(
-            "at least one field (alt, description, metadata, name, permalink, subject_id) must be provided",
+            "at least one field (alt, description, expires_at, metadata, name, permalink, subject_id, visibility) must be provided",
         ));
     }
 
-    // Verify the file exists
-    let existing = state
-        .db
-        .get_file(&id)
-        .map_err(|e| ApiError::internal(e.to_string()))?
-        .ok_or_else(|| ApiError::not_found("File not found"))?;
+    if let Some(Some(expires_at)) = req.expires_at {
+        if expires_at <= Utc::now() {
+            return Err(ApiError::bad_request("expires_at must be in the future"));
+        }
+    }
+    Ok(())
+}
+
+/// The operation applying an update to a live file, once the file may be
+/// moved and its new permalink is free.
+pub(super) fn update_operation(
+    state: &AppState,
+    existing: &FileRecord,
+    req: &UpdateFileRequest,
+) -> Result<WriteOp, ApiError> {
+    if req
+        .permalink
+        .as_ref()
+        .is_some_and(|p| *p != existing.permalink)
+    {
+        ensure_unlocked(existing, "moved")?;
+    }
 
-    // If changing permalink, check uniqueness (allow keeping the same permalink)
+    // If changing permalink, check uniqueness (allow keeping the same permalink or
+    // moving back to one of the file's own redirects)
     if let Some(ref new_permalink) = req.permalink {
         if new_permalink.trim().is_empty() {
             return Err(ApiError::bad_request("permalink must not be empty"));
@@ -293,7 +1011,7 @@ pub async fn update_file(
         if *new_permalink != existing.permalink
             && state
                 .db
-                .permalink_exists(new_permalink)
+                .permalink_in_use(&existing.namespace, new_permalink, Some(&existing.id))
                 .map_err(|e| ApiError::internal(e.to_string()))?
         {
             return Err(ApiError::conflict(format!(
@@ -302,110 +1020,152 @@ pub async fn update_file(
         }
     }
 
-    let operation = WriteOp::UpdateFile {
-        id: id.clone(),
+    Ok(WriteOp::UpdateFile {
+        id: existing.id.clone(),
         alt: Patch::from(req.alt.clone()),
         description: Patch::from(req.description.clone()),
+        expires_at: Patch::from(req.expires_at),
         metadata: Patch::from(req.metadata.clone()),
         name: Patch::from(req.name.clone()),
         permalink: req.permalink.clone(),
         subject_id: Patch::from(req.subject_id.clone()),
-    };
-    state
-        .node
-        .replicate(operation)
-        .await
-        .map_err(replication_error)?;
-
-    let file = state
-        .db
-        .get_file(&id)
-        .map_err(|e| ApiError::internal(e.to_string()))?
-        .ok_or_else(|| ApiError::internal("File not found after update"))?;
-
-    tracing::debug!(file_id = %id, "Updated file");
-    Ok(JSend::success(file_to_response(&file)))
+        visibility: req.visibility,
+    })
 }
 
-pub async fn delete_file(
-    State(state): State<Arc<AppState>>,
-    Path(id): Path<String>,
-) -> Result<Json<JSend<()>>, ApiError> {
-    // Verify the file exists
-    state
-        .db
-        .get_file(&id)
-        .map_err(|e| ApiError::internal(e.to_string()))?
-        .ok_or_else(|| ApiError::not_found("File not found"))?;
-
-    // Phase 1: Remove metadata via muster
-    let operation = WriteOp::DeleteFile { id: id.clone() };
-    state
-        .node
-        .replicate(operation)
-        .await
-        .map_err(replication_error)?;
-
-    // Phase 2: Delete blob from object storage (best-effort)
-    if let Err(e) = state.object_store.delete(&id).await {
-        tracing::warn!(file_id = %id, error = %e, "Failed to delete file from object storage");
+pub(super) fn normalize_tags(tags: &[String]) -> Result<Vec<String>, ApiError> {
+    if tags.is_empty() {
+        return Err(ApiError::bad_request("tags must not be empty"));
     }
-
-    tracing::debug!(file_id = %id, "Deleted file");
-    Ok(JSend::success(()))
+    tags.iter()
+        .map(|tag| normalize_tag(tag).map_err(ApiError::bad_request))
+        .collect()
 }
 
-pub async fn list_files(
-    State(state): State<Arc<AppState>>,
-    AppQuery(params): AppQuery<ListFilesParams>,
-) -> Result<Json<JSendPaginated<FileResponse>>, ApiError> {
-    if params.limit == 0 {
-        return Err(ApiError::bad_request("limit must be greater than 0"));
-    }
-
-    match state
-        .db
-        .list_files(params.file_type.as_deref(), params.subject_id.as_deref())
-    {
-        Ok(files) => {
-            let total = files.len() as u64;
-            let items: Vec<FileResponse> = files
-                .iter()
-                .skip(params.offset as usize)
-                .take(params.limit as usize)
-                .map(file_to_response)
-                .collect();
-
-            Ok(JSendPaginated::success(
-                items,
-                Pagination {
-                    limit: params.limit,
-                    offset: params.offset,
-                    total,
-                },
-            ))
-        }
-        Err(e) => Err(ApiError::internal(e.to_string())),
-    }
-}
-
-// ============================================================================
-// Helpers
-// ============================================================================
-
-fn file_to_response(file: &FileRecord) -> FileResponse {
+pub(super) fn file_to_response(file: &FileRecord) -> FileResponse {
     FileResponse {
         alt: file.alt.clone(),
         byte_size: file.byte_size,
         created_at: file.created_at.to_rfc3339(),
+        deleted_at: file.deleted_at.map(|t| t.to_rfc3339()),
         description: file.description.clone(),
+        expires_at: file.expires_at.map(|t| t.to_rfc3339()),
         file_type: file.file_type,
         id: file.id.clone(),
+        legal_hold: file.legal_hold,
         metadata: file.metadata.clone(),
         mime_type: file.mime_type.clone(),
         name: file.name.clone(),
+        namespace: file.namespace.clone(),
         permalink: file.permalink.clone(),
+        retain_until: file.retain_until.map(|t| t.to_rfc3339()),
+        revision: file.revision,
         subject_id: file.subject_id.clone(),
+        tags: file.tags.clone(),
         updated_at: file.updated_at.to_rfc3339(),
+        version: file.current_version().version,
+        visibility: file.visibility,
+    }
+}
+
+/// Load a file of a namespace. Files of other namespaces are treated as
+/// missing, so tenants can't see each other's files by ID.
+pub(super) fn get_namespaced_file(
+    state: &AppState,
+    namespace: &str,
+    id: &str,
+) -> Result<FileRecord, ApiError> {
+    state
+        .db
+        .get_file(id)
+        .map_err(|e| ApiError::internal(e.to_string()))?
+        .filter(|file| file.namespace == namespace)
+        .ok_or_else(|| ApiError::not_found("File not found"))
+}
+
+/// Load a file for modification, treating trashed files as missing.
+pub(super) fn get_live_file(
+    state: &AppState,
+    namespace: &str,
+    id: &str,
+) -> Result<FileRecord, ApiError> {
+    Some(get_namespaced_file(state, namespace, id)?)
+        .filter(|file| file.deleted_at.is_none())
+        .ok_or_else(|| ApiError::not_found("File not found"))
+}
+
+/// Reject uploads of MIME types the namespace doesn't allow.
+pub(super) fn ensure_mime_type_allowed(
+    namespace: &NamespaceConfig,
+    mime_type: &str,
+) -> Result<(), ApiError> {
+    if namespace.allows_mime_type(mime_type) {
+        Ok(())
+    } else {
+        Err(ApiError::unsupported_media_type(format!(
+            "Files of type {mime_type} are not allowed in namespace '{}'",
+            namespace.name
+        )))
+    }
+}
+
+/// Reject `action` on a file under legal hold or retention.
+pub(super) fn ensure_unlocked(file: &FileRecord, action: &str) -> Result<(), ApiError> {
+    match file.lock(Utc::now()) {
+        Some(lock) => Err(ApiError::locked(format!(
+            "File {} is {lock} and cannot be {action}",
+            file.id
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Parse an RFC 3339 expiry time, which must be in the future.
+fn parse_expires_at(text: &str) -> Result<DateTime<Utc>, ApiError> {
+    let expires_at = DateTime::parse_from_rfc3339(text.trim())
+        .map_err(|e| ApiError::bad_request(format!("expires_at must be an RFC 3339 time: {e}")))?
+        .with_timezone(&Utc);
+    if expires_at <= Utc::now() {
+        return Err(ApiError::bad_request("expires_at must be in the future"));
     }
+    Ok(expires_at)
+}
+
+/// Read an uploaded file field, enforcing the maximum upload size.
+pub(super) async fn read_upload(field: Field<'_>, max_upload_size: u64) -> Result<Bytes, ApiError> {
+    let data = field
+        .bytes()
+        .await
+        .map_err(|e| ApiError::bad_request(format!("Failed to read file: {e}")))?;
+
+    if data.len() as u64 > max_upload_size {
+        return Err(ApiError::payload_too_large(format!(
+            "File exceeds maximum upload size of {max_upload_size} bytes"
+        )));
+    }
+    Ok(data)
+}
+
+/// Determine an upload's MIME type from its multipart Content-Type, or guess it
+/// from the filename.
+pub(super) fn upload_mime_type(
+    content_type: Option<String>,
+    file_name: Option<&str>,
+) -> Option<String> {
+    content_type
+        .filter(|ct| ct != "application/octet-stream")
+        .or_else(|| {
+            file_name
+                .and_then(|n| mime_guess::from_path(n).first())
+                .map(|m| m.to_string())
+        })
+}
+
+/// Hex-encoded SHA-256 digest of file content.
+pub(super) fn sha256_hex(data: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, data)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
 }
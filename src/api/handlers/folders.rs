@@ -0,0 +1,101 @@
+use axum::extract::State;
+use axum::Json;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use super::files::{file_to_response, FileResponse};
+use crate::api::namespace::Namespace;
+use crate::api::response::{ApiError, AppQuery, JSend};
+use crate::AppState;
+
+// ============================================================================
+// Types
+// ============================================================================
+
+#[derive(Debug, Deserialize)]
+pub struct ListFolderParams {
+    /// Permalink prefix to list, usually ending with the delimiter
+    #[serde(default)]
+    pub prefix: String,
+    /// Collapses deeper permalinks into common prefixes. Empty lists recursively.
+    #[serde(default = "default_delimiter")]
+    pub delimiter: String,
+    #[serde(default = "default_limit")]
+    pub limit: u32,
+    /// Opaque cursor from a previous page's `next_cursor`
+    #[serde(default)]
+    pub after: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FolderResponse {
+    pub delimiter: String,
+    pub files: Vec<FileResponse>,
+    pub next_cursor: Option<String>,
+    pub prefix: String,
+    pub prefixes: Vec<String>,
+}
+
+fn default_delimiter() -> String {
+    "/".to_string()
+}
+
+fn default_limit() -> u32 {
+    100
+}
+
+// ============================================================================
+// Handlers
+// ============================================================================
+
+/// List the files and sub-folders directly under a permalink prefix.
+pub async fn list_folder(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+    AppQuery(params): AppQuery<ListFolderParams>,
+) -> Result<Json<JSend<FolderResponse>>, ApiError> {
+    if params.limit == 0 {
+        return Err(ApiError::bad_request("limit must be greater than 0"));
+    }
+
+    let after = params
+        .after
+        .as_deref()
+        .map(|token| decode_cursor(token).ok_or_else(|| ApiError::bad_request("Invalid cursor")))
+        .transpose()?;
+
+    let page = state
+        .db
+        .list_folder(
+            &namespace.name,
+            &params.prefix,
+            Some(params.delimiter.as_str()),
+            after.as_deref(),
+            params.limit as usize,
+        )
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+
+    Ok(JSend::success(FolderResponse {
+        delimiter: params.delimiter,
+        files: page.files.iter().map(file_to_response).collect(),
+        next_cursor: page.next_after.as_deref().map(encode_cursor),
+        prefix: params.prefix,
+        prefixes: page.prefixes,
+    }))
+}
+
+// ============================================================================
+// Helpers
+// ============================================================================
+
+fn encode_cursor(after: &str) -> String {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(after)
+}
+
+fn decode_cursor(token: &str) -> Option<String> {
+    let raw = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(token)
+        .ok()?;
+    String::from_utf8(raw).ok()
+}
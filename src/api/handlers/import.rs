@@ -0,0 +1,322 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use axum::extract::multipart::Field;
+use axum::extract::{Multipart, State};
+use axum::http::StatusCode;
+use axum::Json;
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+
+use super::files::{file_to_response, store_new_file, FileResponse, NewFile};
+use crate::api::context::RequestContext;
+use crate::api::import::{entry_permalink, ArchiveEntry, ArchiveReader, EntryContent};
+use crate::api::namespace::Namespace;
+use crate::api::response::{ApiError, JSend};
+use crate::config::NamespaceConfig;
+use crate::storage::models::{normalize_tag, AuditContext, Visibility};
+use crate::AppState;
+
+// ============================================================================
+// Types
+// ============================================================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportStatus {
+    Created,
+    /// The permalink is already in use
+    Conflict,
+    /// Larger than the namespace's maximum upload size
+    TooLarge,
+    /// The namespace doesn't allow files of its MIME type
+    UnsupportedMediaType,
+    /// A link or other entry that isn't a file
+    NotAFile,
+    /// A path that can't be a permalink, or corrupt content
+    Invalid,
+    /// Storing the file failed
+    Failed,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportResult {
+    pub error: Option<String>,
+    /// The created file
+    pub file: Option<FileResponse>,
+    /// Path of the entry in the archive
+    pub path: String,
+    pub permalink: Option<String>,
+    pub status: ImportStatus,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportArchiveResponse {
+    pub created: u64,
+    /// Why the rest of the archive couldn't be read, if it couldn't. The
+    /// entries before it are in `results`.
+    pub error: Option<String>,
+    pub failed: u64,
+    pub results: Vec<ImportResult>,
+}
+
+/// Fields applied to every file of an import
+struct SharedFields {
+    prefix: String,
+    metadata: Option<HashMap<String, serde_json::Value>>,
+    subject_id: Option<String>,
+    tags: Vec<String>,
+    visibility: Visibility,
+}
+
+/// An uploaded archive spooled to disk, deleted when dropped
+struct SpooledArchive {
+    path: PathBuf,
+}
+
+impl Drop for SpooledArchive {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// ============================================================================
+// Handlers
+// ============================================================================
+
+/// Create a file for every file of a ZIP or tar archive, at its path under
+/// `prefix`, each the same way as `POST /files`. Entries that can't be
+/// imported don't stop the others; each gets its own result.
+/// Route: POST /files/import-archive
+pub async fn import_archive(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+    RequestContext(context): RequestContext,
+    mut multipart: Multipart,
+) -> Result<Json<JSend<ImportArchiveResponse>>, ApiError> {
+    let mut archive: Option<SpooledArchive> = None;
+    let mut shared = SharedFields {
+        prefix: String::new(),
+        metadata: None,
+        subject_id: None,
+        tags: Vec::new(),
+        visibility: namespace.default_visibility,
+    };
+
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| ApiError::bad_request(format!("Invalid multipart data: {e}")))?
+    {
+        let field_name = field.name().unwrap_or("").to_string();
+
+        match field_name.as_str() {
+            "archive" => {
+                archive = Some(spool_archive(field, state.config.max_import_size).await?);
+            }
+            "prefix" => {
+                shared.prefix = field
+                    .text()
+                    .await
+                    .map_err(|e| ApiError::bad_request(format!("Invalid prefix: {e}")))?;
+            }
+            "subject_id" => {
+                shared.subject_id = Some(
+                    field
+                        .text()
+                        .await
+                        .map_err(|e| ApiError::bad_request(format!("Invalid subject_id: {e}")))?,
+                );
+            }
+            "tags" => {
+                let text = field
+                    .text()
+                    .await
+                    .map_err(|e| ApiError::bad_request(format!("Invalid tags: {e}")))?;
+                for tag in text.split(',') {
+                    shared
+                        .tags
+                        .push(normalize_tag(tag).map_err(ApiError::bad_request)?);
+                }
+            }
+            "visibility" => {
+                let text = field
+                    .text()
+                    .await
+                    .map_err(|e| ApiError::bad_request(format!("Invalid visibility: {e}")))?;
+                shared.visibility = text.trim().parse().map_err(ApiError::bad_request)?;
+            }
+            "metadata" => {
+                let text = field
+                    .text()
+                    .await
+                    .map_err(|e| ApiError::bad_request(format!("Invalid metadata: {e}")))?;
+                let parsed: HashMap<String, serde_json::Value> = serde_json::from_str(&text)
+                    .map_err(|e| {
+                        ApiError::bad_request(format!("metadata must be a JSON object: {e}"))
+                    })?;
+                shared.metadata = Some(parsed);
+            }
+            _ => {
+                // Ignore unknown fields
+            }
+        }
+    }
+
+    let archive = archive.ok_or_else(|| ApiError::bad_request("archive field is required"))?;
+    let mut reader = ArchiveReader::open(&archive.path)
+        .await
+        .map_err(|e| ApiError::bad_request(format!("Invalid archive: {e}")))?;
+
+    let mut results = Vec::new();
+    let mut error = None;
+    loop {
+        let entry = match reader.next_entry(namespace.max_upload_size).await {
+            Ok(Some(entry)) => entry,
+            Ok(None) => break,
+            Err(e) => {
+                error = Some(format!("Failed to read archive: {e}"));
+                break;
+            }
+        };
+        results.push(import_entry(&state, &namespace, &context, &shared, entry).await);
+    }
+
+    let created = results
+        .iter()
+        .filter(|r| r.status == ImportStatus::Created)
+        .count() as u64;
+    let failed = results.len() as u64 - created;
+    tracing::info!(
+        namespace = %namespace.name,
+        prefix = %shared.prefix,
+        created,
+        failed,
+        "Imported archive"
+    );
+
+    Ok(JSend::success(ImportArchiveResponse {
+        created,
+        error,
+        failed,
+        results,
+    }))
+}
+
+// ============================================================================
+// Helpers
+// ============================================================================
+
+/// Write an uploaded archive to a temporary file, enforcing the maximum
+/// import size.
+async fn spool_archive(mut field: Field<'_>, max_size: u64) -> Result<SpooledArchive, ApiError> {
+    let archive = SpooledArchive {
+        path: std::env::temp_dir().join(format!("file-manager-import-{}", uuid::Uuid::new_v4())),
+    };
+    let mut file = tokio::fs::File::create(&archive.path)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to spool archive: {e}")))?;
+
+    let mut size = 0;
+    while let Some(chunk) = field
+        .chunk()
+        .await
+        .map_err(|e| ApiError::bad_request(format!("Failed to read archive: {e}")))?
+    {
+        size += chunk.len() as u64;
+        if size > max_size {
+            return Err(ApiError::payload_too_large(format!(
+                "Archive exceeds maximum import size of {max_size} bytes"
+            )));
+        }
+        file.write_all(&chunk)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to spool archive: {e}")))?;
+    }
+    file.flush()
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to spool archive: {e}")))?;
+    Ok(archive)
+}
+
+/// Create the file of one archive entry, reporting why it wasn't if it wasn't.
+async fn import_entry(
+    state: &AppState,
+    namespace: &NamespaceConfig,
+    context: &AuditContext,
+    shared: &SharedFields,
+    entry: ArchiveEntry,
+) -> ImportResult {
+    let ArchiveEntry { path, content } = entry;
+    let permalink = match entry_permalink(&shared.prefix, &path) {
+        Ok(permalink) => permalink,
+        Err(e) => return rejected(path, None, ImportStatus::Invalid, e),
+    };
+    let data = match content {
+        EntryContent::File(data) => data,
+        EntryContent::TooLarge(size) => {
+            let message = format!(
+                "File of {size} bytes exceeds maximum upload size of {} bytes",
+                namespace.max_upload_size
+            );
+            return rejected(path, Some(permalink), ImportStatus::TooLarge, message);
+        }
+        EntryContent::NotAFile => {
+            let message = "Only files can be imported".to_string();
+            return rejected(path, Some(permalink), ImportStatus::NotAFile, message);
+        }
+        EntryContent::Invalid(e) => {
+            return rejected(path, Some(permalink), ImportStatus::Invalid, e);
+        }
+    };
+
+    let new_file = NewFile {
+        data,
+        file_name: Some(path.clone()),
+        content_type: None,
+        permalink: permalink.clone(),
+        alt: None,
+        description: None,
+        expires_at: None,
+        metadata: shared.metadata.clone(),
+        name: None,
+        subject_id: shared.subject_id.clone(),
+        tags: shared.tags.clone(),
+        visibility: shared.visibility,
+    };
+    match store_new_file(state, namespace, context, new_file).await {
+        Ok(file) => ImportResult {
+            error: None,
+            file: Some(file_to_response(&file)),
+            path,
+            permalink: Some(permalink),
+            status: ImportStatus::Created,
+        },
+        Err(ApiError::Fail(code, message) | ApiError::Error(code, message)) => {
+            let status = match code {
+                StatusCode::CONFLICT => ImportStatus::Conflict,
+                StatusCode::PAYLOAD_TOO_LARGE => ImportStatus::TooLarge,
+                StatusCode::UNSUPPORTED_MEDIA_TYPE => ImportStatus::UnsupportedMediaType,
+                StatusCode::BAD_REQUEST => ImportStatus::Invalid,
+                _ => ImportStatus::Failed,
+            };
+            rejected(path, Some(permalink), status, message)
+        }
+    }
+}
+
+fn rejected(
+    path: String,
+    permalink: Option<String>,
+    status: ImportStatus,
+    error: String,
+) -> ImportResult {
+    ImportResult {
+        error: Some(error),
+        file: None,
+        path,
+        permalink,
+        status,
+    }
+}
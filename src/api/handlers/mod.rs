@@ -1,15 +1,41 @@
 mod admin;
+mod audit;
+mod batch;
+mod changes;
 mod files;
+mod folders;
+mod import;
+mod redirects;
 mod static_files;
+mod subjects;
+mod tags;
+mod versions;
+mod webhooks;
 
 use crate::api::response::ApiError;
+use crate::AppState;
 
-pub use admin::{admin_purge, cluster_status, health};
-pub use files::{create_file, delete_file, get_file, list_files, update_file};
+pub use admin::{admin_purge, cluster_status, health, set_retention};
+pub use audit::{list_audit, list_file_audit};
+pub use batch::{batch_files, batch_get_files};
+pub use changes::{list_changes, stream_changes};
+pub use files::{
+    add_tags, create_file, delete_file, get_file, list_files, list_trash, remove_tag,
+    rename_prefix, restore_file, search_files, update_file,
+};
+pub use folders::list_folder;
+pub use import::import_archive;
+pub use redirects::{delete_redirect, list_redirects};
 pub use static_files::serve_static;
+pub use subjects::{erase_subject_files, export_subject_files};
+pub use tags::list_tags;
+pub use versions::{list_versions, restore_version, upload_version};
+pub use webhooks::{
+    create_webhook, delete_webhook, get_webhook, list_deliveries, list_webhooks, redeliver,
+};
 
 /// Map a MusterError to an ApiError
-fn replication_error(e: muster::MusterError) -> ApiError {
+pub(crate) fn replication_error(e: muster::MusterError) -> ApiError {
     match e {
         muster::MusterError::NotLeader { .. } => {
             ApiError::unavailable("No leader available — retry shortly")
@@ -20,3 +46,62 @@ fn replication_error(e: muster::MusterError) -> ApiError {
         _ => ApiError::internal(e.to_string()),
     }
 }
+
+/// Map a failed replication of an operation on the files `ids` to an
+/// ApiError. Apply checks legal holds and retention again, so a lock placed
+/// after the handler's own check is reported as such rather than as a
+/// replication failure.
+fn lock_or_replication_error(
+    state: &AppState,
+    ids: &[&str],
+    action: &str,
+    e: muster::MusterError,
+) -> ApiError {
+    for id in ids {
+        match state.db.get_file(id) {
+            Ok(Some(file)) => {
+                if let Err(locked) = files::ensure_unlocked(&file, action) {
+                    return locked;
+                }
+            }
+            Ok(None) => {}
+            Err(e) => return ApiError::internal(e.to_string()),
+        }
+    }
+    replication_error(e)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+
+    use super::lock_or_replication_error;
+    use crate::api::response::ApiError;
+    use crate::storage::models::FileRecord;
+    use crate::testutil::{sample_file, test_state};
+
+    #[tokio::test]
+    async fn test_lock_or_replication_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(&dir);
+        state.db.put_file(&sample_file("free")).unwrap();
+        state
+            .db
+            .put_file(&FileRecord {
+                legal_hold: true,
+                ..sample_file("held")
+            })
+            .unwrap();
+
+        let status = |ids: &[&str]| match lock_or_replication_error(
+            &state,
+            ids,
+            "deleted",
+            muster::MusterError::NoQuorum,
+        ) {
+            ApiError::Fail(status, _) | ApiError::Error(status, _) => status,
+        };
+        assert_eq!(status(&["free", "held"]), StatusCode::LOCKED);
+        assert_eq!(status(&["free", "gone"]), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
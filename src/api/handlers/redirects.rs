@@ -0,0 +1,121 @@
+use axum::extract::{Path, State};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use super::replication_error;
+use crate::api::context::RequestContext;
+use crate::api::namespace::Namespace;
+use crate::api::response::{ApiError, AppQuery, JSend};
+use crate::storage::models::{Redirect, WriteOp};
+use crate::AppState;
+
+// ============================================================================
+// Types
+// ============================================================================
+
+#[derive(Debug, Deserialize)]
+pub struct ListRedirectsParams {
+    /// Only list redirects to this file
+    #[serde(default)]
+    pub file_id: Option<String>,
+    #[serde(default = "default_limit")]
+    pub limit: u32,
+    /// Permalink to resume after, from a previous page's `next_after`
+    #[serde(default)]
+    pub after: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RedirectResponse {
+    pub file_id: String,
+    pub permalink: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ListRedirectsResponse {
+    pub next_after: Option<String>,
+    pub redirects: Vec<RedirectResponse>,
+}
+
+fn default_limit() -> u32 {
+    100
+}
+
+// ============================================================================
+// Handlers
+// ============================================================================
+
+/// List former permalinks that redirect to a file, in permalink order.
+pub async fn list_redirects(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+    AppQuery(params): AppQuery<ListRedirectsParams>,
+) -> Result<Json<JSend<ListRedirectsResponse>>, ApiError> {
+    if params.limit == 0 {
+        return Err(ApiError::bad_request("limit must be greater than 0"));
+    }
+    let limit = params.limit as usize;
+
+    // Fetch one extra to tell whether another page follows
+    let mut redirects = state
+        .db
+        .list_redirects(
+            &namespace.name,
+            params.file_id.as_deref(),
+            params.after.as_deref(),
+            limit + 1,
+        )
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+
+    let next_after = if redirects.len() > limit {
+        redirects.truncate(limit);
+        redirects.last().map(|r| r.permalink.clone())
+    } else {
+        None
+    };
+
+    Ok(JSend::success(ListRedirectsResponse {
+        next_after,
+        redirects: redirects.into_iter().map(redirect_to_response).collect(),
+    }))
+}
+
+/// Stop redirecting a former permalink, freeing it for reuse.
+/// Route: DELETE /redirects/*permalink
+pub async fn delete_redirect(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+    RequestContext(context): RequestContext,
+    Path(permalink): Path<String>,
+) -> Result<Json<JSend<RedirectResponse>>, ApiError> {
+    let file_id = state
+        .db
+        .get_redirect(&namespace.name, &permalink)
+        .map_err(|e| ApiError::internal(e.to_string()))?
+        .ok_or_else(|| ApiError::not_found("Redirect not found"))?;
+
+    let operation = WriteOp::DeleteRedirect {
+        namespace: namespace.name.clone(),
+        permalink: permalink.clone(),
+    };
+    state
+        .node
+        .replicate(operation.audited(&context))
+        .await
+        .map_err(replication_error)?;
+
+    tracing::debug!(namespace = %namespace.name, permalink = %permalink, file_id = %file_id, "Deleted redirect");
+    Ok(JSend::success(RedirectResponse { file_id, permalink }))
+}
+
+// ============================================================================
+// Helpers
+// ============================================================================
+
+fn redirect_to_response(redirect: Redirect) -> RedirectResponse {
+    RedirectResponse {
+        file_id: redirect.file_id,
+        permalink: redirect.permalink,
+    }
+}
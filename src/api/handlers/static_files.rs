@@ -1,28 +1,69 @@
-use axum::extract::State;
+use axum::extract::{RawQuery, State};
 use axum::http::{header, StatusCode};
 use axum::response::{IntoResponse, Response};
+use chrono::Utc;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use serde::Deserialize;
 use std::sync::Arc;
 
-use crate::api::response::ApiError;
+use crate::api::namespace::{route_prefix, Namespace};
+use crate::api::response::{ApiError, AppQuery};
+use crate::storage::models::{FileRecord, Visibility};
 use crate::AppState;
 
-/// Serve file content by permalink.
+/// Characters escaped in each permalink segment of a redirect Location
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+#[derive(Debug, Deserialize)]
+pub struct StaticParams {
+    /// Serve this content version instead of the current one
+    #[serde(default)]
+    pub version: Option<u32>,
+}
+
+/// Serve public file content by permalink.
+/// Former permalinks of a file are redirected permanently to its current one.
 /// Route: GET /static/*permalink
 pub async fn serve_static(
     State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
     axum::extract::Path(permalink): axum::extract::Path<String>,
+    AppQuery(params): AppQuery<StaticParams>,
+    RawQuery(query): RawQuery,
 ) -> Result<Response, ApiError> {
     // Look up file metadata by permalink
-    let file = state
+    let file = match state
         .db
-        .get_file_by_permalink(&permalink)
+        .get_file_by_permalink(&namespace.name, &permalink)
         .map_err(|e| ApiError::internal(e.to_string()))?
-        .ok_or_else(|| ApiError::not_found("File not found"))?;
+    {
+        Some(file) if is_servable(&file) => file,
+        Some(_) => return Err(ApiError::not_found("File not found")),
+        None => return redirect_to_current(&state, &namespace.name, &permalink, query.as_deref()),
+    };
+
+    let version = match params.version {
+        Some(version) => file
+            .version(version)
+            .ok_or_else(|| ApiError::not_found("Version not found"))?,
+        None => file.current_version(),
+    };
 
     // Fetch content from object storage
     let data = state
-        .object_store
-        .get(&file.id)
+        .object_store_for(&file.namespace)
+        .get(&version.blob_key)
         .await
         .map_err(|e| match e {
             crate::object_store::ObjectStoreError::NotFound(_) => {
@@ -37,14 +78,15 @@ pub async fn serve_static(
 
     headers.insert(
         header::CONTENT_TYPE,
-        file.mime_type
+        version
+            .mime_type
             .parse()
             .unwrap_or(header::HeaderValue::from_static("application/octet-stream")),
     );
 
     headers.insert(
         header::CONTENT_LENGTH,
-        header::HeaderValue::from(file.byte_size),
+        header::HeaderValue::from(version.byte_size),
     );
 
     // Set Content-Disposition with filename from the permalink's last segment
@@ -53,11 +95,61 @@ pub async fn serve_static(
         headers.insert(header::CONTENT_DISPOSITION, value);
     }
 
-    // Cache for 1 hour (files are immutable once uploaded, only metadata changes)
+    // A version's content never changes, but the current version can be replaced
+    let cache_control = if params.version.is_some() {
+        "public, max-age=31536000, immutable"
+    } else {
+        "public, max-age=3600"
+    };
     headers.insert(
         header::CACHE_CONTROL,
-        header::HeaderValue::from_static("public, max-age=3600"),
+        header::HeaderValue::from_static(cache_control),
     );
 
     Ok(response)
 }
+
+/// Redirect a former permalink to the current permalink of its file, or 404.
+fn redirect_to_current(
+    state: &AppState,
+    namespace: &str,
+    permalink: &str,
+    query: Option<&str>,
+) -> Result<Response, ApiError> {
+    let file = state
+        .db
+        .get_redirect(namespace, permalink)
+        .and_then(|id| match id {
+            Some(id) => state.db.get_file(&id),
+            None => Ok(None),
+        })
+        .map_err(|e| ApiError::internal(e.to_string()))?
+        .filter(is_servable)
+        .ok_or_else(|| ApiError::not_found("File not found"))?;
+
+    let mut location = format!("{}/static", route_prefix(namespace));
+    for segment in file.permalink.split('/') {
+        location.push('/');
+        location.extend(utf8_percent_encode(segment, PATH_SEGMENT));
+    }
+    if let Some(query) = query {
+        location.push('?');
+        location.push_str(query);
+    }
+
+    let location = header::HeaderValue::try_from(location)
+        .map_err(|e| ApiError::internal(format!("Invalid redirect location: {e}")))?;
+    Ok((
+        StatusCode::MOVED_PERMANENTLY,
+        [(header::LOCATION, location)],
+    )
+        .into_response())
+}
+
+/// Trashed and expired files are gone, even before they are purged or reaped.
+/// Private files are only available through the API.
+fn is_servable(file: &FileRecord) -> bool {
+    file.deleted_at.is_none()
+        && !file.is_expired(Utc::now())
+        && file.visibility == Visibility::Public
+}
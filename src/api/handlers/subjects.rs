@@ -0,0 +1,297 @@
+use axum::body::Body;
+use axum::extract::{Path, State};
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use chrono::Utc;
+use futures_util::StreamExt;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::oneshot;
+use tokio_util::io::ReaderStream;
+
+use super::replication_error;
+use crate::api::context::RequestContext;
+use crate::api::export::write_export;
+use crate::api::namespace::Namespace;
+use crate::api::response::{ApiError, AppQuery, JSend};
+use crate::storage::models::{AuditContext, Erasure, FileRecord, WriteOp};
+use crate::AppState;
+
+/// Files deleted per replicated step of an erasure
+const ERASE_STEP_SIZE: usize = 100;
+
+/// Bytes of an export buffered ahead of the client
+const EXPORT_BUFFER_SIZE: usize = 64 * 1024;
+
+// ============================================================================
+// Types
+// ============================================================================
+
+#[derive(Debug, Deserialize)]
+pub struct EraseSubjectParams {
+    /// Also erase the subject's files in the trash. Ignored when resuming.
+    #[serde(default)]
+    pub include_trashed: bool,
+    /// Most files to erase in this call
+    #[serde(default = "default_limit")]
+    pub limit: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErasureResponse {
+    pub complete: bool,
+    /// Files left to erase by calling again
+    pub remaining: u64,
+    /// The erasure report: what has been deleted so far
+    pub report: serde_json::Value,
+    /// `sha256=<hex HMAC-SHA256>` of the report, once complete
+    pub signature: Option<String>,
+}
+
+fn default_limit() -> u32 {
+    1000
+}
+
+// ============================================================================
+// Handlers
+// ============================================================================
+
+/// Permanently delete every file of a subject, with the content of all its
+/// versions, for right-to-erasure requests. Erases up to `limit` files per
+/// call; calling again resumes an erasure that isn't complete.
+/// Route: DELETE /subjects/:subject_id/files (only when ERASURE_SIGNING_KEY is set)
+pub async fn erase_subject_files(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+    RequestContext(context): RequestContext,
+    Path(subject_id): Path<String>,
+    AppQuery(params): AppQuery<EraseSubjectParams>,
+) -> Result<Json<JSend<ErasureResponse>>, ApiError> {
+    if params.limit == 0 {
+        return Err(ApiError::bad_request("limit must be greater than 0"));
+    }
+    let key = state
+        .config
+        .erasure_signing_key
+        .clone()
+        .ok_or_else(|| ApiError::not_found("Subject erasure is disabled"))?;
+
+    let erasure = match state
+        .db
+        .get_open_erasure(&namespace.name, &subject_id)
+        .map_err(|e| ApiError::internal(e.to_string()))?
+    {
+        Some(erasure) => erasure,
+        None => {
+            let erasure = Erasure {
+                id: uuid::Uuid::new_v4().to_string(),
+                namespace: namespace.name.clone(),
+                subject_id: subject_id.clone(),
+                include_trashed: params.include_trashed,
+                started_at: Utc::now(),
+                completed_at: None,
+                erased: Vec::new(),
+                retained: Vec::new(),
+                pending_content: Vec::new(),
+            };
+            state
+                .node
+                .replicate(WriteOp::StartErasure(erasure.clone()).audited(&context))
+                .await
+                .map_err(replication_error)?;
+            erasure
+        }
+    };
+
+    // Content left behind by an interrupted call goes first
+    delete_erased_content(&state, &context, &erasure.id).await?;
+
+    let erasable = subject_files(&state, &erasure)?.1;
+    let limit = params.limit as usize;
+    for step in erasable[..erasable.len().min(limit)].chunks(ERASE_STEP_SIZE) {
+        // Records go first. Apply skips files locked or moved since they were
+        // read, and reports the content of those it erased as pending.
+        let operation = WriteOp::EraseFiles {
+            erasure_id: erasure.id.clone(),
+            ids: step.iter().map(|f| f.id.clone()).collect(),
+            erased_at: Utc::now(),
+        };
+        state
+            .node
+            .replicate(operation.audited(&context))
+            .await
+            .map_err(replication_error)?;
+        delete_erased_content(&state, &context, &erasure.id).await?;
+    }
+
+    let (retained, erasable) = subject_files(&state, &erasure)?;
+    let remaining = erasable.len();
+    if remaining == 0 {
+        let operation = WriteOp::CompleteErasure {
+            erasure_id: erasure.id.clone(),
+            retained: retained.iter().map(|f| f.id.clone()).collect(),
+            completed_at: Utc::now(),
+        };
+        state
+            .node
+            .replicate(operation.audited(&context))
+            .await
+            .map_err(replication_error)?;
+    }
+
+    let erasure = state
+        .db
+        .get_erasure(&erasure.id)
+        .map_err(|e| ApiError::internal(e.to_string()))?
+        .ok_or_else(|| ApiError::internal("Erasure not found after applying"))?;
+    let complete = erasure.completed_at.is_some();
+    // Objects serialize with sorted keys, so the report's compact JSON is canonical
+    let report = serde_json::to_value(&erasure).map_err(|e| ApiError::internal(e.to_string()))?;
+    let signature = complete.then(|| sign_report(&key, &report.to_string()));
+
+    tracing::info!(
+        erasure_id = %erasure.id,
+        namespace = %namespace.name,
+        erased = erasure.erased.len(),
+        remaining,
+        complete,
+        "Erased subject files"
+    );
+    Ok(JSend::success(ErasureResponse {
+        complete,
+        remaining: remaining as u64,
+        report,
+        signature,
+    }))
+}
+
+/// The subject files of an erasure, split into those under legal hold or
+/// retention and those left to erase.
+fn subject_files(
+    state: &AppState,
+    erasure: &Erasure,
+) -> Result<(Vec<FileRecord>, Vec<FileRecord>), ApiError> {
+    let now = Utc::now();
+    Ok(state
+        .db
+        .get_subject_files(
+            &erasure.namespace,
+            &erasure.subject_id,
+            erasure.include_trashed,
+        )
+        .map_err(|e| ApiError::internal(e.to_string()))?
+        .into_iter()
+        .partition(|f| f.lock(now).is_some()))
+}
+
+/// Delete the content an erasure reports as pending from object storage, and
+/// replicate that it is gone.
+async fn delete_erased_content(
+    state: &AppState,
+    context: &AuditContext,
+    erasure_id: &str,
+) -> Result<(), ApiError> {
+    let erasure = state
+        .db
+        .get_erasure(erasure_id)
+        .map_err(|e| ApiError::internal(e.to_string()))?
+        .ok_or_else(|| ApiError::internal("Erasure not found after applying"))?;
+    if erasure.pending_content.is_empty() {
+        return Ok(());
+    }
+
+    let object_store = state.object_store_for(&erasure.namespace);
+    for key in &erasure.pending_content {
+        object_store.delete(key).await.map_err(|e| {
+            ApiError::internal(format!(
+                "Failed to delete erased content {key} from object storage, retry to resume: {e}"
+            ))
+        })?;
+    }
+    let operation = WriteOp::ErasureContentDeleted {
+        erasure_id: erasure.id,
+        blob_keys: erasure.pending_content,
+    };
+    state
+        .node
+        .replicate(operation.audited(context))
+        .await
+        .map_err(replication_error)?;
+    Ok(())
+}
+
+/// Download every file of a subject as a ZIP archive, written while it is
+/// sent. See `api::export` for its layout.
+/// Route: GET /subjects/:subject_id/export
+pub async fn export_subject_files(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+    Path(subject_id): Path<String>,
+) -> Result<Response, ApiError> {
+    let files = state
+        .db
+        .get_files_by_subject(&namespace.name, &subject_id)
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    if files.is_empty() {
+        return Err(ApiError::not_found("Subject has no files"));
+    }
+
+    let (writer, reader) = tokio::io::duplex(EXPORT_BUFFER_SIZE);
+    let (done, result) = oneshot::channel();
+    let filename = export_filename(&subject_id);
+    tokio::spawn(async move {
+        let object_store = state.object_store_for(&namespace.name).as_ref();
+        let exported =
+            write_export(object_store, &namespace.name, &subject_id, &files, writer).await;
+        if let Err(e) = &exported {
+            tracing::warn!(namespace = %namespace.name, error = %e, "Failed to export subject files");
+        }
+        let _ = done.send(exported);
+    });
+
+    // A failure part way aborts the response, rather than ending it as if
+    // the archive were complete
+    let failure = futures_util::stream::once(result).filter_map(|result| async move {
+        match result {
+            Ok(Err(e)) => Some(Err(std::io::Error::other(e.to_string()))),
+            _ => None,
+        }
+    });
+    let body = Body::from_stream(ReaderStream::new(reader).chain(failure));
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{filename}\""),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+// ============================================================================
+// Helpers
+// ============================================================================
+
+/// Download filename of a subject's export, keeping it a safe header value
+fn export_filename(subject_id: &str) -> String {
+    let subject: String = subject_id
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect();
+    format!("{subject}-export.zip")
+}
+
+/// Signature of an erasure report's canonical JSON
+fn sign_report(key: &str, report: &str) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key.as_bytes());
+    let tag = hmac::sign(&key, report.as_bytes());
+    format!("sha256={}", crate::webhooks::hex(tag.as_ref()))
+}
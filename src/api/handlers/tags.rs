@@ -0,0 +1,39 @@
+use axum::extract::State;
+use axum::Json;
+use serde::Serialize;
+use std::sync::Arc;
+
+use crate::api::namespace::Namespace;
+use crate::api::response::{ApiError, JSend};
+use crate::AppState;
+
+// ============================================================================
+// Types
+// ============================================================================
+
+#[derive(Debug, Serialize)]
+pub struct TagResponse {
+    pub count: u64,
+    pub tag: String,
+}
+
+// ============================================================================
+// Handlers
+// ============================================================================
+
+/// List every tag in use in a namespace with the number of files that have it.
+pub async fn list_tags(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+) -> Result<Json<JSend<Vec<TagResponse>>>, ApiError> {
+    let tags = state
+        .db
+        .list_tags(&namespace.name)
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+
+    Ok(JSend::success(
+        tags.into_iter()
+            .map(|(tag, count)| TagResponse { count, tag })
+            .collect(),
+    ))
+}
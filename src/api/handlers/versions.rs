@@ -0,0 +1,169 @@
+use axum::extract::{Multipart, Path, State};
+use axum::Json;
+use bytes::Bytes;
+use chrono::Utc;
+use serde::Serialize;
+use std::sync::Arc;
+
+use super::files::{
+    ensure_mime_type_allowed, ensure_unlocked, file_to_response, get_live_file,
+    get_namespaced_file, read_upload, sha256_hex, upload_mime_type, FileResponse,
+};
+use super::lock_or_replication_error;
+use crate::api::context::RequestContext;
+use crate::api::namespace::Namespace;
+use crate::api::response::{ApiError, JSend};
+use crate::storage::models::{FileVersion, WriteOp};
+use crate::AppState;
+
+// ============================================================================
+// Types
+// ============================================================================
+
+#[derive(Debug, Serialize)]
+pub struct VersionResponse {
+    pub byte_size: u64,
+    pub created_at: String,
+    pub current: bool,
+    pub mime_type: String,
+    pub sha256: Option<String>,
+    pub version: u32,
+}
+
+// ============================================================================
+// Handlers
+// ============================================================================
+
+/// Upload new content for a file, keeping its ID, permalink and metadata.
+/// The previous content stays available as an earlier version.
+pub async fn upload_version(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+    RequestContext(context): RequestContext,
+    Path(id): Path<String>,
+    mut multipart: Multipart,
+) -> Result<Json<JSend<FileResponse>>, ApiError> {
+    let existing = get_live_file(&state, &namespace.name, &id)?;
+    ensure_unlocked(&existing, "replaced")?;
+
+    let mut file_data: Option<Bytes> = None;
+    let mut file_name: Option<String> = None;
+    let mut file_content_type: Option<String> = None;
+
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| ApiError::bad_request(format!("Invalid multipart data: {e}")))?
+    {
+        if field.name() == Some("file") {
+            file_name = field.file_name().map(|s| s.to_string());
+            file_content_type = field.content_type().map(|s| s.to_string());
+            file_data = Some(read_upload(field, namespace.max_upload_size).await?);
+        }
+    }
+
+    let file_data = file_data.ok_or_else(|| ApiError::bad_request("file field is required"))?;
+
+    // Fall back to the current type, so a bare upload keeps serving the same format
+    let mime_type = upload_mime_type(file_content_type, file_name.as_deref())
+        .unwrap_or_else(|| existing.mime_type.clone());
+    ensure_mime_type_allowed(&namespace, &mime_type)?;
+    let version = FileVersion {
+        version: existing.current_version().version + 1,
+        blob_key: namespace.blob_key(&uuid::Uuid::new_v4().to_string()),
+        byte_size: file_data.len() as u64,
+        mime_type,
+        sha256: Some(sha256_hex(&file_data)),
+        created_at: Utc::now(),
+    };
+
+    // Phase 1: Upload bytes to object storage under a fresh key
+    let object_store = state.object_store_for(&namespace.name);
+    object_store
+        .put(&version.blob_key, file_data)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to store file: {e}")))?;
+
+    // Phase 2: Record the version via muster
+    let operation = WriteOp::AddVersion {
+        id: id.clone(),
+        version: version.clone(),
+    };
+    if let Err(e) = state.node.replicate(operation.audited(&context)).await {
+        let _ = object_store.delete(&version.blob_key).await;
+        return Err(lock_or_replication_error(&state, &[&id], "replaced", e));
+    }
+
+    // The version is skipped if another upload claimed its number first
+    let file = state
+        .db
+        .get_file(&id)
+        .map_err(|e| ApiError::internal(e.to_string()))?
+        .filter(|file| file.current_version().blob_key == version.blob_key);
+    let Some(file) = file else {
+        let _ = object_store.delete(&version.blob_key).await;
+        return Err(ApiError::conflict(
+            "File content was changed concurrently, retry",
+        ));
+    };
+
+    tracing::debug!(file_id = %id, version = version.version, "Uploaded file version");
+    Ok(JSend::success(file_to_response(&file)))
+}
+
+/// List a file's content versions, oldest first.
+pub async fn list_versions(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+    Path(id): Path<String>,
+) -> Result<Json<JSend<Vec<VersionResponse>>>, ApiError> {
+    let file = get_namespaced_file(&state, &namespace.name, &id)?;
+
+    let current = file.current_version().version;
+    Ok(JSend::success(
+        file.all_versions()
+            .into_iter()
+            .map(|v| VersionResponse {
+                byte_size: v.byte_size,
+                created_at: v.created_at.to_rfc3339(),
+                current: v.version == current,
+                mime_type: v.mime_type,
+                sha256: v.sha256,
+                version: v.version,
+            })
+            .collect(),
+    ))
+}
+
+/// Make an earlier version current again by adding a new version with its content.
+pub async fn restore_version(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+    RequestContext(context): RequestContext,
+    Path((id, version)): Path<(String, u32)>,
+) -> Result<Json<JSend<FileResponse>>, ApiError> {
+    let file = get_live_file(&state, &namespace.name, &id)?;
+    file.version(version)
+        .ok_or_else(|| ApiError::not_found("Version not found"))?;
+    ensure_unlocked(&file, "replaced")?;
+
+    let operation = WriteOp::RestoreVersion {
+        id: id.clone(),
+        version,
+        restored_at: Utc::now(),
+    };
+    state
+        .node
+        .replicate(operation.audited(&context))
+        .await
+        .map_err(|e| lock_or_replication_error(&state, &[&id], "replaced", e))?;
+
+    let file = state
+        .db
+        .get_file(&id)
+        .map_err(|e| ApiError::internal(e.to_string()))?
+        .ok_or_else(|| ApiError::not_found("File not found"))?;
+
+    tracing::debug!(file_id = %id, restored = version, "Restored file version");
+    Ok(JSend::success(file_to_response(&file)))
+}
@@ -0,0 +1,255 @@
+use axum::extract::{Path, State};
+use axum::Json;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use super::replication_error;
+use crate::api::namespace::Namespace;
+use crate::api::response::{ApiError, AppJson, AppQuery, JSend};
+use crate::storage::models::{Webhook, WebhookEvent, WriteOp};
+use crate::storage::webhooks::WebhookDelivery;
+use crate::AppState;
+
+// ============================================================================
+// Types
+// ============================================================================
+
+#[derive(Debug, Deserialize)]
+pub struct CreateWebhookRequest {
+    pub url: String,
+    pub events: Vec<WebhookEvent>,
+    /// Signing secret, generated when omitted
+    #[serde(default)]
+    pub secret: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    #[default]
+    Pending,
+    DeadLetter,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListDeliveriesParams {
+    #[serde(default)]
+    pub status: DeliveryStatus,
+    #[serde(default = "default_limit")]
+    pub limit: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WebhookResponse {
+    pub created_at: String,
+    pub events: Vec<WebhookEvent>,
+    pub id: String,
+    pub namespace: String,
+    /// Only returned when the webhook is created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    pub url: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeliveryResponse {
+    pub attempts: u32,
+    pub created_at: String,
+    pub dead_letter: bool,
+    pub event: WebhookEvent,
+    pub id: u64,
+    pub last_attempt_at: Option<String>,
+    pub last_error: Option<String>,
+    pub next_attempt_at: Option<String>,
+    pub payload: serde_json::Value,
+    pub webhook_id: String,
+}
+
+fn default_limit() -> u32 {
+    100
+}
+
+// ============================================================================
+// Handlers
+// ============================================================================
+
+/// Subscribe a URL to file lifecycle events of the namespace.
+pub async fn create_webhook(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+    AppJson(req): AppJson<CreateWebhookRequest>,
+) -> Result<Json<JSend<WebhookResponse>>, ApiError> {
+    match reqwest::Url::parse(&req.url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+        _ => return Err(ApiError::bad_request("url must be an http or https URL")),
+    }
+    if req.events.is_empty() {
+        return Err(ApiError::bad_request("events must not be empty"));
+    }
+    let mut events = req.events;
+    events.sort_by_key(|e| e.as_str());
+    events.dedup();
+    let secret = match req.secret {
+        Some(secret) if secret.is_empty() => {
+            return Err(ApiError::bad_request("secret must not be empty"));
+        }
+        Some(secret) => secret,
+        None => crate::webhooks::generate_secret(),
+    };
+
+    let webhook = Webhook {
+        id: uuid::Uuid::new_v4().to_string(),
+        namespace: namespace.name,
+        url: req.url,
+        secret,
+        events,
+        created_at: Utc::now(),
+    };
+    state
+        .node
+        .replicate(WriteOp::PutWebhook(webhook.clone()))
+        .await
+        .map_err(replication_error)?;
+
+    let mut response = webhook_to_response(&webhook);
+    response.secret = Some(webhook.secret);
+    Ok(JSend::success(response))
+}
+
+/// List the namespace's webhooks, oldest first.
+pub async fn list_webhooks(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+) -> Result<Json<JSend<Vec<WebhookResponse>>>, ApiError> {
+    let webhooks = state
+        .db
+        .list_webhooks(&namespace.name)
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    Ok(JSend::success(
+        webhooks.iter().map(webhook_to_response).collect(),
+    ))
+}
+
+pub async fn get_webhook(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+    Path(id): Path<String>,
+) -> Result<Json<JSend<WebhookResponse>>, ApiError> {
+    let webhook = get_namespaced_webhook(&state, &namespace.name, &id)?;
+    Ok(JSend::success(webhook_to_response(&webhook)))
+}
+
+/// Delete a webhook, dropping its pending and dead-lettered deliveries.
+pub async fn delete_webhook(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+    Path(id): Path<String>,
+) -> Result<Json<JSend<()>>, ApiError> {
+    get_namespaced_webhook(&state, &namespace.name, &id)?;
+    state
+        .node
+        .replicate(WriteOp::DeleteWebhook { id })
+        .await
+        .map_err(replication_error)?;
+    Ok(JSend::success(()))
+}
+
+/// List a webhook's pending or dead-lettered deliveries, newest first.
+pub async fn list_deliveries(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+    Path(id): Path<String>,
+    AppQuery(params): AppQuery<ListDeliveriesParams>,
+) -> Result<Json<JSend<Vec<DeliveryResponse>>>, ApiError> {
+    if params.limit == 0 {
+        return Err(ApiError::bad_request("limit must be greater than 0"));
+    }
+    get_namespaced_webhook(&state, &namespace.name, &id)?;
+    let dead_letters = matches!(params.status, DeliveryStatus::DeadLetter);
+    let deliveries = state
+        .db
+        .list_webhook_deliveries(&id, dead_letters, params.limit as usize)
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    Ok(JSend::success(
+        deliveries
+            .into_iter()
+            .map(|d| delivery_to_response(d, dead_letters))
+            .collect(),
+    ))
+}
+
+/// Send a delivery again now. Dead-lettered deliveries go back to the outbox
+/// with a fresh set of attempts.
+pub async fn redeliver(
+    State(state): State<Arc<AppState>>,
+    Namespace(namespace): Namespace,
+    Path((id, delivery_id)): Path<(String, u64)>,
+) -> Result<Json<JSend<DeliveryResponse>>, ApiError> {
+    get_namespaced_webhook(&state, &namespace.name, &id)?;
+    state
+        .db
+        .get_webhook_delivery(delivery_id)
+        .map_err(|e| ApiError::internal(e.to_string()))?
+        .filter(|(d, _)| d.webhook_id == id)
+        .ok_or_else(|| ApiError::not_found("Delivery not found"))?;
+
+    state
+        .node
+        .replicate(WriteOp::RedeliverWebhook {
+            delivery_id,
+            at: Utc::now(),
+        })
+        .await
+        .map_err(replication_error)?;
+
+    let (delivery, dead_letter) = state
+        .db
+        .get_webhook_delivery(delivery_id)
+        .map_err(|e| ApiError::internal(e.to_string()))?
+        .ok_or_else(|| ApiError::not_found("Delivery not found"))?;
+    Ok(JSend::success(delivery_to_response(delivery, dead_letter)))
+}
+
+// ============================================================================
+// Helpers
+// ============================================================================
+
+fn get_namespaced_webhook(
+    state: &AppState,
+    namespace: &str,
+    id: &str,
+) -> Result<Webhook, ApiError> {
+    state
+        .db
+        .get_webhook(id)
+        .map_err(|e| ApiError::internal(e.to_string()))?
+        .filter(|w| w.namespace == namespace)
+        .ok_or_else(|| ApiError::not_found("Webhook not found"))
+}
+
+fn webhook_to_response(webhook: &Webhook) -> WebhookResponse {
+    WebhookResponse {
+        created_at: webhook.created_at.to_rfc3339(),
+        events: webhook.events.clone(),
+        id: webhook.id.clone(),
+        namespace: webhook.namespace.clone(),
+        secret: None,
+        url: webhook.url.clone(),
+    }
+}
+
+fn delivery_to_response(delivery: WebhookDelivery, dead_letter: bool) -> DeliveryResponse {
+    DeliveryResponse {
+        attempts: delivery.attempts,
+        created_at: delivery.created_at.to_rfc3339(),
+        dead_letter,
+        event: delivery.event,
+        id: delivery.id,
+        last_attempt_at: delivery.last_attempt_at.map(|t| t.to_rfc3339()),
+        last_error: delivery.last_error,
+        next_attempt_at: (!dead_letter).then(|| delivery.next_attempt_at.to_rfc3339()),
+        payload: serde_json::from_str(&delivery.payload).unwrap_or(serde_json::Value::Null),
+        webhook_id: delivery.webhook_id,
+    }
+}
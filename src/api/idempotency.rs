@@ -0,0 +1,356 @@
+//! `Idempotency-Key` support for every mutating route. The first successful
+//! response to a request with a key is stored through the state machine, and
+//! retries with the same key get it back instead of repeating the change, on
+//! any node, until the key is older than `IDEMPOTENCY_TTL_HOURS`. Reusing a
+//! key for a different request is rejected. Failed requests aren't stored, so
+//! they can be retried with the same key.
+//!
+//! The key is claimed through the state machine before the request is
+//! handled, so a retry reaching any node meanwhile gets 409 instead of making
+//! the change a second time. A claim whose node never answers lapses after
+//! `CLAIM_LEASE`.
+//!
+//! Archive imports are too large to buffer and store, so they reject the
+//! header instead of ignoring it.
+
+use std::sync::Arc;
+
+use axum::body::{Body, Bytes};
+use axum::extract::{FromRequest, Multipart, Request, State};
+use axum::http::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use axum::http::request::Parts;
+use axum::http::{HeaderName, HeaderValue, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use chrono::Utc;
+use ring::digest;
+
+use crate::api::handlers::replication_error;
+use crate::api::namespace::NAMESPACE_HEADER;
+use crate::api::response::ApiError;
+use crate::storage::models::{IdempotencyRecord, WriteOp, CLAIM_LEASE, DEFAULT_NAMESPACE};
+use crate::AppState;
+
+/// Header carrying the client's idempotency key
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// Header set on responses replayed from an earlier request
+pub const REPLAYED_HEADER: &str = "idempotent-replayed";
+
+const MAX_KEY_LENGTH: usize = 255;
+
+/// Middleware making mutating requests with an `Idempotency-Key` header
+/// idempotent. Other requests pass through untouched.
+pub async fn idempotent(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mutating = matches!(
+        *request.method(),
+        Method::POST | Method::PUT | Method::PATCH | Method::DELETE
+    );
+    if !mutating || !request.headers().contains_key(IDEMPOTENCY_KEY_HEADER) {
+        return next.run(request).await;
+    }
+    match run_once(&state, request, next).await {
+        Ok(response) => response,
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Middleware for routes that can't be made idempotent, rejecting requests
+/// with an `Idempotency-Key` header rather than repeating them on retry.
+pub async fn unsupported(request: Request, next: Next) -> Response {
+    if request.headers().contains_key(IDEMPOTENCY_KEY_HEADER) {
+        return ApiError::bad_request("Idempotency-Key is not supported on this route")
+            .into_response();
+    }
+    next.run(request).await
+}
+
+async fn run_once(state: &AppState, request: Request, next: Next) -> Result<Response, ApiError> {
+    let key = request
+        .headers()
+        .get(IDEMPOTENCY_KEY_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|k| !k.is_empty() && k.len() <= MAX_KEY_LENGTH)
+        .ok_or_else(|| {
+            ApiError::bad_request(format!(
+                "Idempotency-Key must be 1 to {MAX_KEY_LENGTH} visible ASCII characters"
+            ))
+        })?
+        .to_string();
+    let namespace = request
+        .headers()
+        .get(NAMESPACE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or(DEFAULT_NAMESPACE)
+        .to_string();
+
+    let (parts, body) = request.into_parts();
+    let limit = state.config.max_namespace_upload_size() as usize;
+    let body = axum::body::to_bytes(body, limit)
+        .await
+        .map_err(|_| ApiError::payload_too_large("Request body is too large"))?;
+    let fingerprint = fingerprint(&parts, &body).await;
+
+    // Hold the key on this node while it's looked up and claimed, so a
+    // request finishing in between can't be repeated
+    let _in_flight = InFlight::claim(state, &namespace, &key).ok_or_else(in_progress)?;
+    if let Some(response) = stored_response(state, &namespace, &key, &fingerprint)? {
+        return Ok(response);
+    }
+
+    // Claim the key through the state machine, so a retry reaching another
+    // node while this one is handled finds the claim
+    let claim = uuid::Uuid::new_v4().to_string();
+    let claimed = IdempotencyRecord {
+        namespace: namespace.clone(),
+        key: key.clone(),
+        fingerprint: fingerprint.clone(),
+        status: 0,
+        headers: Vec::new(),
+        body: String::new(),
+        created_at: Utc::now(),
+        claim: Some(claim.clone()),
+    };
+    state
+        .node
+        .replicate(WriteOp::ClaimIdempotencyKey(claimed))
+        .await
+        .map_err(replication_error)?;
+    let held = state
+        .db
+        .get_idempotency_record(&namespace, &key)
+        .map_err(|e| ApiError::internal(e.to_string()))?
+        .is_some_and(|r| r.claim.as_deref() == Some(claim.as_str()));
+    if !held {
+        return match stored_response(state, &namespace, &key, &fingerprint)? {
+            Some(response) => Ok(response),
+            None => Err(in_progress()),
+        };
+    }
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    if !response.status().is_success() {
+        // Failed requests can be retried with the same key
+        let release = WriteOp::ReleaseIdempotencyKey {
+            namespace,
+            key,
+            claim,
+        };
+        if let Err(e) = state.node.replicate(release).await {
+            tracing::warn!(error = %e, "Failed to release Idempotency-Key claim");
+        }
+        return Ok(response);
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = axum::body::to_bytes(body, usize::MAX)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to read response: {e}")))?;
+    let record = match std::str::from_utf8(&body) {
+        Ok(text) => IdempotencyRecord {
+            namespace,
+            key,
+            fingerprint,
+            status: parts.status.as_u16(),
+            headers: parts
+                .headers
+                .iter()
+                .filter(|(name, _)| **name != CONTENT_LENGTH)
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), value.to_str().ok()?.to_string()))
+                })
+                .collect(),
+            body: text.to_string(),
+            created_at: Utc::now(),
+            claim: None,
+        },
+        // A response that can't be stored can't be replayed either; let a
+        // retry repeat the change rather than wait out the claim
+        Err(_) => {
+            let release = WriteOp::ReleaseIdempotencyKey {
+                namespace,
+                key,
+                claim,
+            };
+            if let Err(e) = state.node.replicate(release).await {
+                tracing::warn!(error = %e, "Failed to release Idempotency-Key claim");
+            }
+            return Ok(Response::from_parts(parts, Body::from(body)));
+        }
+    };
+    // The change is made either way; a retry after the claim lapses will just
+    // repeat it
+    if let Err(e) = state
+        .node
+        .replicate(WriteOp::PutIdempotencyKey(record))
+        .await
+    {
+        tracing::warn!(error = %e, "Failed to store idempotent response");
+    }
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+/// The answer to a request whose key is already in use: the stored response
+/// if the key has one, 409 if another request holds it, or `None` if the key
+/// is free. Rejects a key used for a different request.
+fn stored_response(
+    state: &AppState,
+    namespace: &str,
+    key: &str,
+    fingerprint: &str,
+) -> Result<Option<Response>, ApiError> {
+    let ttl = chrono::Duration::hours(state.config.idempotency_ttl_hours as i64);
+    let now = Utc::now();
+    let stored = state
+        .db
+        .get_idempotency_record(namespace, key)
+        .map_err(|e| ApiError::internal(e.to_string()))?
+        .filter(|r| r.created_at > now - ttl)
+        .filter(|r| !r.is_claim() || r.created_at + CLAIM_LEASE > now);
+    let Some(record) = stored else {
+        return Ok(None);
+    };
+    if record.fingerprint != fingerprint {
+        return Err(ApiError::unprocessable(
+            "Idempotency-Key was already used for a different request",
+        ));
+    }
+    if record.is_claim() {
+        return Err(in_progress());
+    }
+    tracing::debug!(namespace, key, "Replayed idempotent response");
+    Ok(Some(replay(record)))
+}
+
+fn in_progress() -> ApiError {
+    ApiError::conflict("A request with this Idempotency-Key is still in progress")
+}
+
+/// Digest of the request a key was used for. Multipart bodies are digested
+/// field by field, as retries pick a new random boundary, and JSON bodies
+/// after normalizing them.
+pub async fn fingerprint(parts: &Parts, body: &Bytes) -> String {
+    let mut context = digest::Context::new(&digest::SHA256);
+    update_framed(&mut context, parts.method.as_str().as_bytes());
+    let target = parts.uri.path_and_query().map_or("", |p| p.as_str());
+    update_framed(&mut context, target.as_bytes());
+
+    let content_type = parts
+        .headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    let normalized = if content_type.starts_with("multipart/form-data") {
+        digest_multipart(&mut context, content_type, body).await
+    } else if content_type.starts_with("application/json") {
+        match serde_json::from_slice::<serde_json::Value>(body) {
+            Ok(value) => {
+                update_framed(&mut context, value.to_string().as_bytes());
+                true
+            }
+            Err(_) => false,
+        }
+    } else {
+        false
+    };
+    if !normalized {
+        update_framed(&mut context, body);
+    }
+
+    context
+        .finish()
+        .as_ref()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Digest each field of a multipart body. Returns false, leaving the body to
+/// be digested as is, if it isn't valid multipart.
+async fn digest_multipart(context: &mut digest::Context, content_type: &str, body: &Bytes) -> bool {
+    let Ok(request) = Request::builder()
+        .header(CONTENT_TYPE, content_type)
+        .body(Body::from(body.clone()))
+    else {
+        return false;
+    };
+    let Ok(mut multipart) = Multipart::from_request(request, &()).await else {
+        return false;
+    };
+    let mut fields = digest::Context::new(&digest::SHA256);
+    loop {
+        match multipart.next_field().await {
+            Ok(Some(field)) => {
+                for header in [field.name(), field.file_name(), field.content_type()] {
+                    update_framed(&mut fields, header.unwrap_or("").as_bytes());
+                }
+                match field.bytes().await {
+                    Ok(data) => update_framed(&mut fields, &data),
+                    Err(_) => return false,
+                }
+            }
+            Ok(None) => break,
+            Err(_) => return false,
+        }
+    }
+    update_framed(context, fields.finish().as_ref());
+    true
+}
+
+/// Update a digest with a length-prefixed value, so values can't run together
+fn update_framed(context: &mut digest::Context, value: &[u8]) {
+    context.update(&(value.len() as u64).to_be_bytes());
+    context.update(value);
+}
+
+/// Rebuild a stored response
+fn replay(record: IdempotencyRecord) -> Response {
+    let status = StatusCode::from_u16(record.status).unwrap_or(StatusCode::OK);
+    let mut response = (status, record.body).into_response();
+    let headers = response.headers_mut();
+    for (name, value) in &record.headers {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::try_from(name.as_str()),
+            HeaderValue::try_from(value.as_str()),
+        ) {
+            headers.insert(name, value);
+        }
+    }
+    headers.insert(REPLAYED_HEADER, HeaderValue::from_static("true"));
+    response
+}
+
+/// A key being handled on this node, released when dropped
+struct InFlight<'a> {
+    state: &'a AppState,
+    key: (String, String),
+}
+
+impl<'a> InFlight<'a> {
+    /// Claim a key, or `None` if a request with it is being handled already
+    fn claim(state: &'a AppState, namespace: &str, key: &str) -> Option<Self> {
+        let key = (namespace.to_string(), key.to_string());
+        let mut in_flight = state
+            .idempotency_in_flight
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        in_flight
+            .insert(key.clone())
+            .then_some(InFlight { state, key })
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.state
+            .idempotency_in_flight
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.key);
+    }
+}
@@ -0,0 +1,211 @@
+//! Reading archives uploaded to `POST /files/import-archive`. ZIP and tar
+//! archives are told apart by their content and read entry by entry from the
+//! spooled upload, rather than unpacked up front. Each file in the archive is
+//! imported at its path under a caller-chosen permalink prefix.
+
+use std::io::SeekFrom;
+use std::path::Path;
+
+use anyhow::bail;
+use async_zip::tokio::read::seek::ZipFileReader;
+use bytes::Bytes;
+use futures_util::{AsyncReadExt as _, StreamExt};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, BufReader};
+use tokio_tar::{Archive, Entries, EntryType};
+
+/// Unix file type bits of a symbolic link, as stored in ZIP attributes
+const ZIP_SYMLINK_MODE: u16 = 0o120000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Zip,
+    Tar,
+}
+
+impl ArchiveFormat {
+    /// Tell an archive's format from its first 512 bytes
+    pub fn detect(header: &[u8]) -> Option<Self> {
+        if header.starts_with(b"PK\x03\x04") || header.starts_with(b"PK\x05\x06") {
+            Some(ArchiveFormat::Zip)
+        } else if header.get(257..262) == Some(b"ustar") {
+            Some(ArchiveFormat::Tar)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ArchiveEntry {
+    /// Path of the entry in the archive
+    pub path: String,
+    pub content: EntryContent,
+}
+
+#[derive(Debug)]
+pub enum EntryContent {
+    File(Bytes),
+    /// A file larger than the size limit, with its size in bytes. Its content
+    /// isn't read.
+    TooLarge(u64),
+    /// Anything but a file or directory, such as a link
+    NotAFile,
+    /// A file whose content is corrupt
+    Invalid(String),
+}
+
+/// An archive being read, entry by entry
+pub struct ArchiveReader {
+    inner: Inner,
+}
+
+enum Inner {
+    Zip {
+        reader: Box<ZipFileReader<BufReader<File>>>,
+        next: usize,
+    },
+    Tar(Box<Entries<BufReader<File>>>),
+}
+
+impl ArchiveReader {
+    /// Open a ZIP or tar archive. ZIP archives are checked up front, as their
+    /// directory is at the end; tar archives only as they are read.
+    pub async fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut file = File::open(path).await?;
+        let mut header = Vec::with_capacity(512);
+        (&mut file).take(512).read_to_end(&mut header).await?;
+        file.seek(SeekFrom::Start(0)).await?;
+
+        let inner = match ArchiveFormat::detect(&header) {
+            Some(ArchiveFormat::Zip) => Inner::Zip {
+                reader: Box::new(ZipFileReader::with_tokio(BufReader::new(file)).await?),
+                next: 0,
+            },
+            Some(ArchiveFormat::Tar) => {
+                Inner::Tar(Box::new(Archive::new(BufReader::new(file)).entries()?))
+            }
+            None => bail!("not a ZIP or tar archive"),
+        };
+        Ok(ArchiveReader { inner })
+    }
+
+    /// Read the next entry, skipping directories. Files larger than
+    /// `max_size` bytes are returned without their content.
+    pub async fn next_entry(&mut self, max_size: u64) -> anyhow::Result<Option<ArchiveEntry>> {
+        match &mut self.inner {
+            Inner::Zip { reader, next } => next_zip_entry(reader, next, max_size).await,
+            Inner::Tar(entries) => next_tar_entry(entries, max_size).await,
+        }
+    }
+}
+
+async fn next_zip_entry(
+    reader: &mut ZipFileReader<BufReader<File>>,
+    next: &mut usize,
+    max_size: u64,
+) -> anyhow::Result<Option<ArchiveEntry>> {
+    loop {
+        let index = *next;
+        let Some(entry) = reader.file().entries().get(index) else {
+            return Ok(None);
+        };
+        *next += 1;
+
+        let path = String::from_utf8_lossy(entry.filename().as_bytes()).into_owned();
+        if path.ends_with('/') {
+            continue;
+        }
+        let symlink = entry
+            .unix_permissions()
+            .is_some_and(|mode| mode & 0o170000 == ZIP_SYMLINK_MODE);
+        if symlink {
+            return Ok(Some(ArchiveEntry {
+                path,
+                content: EntryContent::NotAFile,
+            }));
+        }
+        let size = entry.uncompressed_size();
+        if size > max_size {
+            return Ok(Some(ArchiveEntry {
+                path,
+                content: EntryContent::TooLarge(size),
+            }));
+        }
+        let crc32 = entry.crc32();
+
+        // Sizes in the directory can be wrong, so reading stops past the limit
+        let mut entry_reader = reader.reader_without_entry(index).await?;
+        let mut data = Vec::with_capacity(size as usize);
+        let content = match (&mut entry_reader)
+            .take(max_size + 1)
+            .read_to_end(&mut data)
+            .await
+        {
+            Ok(_) if data.len() as u64 > max_size => EntryContent::TooLarge(data.len() as u64),
+            Ok(_) if entry_reader.compute_hash() != crc32 => {
+                EntryContent::Invalid("content doesn't match its checksum".to_string())
+            }
+            Ok(_) => EntryContent::File(data.into()),
+            Err(e) => EntryContent::Invalid(format!("failed to decompress: {e}")),
+        };
+        return Ok(Some(ArchiveEntry { path, content }));
+    }
+}
+
+async fn next_tar_entry(
+    entries: &mut Entries<BufReader<File>>,
+    max_size: u64,
+) -> anyhow::Result<Option<ArchiveEntry>> {
+    while let Some(entry) = entries.next().await {
+        let mut entry = entry?;
+        let path = String::from_utf8_lossy(&entry.path_bytes()?).into_owned();
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_dir() || entry_type == EntryType::XGlobalHeader {
+            continue;
+        }
+        if !entry_type.is_file() {
+            return Ok(Some(ArchiveEntry {
+                path,
+                content: EntryContent::NotAFile,
+            }));
+        }
+        let size = entry.header().size()?;
+        if size > max_size {
+            // The unread content is skipped when reading the next entry
+            return Ok(Some(ArchiveEntry {
+                path,
+                content: EntryContent::TooLarge(size),
+            }));
+        }
+        let mut data = Vec::with_capacity(size as usize);
+        entry.read_to_end(&mut data).await?;
+        return Ok(Some(ArchiveEntry {
+            path,
+            content: EntryContent::File(data.into()),
+        }));
+    }
+    Ok(None)
+}
+
+/// Permalink of an archive entry imported under `prefix`. `.` and empty
+/// segments are dropped; paths leaving the archive with `..` are rejected.
+pub fn entry_permalink(prefix: &str, path: &str) -> Result<String, String> {
+    let mut segments = Vec::new();
+    for segment in path.split(['/', '\\']) {
+        match segment {
+            "" | "." => {}
+            ".." => return Err(format!("path '{path}' leaves the archive")),
+            segment => segments.push(segment),
+        }
+    }
+    if segments.is_empty() {
+        return Err("path is empty".to_string());
+    }
+
+    let path = segments.join("/");
+    match prefix.trim_matches('/') {
+        "" => Ok(path),
+        prefix => Ok(format!("{prefix}/{path}")),
+    }
+}
@@ -1,4 +1,10 @@
+pub mod context;
+pub mod export;
 mod handlers;
+pub mod idempotency;
+pub mod import;
+pub mod namespace;
+pub mod precondition;
 pub mod response;
 mod routes;
 
@@ -0,0 +1,83 @@
+//! Namespace selection. Every route serves the namespace named by the
+//! `X-Namespace` header, or the default namespace without one. Routes can also
+//! be reached under `/ns/<namespace>/`, which sets the header.
+
+use axum::extract::{FromRequestParts, Request};
+use axum::http::request::Parts;
+use axum::http::{HeaderValue, Uri};
+use std::sync::Arc;
+
+use crate::api::response::ApiError;
+use crate::config::NamespaceConfig;
+use crate::storage::models::DEFAULT_NAMESPACE;
+use crate::AppState;
+
+/// Header selecting the namespace of a request
+pub const NAMESPACE_HEADER: &str = "x-namespace";
+
+/// Path prefix selecting the namespace of a request
+const NAMESPACE_PREFIX: &str = "/ns/";
+
+/// The namespace a request operates on. Rejects unknown namespaces with 404.
+pub struct Namespace(pub NamespaceConfig);
+
+#[axum::async_trait]
+impl FromRequestParts<Arc<AppState>> for Namespace {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, ApiError> {
+        let name = match parts.headers.get(NAMESPACE_HEADER) {
+            Some(value) => value
+                .to_str()
+                .map_err(|_| ApiError::bad_request("Invalid X-Namespace header"))?,
+            None => DEFAULT_NAMESPACE,
+        };
+        state
+            .config
+            .namespace(name)
+            .cloned()
+            .map(Namespace)
+            .ok_or_else(|| ApiError::not_found(format!("Unknown namespace '{name}'")))
+    }
+}
+
+/// Rewrite `/ns/<namespace>/<path>` to `/<path>` with the namespace header.
+/// Runs before routing, so every route is also available under the prefix.
+pub async fn rewrite_prefix(mut req: Request) -> Request {
+    let Some(rest) = req.uri().path().strip_prefix(NAMESPACE_PREFIX) else {
+        return req;
+    };
+    let (name, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let Ok(namespace) = HeaderValue::from_str(name) else {
+        return req;
+    };
+
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("{path}?{query}"),
+        None => path.to_string(),
+    };
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = path_and_query.parse().ok();
+    let Ok(uri) = Uri::from_parts(parts) else {
+        return req;
+    };
+
+    *req.uri_mut() = uri;
+    req.headers_mut().insert(NAMESPACE_HEADER, namespace);
+    req
+}
+
+/// Path prefix of a namespace's routes in URLs the server hands out
+pub fn route_prefix(namespace: &str) -> String {
+    if namespace == DEFAULT_NAMESPACE {
+        String::new()
+    } else {
+        format!("/ns/{namespace}")
+    }
+}
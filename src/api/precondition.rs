@@ -0,0 +1,95 @@
+//! Optimistic concurrency. Every file record carries a revision, bumped on
+//! each write, which is served as the file's `ETag`. Writes sent with an
+//! `If-Match` header only apply while the file is still at a listed revision,
+//! so an editor can't overwrite a change they haven't seen.
+
+use axum::extract::FromRequestParts;
+use axum::http::header::IF_MATCH;
+use axum::http::request::Parts;
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use crate::api::response::ApiError;
+use crate::storage::models::{FileRecord, WriteOp};
+use crate::AppState;
+
+/// The revisions a write is conditional on, from the `If-Match` header.
+/// `None` without the header or with `*`. Weak or malformed entity tags never
+/// match, as `If-Match` requires a strong comparison.
+pub struct IfMatch(pub Option<Vec<u64>>);
+
+#[axum::async_trait]
+impl FromRequestParts<Arc<AppState>> for IfMatch {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &Arc<AppState>,
+    ) -> Result<Self, Infallible> {
+        let mut revisions = Vec::new();
+        let mut present = false;
+        for value in parts.headers.get_all(IF_MATCH) {
+            present = true;
+            let Ok(value) = value.to_str() else {
+                continue;
+            };
+            for tag in value.split(',').map(str::trim) {
+                if tag == "*" {
+                    return Ok(IfMatch(None));
+                }
+                if let Some(revision) = tag
+                    .strip_prefix('"')
+                    .and_then(|t| t.strip_suffix('"'))
+                    .and_then(|t| t.parse().ok())
+                {
+                    revisions.push(revision);
+                }
+            }
+        }
+        Ok(IfMatch(present.then_some(revisions)))
+    }
+}
+
+impl IfMatch {
+    /// Fail with 412 unless the file is at one of the requested revisions. A
+    /// missing file matches no revision.
+    pub fn check(&self, file: Option<&FileRecord>) -> Result<(), ApiError> {
+        let Some(revisions) = &self.0 else {
+            return Ok(());
+        };
+        match file {
+            Some(file) if revisions.contains(&file.revision) => Ok(()),
+            Some(file) => Err(ApiError::precondition_failed(format!(
+                "file is at revision {}, not {}",
+                file.revision,
+                etags(revisions)
+            ))),
+            None => Err(ApiError::precondition_failed("file no longer exists")),
+        }
+    }
+
+    /// Make an operation on a file conditional on the requested revisions,
+    /// checked when it is applied.
+    pub fn wrap(&self, id: &str, op: WriteOp) -> WriteOp {
+        match &self.0 {
+            Some(revisions) => op.if_match(id, revisions.clone()),
+            None => op,
+        }
+    }
+}
+
+/// The `ETag` of a file
+pub fn etag(file: &FileRecord) -> String {
+    format!("\"{}\"", file.revision)
+}
+
+fn etags(revisions: &[u64]) -> String {
+    if revisions.is_empty() {
+        return "a valid entity tag".to_string();
+    }
+    revisions
+        .iter()
+        .map(|r| format!("\"{r}\""))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -1,3 +1,6 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
 use axum::extract::rejection::JsonRejection;
 use axum::extract::{FromRequest, FromRequestParts, Request};
 use axum::http::StatusCode;
@@ -55,8 +58,12 @@ pub struct PaginatedData<T: Serialize> {
 #[derive(Debug, Serialize)]
 pub struct Pagination {
     pub limit: u32,
+    /// Cursor for the next page (pass as `after`), or null on the last page
+    pub next_cursor: Option<String>,
     pub offset: u32,
-    pub total: u64,
+    /// Number of matching items, when known without reading past the page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
 }
 
 impl<T: Serialize> JSendPaginated<T> {
@@ -164,10 +171,34 @@ impl ApiError {
         ApiError::Fail(StatusCode::PAYLOAD_TOO_LARGE, message.into())
     }
 
+    pub fn unsupported_media_type(message: impl Into<String>) -> Self {
+        ApiError::Fail(StatusCode::UNSUPPORTED_MEDIA_TYPE, message.into())
+    }
+
     pub fn conflict(message: impl Into<String>) -> Self {
         ApiError::Fail(StatusCode::CONFLICT, message.into())
     }
 
+    pub fn gone(message: impl Into<String>) -> Self {
+        ApiError::Fail(StatusCode::GONE, message.into())
+    }
+
+    pub fn precondition_failed(message: impl Into<String>) -> Self {
+        ApiError::Fail(StatusCode::PRECONDITION_FAILED, message.into())
+    }
+
+    pub fn unprocessable(message: impl Into<String>) -> Self {
+        ApiError::Fail(StatusCode::UNPROCESSABLE_ENTITY, message.into())
+    }
+
+    pub fn locked(message: impl Into<String>) -> Self {
+        ApiError::Fail(StatusCode::LOCKED, message.into())
+    }
+
+    pub fn unauthorized(message: impl Into<String>) -> Self {
+        ApiError::Fail(StatusCode::UNAUTHORIZED, message.into())
+    }
+
     pub fn unavailable(message: impl Into<String>) -> Self {
         ApiError::Fail(StatusCode::SERVICE_UNAVAILABLE, message.into())
     }
@@ -227,13 +258,48 @@ where
         parts: &mut axum::http::request::Parts,
         _state: &S,
     ) -> Result<Self, ApiError> {
-        let query = parts.uri.query().unwrap_or_default();
-        serde_qs::from_str(query)
+        let query = index_repeated_keys(parts.uri.query().unwrap_or_default());
+        serde_qs::from_str(&query)
             .map(AppQuery)
             .map_err(|e| ApiError::bad_request(friendly_query_error(&e.to_string())))
     }
 }
 
+/// Rewrite bare keys given more than once (`tag=a&tag=b`) as an indexed list
+/// (`tag[0]=a&tag[1]=b`), which serde_qs reads as a sequence instead of
+/// rejecting the repeat.
+fn index_repeated_keys(query: &str) -> Cow<'_, str> {
+    fn key(pair: &str) -> &str {
+        pair.split_once('=').map_or(pair, |(key, _)| key)
+    }
+    let is_bare = |key: &str| !key.contains('[') && !key.to_ascii_lowercase().contains("%5b");
+
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        *counts.entry(key(pair)).or_default() += 1;
+    }
+    if !counts.iter().any(|(key, count)| *count > 1 && is_bare(key)) {
+        return Cow::Borrowed(query);
+    }
+
+    let mut seen: HashMap<&str, usize> = HashMap::new();
+    let pairs: Vec<String> = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let key = key(pair);
+            if counts[key] < 2 || !is_bare(key) {
+                return pair.to_string();
+            }
+            let index = seen.entry(key).or_default();
+            let indexed = format!("{key}[{index}]{}", &pair[key.len()..]);
+            *index += 1;
+            indexed
+        })
+        .collect();
+    Cow::Owned(pairs.join("&"))
+}
+
 /// Translate serde/serde_qs error messages into human-friendly descriptions.
 fn friendly_query_error(raw: &str) -> String {
     let cleaned = raw
@@ -1,16 +1,20 @@
 use axum::{
     extract::DefaultBodyLimit,
+    middleware::{from_fn, from_fn_with_state, map_request},
     routing::{delete, get, post, put},
     Router,
 };
 use std::sync::Arc;
+use tower::Layer;
 use tower_http::trace::TraceLayer;
 
-use super::handlers;
+use super::{handlers, idempotency, namespace};
 use crate::AppState;
 
 pub fn create_router(state: Arc<AppState>) -> Router {
-    let upload_limit = state.config.max_upload_size as usize;
+    // Handlers enforce each namespace's own limit
+    let upload_limit = state.config.max_namespace_upload_size() as usize;
+    let import_limit = state.config.max_import_size as usize;
 
     let mut router = Router::new()
         // Files
@@ -19,20 +23,211 @@ pub fn create_router(state: Arc<AppState>) -> Router {
             "/files",
             post(handlers::create_file).layer(DefaultBodyLimit::max(upload_limit)),
         )
+        .route("/files/batch", post(handlers::batch_files))
+        .route("/files/batch-get", post(handlers::batch_get_files))
+        .route("/files/rename-prefix", post(handlers::rename_prefix))
+        .route("/files/search", get(handlers::search_files))
+        .route("/files/trash", get(handlers::list_trash))
         .route("/files/:id", delete(handlers::delete_file))
         .route("/files/:id", get(handlers::get_file))
         .route("/files/:id", put(handlers::update_file))
+        .route(
+            "/files/:id/content",
+            put(handlers::upload_version).layer(DefaultBodyLimit::max(upload_limit)),
+        )
+        .route("/files/:id/audit", get(handlers::list_file_audit))
+        .route("/files/:id/restore", post(handlers::restore_file))
+        .route("/files/:id/tags", post(handlers::add_tags))
+        .route("/files/:id/tags/:tag", delete(handlers::remove_tag))
+        .route("/files/:id/versions", get(handlers::list_versions))
+        .route(
+            "/files/:id/versions/:version/restore",
+            post(handlers::restore_version),
+        )
+        // Audit log
+        .route("/audit", get(handlers::list_audit))
+        // Change feed
+        .route("/changes", get(handlers::list_changes))
+        .route("/changes/stream", get(handlers::stream_changes))
+        // Folders
+        .route("/folders", get(handlers::list_folder))
+        // Redirects
+        .route("/redirects", get(handlers::list_redirects))
+        .route("/redirects/*permalink", delete(handlers::delete_redirect))
+        // Subjects
+        .route(
+            "/subjects/:subject_id/export",
+            get(handlers::export_subject_files),
+        )
+        // Tags
+        .route("/tags", get(handlers::list_tags))
+        // Webhooks
+        .route("/webhooks", get(handlers::list_webhooks))
+        .route("/webhooks", post(handlers::create_webhook))
+        .route("/webhooks/:id", delete(handlers::delete_webhook))
+        .route("/webhooks/:id", get(handlers::get_webhook))
+        .route("/webhooks/:id/deliveries", get(handlers::list_deliveries))
+        .route(
+            "/webhooks/:id/deliveries/:delivery_id/redeliver",
+            post(handlers::redeliver),
+        )
         // Static content (permalink download)
         .route("/static/*permalink", get(handlers::serve_static))
         // Internal
         .route("/_internal/cluster/status", get(handlers::cluster_status))
         .route("/_internal/health", get(handlers::health));
 
+    // Admin routes, guarded by ADMIN_TOKEN
+    if state.config.admin_token.is_some() {
+        router = router.route("/admin/files/:id/retention", put(handlers::set_retention));
+    }
+
+    // Subject erasure, enabled by ERASURE_SIGNING_KEY to sign its reports
+    if state.config.erasure_signing_key.is_some() {
+        router = router.route(
+            "/subjects/:subject_id/files",
+            delete(handlers::erase_subject_files),
+        );
+    }
+
     // Test-only routes
     if state.config.test_mode {
         tracing::warn!("Test mode enabled — purge route is available.");
         router = router.route("/admin/purge", delete(handlers::admin_purge));
     }
 
-    router.layer(TraceLayer::new_for_http()).with_state(state)
+    let router = router
+        .route_layer(from_fn_with_state(
+            Arc::clone(&state),
+            idempotency::idempotent,
+        ))
+        // Added after the idempotency layer, which would buffer the archive in
+        // memory and store the whole import report; requests with a key are
+        // rejected rather than silently not made idempotent
+        .route(
+            "/files/import-archive",
+            post(handlers::import_archive)
+                .layer(DefaultBodyLimit::max(import_limit))
+                .layer(from_fn(idempotency::unsupported)),
+        )
+        .layer(TraceLayer::new_for_http())
+        .with_state(state);
+
+    // Namespace prefixes are rewritten before routing, so that every route
+    // above is also served under /ns/<namespace>/
+    Router::new().fallback_service(map_request(namespace::rewrite_prefix).layer(router))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use tower::ServiceExt;
+
+    use super::create_router;
+    use crate::storage::models::{FileRecord, FileType};
+    use crate::testutil::{sample_file, test_state};
+
+    fn file(id: &str, file_type: FileType, tags: &[&str]) -> FileRecord {
+        FileRecord {
+            file_type,
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            ..sample_file(id)
+        }
+    }
+
+    /// `GET /files?<query>`, returning the status and the ids listed, sorted
+    async fn list(query: &str) -> (StatusCode, Vec<String>) {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(&dir);
+        state
+            .db
+            .put_file(&file("a", FileType::Image, &["a"]))
+            .unwrap();
+        state
+            .db
+            .put_file(&file("ab", FileType::Video, &["a", "b"]))
+            .unwrap();
+        state
+            .db
+            .put_file(&file("doc", FileType::Document, &["b"]))
+            .unwrap();
+
+        let request = Request::get(format!("/files?{query}"))
+            .body(Body::empty())
+            .unwrap();
+        let response = create_router(state).oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let mut ids: Vec<String> = body["data"]["items"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|file| file["id"].as_str().unwrap().to_string())
+            .collect();
+        ids.sort();
+        (status, ids)
+    }
+
+    #[tokio::test]
+    async fn test_list_files_repeated_tags() {
+        assert_eq!(
+            list("tag=a&tag=b").await,
+            (StatusCode::OK, vec!["ab".into()])
+        );
+        assert_eq!(
+            list("tag=a&tag=b&tag_match=any").await,
+            (StatusCode::OK, vec!["a".into(), "ab".into(), "doc".into()])
+        );
+        assert_eq!(list("tag=a,b").await, (StatusCode::OK, vec!["ab".into()]));
+        assert_eq!(list("tag=B").await.1, vec!["ab".to_string(), "doc".into()]);
+    }
+
+    #[tokio::test]
+    async fn test_list_files_repeated_file_types() {
+        let images_and_videos = (StatusCode::OK, vec!["a".to_string(), "ab".into()]);
+        assert_eq!(
+            list("file_type=image&file_type=video").await,
+            images_and_videos
+        );
+        assert_eq!(list("file_type=image,video").await, images_and_videos);
+        assert_eq!(
+            list("file_type[]=image&file_type[]=video").await,
+            images_and_videos
+        );
+        assert_eq!(
+            list("file_type=image&file_type=nope").await.0,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn test_import_archive_rejects_idempotency_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let request = Request::post("/files/import-archive")
+            .header("idempotency-key", "retry-1")
+            .header("content-type", "multipart/form-data; boundary=x")
+            .body(Body::from("--x--\r\n"))
+            .unwrap();
+        let response = create_router(test_state(&dir))
+            .oneshot(request)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("Idempotency-Key"));
+    }
+
+    #[tokio::test]
+    async fn test_list_files_repeated_scalar_rejected() {
+        assert_eq!(
+            list("subject_id=a&subject_id=b").await.0,
+            StatusCode::BAD_REQUEST
+        );
+    }
 }
@@ -1,5 +1,7 @@
 use thiserror::Error;
 
+use crate::storage::models::{Visibility, DEFAULT_NAMESPACE};
+
 #[derive(Debug, Error)]
 pub enum ConfigError {
     #[error("Invalid configuration: {0}")]
@@ -8,13 +10,77 @@ pub enum ConfigError {
 
 #[derive(Debug, Clone)]
 pub struct Config {
+    /// Bearer token for admin routes such as retention changes. Those routes
+    /// are disabled when unset.
+    pub admin_token: Option<String>,
+    /// Days audit log entries are kept, or 0 to keep them forever
+    pub audit_retention_days: u64,
+    /// Days change feed entries are kept, or 0 to keep them forever
+    pub change_retention_days: u64,
     pub cluster: ClusterConfig,
+    /// Key erasure reports are signed with. Subject erasure is disabled when
+    /// unset.
+    pub erasure_signing_key: Option<String>,
+    /// Hours the response to a request with an idempotency key is replayed
+    pub idempotency_ttl_hours: u64,
+    /// Metadata keys to maintain a query index for
+    pub indexed_metadata_keys: Vec<String>,
+    /// Every namespace, the default one first
+    pub namespaces: Vec<NamespaceConfig>,
     pub node: NodeConfig,
     pub storage: StorageConfig,
     /// Enables dangerous operations like purge. Must never be true in production.
     pub test_mode: bool,
+    /// Maximum size in bytes of an archive uploaded for import
+    pub max_import_size: u64,
     /// Maximum upload size in bytes
     pub max_upload_size: u64,
+    /// Days a file stays in the trash before it is permanently deleted
+    pub trash_retention_days: u64,
+}
+
+/// Settings of one namespace. Additional namespaces inherit every setting
+/// they don't override from the default namespace.
+#[derive(Debug, Clone)]
+pub struct NamespaceConfig {
+    pub name: String,
+    /// MIME types that may be uploaded, exact or `type/*` (empty allows any)
+    pub allowed_mime_types: Vec<String>,
+    /// Visibility of files created without choosing one
+    pub default_visibility: Visibility,
+    /// Maximum upload size in bytes
+    pub max_upload_size: u64,
+    /// Object storage of the namespace, or `None` to share the default
+    /// namespace's backend under a `<namespace>/` key prefix
+    pub storage: Option<StorageConfig>,
+}
+
+impl NamespaceConfig {
+    pub fn is_default(&self) -> bool {
+        self.name == DEFAULT_NAMESPACE
+    }
+
+    /// Whether files of this MIME type may be uploaded
+    pub fn allows_mime_type(&self, mime_type: &str) -> bool {
+        self.allowed_mime_types.is_empty()
+            || self
+                .allowed_mime_types
+                .iter()
+                .any(|allowed| match allowed.strip_suffix('*') {
+                    Some(prefix) => mime_type.starts_with(prefix),
+                    None => mime_type == allowed,
+                })
+    }
+
+    /// Object storage key for a blob. Namespaces sharing the default backend
+    /// keep their blobs under a prefix.
+    pub fn blob_key(&self, id: &str) -> String {
+        if self.is_default() || self.storage.is_some() {
+            id.to_string()
+        } else {
+            format!("{}/{id}", self.name)
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -122,6 +188,15 @@ impl Config {
             .and_then(|s| s.parse().ok())
             .unwrap_or(9993);
 
+        let indexed_metadata_keys: Vec<String> = std::env::var("INDEXED_METADATA_KEYS")
+            .map(|k| {
+                k.split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+
         let test_mode = std::env::var("TEST_MODE")
             .map(|v| v == "true" || v == "1")
             .unwrap_or(false);
@@ -131,6 +206,32 @@ impl Config {
             .and_then(|s| s.parse().ok())
             .unwrap_or(50 * 1024 * 1024); // 50MB
 
+        let max_import_size = std::env::var("MAX_IMPORT_SIZE")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(1024 * 1024 * 1024); // 1GB
+
+        let trash_retention_days = std::env::var("TRASH_RETENTION_DAYS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(30);
+
+        let audit_retention_days = std::env::var("AUDIT_RETENTION_DAYS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(365);
+
+        let change_retention_days = std::env::var("CHANGE_RETENTION_DAYS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(7);
+
+        let idempotency_ttl_hours = std::env::var("IDEMPOTENCY_TTL_HOURS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .filter(|&h| h > 0)
+            .unwrap_or(24);
+
         let storage_backend = match std::env::var("STORAGE_BACKEND")
             .unwrap_or_else(|_| "local".to_string())
             .to_lowercase()
@@ -143,10 +244,41 @@ impl Config {
         let local_storage_path =
             std::env::var("LOCAL_STORAGE_PATH").unwrap_or_else(|_| "./files".to_string());
 
+        let admin_token = std::env::var("ADMIN_TOKEN").ok().filter(|t| !t.is_empty());
+        let erasure_signing_key = std::env::var("ERASURE_SIGNING_KEY")
+            .ok()
+            .filter(|k| !k.is_empty());
+
         let gcs_bucket = std::env::var("GCS_BUCKET").ok();
         let gcs_credentials_file = std::env::var("GCS_CREDENTIALS_FILE").ok();
 
+        let storage = StorageConfig {
+            backend: storage_backend,
+            local_storage_path,
+            gcs_bucket,
+            gcs_credentials_file,
+        };
+        let default_namespace = NamespaceConfig {
+            name: DEFAULT_NAMESPACE.to_string(),
+            allowed_mime_types: list_var("ALLOWED_MIME_TYPES").unwrap_or_default(),
+            default_visibility: match std::env::var("DEFAULT_VISIBILITY") {
+                Ok(v) => v.parse().map_err(ConfigError::ValidationError)?,
+                Err(_) => Visibility::Public,
+            },
+            max_upload_size,
+            storage: None,
+        };
+        let mut namespaces = vec![default_namespace.clone()];
+        for name in list_var("NAMESPACES").unwrap_or_default() {
+            namespaces.push(load_namespace(name, &default_namespace, &storage)?);
+        }
+
         let config = Config {
+            admin_token,
+            audit_retention_days,
+            change_retention_days,
+            erasure_signing_key,
+            idempotency_ttl_hours,
             node: NodeConfig {
                 id: node_id,
                 bind_address,
@@ -161,14 +293,13 @@ impl Config {
                 },
                 ..Default::default()
             },
-            storage: StorageConfig {
-                backend: storage_backend,
-                local_storage_path,
-                gcs_bucket,
-                gcs_credentials_file,
-            },
+            indexed_metadata_keys,
+            namespaces,
+            storage,
             test_mode,
+            max_import_size,
             max_upload_size,
+            trash_retention_days,
         };
 
         config.validate()?;
@@ -189,6 +320,31 @@ impl Config {
             ));
         }
 
+        for (i, namespace) in self.namespaces.iter().enumerate().skip(1) {
+            validate_namespace_name(&namespace.name)?;
+            if self.namespaces[..i]
+                .iter()
+                .any(|n| n.name == namespace.name)
+            {
+                return Err(ConfigError::ValidationError(format!(
+                    "Namespace '{}' is listed twice in NAMESPACES",
+                    namespace.name
+                )));
+            }
+            if let Some(StorageConfig {
+                backend: StorageBackend::Gcs,
+                gcs_bucket: None,
+                ..
+            }) = namespace.storage
+            {
+                return Err(ConfigError::ValidationError(format!(
+                    "{} is required when {}=gcs",
+                    namespace_var(&namespace.name, "GCS_BUCKET"),
+                    namespace_var(&namespace.name, "STORAGE_BACKEND"),
+                )));
+            }
+        }
+
         let cluster_size = self.cluster.peers.len() + 1;
         if cluster_size > 1 && cluster_size.is_multiple_of(2) {
             tracing::warn!(
@@ -205,4 +361,107 @@ impl Config {
     pub fn is_single_node(&self) -> bool {
         self.cluster.peers.is_empty() && self.cluster.discovery.dns_name.is_none()
     }
+
+    /// Look up a namespace by name
+    pub fn namespace(&self, name: &str) -> Option<&NamespaceConfig> {
+        self.namespaces.iter().find(|n| n.name == name)
+    }
+
+    /// The largest upload any namespace accepts
+    pub fn max_namespace_upload_size(&self) -> u64 {
+        self.namespaces
+            .iter()
+            .map(|n| n.max_upload_size)
+            .max()
+            .unwrap_or(self.max_upload_size)
+    }
+}
+
+/// Read a comma-separated list variable, skipping empty entries.
+fn list_var(name: &str) -> Option<Vec<String>> {
+    std::env::var(name).ok().map(|v| {
+        v.split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect()
+    })
+}
+
+/// Name of a namespace's variable, e.g. `NAMESPACE_MY_APP_GCS_BUCKET`.
+fn namespace_var(namespace: &str, key: &str) -> String {
+    format!(
+        "NAMESPACE_{}_{key}",
+        namespace.to_uppercase().replace('-', "_")
+    )
+}
+
+/// Namespace names are lowercase letters, digits and `-`, so they are safe in
+/// URLs, object storage keys and environment variable names.
+fn validate_namespace_name(name: &str) -> Result<(), ConfigError> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+    if !valid {
+        return Err(ConfigError::ValidationError(format!(
+            "Invalid namespace '{name}': use 1-64 lowercase letters, digits and '-'"
+        )));
+    }
+    if name == DEFAULT_NAMESPACE {
+        return Err(ConfigError::ValidationError(format!(
+            "Namespace '{DEFAULT_NAMESPACE}' always exists and is configured by the global variables"
+        )));
+    }
+    Ok(())
+}
+
+/// Load an additional namespace from its `NAMESPACE_<NAME>_*` variables.
+fn load_namespace(
+    name: String,
+    default: &NamespaceConfig,
+    default_storage: &StorageConfig,
+) -> Result<NamespaceConfig, ConfigError> {
+    let var = |key: &str| std::env::var(namespace_var(&name, key)).ok();
+
+    let max_upload_size = match var("MAX_UPLOAD_SIZE") {
+        Some(v) => v.parse().map_err(|_| {
+            ConfigError::ValidationError(format!(
+                "{} must be a number of bytes",
+                namespace_var(&name, "MAX_UPLOAD_SIZE")
+            ))
+        })?,
+        None => default.max_upload_size,
+    };
+    let default_visibility = match var("DEFAULT_VISIBILITY") {
+        Some(v) => v.parse().map_err(ConfigError::ValidationError)?,
+        None => default.default_visibility,
+    };
+    let allowed_mime_types = list_var(&namespace_var(&name, "ALLOWED_MIME_TYPES"))
+        .unwrap_or_else(|| default.allowed_mime_types.clone());
+
+    // A namespace only gets its own backend when it configures one
+    let storage = var("STORAGE_BACKEND").map(|backend| StorageConfig {
+        backend: match backend.to_lowercase().as_str() {
+            "gcs" => StorageBackend::Gcs,
+            _ => StorageBackend::Local,
+        },
+        local_storage_path: var("LOCAL_STORAGE_PATH").unwrap_or_else(|| {
+            format!(
+                "{}-{name}",
+                default_storage.local_storage_path.trim_end_matches('/')
+            )
+        }),
+        gcs_bucket: var("GCS_BUCKET"),
+        gcs_credentials_file: var("GCS_CREDENTIALS_FILE")
+            .or_else(|| default_storage.gcs_credentials_file.clone()),
+    });
+
+    Ok(NamespaceConfig {
+        name,
+        allowed_mime_types,
+        default_visibility,
+        max_upload_size,
+        storage,
+    })
 }
@@ -9,12 +9,15 @@
 pub mod api;
 pub mod config;
 pub mod object_store;
+pub mod purger;
 pub mod state_machine;
 pub mod storage;
 #[cfg(test)]
 pub mod testutil;
+pub mod webhooks;
 
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
 
 use config::Config;
 use state_machine::FileStateMachine;
@@ -25,5 +28,28 @@ pub struct AppState {
     pub config: Config,
     pub db: Database,
     pub node: Arc<muster::RedbNode<FileStateMachine>>,
+    /// Object storage of the default namespace, shared by namespaces without
+    /// a backend of their own
     pub object_store: Arc<dyn object_store::ObjectStore>,
+    /// Object storage of namespaces configured with their own backend
+    pub namespace_stores: HashMap<String, Arc<dyn object_store::ObjectStore>>,
+    /// Sequence number of the last operation applied on this node
+    pub changes: tokio::sync::watch::Receiver<u64>,
+    /// Idempotency keys of requests being handled on this node, by namespace
+    pub idempotency_in_flight: Mutex<HashSet<(String, String)>>,
+}
+
+impl AppState {
+    /// Object storage holding a namespace's blobs
+    pub fn object_store_for(&self, namespace: &str) -> &Arc<dyn object_store::ObjectStore> {
+        self.namespace_stores
+            .get(namespace)
+            .unwrap_or(&self.object_store)
+    }
+
+    /// Whether this node is the cluster leader
+    pub async fn is_leader(&self) -> bool {
+        let info = self.node.cluster_info().await;
+        info.leader_id.as_deref() == Some(info.node_id.as_str())
+    }
 }
@@ -1,14 +1,15 @@
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use tracing::info;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 use file_manager::{
     api,
-    config::{Config, StorageBackend},
-    object_store as obj,
+    config::{Config, StorageBackend, StorageConfig},
+    object_store as obj, purger,
     state_machine::FileStateMachine,
-    storage::Database,
-    AppState,
+    storage::{Database, DatabaseOptions},
+    webhooks, AppState,
 };
 
 #[tokio::main(flavor = "current_thread")]
@@ -51,31 +52,27 @@ async fn main() -> anyhow::Result<()> {
     info!("Loaded configuration for node: {}", config.node.id);
 
     // Initialize database
-    let db = Database::open(&config.node.data_dir)?;
+    let db = Database::open_with_options(
+        &config.node.data_dir,
+        &DatabaseOptions {
+            indexed_metadata_keys: config.indexed_metadata_keys.clone(),
+        },
+    )?;
     info!("Database opened at: {}", config.node.data_dir);
 
-    // Initialize object store backend
-    let object_store: Arc<dyn obj::ObjectStore> = match config.storage.backend {
-        StorageBackend::Local => {
-            let store = obj::LocalStore::new(&config.storage.local_storage_path)?;
-            info!(
-                "Using local storage backend at: {}",
-                config.storage.local_storage_path
-            );
-            Arc::new(store)
+    // Initialize object store backends
+    let object_store = create_object_store(&config.storage).await?;
+    let mut namespace_stores = HashMap::new();
+    for namespace in &config.namespaces {
+        if let Some(ref storage) = namespace.storage {
+            info!("Namespace {} has its own object storage", namespace.name);
+            namespace_stores.insert(namespace.name.clone(), create_object_store(storage).await?);
         }
-        StorageBackend::Gcs => {
-            let bucket = config
-                .storage
-                .gcs_bucket
-                .as_deref()
-                .expect("GCS_BUCKET validated in config");
-            let store =
-                obj::GcsStore::new(bucket, config.storage.gcs_credentials_file.as_deref()).await?;
-            info!("Using GCS storage backend, bucket: {}", bucket);
-            Arc::new(store)
-        }
-    };
+    }
+    info!(
+        namespaces = ?config.namespaces.iter().map(|n| n.name.as_str()).collect::<Vec<_>>(),
+        "Serving namespaces"
+    );
 
     // Build muster configuration
     let cluster_port = config.cluster.cluster_port;
@@ -109,6 +106,7 @@ async fn main() -> anyhow::Result<()> {
 
     // Create the state machine
     let state_machine = FileStateMachine::new(db.clone());
+    let changes = state_machine.subscribe();
 
     // Create the cluster node
     let node = muster::MusterNode::new(muster_config, muster_storage, state_machine)?;
@@ -122,8 +120,17 @@ async fn main() -> anyhow::Result<()> {
         db,
         node: Arc::clone(&node),
         object_store,
+        namespace_stores,
+        changes,
+        idempotency_in_flight: Mutex::default(),
     });
 
+    // Start the leader-only purger for expired and trashed files
+    let purger_handle = tokio::spawn(purger::run(Arc::clone(&state)));
+
+    // Start the leader-only webhook dispatcher
+    let webhooks_handle = tokio::spawn(webhooks::run(Arc::clone(&state)));
+
     // Build and start the HTTP server
     let app = api::create_router(Arc::clone(&state));
     let listener = tokio::net::TcpListener::bind(&config.node.bind_address).await?;
@@ -135,6 +142,8 @@ async fn main() -> anyhow::Result<()> {
 
     // Cleanup: abort background tasks
     info!("Shutting down background tasks");
+    purger_handle.abort();
+    webhooks_handle.abort();
     for handle in cluster_handles {
         handle.abort();
     }
@@ -148,6 +157,28 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+async fn create_object_store(storage: &StorageConfig) -> anyhow::Result<Arc<dyn obj::ObjectStore>> {
+    match storage.backend {
+        StorageBackend::Local => {
+            let store = obj::LocalStore::new(&storage.local_storage_path)?;
+            info!(
+                "Using local storage backend at: {}",
+                storage.local_storage_path
+            );
+            Ok(Arc::new(store))
+        }
+        StorageBackend::Gcs => {
+            let bucket = storage
+                .gcs_bucket
+                .as_deref()
+                .expect("GCS_BUCKET validated in config");
+            let store = obj::GcsStore::new(bucket, storage.gcs_credentials_file.as_deref()).await?;
+            info!("Using GCS storage backend, bucket: {}", bucket);
+            Ok(Arc::new(store))
+        }
+    }
+}
+
 async fn shutdown_signal() {
     let ctrl_c = async {
         tokio::signal::ctrl_c()
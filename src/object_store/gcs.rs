@@ -1,5 +1,6 @@
 use async_trait::async_trait;
 use bytes::Bytes;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
 use reqwest::Client;
 use serde::Deserialize;
 
@@ -25,6 +26,19 @@ struct TokenResponse {
     access_token: String,
 }
 
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListResponse {
+    #[serde(default)]
+    items: Vec<ListItem>,
+    next_page_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ListItem {
+    name: String,
+}
+
 impl GcsStore {
     pub async fn new(bucket: &str, credentials_file: Option<&str>) -> Result<Self, anyhow::Error> {
         let client = Client::builder().build()?;
@@ -107,30 +121,46 @@ impl GcsStore {
     fn upload_url(&self, key: &str) -> String {
         format!(
             "https://storage.googleapis.com/upload/storage/v1/b/{}/o?uploadType=media&name={}",
-            self.bucket, key
+            self.bucket,
+            encode_key(key)
         )
     }
 
     fn object_url(&self, key: &str) -> String {
         format!(
             "https://storage.googleapis.com/storage/v1/b/{}/o/{}?alt=media",
-            self.bucket, key
+            self.bucket,
+            encode_key(key)
         )
     }
 
     fn delete_url(&self, key: &str) -> String {
         format!(
             "https://storage.googleapis.com/storage/v1/b/{}/o/{}",
-            self.bucket, key
+            self.bucket,
+            encode_key(key)
         )
     }
 
     fn metadata_url(&self, key: &str) -> String {
         format!(
             "https://storage.googleapis.com/storage/v1/b/{}/o/{}",
-            self.bucket, key
+            self.bucket,
+            encode_key(key)
         )
     }
+
+    fn list_url(&self, page_token: Option<&str>) -> String {
+        let mut url = format!(
+            "https://storage.googleapis.com/storage/v1/b/{}/o?fields=items(name),nextPageToken",
+            self.bucket
+        );
+        if let Some(token) = page_token {
+            url.push_str("&pageToken=");
+            url.push_str(token);
+        }
+        url
+    }
 }
 
 #[async_trait]
@@ -226,6 +256,50 @@ impl ObjectStore for GcsStore {
 
         Ok(resp.status().is_success())
     }
+
+    async fn list(&self) -> Result<Vec<String>, ObjectStoreError> {
+        let token = self.access_token.read().await.clone();
+        let mut keys = Vec::new();
+        let mut page_token: Option<String> = None;
+
+        loop {
+            let resp = self
+                .client
+                .get(self.list_url(page_token.as_deref()))
+                .bearer_auth(&token)
+                .send()
+                .await
+                .map_err(|e| ObjectStoreError::Backend(e.to_string()))?;
+
+            if !resp.status().is_success() {
+                let status = resp.status();
+                let body = resp.text().await.unwrap_or_default();
+                return Err(ObjectStoreError::Backend(format!(
+                    "GCS list failed ({status}): {body}"
+                )));
+            }
+
+            let page: ListResponse = resp
+                .json()
+                .await
+                .map_err(|e| ObjectStoreError::Backend(e.to_string()))?;
+
+            keys.extend(page.items.into_iter().map(|item| item.name));
+
+            match page.next_page_token {
+                Some(next) => page_token = Some(next),
+                None => break,
+            }
+        }
+
+        Ok(keys)
+    }
+}
+
+/// Object names go in URL paths and queries, where namespaced keys' `/` must
+/// be escaped
+fn encode_key(key: &str) -> String {
+    utf8_percent_encode(key, NON_ALPHANUMERIC).to_string()
 }
 
 fn base64_url_encode(data: &[u8]) -> String {
@@ -25,6 +25,10 @@ impl LocalStore {
 impl ObjectStore for LocalStore {
     async fn put(&self, key: &str, data: Bytes) -> Result<(), ObjectStoreError> {
         let path = self.object_path(key);
+        // Keys may contain `/`, like a namespace prefix
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
         tokio::fs::write(&path, &data).await?;
         Ok(())
     }
@@ -50,4 +54,22 @@ impl ObjectStore for LocalStore {
         let path = self.object_path(key);
         Ok(path.exists())
     }
+
+    async fn list(&self) -> Result<Vec<String>, ObjectStoreError> {
+        let mut keys = Vec::new();
+        let mut dirs = vec![(self.base_path.clone(), String::new())];
+        while let Some((dir, prefix)) = dirs.pop() {
+            let mut entries = tokio::fs::read_dir(&dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let key = format!("{prefix}{}", entry.file_name().to_string_lossy());
+                let file_type = entry.file_type().await?;
+                if file_type.is_dir() {
+                    dirs.push((entry.path(), format!("{key}/")));
+                } else if file_type.is_file() {
+                    keys.push(key);
+                }
+            }
+        }
+        Ok(keys)
+    }
 }
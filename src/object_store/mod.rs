@@ -26,4 +26,6 @@ pub trait ObjectStore: Send + Sync {
     async fn get(&self, key: &str) -> Result<Bytes, ObjectStoreError>;
     async fn delete(&self, key: &str) -> Result<(), ObjectStoreError>;
     async fn exists(&self, key: &str) -> Result<bool, ObjectStoreError>;
+    /// List every key currently held by the backend.
+    async fn list(&self) -> Result<Vec<String>, ObjectStoreError>;
 }
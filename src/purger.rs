@@ -0,0 +1,150 @@
+//! Background task that permanently deletes expired files and files left in
+//! the trash longer than the retention period, and prunes the audit log,
+//! change feed and idempotency keys. Runs on every node but only acts on the leader, replicating
+//! the deletes so followers catch up through muster.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+
+use crate::storage::models::{AuditContext, FileRecord, WriteOp};
+use crate::AppState;
+
+/// How often to check for expired files and files past trash retention.
+pub const PURGE_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Maximum number of files deleted per check and kind, to keep replication
+/// bursts small.
+const BATCH_SIZE: usize = 100;
+
+/// Run the purger until the task is aborted.
+pub async fn run(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        if !state.is_leader().await {
+            continue;
+        }
+        match reap_expired(&state).await {
+            Ok(0) => {}
+            Ok(count) => tracing::info!(files = count, "Deleted expired files"),
+            Err(e) => tracing::warn!(error = %e, "Failed to delete expired files"),
+        }
+        match purge_trash(&state).await {
+            Ok(0) => {}
+            Ok(count) => tracing::info!(files = count, "Purged files from trash"),
+            Err(e) => tracing::warn!(error = %e, "Failed to purge trash"),
+        }
+        if let Err(e) = prune_audit(&state).await {
+            tracing::warn!(error = %e, "Failed to prune audit log");
+        }
+        if let Err(e) = prune_changes(&state).await {
+            tracing::warn!(error = %e, "Failed to prune change feed");
+        }
+        if let Err(e) = prune_idempotency_keys(&state).await {
+            tracing::warn!(error = %e, "Failed to prune idempotency keys");
+        }
+    }
+}
+
+/// Permanently delete one batch of expired files. Returns the number of files
+/// deleted.
+pub async fn reap_expired(state: &AppState) -> anyhow::Result<u64> {
+    let files = state.db.get_expired(Utc::now(), BATCH_SIZE)?;
+    delete_files(state, files).await
+}
+
+/// Permanently delete one batch of files trashed before the retention cutoff.
+/// Returns the number of files deleted.
+pub async fn purge_trash(state: &AppState) -> anyhow::Result<u64> {
+    let retention = chrono::Duration::days(state.config.trash_retention_days as i64);
+    let now = Utc::now();
+    let files = state
+        .db
+        .get_trashed_before(now - retention, now, BATCH_SIZE)?;
+    delete_files(state, files).await
+}
+
+/// Delete audit log entries older than the audit retention period, if any.
+pub async fn prune_audit(state: &AppState) -> anyhow::Result<()> {
+    if state.config.audit_retention_days == 0 {
+        return Ok(());
+    }
+    let retention = chrono::Duration::days(state.config.audit_retention_days as i64);
+    let before = Utc::now() - retention;
+    if state
+        .db
+        .oldest_audit_timestamp()?
+        .is_some_and(|t| t < before)
+    {
+        state.node.replicate(WriteOp::PruneAudit { before }).await?;
+    }
+    Ok(())
+}
+
+/// Delete change feed entries older than the change retention period, if any.
+pub async fn prune_changes(state: &AppState) -> anyhow::Result<()> {
+    if state.config.change_retention_days == 0 {
+        return Ok(());
+    }
+    let retention = chrono::Duration::days(state.config.change_retention_days as i64);
+    let before = Utc::now() - retention;
+    if state
+        .db
+        .oldest_change_timestamp()?
+        .is_some_and(|t| t < before)
+    {
+        state
+            .node
+            .replicate(WriteOp::PruneChanges { before })
+            .await?;
+    }
+    Ok(())
+}
+
+/// Forget idempotency keys older than the idempotency TTL, if any.
+pub async fn prune_idempotency_keys(state: &AppState) -> anyhow::Result<()> {
+    let ttl = chrono::Duration::hours(state.config.idempotency_ttl_hours as i64);
+    let before = Utc::now() - ttl;
+    if state
+        .db
+        .oldest_idempotency_timestamp()?
+        .is_some_and(|t| t < before)
+    {
+        state
+            .node
+            .replicate(WriteOp::PruneIdempotencyKeys { before })
+            .await?;
+    }
+    Ok(())
+}
+
+/// Delete file records via muster, then their blobs. Files under legal hold
+/// or retention are left alone until the lock is lifted.
+async fn delete_files(state: &AppState, files: Vec<FileRecord>) -> anyhow::Result<u64> {
+    let now = Utc::now();
+    let context = AuditContext {
+        actor: Some("purger".to_string()),
+        request_id: None,
+        source_node: Some(state.config.node.id.clone()),
+        timestamp: now,
+    };
+    let mut count = 0;
+    for file in files.into_iter().filter(|f| f.lock(now).is_none()) {
+        let operation = WriteOp::DeleteFile {
+            id: file.id.clone(),
+        };
+        state.node.replicate(operation.audited(&context)).await?;
+        count += 1;
+
+        let object_store = state.object_store_for(&file.namespace);
+        for key in file.blob_keys() {
+            if let Err(e) = object_store.delete(&key).await {
+                tracing::warn!(file_id = %file.id, key = %key, error = %e, "Failed to delete file from object storage");
+            }
+        }
+    }
+    Ok(count)
+}
@@ -1,73 +1,816 @@
 //! file-manager's state machine for muster cluster replication.
 
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::storage::audit::{diff_records, AuditEntry, FieldChange};
+use crate::storage::changes::{Change, ChangeEvent, ChangeKind};
+use crate::storage::models::{
+    AuditContext, Erasure, FileRecord, IdempotencyRecord, Redirect, WebhookEvent, WriteOp,
+    DEFAULT_NAMESPACE,
+};
+use crate::storage::snapshot::{self, SnapshotRecords};
+use crate::storage::webhooks::{WebhookPayload, WebhookState};
+use crate::storage::{Database, Transaction};
 
-use crate::storage::models::{FileRecord, WriteOp};
-use crate::storage::Database;
+type ApplyError = Box<dyn std::error::Error + Send + Sync>;
 
 /// The file-manager state machine, replicated by muster.
 pub struct FileStateMachine {
     db: Database,
+    /// Sequence number of the last applied operation, for change feed tails
+    changes: watch::Sender<u64>,
 }
 
 impl FileStateMachine {
     pub fn new(db: Database) -> Self {
-        Self { db }
+        Self {
+            db,
+            changes: watch::channel(0).0,
+        }
+    }
+
+    /// Watch the sequence number of the last applied operation, to tail the
+    /// change feed.
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.changes.subscribe()
+    }
+
+    /// Reject `action` on a file under legal hold or retention at `at`, the
+    /// time the operation was decided, so the lock holds on every node
+    /// whatever the API checked and however late the entry is applied.
+    fn ensure_unlocked(
+        &self,
+        txn: &Transaction,
+        at: DateTime<Utc>,
+        id: &str,
+        action: &str,
+    ) -> Result<(), ApplyError> {
+        if let Some(lock) = txn.get_file(id)?.and_then(|f| f.lock(at)) {
+            return Err(format!("cannot {action} file {id}: it is {lock}").into());
+        }
+        Ok(())
+    }
+
+    /// Reject a conditional operation unless the file is at one of
+    /// `revisions`. Checked here rather than by the API, so two writers racing
+    /// from different nodes can't both pass.
+    fn ensure_revision(
+        &self,
+        txn: &Transaction,
+        id: &str,
+        revisions: &[u64],
+    ) -> Result<(), ApplyError> {
+        match txn.get_file(id)? {
+            Some(file) if revisions.contains(&file.revision) => Ok(()),
+            Some(file) => Err(format!(
+                "precondition failed: file {id} is at revision {}",
+                file.revision
+            )
+            .into()),
+            None => Err(format!("precondition failed: file {id} not found").into()),
+        }
+    }
+
+    /// What an operation is about to change, captured before applying it.
+    fn change_targets(
+        &self,
+        txn: &Transaction,
+        op: &WriteOp,
+    ) -> Result<Vec<ChangeTarget>, ApplyError> {
+        let file = |id: &str| -> Result<Vec<ChangeTarget>, ApplyError> {
+            Ok(txn
+                .get_file(id)?
+                .map(ChangeTarget::file)
+                .into_iter()
+                .collect())
+        };
+        Ok(match op {
+            WriteOp::CreateFile(record) => vec![ChangeTarget {
+                namespace: record.namespace.clone(),
+                file_id: Some(record.id.clone()),
+                before: txn.get_file(&record.id)?,
+                always: false,
+                erased: false,
+                after: None,
+                changes: BTreeMap::new(),
+                item: None,
+            }],
+            WriteOp::DeleteFile { id }
+            | WriteOp::UpdateFile { id, .. }
+            | WriteOp::AddTags { id, .. }
+            | WriteOp::RemoveTags { id, .. }
+            | WriteOp::AddVersion { id, .. }
+            | WriteOp::RestoreVersion { id, .. }
+            | WriteOp::TrashFile { id, .. }
+            | WriteOp::RestoreFile { id }
+            | WriteOp::SetRetention { id, .. } => file(id)?,
+            WriteOp::RenamePrefix {
+                namespace,
+                from,
+                to,
+                ..
+            } => {
+                let mut targets = Vec::new();
+                for rename in txn.plan_rename_prefix(namespace, from, to)?.renames {
+                    targets.extend(file(&rename.id)?);
+                }
+                targets
+            }
+            // The file record doesn't change, but the redirect to it is gone
+            WriteOp::DeleteRedirect {
+                namespace,
+                permalink,
+            } => match txn.get_redirect(namespace, permalink)? {
+                Some(file_id) => vec![ChangeTarget {
+                    namespace: namespace.clone(),
+                    file_id: Some(file_id),
+                    before: None,
+                    always: true,
+                    erased: false,
+                    after: None,
+                    changes: BTreeMap::new(),
+                    item: None,
+                }],
+                None => Vec::new(),
+            },
+            WriteOp::EraseFiles { ids, .. } => {
+                let mut targets = Vec::new();
+                for id in ids {
+                    targets.extend(file(id)?.into_iter().map(|mut target| {
+                        target.before = None;
+                        target.always = true;
+                        target.erased = true;
+                        target
+                    }));
+                }
+                targets
+            }
+            WriteOp::Batch { ops } => {
+                let mut targets = Vec::new();
+                for (i, item) in ops.iter().enumerate() {
+                    for mut target in self.change_targets(txn, item)? {
+                        target.item = Some(i);
+                        targets.push(target);
+                    }
+                }
+                targets
+            }
+            WriteOp::PurgeAll => vec![ChangeTarget {
+                namespace: DEFAULT_NAMESPACE.to_string(),
+                file_id: None,
+                before: None,
+                always: true,
+                erased: false,
+                after: None,
+                changes: BTreeMap::new(),
+                item: None,
+            }],
+            WriteOp::Audited { .. }
+            | WriteOp::IfMatch { .. }
+            | WriteOp::PruneAudit { .. }
+            | WriteOp::PruneChanges { .. }
+            | WriteOp::PutWebhook(_)
+            | WriteOp::DeleteWebhook { .. }
+            | WriteOp::WebhookDelivered { .. }
+            | WriteOp::WebhookFailed { .. }
+            | WriteOp::RedeliverWebhook { .. }
+            | WriteOp::PutIdempotencyKey(_)
+            | WriteOp::ClaimIdempotencyKey(_)
+            | WriteOp::ReleaseIdempotencyKey { .. }
+            | WriteOp::PruneIdempotencyKeys { .. }
+            | WriteOp::StartErasure(_)
+            | WriteOp::ErasureContentDeleted { .. }
+            | WriteOp::CompleteErasure { .. } => Vec::new(),
+        })
+    }
+
+    /// Diff each target against its file record now the operation is applied.
+    /// Files an erasure skipped are dropped, as nothing happened to them.
+    fn capture_changes(
+        &self,
+        txn: &Transaction,
+        targets: &mut Vec<ChangeTarget>,
+    ) -> Result<(), ApplyError> {
+        let mut kept = Vec::with_capacity(targets.len());
+        for mut target in targets.drain(..) {
+            if let (Some(id), true) = (&target.file_id, target.erased) {
+                if txn.get_file(id)?.is_some() {
+                    continue;
+                }
+            }
+            if let (Some(id), false) = (&target.file_id, target.always) {
+                target.after = txn.get_file(id)?;
+                target.changes = diff_records(target.before.as_ref(), target.after.as_ref())?;
+            }
+            kept.push(target);
+        }
+        *targets = kept;
+        Ok(())
+    }
+
+    /// Append an audit entry for every target the operation changed.
+    fn record_audit(
+        &self,
+        txn: &Transaction,
+        context: Option<&AuditContext>,
+        op: &WriteOp,
+        timestamp: DateTime<Utc>,
+        targets: &[ChangeTarget],
+    ) -> Result<(), ApplyError> {
+        let entries = targets
+            .iter()
+            .filter(|t| t.changed())
+            .map(|target| AuditEntry {
+                seq: 0,
+                timestamp,
+                namespace: target.namespace.clone(),
+                file_id: target.file_id.clone(),
+                actor: context.and_then(|c| c.actor.clone()),
+                request_id: context.and_then(|c| c.request_id.clone()),
+                source_node: context.and_then(|c| c.source_node.clone()),
+                op: target.op(op).clone(),
+                changes: target.changes.clone(),
+            })
+            .collect();
+        txn.append_audit(entries)?;
+        Ok(())
+    }
+
+    /// Take the operation's sequence number in the change feed, with an event
+    /// for every target it changed, and queue webhook deliveries for them.
+    /// Returns the sequence number.
+    fn record_changes(
+        &self,
+        txn: &Transaction,
+        op: &WriteOp,
+        timestamp: DateTime<Utc>,
+        targets: &[ChangeTarget],
+    ) -> Result<u64, ApplyError> {
+        let changed: Vec<(&ChangeTarget, ChangeEvent)> = targets
+            .iter()
+            .filter_map(|target| {
+                target
+                    .change_event(target.op(op))
+                    .map(|event| (target, event))
+            })
+            .collect();
+        let events = changed.iter().map(|(_, event)| event.clone()).collect();
+        let seq = txn.append_change(timestamp, op.name(), events)?;
+
+        for (target, event) in changed {
+            let Some(webhook_event) = webhook_event(target.op(op), event.kind) else {
+                continue;
+            };
+            let payload = WebhookPayload {
+                changes: target.changes.clone(),
+                event: webhook_event,
+                file: target.after.clone().or_else(|| target.before.clone()),
+                namespace: target.namespace.clone(),
+                seq,
+                timestamp,
+            };
+            txn.enqueue_webhook_deliveries(
+                &target.namespace,
+                webhook_event,
+                &serde_json::to_string(&payload)?,
+                timestamp,
+            )?;
+        }
+
+        Ok(seq)
+    }
+
+    /// Apply an operation and record its changes in a transaction, returning
+    /// its sequence number in the change feed.
+    fn apply_in(
+        &self,
+        txn: &Transaction,
+        op: &WriteOp,
+        timestamp: DateTime<Utc>,
+    ) -> Result<u64, ApplyError> {
+        // Operations from before the audit log have no context
+        let (context, op) = match op {
+            WriteOp::Audited { context, op } => (Some(context), op.as_ref()),
+            op => (None, op),
+        };
+        let op = match op {
+            WriteOp::IfMatch { id, revisions, op } => {
+                self.ensure_revision(txn, id, revisions)?;
+                op.as_ref()
+            }
+            op => op,
+        };
+        let mut targets = self.change_targets(txn, op)?;
+        self.apply_op(txn, op, timestamp)?;
+        self.capture_changes(txn, &mut targets)?;
+        self.record_audit(txn, context, op, timestamp, &targets)?;
+        self.record_changes(txn, op, timestamp, &targets)
+    }
+}
+
+/// A file, or the whole store, an operation is about to change
+struct ChangeTarget {
+    namespace: String,
+    file_id: Option<String>,
+    /// The file record before the change
+    before: Option<FileRecord>,
+    /// Record the change even if the file record is unchanged
+    always: bool,
+    /// The file is being erased, so nothing of its data is recorded: no
+    /// before and after, changed fields or permalink
+    erased: bool,
+    /// The file record after the change, captured after applying
+    after: Option<FileRecord>,
+    /// Changed file record fields, captured after applying
+    changes: BTreeMap<String, FieldChange>,
+    /// Index of the operation changing the target, for batches
+    item: Option<usize>,
+}
+
+impl ChangeTarget {
+    fn file(record: FileRecord) -> Self {
+        Self {
+            namespace: record.namespace.clone(),
+            file_id: Some(record.id.clone()),
+            before: Some(record),
+            always: false,
+            erased: false,
+            after: None,
+            changes: BTreeMap::new(),
+            item: None,
+        }
+    }
+
+    /// The operation changing this target: its item for batches
+    fn op<'a>(&self, op: &'a WriteOp) -> &'a WriteOp {
+        match (op, self.item) {
+            (WriteOp::Batch { ops }, Some(i)) => &ops[i],
+            _ => op,
+        }
+    }
+
+    fn changed(&self) -> bool {
+        self.always || !self.changes.is_empty()
+    }
+
+    /// The change feed event for this target, if the operation changed it
+    fn change_event(&self, op: &WriteOp) -> Option<ChangeEvent> {
+        if !self.changed() {
+            return None;
+        }
+        let mut event = ChangeEvent {
+            kind: ChangeKind::Updated,
+            namespace: Some(self.namespace.clone()),
+            file_id: self.file_id.clone(),
+            permalink: self.before.as_ref().map(|f| f.permalink.clone()),
+            previous_permalink: None,
+        };
+        if self.erased {
+            event.kind = ChangeKind::Deleted;
+            return Some(event);
+        }
+        match op {
+            WriteOp::PurgeAll => {
+                event.kind = ChangeKind::Purged;
+                event.namespace = None;
+                return Some(event);
+            }
+            WriteOp::DeleteRedirect { permalink, .. } => {
+                event.kind = ChangeKind::RedirectDeleted;
+                event.permalink = Some(permalink.clone());
+                return Some(event);
+            }
+            _ => {}
+        }
+
+        event.kind = match (&self.before, &self.after) {
+            (None, _) => ChangeKind::Created,
+            (Some(_), None) => ChangeKind::Deleted,
+            (Some(before), Some(after)) => match (before.deleted_at, after.deleted_at) {
+                (None, Some(_)) => ChangeKind::Trashed,
+                (Some(_), None) => ChangeKind::Restored,
+                _ => ChangeKind::Updated,
+            },
+        };
+        if let Some(after) = &self.after {
+            event.permalink = Some(after.permalink.clone());
+            event.previous_permalink = self
+                .before
+                .as_ref()
+                .filter(|before| before.permalink != after.permalink)
+                .map(|before| before.permalink.clone());
+        }
+        Some(event)
+    }
+}
+
+/// The webhook event for a change to a file, if any
+fn webhook_event(op: &WriteOp, kind: ChangeKind) -> Option<WebhookEvent> {
+    match kind {
+        ChangeKind::Created => Some(WebhookEvent::FileCreated),
+        ChangeKind::Trashed | ChangeKind::Deleted => Some(WebhookEvent::FileDeleted),
+        ChangeKind::Updated
+            if matches!(
+                op,
+                WriteOp::AddVersion { .. } | WriteOp::RestoreVersion { .. }
+            ) =>
+        {
+            Some(WebhookEvent::FileContentReplaced)
+        }
+        ChangeKind::Updated | ChangeKind::Restored => Some(WebhookEvent::FileUpdated),
+        ChangeKind::RedirectDeleted | ChangeKind::Purged => None,
     }
 }
 
-/// Full state snapshot for syncing lagging followers.
+/// Full state snapshot for syncing lagging followers. File records and the
+/// logs are read from the database as the snapshot is written out, rather
+/// than collected first.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FileSnapshot {
-    pub files: Vec<FileRecord>,
+    /// Written in chunks as `chunks` by some earlier versions
+    #[serde(alias = "chunks", deserialize_with = "snapshot::deserialize_files")]
+    pub files: SnapshotRecords<FileRecord>,
+    #[serde(default)]
+    pub redirects: Vec<Redirect>,
+    #[serde(default)]
+    pub audit_log: SnapshotRecords<AuditEntry>,
+    /// Last sequence number assigned in the audit log
+    #[serde(default)]
+    pub audit_seq: u64,
+    #[serde(default)]
+    pub change_log: SnapshotRecords<Change>,
+    /// Sequence number of the last applied operation
+    #[serde(default)]
+    pub change_seq: u64,
+    /// Highest sequence number pruned from the change feed
+    #[serde(default)]
+    pub changes_pruned_seq: u64,
+    #[serde(default)]
+    pub webhooks: WebhookState,
+    #[serde(default)]
+    pub idempotency_keys: Vec<IdempotencyRecord>,
+    #[serde(default)]
+    pub erasures: Vec<Erasure>,
 }
 
 impl muster::StateMachine for FileStateMachine {
     type WriteOp = WriteOp;
     type Snapshot = FileSnapshot;
 
-    fn apply(&self, op: &WriteOp) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    fn apply(&self, op: &WriteOp) -> Result<(), ApplyError> {
+        let timestamp = match op {
+            WriteOp::Audited { context, .. } => context.timestamp,
+            _ => Utc::now(),
+        };
+
+        // The operation, its audit entries, change feed entry and webhook
+        // deliveries are committed together. A rejected operation writes
+        // nothing but still takes its sequence number, so the feed counts
+        // every log entry applied, on every node alike.
+        let txn = self.db.begin_transaction()?;
+        let result = self.apply_in(&txn, op, timestamp);
+        let seq = match result {
+            Ok(seq) => {
+                txn.commit()?;
+                seq
+            }
+            Err(_) => {
+                drop(txn);
+                self.db.append_change(timestamp, op.name(), Vec::new())?
+            }
+        };
+        self.changes.send_replace(seq);
+        result.map(|_| ())
+    }
+
+    fn snapshot(&self) -> Result<FileSnapshot, ApplyError> {
+        let read_txn = Arc::new(self.db.begin_read()?);
+        let redirects = self.db.get_all_redirects()?;
+        let audit_seq = self.db.audit_seq()?;
+        let (change_seq, changes_pruned_seq) = self.db.change_seqs()?;
+        let webhooks = self.db.get_webhook_state()?;
+        let idempotency_keys = self.db.get_idempotency_records()?;
+        let erasures = self.db.get_erasures()?;
+        Ok(FileSnapshot {
+            files: SnapshotRecords::Stored(Arc::clone(&read_txn)),
+            redirects,
+            audit_log: SnapshotRecords::Stored(Arc::clone(&read_txn)),
+            audit_seq,
+            change_log: SnapshotRecords::Stored(read_txn),
+            change_seq,
+            changes_pruned_seq,
+            webhooks,
+            idempotency_keys,
+            erasures,
+        })
+    }
+
+    fn restore(&self, snapshot: FileSnapshot) -> Result<(), ApplyError> {
+        // Replace rather than merge, so records deleted on the leader don't
+        // survive, all at once so a failed restore leaves the old state whole
+        let txn = self.db.begin_transaction()?;
+        let count = txn.replace_all_files(snapshot.files, &snapshot.redirects)?;
+        txn.replace_audit_log(snapshot.audit_log, snapshot.audit_seq)?;
+        txn.replace_change_log(
+            snapshot.change_log,
+            snapshot.change_seq,
+            snapshot.changes_pruned_seq,
+        )?;
+        txn.replace_webhook_state(&snapshot.webhooks)?;
+        txn.replace_idempotency_records(&snapshot.idempotency_keys)?;
+        txn.replace_erasures(&snapshot.erasures)?;
+        txn.commit()?;
+        self.changes.send_replace(snapshot.change_seq);
+        tracing::info!(files = count, "Restored state from snapshot");
+        Ok(())
+    }
+}
+
+impl FileStateMachine {
+    /// Apply an operation decided at `at`, the timestamp of its audit context.
+    fn apply_op(
+        &self,
+        txn: &Transaction,
+        op: &WriteOp,
+        at: DateTime<Utc>,
+    ) -> Result<(), ApplyError> {
         match op {
             WriteOp::CreateFile(file) => {
-                self.db.put_file(file)?;
+                txn.put_file(file)?;
             }
             WriteOp::DeleteFile { id } => {
-                self.db.delete_file(id)?;
+                self.ensure_unlocked(txn, at, id, "delete")?;
+                txn.delete_file(id)?;
             }
             WriteOp::UpdateFile {
                 id,
                 alt,
                 description,
+                expires_at,
                 metadata,
                 name,
                 permalink,
                 subject_id,
+                visibility,
             } => {
-                self.db.update_file(
+                if let Some(permalink) = permalink {
+                    let moved = txn.get_file(id)?.is_some_and(|f| f.permalink != *permalink);
+                    if moved {
+                        self.ensure_unlocked(txn, at, id, "change the permalink of")?;
+                    }
+                }
+                txn.update_file(
                     id,
                     alt.as_option().map(|o| o.map(String::as_str)),
                     description.as_option().map(|o| o.map(String::as_str)),
+                    expires_at.as_option().map(|o| o.copied()),
                     metadata.as_option(),
                     name.as_option().map(|o| o.map(String::as_str)),
                     permalink.as_deref(),
                     subject_id.as_option().map(|o| o.map(String::as_str)),
+                    *visibility,
+                    at,
                 )?;
             }
-        }
-        Ok(())
-    }
-
-    fn snapshot(&self) -> Result<FileSnapshot, Box<dyn std::error::Error + Send + Sync>> {
-        let files = self.db.get_all_files()?;
-        Ok(FileSnapshot { files })
-    }
-
-    fn restore(
-        &self,
-        snapshot: FileSnapshot,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        for file in &snapshot.files {
-            self.db.put_file(file)?;
+            WriteOp::Batch { ops } => {
+                let mut ids = HashSet::new();
+                for item in ops {
+                    let Some(id) = item.file_id().filter(|_| item.is_batchable()) else {
+                        return Err(format!("{} operations cannot be batched", item.name()).into());
+                    };
+                    if !ids.insert(id) {
+                        return Err(format!("file {id} appears more than once in the batch").into());
+                    }
+                    let Some(file) = txn.get_file(id)? else {
+                        return Err(format!("file {id} not found").into());
+                    };
+                    match item {
+                        WriteOp::DeleteFile { .. } | WriteOp::TrashFile { .. } => {
+                            self.ensure_unlocked(txn, at, id, "delete")?;
+                        }
+                        WriteOp::UpdateFile {
+                            permalink: Some(permalink),
+                            ..
+                        } if *permalink != file.permalink => {
+                            self.ensure_unlocked(txn, at, id, "change the permalink of")?;
+                        }
+                        _ => {}
+                    }
+                }
+                txn.apply_batch(ops, at)?;
+                tracing::debug!(ops = ops.len(), "Applied batch");
+            }
+            WriteOp::PurgeAll => {
+                let stats = txn.purge_all()?;
+                tracing::warn!(files = stats.files, "Purged all file records");
+            }
+            WriteOp::AddTags { id, tags } => {
+                txn.add_tags(id, tags, at)?;
+            }
+            WriteOp::RemoveTags { id, tags } => {
+                txn.remove_tags(id, tags, at)?;
+            }
+            WriteOp::DeleteRedirect {
+                namespace,
+                permalink,
+            } => {
+                txn.delete_redirect(namespace, permalink)?;
+            }
+            WriteOp::AddVersion { id, version } => {
+                self.ensure_unlocked(txn, at, id, "replace the content of")?;
+                if !txn.add_version(id, version)? {
+                    tracing::warn!(
+                        file_id = %id,
+                        version = version.version,
+                        "Skipped stale file version"
+                    );
+                }
+            }
+            WriteOp::RestoreVersion {
+                id,
+                version,
+                restored_at,
+            } => {
+                self.ensure_unlocked(txn, at, id, "replace the content of")?;
+                txn.restore_version(id, *version, *restored_at)?;
+            }
+            WriteOp::TrashFile { id, deleted_at } => {
+                self.ensure_unlocked(txn, at, id, "delete")?;
+                txn.trash_file(id, *deleted_at)?;
+            }
+            WriteOp::RestoreFile { id } => {
+                txn.restore_file(id)?;
+            }
+            WriteOp::SetRetention {
+                id,
+                legal_hold,
+                retain_until,
+            } => {
+                if let Some(until) = retain_until.as_option() {
+                    let current = txn
+                        .get_file(id)?
+                        .and_then(|f| f.retain_until)
+                        .filter(|t| *t > at);
+                    if let Some(current) = current {
+                        if until.is_none_or(|t| *t < current) {
+                            return Err(format!(
+                                "cannot shorten the retention of file {id}, retained until {}",
+                                current.to_rfc3339()
+                            )
+                            .into());
+                        }
+                    }
+                }
+                txn.set_retention(
+                    id,
+                    *legal_hold,
+                    retain_until.as_option().map(|o| o.copied()),
+                    at,
+                )?;
+            }
+            WriteOp::RenamePrefix {
+                namespace,
+                from,
+                to,
+                renamed_at,
+            } => {
+                // A permalink taken since the request was checked rejects the
+                // rename, so the caller learns nothing was renamed
+                let plan = txn.plan_rename_prefix(namespace, from, to)?;
+                if let Some(conflict) = plan.conflicts.first() {
+                    return Err(format!(
+                        "{} renamed permalinks are already in use, e.g. '{}'",
+                        plan.conflicts.len(),
+                        conflict.permalink
+                    )
+                    .into());
+                }
+                for rename in &plan.renames {
+                    self.ensure_unlocked(txn, at, &rename.id, "change the permalink of")?;
+                }
+                let plan = txn.rename_prefix(namespace, from, to, *renamed_at)?;
+                tracing::info!(
+                    namespace,
+                    from,
+                    to,
+                    files = plan.renames.len(),
+                    "Renamed permalink prefix"
+                );
+            }
+            WriteOp::PruneAudit { before } => {
+                let pruned = txn.prune_audit(*before)?;
+                tracing::info!(entries = pruned, "Pruned audit log");
+            }
+            WriteOp::PruneChanges { before } => {
+                let pruned = txn.prune_changes(*before)?;
+                tracing::info!(changes = pruned, "Pruned change feed");
+            }
+            WriteOp::PutWebhook(webhook) => {
+                txn.put_webhook(webhook)?;
+            }
+            WriteOp::DeleteWebhook { id } => {
+                txn.delete_webhook(id)?;
+            }
+            WriteOp::WebhookDelivered { delivery_id } => {
+                txn.complete_webhook_delivery(*delivery_id)?;
+            }
+            WriteOp::WebhookFailed {
+                delivery_id,
+                attempted_at,
+                error,
+                retry_at,
+            } => {
+                txn.fail_webhook_delivery(*delivery_id, *attempted_at, error, *retry_at)?;
+                if retry_at.is_none() {
+                    tracing::warn!(delivery_id, error, "Dead-lettered webhook delivery");
+                }
+            }
+            WriteOp::RedeliverWebhook { delivery_id, at } => {
+                txn.redeliver_webhook_delivery(*delivery_id, *at)?;
+            }
+            WriteOp::PutIdempotencyKey(record) => {
+                txn.put_idempotency_record(record)?;
+            }
+            WriteOp::ClaimIdempotencyKey(record) => {
+                txn.claim_idempotency_key(record)?;
+            }
+            WriteOp::ReleaseIdempotencyKey {
+                namespace,
+                key,
+                claim,
+            } => {
+                txn.release_idempotency_key(namespace, key, claim)?;
+            }
+            WriteOp::PruneIdempotencyKeys { before } => {
+                let pruned = txn.prune_idempotency_records(*before)?;
+                tracing::info!(keys = pruned, "Pruned idempotency keys");
+            }
+            WriteOp::StartErasure(erasure) => {
+                if txn.put_erasure(erasure)? {
+                    tracing::info!(
+                        erasure_id = %erasure.id,
+                        namespace = %erasure.namespace,
+                        "Started erasure"
+                    );
+                }
+            }
+            WriteOp::EraseFiles {
+                erasure_id,
+                ids,
+                erased_at,
+            } => {
+                let Some(erasure) = txn.get_erasure(erasure_id)? else {
+                    return Err(format!("erasure {erasure_id} not found").into());
+                };
+                if erasure.completed_at.is_some() {
+                    return Err(format!("erasure {erasure_id} is already complete").into());
+                }
+                // Only the subject's own files, and never locked ones. Files
+                // that changed since the step was planned are skipped, and
+                // the API deletes content only for those in the report.
+                let mut erasable = Vec::with_capacity(ids.len());
+                for id in ids {
+                    let Some(file) = txn.get_file(id)? else {
+                        continue;
+                    };
+                    let of_subject = file.namespace == erasure.namespace
+                        && file.subject_id.as_deref() == Some(erasure.subject_id.as_str());
+                    let excluded = file.deleted_at.is_some() && !erasure.include_trashed;
+                    if !of_subject || excluded || file.lock(at).is_some() {
+                        tracing::debug!(erasure_id, file_id = %id, "Skipped file no longer erasable");
+                        continue;
+                    }
+                    erasable.push(id.clone());
+                }
+                let erased = txn.erase_files(erasure_id, &erasable, *erased_at)?;
+                tracing::debug!(erasure_id, files = erased, "Erased files");
+            }
+            WriteOp::ErasureContentDeleted {
+                erasure_id,
+                blob_keys,
+            } => {
+                txn.clear_erasure_content(erasure_id, blob_keys)?;
+            }
+            WriteOp::CompleteErasure {
+                erasure_id,
+                retained,
+                completed_at,
+            } => {
+                if txn.complete_erasure(erasure_id, retained, *completed_at)? {
+                    tracing::info!(erasure_id, retained = retained.len(), "Completed erasure");
+                }
+            }
+            WriteOp::Audited { .. } => {
+                return Err("audited operations cannot be nested".into());
+            }
+            WriteOp::IfMatch { .. } => {
+                return Err("conditional operations cannot be nested".into());
+            }
         }
         Ok(())
     }
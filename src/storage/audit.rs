@@ -0,0 +1,294 @@
+//! Append-only audit log of every change applied by the state machine.
+//! Entries are numbered in apply order, which is the same on every node. Like
+//! redirects they can't be rebuilt from `FILES`, so they are part of snapshots.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use redb::{ReadableTable, WriteTransaction};
+use serde::{Deserialize, Serialize};
+
+use super::db::{Database, DatabaseError, Transaction};
+use super::models::{FileRecord, WriteOp};
+use super::query::RangeFilter;
+use super::snapshot::SnapshotRecords;
+use super::tables::*;
+
+/// `META` key holding the last assigned sequence number
+const AUDIT_SEQ: &str = "audit_seq";
+
+/// One change to one file, or to no file in particular (e.g. a purge)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Position in the log, assigned when the entry is appended
+    pub seq: u64,
+    pub timestamp: DateTime<Utc>,
+    pub namespace: String,
+    pub file_id: Option<String>,
+    pub actor: Option<String>,
+    pub request_id: Option<String>,
+    pub source_node: Option<String>,
+    pub op: WriteOp,
+    /// Changed file record fields
+    pub changes: BTreeMap<String, FieldChange>,
+}
+
+/// A file record field before and after a change, `null` when absent
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FieldChange {
+    pub before: serde_json::Value,
+    pub after: serde_json::Value,
+}
+
+/// Which audit entries to list
+#[derive(Debug, Clone, Default)]
+pub struct AuditFilter {
+    pub namespace: String,
+    pub actor: Option<String>,
+    pub file_id: Option<String>,
+    /// Operation name, e.g. `update_file`
+    pub operation: Option<String>,
+    pub request_id: Option<String>,
+    pub timestamp: RangeFilter<DateTime<Utc>>,
+}
+
+impl AuditFilter {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        entry.namespace == self.namespace
+            && self
+                .actor
+                .as_ref()
+                .is_none_or(|a| entry.actor.as_ref() == Some(a))
+            && self
+                .file_id
+                .as_ref()
+                .is_none_or(|f| entry.file_id.as_ref() == Some(f))
+            && self.operation.as_ref().is_none_or(|o| o == entry.op.name())
+            && self
+                .request_id
+                .as_ref()
+                .is_none_or(|r| entry.request_id.as_ref() == Some(r))
+            && self.timestamp.contains(&entry.timestamp)
+    }
+}
+
+/// Fields that differ between two versions of a file record. A missing record
+/// counts as every field being `null`.
+pub fn diff_records(
+    before: Option<&FileRecord>,
+    after: Option<&FileRecord>,
+) -> Result<BTreeMap<String, FieldChange>, serde_json::Error> {
+    let fields = |record: Option<&FileRecord>| -> Result<_, serde_json::Error> {
+        Ok(match record.map(serde_json::to_value).transpose()? {
+            Some(serde_json::Value::Object(fields)) => fields,
+            _ => serde_json::Map::new(),
+        })
+    };
+    let before = fields(before)?;
+    let after = fields(after)?;
+
+    let mut changes = BTreeMap::new();
+    for name in before.keys().chain(after.keys()) {
+        let old = before.get(name).unwrap_or(&serde_json::Value::Null);
+        let new = after.get(name).unwrap_or(&serde_json::Value::Null);
+        if old != new && !changes.contains_key(name) {
+            changes.insert(
+                name.clone(),
+                FieldChange {
+                    before: old.clone(),
+                    after: new.clone(),
+                },
+            );
+        }
+    }
+    Ok(changes)
+}
+
+fn insert_entry(write_txn: &WriteTransaction, entry: &AuditEntry) -> Result<(), DatabaseError> {
+    let data = rmp_serde::to_vec_named(entry)?;
+    write_txn
+        .open_table(AUDIT_LOG)?
+        .insert(entry.seq, data.as_slice())?;
+    if let Some(ref file_id) = entry.file_id {
+        write_txn
+            .open_table(FILE_AUDIT)?
+            .insert((file_id.as_str(), entry.seq), ())?;
+    }
+    Ok(())
+}
+
+/// Delete every audit entry for an erased file, which would otherwise keep
+/// copies of its data.
+pub(super) fn remove_file_audit(
+    write_txn: &WriteTransaction,
+    file_id: &str,
+) -> Result<(), DatabaseError> {
+    let mut by_file = write_txn.open_table(FILE_AUDIT)?;
+    let seqs = by_file
+        .range((file_id, 0)..=(file_id, u64::MAX))?
+        .map(|key| Ok(key?.0.value().1))
+        .collect::<Result<Vec<u64>, DatabaseError>>()?;
+    let mut log = write_txn.open_table(AUDIT_LOG)?;
+    for seq in seqs {
+        log.remove(seq)?;
+        by_file.remove((file_id, seq))?;
+    }
+    Ok(())
+}
+
+impl Database {
+    /// Append entries to the audit log, numbering them in order
+    pub fn append_audit(&self, entries: Vec<AuditEntry>) -> Result<(), DatabaseError> {
+        self.write(|txn| txn.append_audit(entries))
+    }
+
+    /// List matching audit entries, newest first, starting before the given
+    /// sequence number
+    pub fn list_audit(
+        &self,
+        filter: &AuditFilter,
+        before: Option<u64>,
+        limit: usize,
+    ) -> Result<Vec<AuditEntry>, DatabaseError> {
+        let read_txn = self.begin_read()?;
+        let log = read_txn.open_table(AUDIT_LOG)?;
+        let before = before.unwrap_or(u64::MAX);
+
+        let mut entries = Vec::new();
+        let mut push = |data: &[u8]| -> Result<bool, DatabaseError> {
+            let entry: AuditEntry = rmp_serde::from_slice(data)?;
+            if filter.matches(&entry) {
+                entries.push(entry);
+            }
+            Ok(entries.len() < limit)
+        };
+
+        match filter.file_id {
+            Some(ref file_id) => {
+                let by_file = read_txn.open_table(FILE_AUDIT)?;
+                for key in by_file
+                    .range((file_id.as_str(), 0)..(file_id.as_str(), before))?
+                    .rev()
+                {
+                    let (key, _) = key?;
+                    let Some(data) = log.get(key.value().1)? else {
+                        continue;
+                    };
+                    if !push(data.value())? {
+                        break;
+                    }
+                }
+            }
+            None => {
+                for entry in log.range(..before)?.rev() {
+                    let (_, data) = entry?;
+                    if !push(data.value())? {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Timestamp of the oldest audit entry, if any
+    pub fn oldest_audit_timestamp(&self) -> Result<Option<DateTime<Utc>>, DatabaseError> {
+        let read_txn = self.begin_read()?;
+        let log = read_txn.open_table(AUDIT_LOG)?;
+        let oldest = match log.first()? {
+            Some((_, data)) => {
+                let entry: AuditEntry = rmp_serde::from_slice(data.value())?;
+                Some(entry.timestamp)
+            }
+            None => None,
+        };
+        Ok(oldest)
+    }
+
+    /// Delete audit entries recorded before a time. Returns the number deleted.
+    pub fn prune_audit(&self, before: DateTime<Utc>) -> Result<u64, DatabaseError> {
+        self.write(|txn| txn.prune_audit(before))
+    }
+
+    /// Last sequence number assigned in the audit log
+    pub fn audit_seq(&self) -> Result<u64, DatabaseError> {
+        let read_txn = self.begin_read()?;
+        let seq = read_txn
+            .open_table(META)?
+            .get(AUDIT_SEQ)?
+            .map(|v| v.value())
+            .unwrap_or(0);
+        Ok(seq)
+    }
+
+    /// Replace the audit log with one from a snapshot
+    pub fn replace_audit_log(
+        &self,
+        entries: SnapshotRecords<AuditEntry>,
+        seq: u64,
+    ) -> Result<(), DatabaseError> {
+        self.write(|txn| txn.replace_audit_log(entries, seq))
+    }
+}
+
+impl Transaction {
+    /// Replace the audit log with one from a snapshot
+    pub fn replace_audit_log(
+        &self,
+        entries: SnapshotRecords<AuditEntry>,
+        seq: u64,
+    ) -> Result<(), DatabaseError> {
+        let write_txn = &self.write_txn;
+        write_txn.open_table(AUDIT_LOG)?.retain(|_, _| false)?;
+        write_txn.open_table(FILE_AUDIT)?.retain(|_, _| false)?;
+        entries.try_for_each(|entry| insert_entry(write_txn, &entry))?;
+        write_txn.open_table(META)?.insert(AUDIT_SEQ, seq)?;
+        Ok(())
+    }
+
+    /// Append entries to the audit log, numbering them in order
+    pub fn append_audit(&self, entries: Vec<AuditEntry>) -> Result<(), DatabaseError> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let write_txn = &self.write_txn;
+        {
+            let mut meta = write_txn.open_table(META)?;
+            let mut seq = meta.get(AUDIT_SEQ)?.map(|v| v.value()).unwrap_or(0);
+            for mut entry in entries {
+                seq += 1;
+                entry.seq = seq;
+                insert_entry(write_txn, &entry)?;
+            }
+            meta.insert(AUDIT_SEQ, seq)?;
+        }
+        Ok(())
+    }
+
+    /// Delete audit entries recorded before a time. Returns the number deleted.
+    pub fn prune_audit(&self, before: DateTime<Utc>) -> Result<u64, DatabaseError> {
+        let write_txn = &self.write_txn;
+        let mut pruned = 0;
+        {
+            let mut log = write_txn.open_table(AUDIT_LOG)?;
+            let mut by_file = write_txn.open_table(FILE_AUDIT)?;
+            loop {
+                let entry: AuditEntry = match log.first()? {
+                    Some((_, data)) => rmp_serde::from_slice(data.value())?,
+                    None => break,
+                };
+                // Entries are appended in time order, give or take clock skew
+                // between the nodes accepting requests
+                if entry.timestamp >= before {
+                    break;
+                }
+                log.remove(entry.seq)?;
+                if let Some(ref file_id) = entry.file_id {
+                    by_file.remove((file_id.as_str(), entry.seq))?;
+                }
+                pruned += 1;
+            }
+        }
+        Ok(pruned)
+    }
+}
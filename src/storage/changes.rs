@@ -0,0 +1,300 @@
+//! Ordered change feed for downstream consumers. Every applied operation takes
+//! the next sequence number, so a consumer that remembers the last one it saw
+//! can resume exactly where it left off. Muster doesn't hand its log position
+//! to the state machine, so the count is kept here and carried in snapshots;
+//! it advances once per log entry applied, rejected or not, on every node
+//! alike.
+
+use chrono::{DateTime, Utc};
+use redb::{ReadableTable, WriteTransaction};
+use serde::{Deserialize, Serialize};
+
+use super::db::{Database, DatabaseError, Transaction};
+use super::snapshot::SnapshotRecords;
+use super::tables::*;
+
+/// `META` key holding the sequence number of the last applied operation
+const CHANGE_SEQ: &str = "change_seq";
+
+/// `META` key holding the highest sequence number pruned from the feed
+const CHANGES_PRUNED_SEQ: &str = "changes_pruned_seq";
+
+/// Most changes read for one page, whatever namespace they are in, so a page
+/// of a quiet namespace doesn't read the whole feed
+const MAX_SCANNED_CHANGES: usize = 1000;
+
+/// The files one applied operation changed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Change {
+    pub seq: u64,
+    pub timestamp: DateTime<Utc>,
+    /// Operation name, e.g. `update_file`
+    pub operation: String,
+    pub events: Vec<ChangeEvent>,
+}
+
+/// A change to one file, or to every file of every namespace for a purge
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChangeEvent {
+    pub file_id: Option<String>,
+    pub kind: ChangeKind,
+    /// `None` for changes to every namespace
+    pub namespace: Option<String>,
+    /// Current permalink, or the last one of a deleted file or redirect
+    pub permalink: Option<String>,
+    /// Permalink before the change, when it moved
+    pub previous_permalink: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Created,
+    Updated,
+    Trashed,
+    Restored,
+    Deleted,
+    RedirectDeleted,
+    Purged,
+}
+
+/// A page of the change feed
+#[derive(Debug, Clone)]
+pub struct ChangePage {
+    pub changes: Vec<Change>,
+    /// Sequence number of the last applied operation
+    pub last_seq: u64,
+    /// Highest sequence number pruned from the feed. Consumers that saw less
+    /// have missed changes.
+    pub pruned_seq: u64,
+    /// Sequence number the next page starts after: the last change read, or
+    /// `last_seq` once the feed was read to the end
+    pub next_since: u64,
+}
+
+fn meta_seq(write_txn: &WriteTransaction, key: &str) -> Result<u64, DatabaseError> {
+    Ok(write_txn
+        .open_table(META)?
+        .get(key)?
+        .map(|v| v.value())
+        .unwrap_or(0))
+}
+
+fn insert_change(write_txn: &WriteTransaction, change: &Change) -> Result<(), DatabaseError> {
+    let data = rmp_serde::to_vec_named(change)?;
+    write_txn
+        .open_table(CHANGE_LOG)?
+        .insert(change.seq, data.as_slice())?;
+    let mut by_file = write_txn.open_table(FILE_CHANGES)?;
+    for file_id in change.events.iter().filter_map(|e| e.file_id.as_deref()) {
+        by_file.insert((file_id, change.seq), ())?;
+    }
+    Ok(())
+}
+
+/// Remove the permalinks of an erased file from every change to it. The
+/// events stay, so consumers still see that the file changed.
+pub(super) fn scrub_file_changes(
+    write_txn: &WriteTransaction,
+    file_id: &str,
+) -> Result<(), DatabaseError> {
+    let seqs = write_txn
+        .open_table(FILE_CHANGES)?
+        .range((file_id, 0)..=(file_id, u64::MAX))?
+        .map(|key| Ok(key?.0.value().1))
+        .collect::<Result<Vec<u64>, DatabaseError>>()?;
+    let mut log = write_txn.open_table(CHANGE_LOG)?;
+    for seq in seqs {
+        let mut change: Change = match log.get(seq)? {
+            Some(data) => rmp_serde::from_slice(data.value())?,
+            None => continue,
+        };
+        for event in &mut change.events {
+            if event.file_id.as_deref() == Some(file_id) {
+                event.permalink = None;
+                event.previous_permalink = None;
+            }
+        }
+        let data = rmp_serde::to_vec_named(&change)?;
+        log.insert(seq, data.as_slice())?;
+    }
+    Ok(())
+}
+
+impl Database {
+    /// Take the next sequence number for an applied operation, recording its
+    /// events if it changed anything. Returns the sequence number.
+    pub fn append_change(
+        &self,
+        timestamp: DateTime<Utc>,
+        operation: &str,
+        events: Vec<ChangeEvent>,
+    ) -> Result<u64, DatabaseError> {
+        self.write(|txn| txn.append_change(timestamp, operation, events))
+    }
+
+    /// List changes applied after a sequence number, oldest first, keeping
+    /// the events in a namespace. Changes without any are skipped. At most
+    /// `MAX_SCANNED_CHANGES` are read, so a page can be short or empty before
+    /// the end of the feed; `next_since` says where to carry on.
+    pub fn list_changes(
+        &self,
+        namespace: &str,
+        since: u64,
+        limit: usize,
+    ) -> Result<ChangePage, DatabaseError> {
+        let read_txn = self.begin_read()?;
+        let meta = read_txn.open_table(META)?;
+        let pruned_seq = meta
+            .get(CHANGES_PRUNED_SEQ)?
+            .map(|v| v.value())
+            .unwrap_or(0);
+        let last_seq = meta.get(CHANGE_SEQ)?.map(|v| v.value()).unwrap_or(0);
+
+        let log = read_txn.open_table(CHANGE_LOG)?;
+        let mut changes = Vec::new();
+        let mut next_since = since;
+        let mut read_to_end = true;
+        for (scanned, entry) in log.range(since.saturating_add(1)..)?.enumerate() {
+            if changes.len() >= limit || scanned >= MAX_SCANNED_CHANGES {
+                read_to_end = false;
+                break;
+            }
+            let (_, data) = entry?;
+            let mut change: Change = rmp_serde::from_slice(data.value())?;
+            change
+                .events
+                .retain(|e| e.namespace.as_deref().is_none_or(|ns| ns == namespace));
+            let seq = change.seq;
+            if !change.events.is_empty() {
+                changes.push(change);
+            }
+            next_since = seq;
+        }
+        if read_to_end {
+            next_since = last_seq.max(since);
+        }
+        Ok(ChangePage {
+            changes,
+            last_seq,
+            pruned_seq,
+            next_since,
+        })
+    }
+
+    /// Timestamp of the oldest change in the feed, if any
+    pub fn oldest_change_timestamp(&self) -> Result<Option<DateTime<Utc>>, DatabaseError> {
+        let read_txn = self.begin_read()?;
+        let log = read_txn.open_table(CHANGE_LOG)?;
+        let oldest = match log.first()? {
+            Some((_, data)) => {
+                let change: Change = rmp_serde::from_slice(data.value())?;
+                Some(change.timestamp)
+            }
+            None => None,
+        };
+        Ok(oldest)
+    }
+
+    /// Delete changes applied before a time. Consumers behind the pruned
+    /// changes can no longer resume. Returns the number deleted.
+    pub fn prune_changes(&self, before: DateTime<Utc>) -> Result<u64, DatabaseError> {
+        self.write(|txn| txn.prune_changes(before))
+    }
+
+    /// Sequence number of the last applied operation and the highest one
+    /// pruned from the feed
+    pub fn change_seqs(&self) -> Result<(u64, u64), DatabaseError> {
+        let read_txn = self.begin_read()?;
+        let meta = read_txn.open_table(META)?;
+        let seq = meta.get(CHANGE_SEQ)?.map(|v| v.value()).unwrap_or(0);
+        let pruned_seq = meta
+            .get(CHANGES_PRUNED_SEQ)?
+            .map(|v| v.value())
+            .unwrap_or(0);
+        Ok((seq, pruned_seq))
+    }
+
+    /// Replace the change feed with one from a snapshot
+    pub fn replace_change_log(
+        &self,
+        changes: SnapshotRecords<Change>,
+        seq: u64,
+        pruned_seq: u64,
+    ) -> Result<(), DatabaseError> {
+        self.write(|txn| txn.replace_change_log(changes, seq, pruned_seq))
+    }
+}
+
+impl Transaction {
+    /// Replace the change feed with one from a snapshot
+    pub fn replace_change_log(
+        &self,
+        changes: SnapshotRecords<Change>,
+        seq: u64,
+        pruned_seq: u64,
+    ) -> Result<(), DatabaseError> {
+        let write_txn = &self.write_txn;
+        write_txn.open_table(CHANGE_LOG)?.retain(|_, _| false)?;
+        write_txn.open_table(FILE_CHANGES)?.retain(|_, _| false)?;
+        changes.try_for_each(|change| insert_change(write_txn, &change))?;
+        let mut meta = write_txn.open_table(META)?;
+        meta.insert(CHANGE_SEQ, seq)?;
+        meta.insert(CHANGES_PRUNED_SEQ, pruned_seq)?;
+        Ok(())
+    }
+
+    /// Take the next sequence number for an applied operation, recording its
+    /// events if it changed anything. Returns the sequence number.
+    pub fn append_change(
+        &self,
+        timestamp: DateTime<Utc>,
+        operation: &str,
+        events: Vec<ChangeEvent>,
+    ) -> Result<u64, DatabaseError> {
+        let write_txn = &self.write_txn;
+        let seq = meta_seq(write_txn, CHANGE_SEQ)? + 1;
+        if !events.is_empty() {
+            let change = Change {
+                seq,
+                timestamp,
+                operation: operation.to_string(),
+                events,
+            };
+            insert_change(write_txn, &change)?;
+        }
+        write_txn.open_table(META)?.insert(CHANGE_SEQ, seq)?;
+        Ok(seq)
+    }
+
+    /// Delete changes applied before a time. Consumers behind the pruned
+    /// changes can no longer resume. Returns the number deleted.
+    pub fn prune_changes(&self, before: DateTime<Utc>) -> Result<u64, DatabaseError> {
+        let write_txn = &self.write_txn;
+        let mut pruned = 0;
+        let mut pruned_seq = meta_seq(write_txn, CHANGES_PRUNED_SEQ)?;
+        {
+            let mut log = write_txn.open_table(CHANGE_LOG)?;
+            let mut by_file = write_txn.open_table(FILE_CHANGES)?;
+            loop {
+                let change: Change = match log.first()? {
+                    Some((_, data)) => rmp_serde::from_slice(data.value())?,
+                    None => break,
+                };
+                if change.timestamp >= before {
+                    break;
+                }
+                log.remove(change.seq)?;
+                for file_id in change.events.iter().filter_map(|e| e.file_id.as_deref()) {
+                    by_file.remove((file_id, change.seq))?;
+                }
+                pruned_seq = pruned_seq.max(change.seq);
+                pruned += 1;
+            }
+        }
+        write_txn
+            .open_table(META)?
+            .insert(CHANGES_PRUNED_SEQ, pruned_seq)?;
+        Ok(pruned)
+    }
+}
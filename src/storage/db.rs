@@ -1,9 +1,12 @@
-use redb::{Database as RedbDatabase, ReadTransaction, ReadableTable, WriteTransaction};
+use redb::{Database as RedbDatabase, ReadTransaction, ReadableTableMetadata, WriteTransaction};
 use std::path::Path;
 use std::sync::Arc;
 use thiserror::Error;
 
+use super::indexes;
+use super::redirects;
 use super::tables::*;
+use super::webhooks;
 
 #[derive(Debug, Error)]
 pub enum DatabaseError {
@@ -75,6 +78,27 @@ impl Clone for Database {
     }
 }
 
+/// Changes made together, committed at once or not at all. Dropping it
+/// without committing discards them. The state machine applies each operation
+/// in one, with its audit entries, change feed entry and webhook deliveries.
+pub struct Transaction {
+    pub(super) write_txn: WriteTransaction,
+}
+
+impl Transaction {
+    pub fn commit(self) -> Result<(), DatabaseError> {
+        self.write_txn.commit()?;
+        Ok(())
+    }
+}
+
+/// Options for opening a database
+#[derive(Debug, Clone, Default)]
+pub struct DatabaseOptions {
+    /// Metadata keys to maintain an index for
+    pub indexed_metadata_keys: Vec<String>,
+}
+
 /// Statistics from a purge operation
 #[derive(Debug, Default)]
 pub struct PurgeStats {
@@ -84,6 +108,15 @@ pub struct PurgeStats {
 impl Database {
     /// Open or create a database at the given path
     pub fn open<P: AsRef<Path>>(data_dir: P) -> Result<Self, DatabaseError> {
+        Self::open_with_options(data_dir, &DatabaseOptions::default())
+    }
+
+    /// Open or create a database at the given path, rebuilding the indexes if
+    /// the indexed metadata keys differ from the previous open
+    pub fn open_with_options<P: AsRef<Path>>(
+        data_dir: P,
+        options: &DatabaseOptions,
+    ) -> Result<Self, DatabaseError> {
         std::fs::create_dir_all(data_dir.as_ref())?;
         let db_path = data_dir.as_ref().join("file-manager.redb");
         let db = Arc::new(RedbDatabase::create(db_path)?);
@@ -93,8 +126,23 @@ impl Database {
         {
             let _ = write_txn.open_table(FILES)?;
             let _ = write_txn.open_table(FILE_PERMALINKS)?;
-            let _ = write_txn.open_table(SUBJECT_FILES)?;
+            let _ = write_txn.open_table(PERMALINK_REDIRECTS)?;
+            let _ = write_txn.open_table(FILE_REDIRECTS)?;
+            let _ = write_txn.open_table(AUDIT_LOG)?;
+            let _ = write_txn.open_table(FILE_AUDIT)?;
+            let _ = write_txn.open_table(CHANGE_LOG)?;
+            let _ = write_txn.open_table(FILE_CHANGES)?;
+            let _ = write_txn.open_table(WEBHOOKS)?;
+            let _ = write_txn.open_table(WEBHOOK_OUTBOX)?;
+            let _ = write_txn.open_table(WEBHOOK_QUEUES)?;
+            let _ = write_txn.open_table(WEBHOOK_DEAD_LETTERS)?;
+            let _ = write_txn.open_table(IDEMPOTENCY_KEYS)?;
+            let _ = write_txn.open_table(ERASURES)?;
+            let _ = write_txn.open_table(OPEN_ERASURES)?;
+            let _ = write_txn.open_table(META)?;
         }
+        indexes::migrate(&write_txn, &options.indexed_metadata_keys)?;
+        webhooks::migrate_queues(&write_txn)?;
         write_txn.commit()?;
 
         Ok(Self { db })
@@ -115,62 +163,73 @@ impl Database {
         Ok(self.db.begin_write()?)
     }
 
+    /// Begin a transaction to make several changes in, all committed together
+    pub fn begin_transaction(&self) -> Result<Transaction, DatabaseError> {
+        Ok(Transaction {
+            write_txn: self.begin_write()?,
+        })
+    }
+
+    /// Make a change in a transaction of its own
+    pub(super) fn write<T, F>(&self, change: F) -> Result<T, DatabaseError>
+    where
+        F: FnOnce(&Transaction) -> Result<T, DatabaseError>,
+    {
+        let txn = self.begin_transaction()?;
+        let result = change(&txn)?;
+        txn.commit()?;
+        Ok(result)
+    }
+
     // ========================================================================
     // Admin operations
     // ========================================================================
 
     /// Purge all data - for testing only
     pub fn purge_all(&self) -> Result<PurgeStats, DatabaseError> {
-        let write_txn = self.begin_write()?;
+        self.write(|txn| txn.purge_all())
+    }
+}
+
+impl Transaction {
+    /// Purge all data - for testing only
+    pub fn purge_all(&self) -> Result<PurgeStats, DatabaseError> {
+        let write_txn = &self.write_txn;
         let mut stats = PurgeStats::default();
 
         // Clear files
         {
-            let table = write_txn.open_table(FILES)?;
-            let keys: Vec<String> = table
-                .iter()?
-                .map(|r| r.map(|(k, _)| k.value().to_string()))
-                .collect::<Result<Vec<_>, _>>()?;
-            drop(table);
-
             let mut table = write_txn.open_table(FILES)?;
-            for key in keys {
-                table.remove(key.as_str())?;
-                stats.files += 1;
-            }
+            stats.files = table.len()?;
+            table.retain(|_, _| false)?;
         }
 
-        // Clear permalink index
-        {
-            let table = write_txn.open_table(FILE_PERMALINKS)?;
-            let keys: Vec<String> = table
-                .iter()?
-                .map(|r| r.map(|(k, _)| k.value().to_string()))
-                .collect::<Result<Vec<_>, _>>()?;
-            drop(table);
-
-            let mut table = write_txn.open_table(FILE_PERMALINKS)?;
-            for key in keys {
-                table.remove(key.as_str())?;
-            }
-        }
-
-        // Clear subject index
-        {
-            let table = write_txn.open_table(SUBJECT_FILES)?;
-            let keys: Vec<String> = table
-                .iter()?
-                .map(|r| r.map(|(k, _)| k.value().to_string()))
-                .collect::<Result<Vec<_>, _>>()?;
-            drop(table);
-
-            let mut table = write_txn.open_table(SUBJECT_FILES)?;
-            for key in keys {
-                table.remove(key.as_str())?;
-            }
-        }
+        // Clear permalink and secondary indexes
+        indexes::clear_indexes(write_txn)?;
+        redirects::clear_redirects(write_txn)?;
+
+        // Clear the logs, webhooks and everything else kept beside the files,
+        // with the sequence numbers they are assigned from. The index version
+        // stays, as the cleared indexes are still current.
+        write_txn.open_table(AUDIT_LOG)?.retain(|_, _| false)?;
+        write_txn.open_table(FILE_AUDIT)?.retain(|_, _| false)?;
+        write_txn.open_table(CHANGE_LOG)?.retain(|_, _| false)?;
+        write_txn.open_table(FILE_CHANGES)?.retain(|_, _| false)?;
+        write_txn.open_table(WEBHOOKS)?.retain(|_, _| false)?;
+        write_txn.open_table(WEBHOOK_OUTBOX)?.retain(|_, _| false)?;
+        write_txn.open_table(WEBHOOK_QUEUES)?.retain(|_, _| false)?;
+        write_txn
+            .open_table(WEBHOOK_DEAD_LETTERS)?
+            .retain(|_, _| false)?;
+        write_txn
+            .open_table(IDEMPOTENCY_KEYS)?
+            .retain(|_, _| false)?;
+        write_txn.open_table(ERASURES)?.retain(|_, _| false)?;
+        write_txn.open_table(OPEN_ERASURES)?.retain(|_, _| false)?;
+        write_txn
+            .open_table(META)?
+            .retain(|key, _| key == indexes::INDEX_VERSION_KEY)?;
 
-        write_txn.commit()?;
         Ok(stats)
     }
 }
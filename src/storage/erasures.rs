@@ -0,0 +1,289 @@
+//! Right-to-erasure requests. Erasing a large subject takes several steps, so
+//! each request is stored with the report of what it has deleted so far, and
+//! a request interrupted part way can be resumed from any node. Reports are
+//! kept as proof of erasure and are part of snapshots.
+
+use chrono::{DateTime, Utc};
+use redb::ReadableTable;
+
+use super::audit::remove_file_audit;
+use super::changes::scrub_file_changes;
+use super::db::{Database, DatabaseError, Transaction};
+use super::files::{load_file, write_delete};
+use super::indexes::scoped;
+use super::models::{ErasedFile, Erasure, FileRecord};
+use super::tables::*;
+
+/// Key of an erasure in `OPEN_ERASURES`
+fn open_key(erasure: &Erasure) -> String {
+    scoped(&erasure.namespace, &erasure.subject_id)
+}
+
+impl Database {
+    /// Store a new erasure. An erasure already stored with its ID is kept.
+    /// Returns whether this one was stored.
+    pub fn put_erasure(&self, erasure: &Erasure) -> Result<bool, DatabaseError> {
+        self.write(|txn| txn.put_erasure(erasure))
+    }
+
+    pub fn get_erasure(&self, id: &str) -> Result<Option<Erasure>, DatabaseError> {
+        let read_txn = self.begin_read()?;
+        let table = read_txn.open_table(ERASURES)?;
+        let erasure = match table.get(id)? {
+            Some(data) => Some(rmp_serde::from_slice(data.value())?),
+            None => None,
+        };
+        Ok(erasure)
+    }
+
+    /// The erasure of a subject that was started but not completed, if any
+    pub fn get_open_erasure(
+        &self,
+        namespace: &str,
+        subject_id: &str,
+    ) -> Result<Option<Erasure>, DatabaseError> {
+        let read_txn = self.begin_read()?;
+        let subject = scoped(namespace, subject_id);
+        let id = match read_txn
+            .open_table(OPEN_ERASURES)?
+            .range((subject.as_str(), "")..)?
+            .next()
+        {
+            Some(entry) => {
+                let (key, _) = entry?;
+                let (key_subject, id) = key.value();
+                (key_subject == subject).then(|| id.to_string())
+            }
+            None => None,
+        };
+        let erasure = match id {
+            Some(id) => match read_txn.open_table(ERASURES)?.get(id.as_str())? {
+                Some(data) => Some(rmp_serde::from_slice(data.value())?),
+                None => None,
+            },
+            None => None,
+        };
+        Ok(erasure)
+    }
+
+    /// Every file of a subject, oldest first, followed by its trashed files
+    /// when `include_trashed` is set
+    pub fn get_subject_files(
+        &self,
+        namespace: &str,
+        subject_id: &str,
+        include_trashed: bool,
+    ) -> Result<Vec<FileRecord>, DatabaseError> {
+        let mut files = self.get_files_by_subject(namespace, subject_id)?;
+        if !include_trashed {
+            return Ok(files);
+        }
+
+        // Trashed files are left out of the subject index, and have their own
+        let read_txn = self.begin_read()?;
+        let trash = read_txn.open_table(TRASHED_SUBJECT_FILES)?;
+        let files_table = read_txn.open_table(FILES)?;
+        let subject = scoped(namespace, subject_id);
+        let start = (subject.as_str(), i64::MIN, "");
+        let end = (subject.as_str(), i64::MAX, "");
+        for entry in trash.range(start..end)? {
+            let (key, _) = entry?;
+            let (_, _, id) = key.value();
+            if let Some(data) = files_table.get(id)? {
+                files.push(rmp_serde::from_slice(data.value())?);
+            }
+        }
+        Ok(files)
+    }
+
+    /// Permanently delete files in one transaction, adding each to the
+    /// erasure's report. Their audit entries are deleted and their permalinks
+    /// removed from the change feed. Returns the number deleted.
+    pub fn erase_files(
+        &self,
+        erasure_id: &str,
+        ids: &[String],
+        erased_at: DateTime<Utc>,
+    ) -> Result<usize, DatabaseError> {
+        self.write(|txn| txn.erase_files(erasure_id, ids, erased_at))
+    }
+
+    /// Drop blob keys from an erasure's pending content, once deleted from
+    /// object storage
+    pub fn clear_erasure_content(
+        &self,
+        erasure_id: &str,
+        blob_keys: &[String],
+    ) -> Result<(), DatabaseError> {
+        self.write(|txn| txn.clear_erasure_content(erasure_id, blob_keys))
+    }
+
+    /// Mark an erasure complete. Returns false if it doesn't exist or is
+    /// already complete.
+    pub fn complete_erasure(
+        &self,
+        erasure_id: &str,
+        retained: &[String],
+        completed_at: DateTime<Utc>,
+    ) -> Result<bool, DatabaseError> {
+        self.write(|txn| txn.complete_erasure(erasure_id, retained, completed_at))
+    }
+
+    /// Get every erasure, for snapshots
+    pub fn get_erasures(&self) -> Result<Vec<Erasure>, DatabaseError> {
+        let read_txn = self.begin_read()?;
+        let mut erasures = Vec::new();
+        for entry in read_txn.open_table(ERASURES)?.iter()? {
+            let (_, data) = entry?;
+            erasures.push(rmp_serde::from_slice(data.value())?);
+        }
+        Ok(erasures)
+    }
+
+    /// Replace every erasure with those from a snapshot
+    pub fn replace_erasures(&self, erasures: &[Erasure]) -> Result<(), DatabaseError> {
+        self.write(|txn| txn.replace_erasures(erasures))
+    }
+}
+
+impl Transaction {
+    /// Replace every erasure with those from a snapshot
+    pub fn replace_erasures(&self, erasures: &[Erasure]) -> Result<(), DatabaseError> {
+        let mut table = self.write_txn.open_table(ERASURES)?;
+        let mut open = self.write_txn.open_table(OPEN_ERASURES)?;
+        table.retain(|_, _| false)?;
+        open.retain(|_, _| false)?;
+        for erasure in erasures {
+            let data = rmp_serde::to_vec_named(erasure)?;
+            table.insert(erasure.id.as_str(), data.as_slice())?;
+            if erasure.completed_at.is_none() {
+                open.insert((open_key(erasure).as_str(), erasure.id.as_str()), ())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Store a new erasure. An erasure already stored with its ID is kept.
+    /// Returns whether this one was stored.
+    pub fn put_erasure(&self, erasure: &Erasure) -> Result<bool, DatabaseError> {
+        let write_txn = &self.write_txn;
+        let stored = {
+            let mut table = write_txn.open_table(ERASURES)?;
+            if table.get(erasure.id.as_str())?.is_some() {
+                false
+            } else {
+                let data = rmp_serde::to_vec_named(erasure)?;
+                table.insert(erasure.id.as_str(), data.as_slice())?;
+                if erasure.completed_at.is_none() {
+                    write_txn
+                        .open_table(OPEN_ERASURES)?
+                        .insert((open_key(erasure).as_str(), erasure.id.as_str()), ())?;
+                }
+                true
+            }
+        };
+        Ok(stored)
+    }
+
+    pub fn get_erasure(&self, id: &str) -> Result<Option<Erasure>, DatabaseError> {
+        let table = self.write_txn.open_table(ERASURES)?;
+        let erasure = match table.get(id)? {
+            Some(data) => Some(rmp_serde::from_slice(data.value())?),
+            None => None,
+        };
+        Ok(erasure)
+    }
+
+    /// Permanently delete files, adding each to the erasure's report. Their
+    /// audit entries are deleted and their permalinks removed from the change
+    /// feed, so no copy of their data is left. Returns the number deleted.
+    pub fn erase_files(
+        &self,
+        erasure_id: &str,
+        ids: &[String],
+        erased_at: DateTime<Utc>,
+    ) -> Result<usize, DatabaseError> {
+        let write_txn = &self.write_txn;
+        let erased = {
+            let mut table = write_txn.open_table(ERASURES)?;
+            let mut erasure: Erasure = match table.get(erasure_id)? {
+                Some(data) => rmp_serde::from_slice(data.value())?,
+                None => return Ok(0),
+            };
+            let mut erased = 0;
+            for id in ids {
+                let Some(file) = load_file(write_txn, id)? else {
+                    continue;
+                };
+                write_delete(write_txn, id)?;
+                remove_file_audit(write_txn, id)?;
+                scrub_file_changes(write_txn, id)?;
+                erasure.pending_content.extend(file.blob_keys());
+                erasure.erased.push(ErasedFile {
+                    id: file.id.clone(),
+                    permalink: file.permalink.clone(),
+                    trashed: file.deleted_at.is_some(),
+                    versions: file.all_versions(),
+                    erased_at,
+                });
+                erased += 1;
+            }
+            let data = rmp_serde::to_vec_named(&erasure)?;
+            table.insert(erasure_id, data.as_slice())?;
+            erased
+        };
+        Ok(erased)
+    }
+
+    /// Drop blob keys from an erasure's pending content, once deleted from
+    /// object storage
+    pub fn clear_erasure_content(
+        &self,
+        erasure_id: &str,
+        blob_keys: &[String],
+    ) -> Result<(), DatabaseError> {
+        let mut table = self.write_txn.open_table(ERASURES)?;
+        let mut erasure: Erasure = match table.get(erasure_id)? {
+            Some(data) => rmp_serde::from_slice(data.value())?,
+            None => return Ok(()),
+        };
+        erasure
+            .pending_content
+            .retain(|key| !blob_keys.contains(key));
+        let data = rmp_serde::to_vec_named(&erasure)?;
+        table.insert(erasure_id, data.as_slice())?;
+        Ok(())
+    }
+
+    /// Mark an erasure complete. Returns false if it doesn't exist or is
+    /// already complete.
+    pub fn complete_erasure(
+        &self,
+        erasure_id: &str,
+        retained: &[String],
+        completed_at: DateTime<Utc>,
+    ) -> Result<bool, DatabaseError> {
+        let write_txn = &self.write_txn;
+        let completed = {
+            let mut table = write_txn.open_table(ERASURES)?;
+            let erasure: Option<Erasure> = match table.get(erasure_id)? {
+                Some(data) => Some(rmp_serde::from_slice(data.value())?),
+                None => None,
+            };
+            match erasure {
+                Some(mut erasure) if erasure.completed_at.is_none() => {
+                    erasure.completed_at = Some(completed_at);
+                    erasure.retained = retained.to_vec();
+                    let data = rmp_serde::to_vec_named(&erasure)?;
+                    table.insert(erasure_id, data.as_slice())?;
+                    write_txn
+                        .open_table(OPEN_ERASURES)?
+                        .remove((open_key(&erasure).as_str(), erasure_id))?;
+                    true
+                }
+                _ => false,
+            }
+        };
+        Ok(completed)
+    }
+}
@@ -1,9 +1,18 @@
-use std::collections::HashMap;
-
-use redb::ReadableTable;
-
-use super::db::{Database, DatabaseError};
-use super::models::FileRecord;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use redb::{ReadableTable, ReadableTableMetadata, WriteTransaction};
+
+use super::db::{Database, DatabaseError, Transaction};
+use super::indexes::{
+    clear_indexes, count_key, index_file, scoped, time_key, unindex_file, unscoped, TAG_INDEX,
+};
+use super::models::{
+    FileRecord, FileType, FileVersion, PermalinkConflict, PermalinkRename, Redirect, RenamePlan,
+    Visibility, WriteOp,
+};
+use super::query::{FileFilter, FilePage, Sort};
+use super::redirects::{add_redirect, clear_redirects, remove_file_redirects, remove_redirect};
+use super::snapshot::SnapshotRecords;
 use super::tables::*;
 
 impl Database {
@@ -11,40 +20,10 @@ impl Database {
     // File operations
     // ========================================================================
 
-    /// Store a file record and update the permalink and subject indexes
+    /// Store a file record and update the permalink and secondary indexes. The
+    /// stored revision follows the one it overwrites, or is 1 for a new file.
     pub fn put_file(&self, file: &FileRecord) -> Result<(), DatabaseError> {
-        debug_assert!(!file.id.is_empty(), "file id must not be empty");
-        debug_assert!(
-            !file.permalink.is_empty(),
-            "file permalink must not be empty"
-        );
-
-        let write_txn = self.begin_write()?;
-        {
-            let mut table = write_txn.open_table(FILES)?;
-            let data = rmp_serde::to_vec_named(file)?;
-            table.insert(file.id.as_str(), data.as_slice())?;
-
-            let mut permalink_table = write_txn.open_table(FILE_PERMALINKS)?;
-            permalink_table.insert(file.permalink.as_str(), file.id.as_str())?;
-
-            // Maintain subject index
-            if let Some(ref subject_id) = file.subject_id {
-                let mut subject_table = write_txn.open_table(SUBJECT_FILES)?;
-                let mut file_ids: Vec<String> = subject_table
-                    .get(subject_id.as_str())?
-                    .map(|v| rmp_serde::from_slice(v.value()).unwrap_or_default())
-                    .unwrap_or_default();
-
-                if !file_ids.contains(&file.id) {
-                    file_ids.push(file.id.clone());
-                    let index_data = rmp_serde::to_vec_named(&file_ids)?;
-                    subject_table.insert(subject_id.as_str(), index_data.as_slice())?;
-                }
-            }
-        }
-        write_txn.commit()?;
-        Ok(())
+        self.write(|txn| txn.put_file(file))
     }
 
     /// Get a file by its UUID
@@ -61,15 +40,32 @@ impl Database {
         }
     }
 
-    /// Get a file by its permalink (resolves permalink -> uuid -> file)
+    /// Get many files by UUID in one read, in the order asked for. Missing
+    /// files are `None`.
+    pub fn get_files(&self, ids: &[String]) -> Result<Vec<Option<FileRecord>>, DatabaseError> {
+        let read_txn = self.begin_read()?;
+        let table = read_txn.open_table(FILES)?;
+        let mut files = Vec::with_capacity(ids.len());
+        for id in ids {
+            files.push(match table.get(id.as_str())? {
+                Some(data) => Some(rmp_serde::from_slice(data.value())?),
+                None => None,
+            });
+        }
+        Ok(files)
+    }
+
+    /// Get a file by its permalink within a namespace (resolves permalink ->
+    /// uuid -> file)
     pub fn get_file_by_permalink(
         &self,
+        namespace: &str,
         permalink: &str,
     ) -> Result<Option<FileRecord>, DatabaseError> {
         let read_txn = self.begin_read()?;
         let permalink_table = read_txn.open_table(FILE_PERMALINKS)?;
 
-        let id = match permalink_table.get(permalink)? {
+        let id = match permalink_table.get(scoped(namespace, permalink).as_str())? {
             Some(data) => data.value().to_string(),
             None => return Ok(None),
         };
@@ -84,245 +80,905 @@ impl Database {
         }
     }
 
-    /// Get all files for a subject
-    pub fn get_files_by_subject(&self, subject_id: &str) -> Result<Vec<FileRecord>, DatabaseError> {
+    /// Get all files for a subject within a namespace, oldest first
+    pub fn get_files_by_subject(
+        &self,
+        namespace: &str,
+        subject_id: &str,
+    ) -> Result<Vec<FileRecord>, DatabaseError> {
+        let filter = FileFilter {
+            namespace: namespace.to_string(),
+            subject_id: Some(subject_id.to_string()),
+            ..Default::default()
+        };
+        Ok(self
+            .query_files(&filter, Sort::default(), None, 0, usize::MAX)?
+            .files)
+    }
+
+    /// Delete a file by its UUID and clean up the permalink and secondary indexes
+    pub fn delete_file(&self, id: &str) -> Result<bool, DatabaseError> {
+        self.write(|txn| txn.delete_file(id))
+    }
+
+    /// Update a file's mutable fields, marking it updated at `updated_at`
+    #[allow(clippy::too_many_arguments)]
+    pub fn update_file(
+        &self,
+        id: &str,
+        alt: Option<Option<&str>>,
+        description: Option<Option<&str>>,
+        expires_at: Option<Option<chrono::DateTime<chrono::Utc>>>,
+        metadata: Option<Option<&HashMap<String, serde_json::Value>>>,
+        name: Option<Option<&str>>,
+        permalink: Option<&str>,
+        subject_id: Option<Option<&str>>,
+        visibility: Option<Visibility>,
+        updated_at: chrono::DateTime<chrono::Utc>,
+    ) -> Result<bool, DatabaseError> {
+        self.write(|txn| {
+            txn.update_file(
+                id,
+                alt,
+                description,
+                expires_at,
+                metadata,
+                name,
+                permalink,
+                subject_id,
+                visibility,
+                updated_at,
+            )
+        })
+    }
+
+    /// Apply batchable operations in a single transaction, so either all of
+    /// them are written or none
+    pub fn apply_batch(
+        &self,
+        ops: &[WriteOp],
+        updated_at: chrono::DateTime<chrono::Utc>,
+    ) -> Result<(), DatabaseError> {
+        self.write(|txn| txn.apply_batch(ops, updated_at))
+    }
+
+    /// Add tags to a file, marking it updated at `updated_at` if they change.
+    /// Returns false if the file doesn't exist.
+    pub fn add_tags(
+        &self,
+        id: &str,
+        tags: &[String],
+        updated_at: chrono::DateTime<chrono::Utc>,
+    ) -> Result<bool, DatabaseError> {
+        self.write(|txn| txn.add_tags(id, tags, updated_at))
+    }
+
+    /// Remove tags from a file, marking it updated at `updated_at` if they
+    /// change. Returns false if the file doesn't exist.
+    pub fn remove_tags(
+        &self,
+        id: &str,
+        tags: &[String],
+        updated_at: chrono::DateTime<chrono::Utc>,
+    ) -> Result<bool, DatabaseError> {
+        self.write(|txn| txn.remove_tags(id, tags, updated_at))
+    }
+
+    /// Move a file to the trash. Returns false if it doesn't exist or is
+    /// already trashed.
+    pub fn trash_file(
+        &self,
+        id: &str,
+        deleted_at: chrono::DateTime<chrono::Utc>,
+    ) -> Result<bool, DatabaseError> {
+        self.write(|txn| txn.trash_file(id, deleted_at))
+    }
+
+    /// Take a file out of the trash. Returns false if it doesn't exist or isn't
+    /// trashed.
+    pub fn restore_file(&self, id: &str) -> Result<bool, DatabaseError> {
+        self.write(|txn| txn.restore_file(id))
+    }
+
+    /// List a namespace's trashed files, most recently deleted first
+    pub fn list_trash(
+        &self,
+        namespace: &str,
+        offset: usize,
+        limit: usize,
+    ) -> Result<FilePage, DatabaseError> {
         let read_txn = self.begin_read()?;
-        let subject_table = read_txn.open_table(SUBJECT_FILES)?;
+        let trash = read_txn.open_table(TRASHED_NAMESPACE_FILES)?;
         let files_table = read_txn.open_table(FILES)?;
 
-        let file_ids: Vec<String> = match subject_table.get(subject_id)? {
-            Some(data) => rmp_serde::from_slice(data.value())?,
-            None => return Ok(Vec::new()),
-        };
-
+        let start = (namespace, i64::MIN, "");
+        let end = (namespace, i64::MAX, "");
         let mut files = Vec::new();
-        for file_id in file_ids {
-            if let Some(data) = files_table.get(file_id.as_str())? {
-                let file: FileRecord = rmp_serde::from_slice(data.value())?;
-                files.push(file);
+        let mut seen = 0;
+        let mut more = false;
+        for entry in trash.range(start..end)?.rev() {
+            if files.len() == limit {
+                more = true;
+                break;
+            }
+            let (key, _) = entry?;
+            seen += 1;
+            if seen <= offset {
+                continue;
+            }
+            let (_, _, id) = key.value();
+            if let Some(data) = files_table.get(id)? {
+                files.push(rmp_serde::from_slice(data.value())?);
             }
         }
 
-        Ok(files)
+        // The total is only known without counting the rest of the trash on
+        // its last page
+        let total = (!more).then_some(seen as u64);
+        Ok(FilePage {
+            files,
+            total,
+            next_cursor: None,
+        })
     }
 
-    /// Delete a file by its UUID and clean up the permalink and subject indexes
-    pub fn delete_file(&self, id: &str) -> Result<bool, DatabaseError> {
-        let write_txn = self.begin_write()?;
-
-        // Get the file for index cleanup
-        let file_info: Option<(String, Option<String>)> = {
-            let table = write_txn.open_table(FILES)?;
-            let result = match table.get(id)? {
-                Some(data) => {
-                    let file: FileRecord = rmp_serde::from_slice(data.value())?;
-                    Some((file.permalink, file.subject_id))
-                }
-                None => None,
+    /// Trashed files deleted before `cutoff` and not locked at `now`, oldest
+    /// first. Locked files are skipped, so they never hold up later ones.
+    pub fn get_trashed_before(
+        &self,
+        cutoff: chrono::DateTime<chrono::Utc>,
+        now: chrono::DateTime<chrono::Utc>,
+        limit: usize,
+    ) -> Result<Vec<FileRecord>, DatabaseError> {
+        let read_txn = self.begin_read()?;
+        let trash = read_txn.open_table(TRASHED_FILES)?;
+        let files_table = read_txn.open_table(FILES)?;
+        unlocked_files(
+            &files_table,
+            trash.range(..(time_key(&cutoff), ""))?,
+            now,
+            limit,
+        )
+    }
+
+    /// Change a file's legal hold and retention period, marking it updated at
+    /// `updated_at`. Returns false if the file doesn't exist.
+    pub fn set_retention(
+        &self,
+        id: &str,
+        legal_hold: Option<bool>,
+        retain_until: Option<Option<chrono::DateTime<chrono::Utc>>>,
+        updated_at: chrono::DateTime<chrono::Utc>,
+    ) -> Result<bool, DatabaseError> {
+        self.write(|txn| txn.set_retention(id, legal_hold, retain_until, updated_at))
+    }
+
+    /// Files whose expiry time is at or before `now` and that aren't locked
+    /// at `now`, soonest first. Locked files are skipped, so they never hold
+    /// up later ones.
+    pub fn get_expired(
+        &self,
+        now: chrono::DateTime<chrono::Utc>,
+        limit: usize,
+    ) -> Result<Vec<FileRecord>, DatabaseError> {
+        let read_txn = self.begin_read()?;
+        let expiring = read_txn.open_table(EXPIRING_FILES)?;
+        let files_table = read_txn.open_table(FILES)?;
+        unlocked_files(
+            &files_table,
+            expiring.range(..(time_key(&now) + 1, ""))?,
+            now,
+            limit,
+        )
+    }
+
+    /// Make `version` the file's current content. Returns false if the file
+    /// doesn't exist or `version.version` isn't the next version number.
+    pub fn add_version(&self, id: &str, version: &FileVersion) -> Result<bool, DatabaseError> {
+        self.write(|txn| txn.add_version(id, version))
+    }
+
+    /// Add a new current version with the content of `version`, created at
+    /// `restored_at`. Returns false if the file or version doesn't exist.
+    pub fn restore_version(
+        &self,
+        id: &str,
+        version: u32,
+        restored_at: chrono::DateTime<chrono::Utc>,
+    ) -> Result<bool, DatabaseError> {
+        self.write(|txn| txn.restore_version(id, version, restored_at))
+    }
+
+    /// Every tag in use in a namespace with the number of files that have it,
+    /// sorted by tag
+    pub fn list_tags(&self, namespace: &str) -> Result<Vec<(String, u64)>, DatabaseError> {
+        let read_txn = self.begin_read()?;
+        let table = read_txn.open_table(INDEX_COUNTS)?;
+
+        let prefix = count_key(TAG_INDEX, &scoped(namespace, ""));
+        let mut tags = Vec::new();
+        for result in table.range(prefix.as_str()..)? {
+            let (key, count) = result?;
+            let Some(tag) = key.value().strip_prefix(prefix.as_str()) else {
+                break;
             };
-            result
+            tags.push((tag.to_string(), count.value()));
+        }
+        Ok(tags)
+    }
+
+    /// Get all files
+    pub fn get_all_files(&self) -> Result<Vec<FileRecord>, DatabaseError> {
+        let read_txn = self.begin_read()?;
+        let table = read_txn.open_table(FILES)?;
+
+        let mut files = Vec::new();
+        for result in table.iter()? {
+            let (_, value) = result?;
+            let file: FileRecord = rmp_serde::from_slice(value.value())?;
+            files.push(file);
+        }
+
+        Ok(files)
+    }
+
+    /// Count all file records
+    pub fn count_files(&self) -> Result<u64, DatabaseError> {
+        let read_txn = self.begin_read()?;
+        let table = read_txn.open_table(FILES)?;
+        Ok(table.len()?)
+    }
+
+    /// List a namespace's files with optional file_type and subject_id
+    /// filters, oldest first
+    pub fn list_files(
+        &self,
+        namespace: &str,
+        file_type: Option<&str>,
+        subject_id: Option<&str>,
+    ) -> Result<Vec<FileRecord>, DatabaseError> {
+        let file_types = match file_type {
+            Some(ft) => match ft.parse::<FileType>() {
+                Ok(ft) => vec![ft],
+                // No file can match an unknown type
+                Err(_) => return Ok(Vec::new()),
+            },
+            None => Vec::new(),
         };
 
-        let deleted = match file_info {
-            Some((permalink, subject_id)) => {
-                // Remove from files table
-                {
-                    let mut table = write_txn.open_table(FILES)?;
-                    table.remove(id)?;
-                }
-                // Remove from permalink index
-                {
-                    let mut permalink_table = write_txn.open_table(FILE_PERMALINKS)?;
-                    permalink_table.remove(permalink.as_str())?;
-                }
-                // Remove from subject index
-                if let Some(ref subject_id) = subject_id {
-                    let file_ids: Option<Vec<String>> = {
-                        let subject_table = write_txn.open_table(SUBJECT_FILES)?;
-                        let result = subject_table.get(subject_id.as_str())?;
-                        match result {
-                            Some(data) => Some(rmp_serde::from_slice(data.value())?),
-                            None => None,
-                        }
-                    };
-
-                    if let Some(mut ids) = file_ids {
-                        ids.retain(|fid| fid != id);
-                        let mut subject_table = write_txn.open_table(SUBJECT_FILES)?;
-                        if ids.is_empty() {
-                            subject_table.remove(subject_id.as_str())?;
-                        } else {
-                            let new_data = rmp_serde::to_vec_named(&ids)?;
-                            subject_table.insert(subject_id.as_str(), new_data.as_slice())?;
-                        }
-                    }
-                }
-                true
-            }
-            None => false,
+        let filter = FileFilter {
+            namespace: namespace.to_string(),
+            file_types,
+            subject_id: subject_id.map(|s| s.to_string()),
+            ..Default::default()
         };
+        Ok(self
+            .query_files(&filter, Sort::default(), None, 0, usize::MAX)?
+            .files)
+    }
+
+    /// Check if a permalink is already in use within a namespace
+    pub fn permalink_exists(
+        &self,
+        namespace: &str,
+        permalink: &str,
+    ) -> Result<bool, DatabaseError> {
+        let read_txn = self.begin_read()?;
+        let table = read_txn.open_table(FILE_PERMALINKS)?;
+        Ok(table.get(scoped(namespace, permalink).as_str())?.is_some())
+    }
 
-        write_txn.commit()?;
-        Ok(deleted)
+    // ========================================================================
+    // Permalink operations
+    // ========================================================================
+
+    /// Work out what renaming a permalink prefix would change, without writing.
+    pub fn plan_rename_prefix(
+        &self,
+        namespace: &str,
+        from: &str,
+        to: &str,
+    ) -> Result<RenamePlan, DatabaseError> {
+        let read_txn = self.begin_read()?;
+        plan_rename(
+            &read_txn.open_table(FILE_PERMALINKS)?,
+            &read_txn.open_table(PERMALINK_REDIRECTS)?,
+            namespace,
+            from,
+            to,
+        )
+    }
+
+    /// Replace the `from` prefix of every matching permalink with `to` in a
+    /// single transaction. Nothing is written if any new permalink is already
+    /// taken by a file outside the rename; the returned plan lists the conflicts.
+    /// Renamed files are updated at `renamed_at`.
+    pub fn rename_prefix(
+        &self,
+        namespace: &str,
+        from: &str,
+        to: &str,
+        renamed_at: chrono::DateTime<chrono::Utc>,
+    ) -> Result<RenamePlan, DatabaseError> {
+        self.write(|txn| txn.rename_prefix(namespace, from, to, renamed_at))
     }
 
-    /// Update a file's mutable fields
+    // ========================================================================
+    // Snapshot operations
+    // ========================================================================
+
+    /// Replace every file record and redirect with the given ones and rebuild the
+    /// permalink and secondary indexes, all in a single write transaction. Returns
+    /// the number of records written.
+    pub fn replace_all_files(
+        &self,
+        files: SnapshotRecords<FileRecord>,
+        redirects: &[Redirect],
+    ) -> Result<u64, DatabaseError> {
+        self.write(|txn| txn.replace_all_files(files, redirects))
+    }
+}
+
+impl Transaction {
+    /// Replace every file record and redirect with the given ones and rebuild the
+    /// permalink and secondary indexes. Returns the number of records written.
+    pub fn replace_all_files(
+        &self,
+        files: SnapshotRecords<FileRecord>,
+        redirects: &[Redirect],
+    ) -> Result<u64, DatabaseError> {
+        let write_txn = &self.write_txn;
+        write_txn.open_table(FILES)?.retain(|_, _| false)?;
+        clear_indexes(write_txn)?;
+        clear_redirects(write_txn)?;
+        for redirect in redirects {
+            add_redirect(
+                write_txn,
+                &redirect.namespace,
+                &redirect.permalink,
+                &redirect.file_id,
+            )?;
+        }
+
+        let mut count = 0;
+        files.try_for_each(|file| {
+            let data = rmp_serde::to_vec_named(&file)?;
+            write_txn
+                .open_table(FILES)?
+                .insert(file.id.as_str(), data.as_slice())?;
+            index_file(write_txn, &file)?;
+            count += 1;
+            Ok(())
+        })?;
+        Ok(count)
+    }
+
+    /// Store a file record and update the permalink and secondary indexes. The
+    /// stored revision follows the one it overwrites, or is 1 for a new file.
+    pub fn put_file(&self, file: &FileRecord) -> Result<(), DatabaseError> {
+        debug_assert!(!file.id.is_empty(), "file id must not be empty");
+        debug_assert!(
+            !file.permalink.is_empty(),
+            "file permalink must not be empty"
+        );
+
+        let write_txn = &self.write_txn;
+        let mut file = file.clone();
+        file.revision = 0;
+        // Drop stale index entries if the record is being overwritten
+        if let Some(previous) = load_file(write_txn, &file.id)? {
+            unindex_file(write_txn, &previous)?;
+            file.revision = previous.revision;
+        }
+
+        store_file(write_txn, &mut file)?;
+
+        // A live permalink takes precedence over a redirect
+        remove_redirect(write_txn, &file.namespace, &file.permalink)?;
+        index_file(write_txn, &file)?;
+        Ok(())
+    }
+
+    /// Get a file by its UUID, as changed so far in this transaction
+    pub fn get_file(&self, id: &str) -> Result<Option<FileRecord>, DatabaseError> {
+        load_file(&self.write_txn, id)
+    }
+
+    /// Delete a file by its UUID and clean up the permalink and secondary indexes
+    pub fn delete_file(&self, id: &str) -> Result<bool, DatabaseError> {
+        write_delete(&self.write_txn, id)
+    }
+
+    /// Update a file's mutable fields, marking it updated at `updated_at`
     #[allow(clippy::too_many_arguments)]
     pub fn update_file(
         &self,
         id: &str,
         alt: Option<Option<&str>>,
         description: Option<Option<&str>>,
+        expires_at: Option<Option<chrono::DateTime<chrono::Utc>>>,
         metadata: Option<Option<&HashMap<String, serde_json::Value>>>,
         name: Option<Option<&str>>,
         permalink: Option<&str>,
         subject_id: Option<Option<&str>>,
+        visibility: Option<Visibility>,
+        updated_at: chrono::DateTime<chrono::Utc>,
     ) -> Result<bool, DatabaseError> {
-        let write_txn = self.begin_write()?;
-
-        let existing = {
-            let table = write_txn.open_table(FILES)?;
-            let result = match table.get(id)? {
-                Some(data) => {
-                    let file: FileRecord = rmp_serde::from_slice(data.value())?;
-                    Some(file)
-                }
-                None => None,
-            };
-            result
-        };
+        write_update(
+            &self.write_txn,
+            id,
+            alt,
+            description,
+            expires_at,
+            metadata,
+            name,
+            permalink,
+            subject_id,
+            visibility,
+            updated_at,
+        )
+    }
 
-        let updated = match existing {
-            Some(mut file) => {
-                if let Some(a) = alt {
-                    file.alt = a.map(|s| s.to_string());
+    /// Apply batchable operations. Other operations are ignored; the state
+    /// machine rejects them before getting here.
+    pub fn apply_batch(
+        &self,
+        ops: &[WriteOp],
+        updated_at: chrono::DateTime<chrono::Utc>,
+    ) -> Result<(), DatabaseError> {
+        let write_txn = &self.write_txn;
+        for op in ops {
+            match op {
+                WriteOp::UpdateFile {
+                    id,
+                    alt,
+                    description,
+                    expires_at,
+                    metadata,
+                    name,
+                    permalink,
+                    subject_id,
+                    visibility,
+                } => {
+                    write_update(
+                        write_txn,
+                        id,
+                        alt.as_option().map(|o| o.map(String::as_str)),
+                        description.as_option().map(|o| o.map(String::as_str)),
+                        expires_at.as_option().map(|o| o.copied()),
+                        metadata.as_option(),
+                        name.as_option().map(|o| o.map(String::as_str)),
+                        permalink.as_deref(),
+                        subject_id.as_option().map(|o| o.map(String::as_str)),
+                        *visibility,
+                        updated_at,
+                    )?;
                 }
-                if let Some(d) = description {
-                    file.description = d.map(|s| s.to_string());
+                WriteOp::AddTags { id, tags } => {
+                    write_tags(write_txn, id, updated_at, |current| {
+                        current.extend(tags.iter().cloned())
+                    })?;
                 }
-                if let Some(m) = metadata {
-                    file.metadata = m.cloned();
+                WriteOp::RemoveTags { id, tags } => {
+                    write_tags(write_txn, id, updated_at, |current| {
+                        current.retain(|t| !tags.contains(t))
+                    })?;
                 }
-                if let Some(n) = name {
-                    file.name = n.map(|s| s.to_string());
+                WriteOp::TrashFile { id, deleted_at } => {
+                    write_deleted_at(write_txn, id, Some(*deleted_at))?;
                 }
-                if let Some(new_permalink) = permalink {
-                    // Remove old permalink index entry
-                    {
-                        let mut permalink_table = write_txn.open_table(FILE_PERMALINKS)?;
-                        permalink_table.remove(file.permalink.as_str())?;
-                    }
-                    file.permalink = new_permalink.to_string();
-                    // Add new permalink index entry
-                    {
-                        let mut permalink_table = write_txn.open_table(FILE_PERMALINKS)?;
-                        permalink_table.insert(new_permalink, id)?;
-                    }
+                WriteOp::DeleteFile { id } => {
+                    write_delete(write_txn, id)?;
                 }
-                // Handle subject_id change with index maintenance
-                if let Some(new_subject) = subject_id {
-                    let old_subject = file.subject_id.clone();
-
-                    // Remove from old subject index
-                    if let Some(ref old_sid) = old_subject {
-                        let old_ids: Option<Vec<String>> = {
-                            let subject_table = write_txn.open_table(SUBJECT_FILES)?;
-                            let result = match subject_table.get(old_sid.as_str())? {
-                                Some(data) => Some(rmp_serde::from_slice(data.value())?),
-                                None => None,
-                            };
-                            result
-                        };
-                        if let Some(mut ids) = old_ids {
-                            ids.retain(|fid| fid != id);
-                            let mut subject_table = write_txn.open_table(SUBJECT_FILES)?;
-                            if ids.is_empty() {
-                                subject_table.remove(old_sid.as_str())?;
-                            } else {
-                                let data = rmp_serde::to_vec_named(&ids)?;
-                                subject_table.insert(old_sid.as_str(), data.as_slice())?;
-                            }
-                        }
-                    }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
 
-                    // Add to new subject index
-                    file.subject_id = new_subject.map(|s| s.to_string());
-                    if let Some(ref new_sid) = file.subject_id {
-                        let mut subject_table = write_txn.open_table(SUBJECT_FILES)?;
-                        let mut file_ids: Vec<String> = subject_table
-                            .get(new_sid.as_str())?
-                            .map(|v| rmp_serde::from_slice(v.value()).unwrap_or_default())
-                            .unwrap_or_default();
-
-                        if !file_ids.contains(&id.to_string()) {
-                            file_ids.push(id.to_string());
-                            let data = rmp_serde::to_vec_named(&file_ids)?;
-                            subject_table.insert(new_sid.as_str(), data.as_slice())?;
-                        }
-                    }
-                }
+    /// Add tags to a file, marking it updated at `updated_at` if they change.
+    /// Returns false if the file doesn't exist.
+    pub fn add_tags(
+        &self,
+        id: &str,
+        tags: &[String],
+        updated_at: chrono::DateTime<chrono::Utc>,
+    ) -> Result<bool, DatabaseError> {
+        write_tags(&self.write_txn, id, updated_at, |current| {
+            current.extend(tags.iter().cloned())
+        })
+    }
+
+    /// Remove tags from a file, marking it updated at `updated_at` if they
+    /// change. Returns false if the file doesn't exist.
+    pub fn remove_tags(
+        &self,
+        id: &str,
+        tags: &[String],
+        updated_at: chrono::DateTime<chrono::Utc>,
+    ) -> Result<bool, DatabaseError> {
+        write_tags(&self.write_txn, id, updated_at, |current| {
+            current.retain(|t| !tags.contains(t))
+        })
+    }
+
+    /// Move a file to the trash. Returns false if it doesn't exist or is
+    /// already trashed.
+    pub fn trash_file(
+        &self,
+        id: &str,
+        deleted_at: chrono::DateTime<chrono::Utc>,
+    ) -> Result<bool, DatabaseError> {
+        write_deleted_at(&self.write_txn, id, Some(deleted_at))
+    }
+
+    /// Take a file out of the trash. Returns false if it doesn't exist or isn't
+    /// trashed.
+    pub fn restore_file(&self, id: &str) -> Result<bool, DatabaseError> {
+        write_deleted_at(&self.write_txn, id, None)
+    }
 
-                file.updated_at = chrono::Utc::now();
+    /// Change a file's legal hold and retention period, marking it updated at
+    /// `updated_at`. Returns false if the file doesn't exist.
+    pub fn set_retention(
+        &self,
+        id: &str,
+        legal_hold: Option<bool>,
+        retain_until: Option<Option<chrono::DateTime<chrono::Utc>>>,
+        updated_at: chrono::DateTime<chrono::Utc>,
+    ) -> Result<bool, DatabaseError> {
+        let write_txn = &self.write_txn;
+        let updated = match load_file(write_txn, id)? {
+            Some(mut file) => {
+                if let Some(hold) = legal_hold {
+                    file.legal_hold = hold;
+                }
+                if let Some(until) = retain_until {
+                    file.retain_until = until;
+                }
+                file.updated_at = updated_at;
 
-                let serialized = rmp_serde::to_vec_named(&file)?;
-                let mut table = write_txn.open_table(FILES)?;
-                table.insert(id, serialized.as_slice())?;
+                // No indexed fields change
+                store_file(write_txn, &mut file)?;
                 true
             }
             None => false,
         };
+        Ok(updated)
+    }
+
+    /// Make `version` the file's current content. Returns false if the file
+    /// doesn't exist or `version.version` isn't the next version number, so
+    /// concurrent uploads can't both claim a number.
+    pub fn add_version(&self, id: &str, version: &FileVersion) -> Result<bool, DatabaseError> {
+        self.modify_versions(id, |versions| {
+            let next = versions.last().map_or(1, |v| v.version + 1);
+            if version.version != next {
+                return None;
+            }
+            Some(version.clone())
+        })
+    }
 
-        write_txn.commit()?;
+    /// Add a new current version with the content of `version`, created at
+    /// `restored_at`. Returns false if the file or version doesn't exist.
+    pub fn restore_version(
+        &self,
+        id: &str,
+        version: u32,
+        restored_at: chrono::DateTime<chrono::Utc>,
+    ) -> Result<bool, DatabaseError> {
+        self.modify_versions(id, |versions| {
+            let restored = versions.iter().find(|v| v.version == version)?;
+            Some(FileVersion {
+                version: versions.last().map_or(1, |v| v.version + 1),
+                created_at: restored_at,
+                ..restored.clone()
+            })
+        })
+    }
+
+    /// Append the version returned by `next_version`, given the file's current
+    /// versions, and make it current.
+    fn modify_versions<F>(&self, id: &str, next_version: F) -> Result<bool, DatabaseError>
+    where
+        F: FnOnce(&[FileVersion]) -> Option<FileVersion>,
+    {
+        let write_txn = &self.write_txn;
+        let updated = match load_file(write_txn, id)? {
+            Some(mut file) => {
+                let mut versions = file.all_versions();
+                match next_version(&versions) {
+                    Some(version) => {
+                        unindex_file(write_txn, &file)?;
+                        file.byte_size = version.byte_size;
+                        file.file_type = FileType::from_mime(&version.mime_type);
+                        file.mime_type = version.mime_type.clone();
+                        file.updated_at = version.created_at;
+                        versions.push(version);
+                        file.versions = versions;
+
+                        store_file(write_txn, &mut file)?;
+                        index_file(write_txn, &file)?;
+                        true
+                    }
+                    None => false,
+                }
+            }
+            None => false,
+        };
         Ok(updated)
     }
 
-    /// Get all files (for snapshot/restore)
-    pub fn get_all_files(&self) -> Result<Vec<FileRecord>, DatabaseError> {
-        let read_txn = self.begin_read()?;
-        let table = read_txn.open_table(FILES)?;
+    /// Work out what renaming a permalink prefix would change, without writing.
+    pub fn plan_rename_prefix(
+        &self,
+        namespace: &str,
+        from: &str,
+        to: &str,
+    ) -> Result<RenamePlan, DatabaseError> {
+        plan_rename(
+            &self.write_txn.open_table(FILE_PERMALINKS)?,
+            &self.write_txn.open_table(PERMALINK_REDIRECTS)?,
+            namespace,
+            from,
+            to,
+        )
+    }
 
-        let mut files = Vec::new();
-        for result in table.iter()? {
-            let (_, value) = result?;
-            let file: FileRecord = rmp_serde::from_slice(value.value())?;
-            files.push(file);
+    /// Replace the `from` prefix of every matching permalink with `to`. Nothing
+    /// is written if any new permalink is already taken by a file outside the
+    /// rename; the returned plan lists the conflicts. Renamed files are updated
+    /// at `renamed_at`.
+    pub fn rename_prefix(
+        &self,
+        namespace: &str,
+        from: &str,
+        to: &str,
+        renamed_at: chrono::DateTime<chrono::Utc>,
+    ) -> Result<RenamePlan, DatabaseError> {
+        let plan = self.plan_rename_prefix(namespace, from, to)?;
+        if !plan.conflicts.is_empty() || plan.renames.is_empty() {
+            return Ok(plan);
         }
 
-        Ok(files)
+        let write_txn = &self.write_txn;
+        // Unindex and redirect everything first so no file sees another's stale
+        // permalink, and a new permalink can't be left redirecting elsewhere
+        let mut files = Vec::with_capacity(plan.renames.len());
+        for rename in &plan.renames {
+            if let Some(file) = load_file(write_txn, &rename.id)? {
+                unindex_file(write_txn, &file)?;
+                add_redirect(write_txn, namespace, &rename.from, &file.id)?;
+                files.push((file, rename));
+            }
+        }
+        for (mut file, rename) in files {
+            remove_redirect(write_txn, namespace, &rename.to)?;
+            file.permalink = rename.to.clone();
+            file.updated_at = renamed_at;
+            store_file(write_txn, &mut file)?;
+            index_file(write_txn, &file)?;
+        }
+        Ok(plan)
     }
+}
 
-    /// List files with optional file_type and subject_id filters
-    pub fn list_files(
-        &self,
-        file_type: Option<&str>,
-        subject_id: Option<&str>,
-    ) -> Result<Vec<FileRecord>, DatabaseError> {
-        // Use subject index when subject_id is provided
-        let all = match subject_id {
-            Some(sid) => self.get_files_by_subject(sid)?,
-            None => self.get_all_files()?,
+/// Compute the renames for a prefix and any permalinks they would collide with.
+fn plan_rename(
+    permalinks: &impl ReadableTable<&'static str, &'static str>,
+    redirects: &impl ReadableTable<&'static str, &'static str>,
+    namespace: &str,
+    from: &str,
+    to: &str,
+) -> Result<RenamePlan, DatabaseError> {
+    let mut plan = RenamePlan::default();
+    let scoped_from = scoped(namespace, from);
+    for entry in permalinks.range(scoped_from.as_str()..)? {
+        let (key, id) = entry?;
+        let Some(rest) = key.value().strip_prefix(scoped_from.as_str()) else {
+            break;
         };
+        plan.renames.push(PermalinkRename {
+            id: id.value().to_string(),
+            from: unscoped(key.value()).1.to_string(),
+            to: format!("{to}{rest}"),
+        });
+    }
 
-        if let Some(ft) = file_type {
-            Ok(all
-                .into_iter()
-                .filter(|f| {
-                    let type_str = serde_json::to_string(&f.file_type)
-                        .unwrap_or_default()
-                        .trim_matches('"')
-                        .to_string();
-                    type_str == ft
-                })
-                .collect())
-        } else {
-            Ok(all)
+    // A taken permalink is only free if its owner is being renamed too
+    let renamed: HashSet<&str> = plan.renames.iter().map(|r| r.id.as_str()).collect();
+    let mut conflicts = Vec::new();
+    for rename in &plan.renames {
+        let key = scoped(namespace, &rename.to);
+        let owner = match permalinks.get(key.as_str())? {
+            Some(owner) => Some(owner.value().to_string()),
+            None => redirects
+                .get(key.as_str())?
+                .map(|v| v.value().to_string())
+                .filter(|owner| *owner != rename.id),
+        };
+        if let Some(owner) = owner {
+            if !renamed.contains(owner.as_str()) {
+                conflicts.push(PermalinkConflict {
+                    permalink: rename.to.clone(),
+                    file_id: owner,
+                });
+            }
         }
     }
+    plan.conflicts = conflicts;
+    Ok(plan)
+}
 
-    /// Check if a permalink is already in use
-    pub fn permalink_exists(&self, permalink: &str) -> Result<bool, DatabaseError> {
-        let read_txn = self.begin_read()?;
-        let table = read_txn.open_table(FILE_PERMALINKS)?;
-        Ok(table.get(permalink)?.is_some())
+/// Up to `limit` files of a time index range that aren't locked at `now`
+fn unlocked_files(
+    files_table: &impl ReadableTable<&'static str, &'static [u8]>,
+    range: redb::Range<'_, (i64, &'static str), ()>,
+    now: chrono::DateTime<chrono::Utc>,
+    limit: usize,
+) -> Result<Vec<FileRecord>, DatabaseError> {
+    let mut files = Vec::new();
+    for entry in range {
+        if files.len() >= limit {
+            break;
+        }
+        let (key, _) = entry?;
+        let (_, id) = key.value();
+        if let Some(data) = files_table.get(id)? {
+            let file: FileRecord = rmp_serde::from_slice(data.value())?;
+            if file.lock(now).is_none() {
+                files.push(file);
+            }
+        }
     }
+    Ok(files)
+}
+
+/// Update a file's mutable fields inside a write transaction
+#[allow(clippy::too_many_arguments)]
+fn write_update(
+    write_txn: &WriteTransaction,
+    id: &str,
+    alt: Option<Option<&str>>,
+    description: Option<Option<&str>>,
+    expires_at: Option<Option<chrono::DateTime<chrono::Utc>>>,
+    metadata: Option<Option<&HashMap<String, serde_json::Value>>>,
+    name: Option<Option<&str>>,
+    permalink: Option<&str>,
+    subject_id: Option<Option<&str>>,
+    visibility: Option<Visibility>,
+    updated_at: chrono::DateTime<chrono::Utc>,
+) -> Result<bool, DatabaseError> {
+    let updated = match load_file(write_txn, id)? {
+        Some(mut file) => {
+            unindex_file(write_txn, &file)?;
+
+            if let Some(a) = alt {
+                file.alt = a.map(|s| s.to_string());
+            }
+            if let Some(d) = description {
+                file.description = d.map(|s| s.to_string());
+            }
+            if let Some(e) = expires_at {
+                file.expires_at = e;
+            }
+            if let Some(m) = metadata {
+                file.metadata = m.cloned();
+            }
+            if let Some(n) = name {
+                file.name = n.map(|s| s.to_string());
+            }
+            if let Some(new_permalink) = permalink {
+                if new_permalink != file.permalink {
+                    add_redirect(write_txn, &file.namespace, &file.permalink, id)?;
+                    remove_redirect(write_txn, &file.namespace, new_permalink)?;
+                }
+                file.permalink = new_permalink.to_string();
+            }
+            if let Some(new_subject) = subject_id {
+                file.subject_id = new_subject.map(|s| s.to_string());
+            }
+            if let Some(v) = visibility {
+                file.visibility = v;
+            }
+
+            file.updated_at = updated_at;
+
+            store_file(write_txn, &mut file)?;
+            index_file(write_txn, &file)?;
+            true
+        }
+        None => false,
+    };
+    Ok(updated)
+}
+
+/// Change a file's tags inside a write transaction
+fn write_tags<F>(
+    write_txn: &WriteTransaction,
+    id: &str,
+    updated_at: chrono::DateTime<chrono::Utc>,
+    modify: F,
+) -> Result<bool, DatabaseError>
+where
+    F: FnOnce(&mut BTreeSet<String>),
+{
+    let updated = match load_file(write_txn, id)? {
+        Some(mut file) => {
+            let mut tags: BTreeSet<String> = file.tags.iter().cloned().collect();
+            modify(&mut tags);
+            let tags: Vec<String> = tags.into_iter().collect();
+
+            if tags != file.tags {
+                unindex_file(write_txn, &file)?;
+                file.tags = tags;
+                file.updated_at = updated_at;
+
+                store_file(write_txn, &mut file)?;
+                index_file(write_txn, &file)?;
+            }
+            true
+        }
+        None => false,
+    };
+    Ok(updated)
+}
+
+/// Move a file to or out of the trash inside a write transaction
+fn write_deleted_at(
+    write_txn: &WriteTransaction,
+    id: &str,
+    deleted_at: Option<chrono::DateTime<chrono::Utc>>,
+) -> Result<bool, DatabaseError> {
+    let updated = match load_file(write_txn, id)? {
+        Some(mut file) if file.deleted_at.is_some() != deleted_at.is_some() => {
+            unindex_file(write_txn, &file)?;
+            file.deleted_at = deleted_at;
+
+            store_file(write_txn, &mut file)?;
+            index_file(write_txn, &file)?;
+            true
+        }
+        _ => false,
+    };
+    Ok(updated)
+}
+
+/// Delete a file record inside a write transaction, with its permalink,
+/// redirects and index entries
+pub(super) fn write_delete(write_txn: &WriteTransaction, id: &str) -> Result<bool, DatabaseError> {
+    let deleted = match load_file(write_txn, id)? {
+        Some(file) => {
+            write_txn.open_table(FILES)?.remove(id)?;
+            unindex_file(write_txn, &file)?;
+            remove_file_redirects(write_txn, id)?;
+            true
+        }
+        None => false,
+    };
+    Ok(deleted)
+}
+
+/// Write a file record inside a write transaction, bumping its revision
+fn store_file(write_txn: &WriteTransaction, file: &mut FileRecord) -> Result<(), DatabaseError> {
+    file.revision += 1;
+    let data = rmp_serde::to_vec_named(file)?;
+    write_txn
+        .open_table(FILES)?
+        .insert(file.id.as_str(), data.as_slice())?;
+    Ok(())
+}
+
+/// Read a file record inside a write transaction
+pub(super) fn load_file(
+    write_txn: &WriteTransaction,
+    id: &str,
+) -> Result<Option<FileRecord>, DatabaseError> {
+    let table = write_txn.open_table(FILES)?;
+    let result = match table.get(id)? {
+        Some(data) => Some(rmp_serde::from_slice(data.value())?),
+        None => None,
+    };
+    Ok(result)
 }
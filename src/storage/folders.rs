@@ -0,0 +1,131 @@
+use std::ops::Bound;
+
+use super::db::{Database, DatabaseError};
+use super::indexes::{scoped, unscoped};
+use super::models::FileRecord;
+use super::tables::*;
+
+/// One page of a folder listing
+#[derive(Debug, Default)]
+pub struct FolderPage {
+    /// Files directly under the prefix, in permalink order
+    pub files: Vec<FileRecord>,
+    /// Collapsed sub-prefixes, each ending with the delimiter
+    pub prefixes: Vec<String>,
+    /// Last permalink or prefix returned, to resume from if more entries follow
+    pub next_after: Option<String>,
+}
+
+impl Database {
+    /// List the permalinks under `prefix` in a namespace like a directory.
+    ///
+    /// Permalinks whose remainder after `prefix` contains `delimiter` are
+    /// collapsed into a single common prefix (S3-style). Files and prefixes
+    /// share one permalink-ordered sequence, of which at most `limit` entries
+    /// are returned, resuming after the entry `after` if given.
+    pub fn list_folder(
+        &self,
+        namespace: &str,
+        prefix: &str,
+        delimiter: Option<&str>,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<FolderPage, DatabaseError> {
+        let delimiter = delimiter.filter(|d| !d.is_empty());
+        let read_txn = self.begin_read()?;
+        let permalinks = read_txn.open_table(FILE_PERMALINKS)?;
+        let files_table = read_txn.open_table(FILES)?;
+
+        // Work on scoped permalinks, unscoping what is returned
+        let prefix = scoped(namespace, prefix);
+        let prefix = prefix.as_str();
+        let after = after.map(|after| scoped(namespace, after));
+        let after = after.as_deref();
+
+        let common_prefix = |permalink: &str| -> Option<String> {
+            let rest = permalink.strip_prefix(prefix)?;
+            let end = rest.find(delimiter?)? + delimiter?.len();
+            Some(format!("{prefix}{}", &rest[..end]))
+        };
+
+        // Resume after the last entry, skipping the rest of a collapsed prefix
+        let (mut start, mut start_excluded, mut skip_prefix) = match after {
+            Some(after) if after > prefix => match common_prefix(after) {
+                Some(collapsed) => match successor(&collapsed) {
+                    Some(successor) => (successor, false, None),
+                    None => (after.to_string(), true, Some(collapsed)),
+                },
+                None => (after.to_string(), true, None),
+            },
+            _ => (prefix.to_string(), false, None),
+        };
+
+        let mut page = FolderPage::default();
+        let mut last: Option<String> = None;
+        'scan: loop {
+            let lower = if start_excluded {
+                Bound::Excluded(start.as_str())
+            } else {
+                Bound::Included(start.as_str())
+            };
+            for entry in permalinks.range::<&str>((lower, Bound::Unbounded))? {
+                let (key, id) = entry?;
+                let permalink = key.value();
+                if !permalink.starts_with(prefix) {
+                    break 'scan;
+                }
+                if skip_prefix
+                    .as_deref()
+                    .is_some_and(|p| permalink.starts_with(p))
+                {
+                    continue;
+                }
+
+                if page.files.len() + page.prefixes.len() == limit {
+                    page.next_after = last.map(|last| unscoped(&last).1.to_string());
+                    break 'scan;
+                }
+
+                match common_prefix(permalink) {
+                    Some(collapsed) => {
+                        last = Some(collapsed.clone());
+                        page.prefixes.push(unscoped(&collapsed).1.to_string());
+                        // Jump past the collapsed prefix instead of walking it
+                        if let Some(successor) = successor(&collapsed) {
+                            start = successor;
+                            start_excluded = false;
+                            skip_prefix = None;
+                            continue 'scan;
+                        }
+                        skip_prefix = Some(collapsed);
+                    }
+                    None => {
+                        last = Some(permalink.to_string());
+                        if let Some(data) = files_table.get(id.value())? {
+                            let file: FileRecord = rmp_serde::from_slice(data.value())?;
+                            // Trashed files keep their permalink but aren't listed
+                            if file.deleted_at.is_none() {
+                                page.files.push(file);
+                            }
+                        }
+                    }
+                }
+            }
+            break;
+        }
+
+        Ok(page)
+    }
+}
+
+/// The smallest string greater than every string starting with `prefix`, if
+/// it can be formed by incrementing a trailing ASCII character.
+fn successor(prefix: &str) -> Option<String> {
+    let last = prefix.chars().last()?;
+    if !last.is_ascii() || last == '\u{7f}' {
+        return None;
+    }
+    let mut successor = prefix[..prefix.len() - 1].to_string();
+    successor.push((last as u8 + 1) as char);
+    Some(successor)
+}
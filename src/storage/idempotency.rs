@@ -0,0 +1,187 @@
+//! Responses to requests made with an `Idempotency-Key`, so a client retrying
+//! after a timeout gets the original response instead of repeating the change.
+//! Stored through the state machine so a retry can land on any node, and
+//! forgotten once older than the idempotency TTL. Part of snapshots.
+//!
+//! A request claims its key through the state machine before it is handled,
+//! so a retry reaching another node meanwhile finds the claim instead of
+//! making the change again. A claim left by a node that went away lapses
+//! after `CLAIM_LEASE`.
+
+use chrono::{DateTime, Utc};
+use redb::{ReadableTable, ReadableTableMetadata};
+
+use super::db::{Database, DatabaseError, Transaction};
+use super::models::{IdempotencyRecord, CLAIM_LEASE};
+use super::tables::*;
+
+impl Database {
+    /// Store the response to a request. A response already stored for the key
+    /// is kept. Returns whether this one was stored.
+    pub fn put_idempotency_record(
+        &self,
+        record: &IdempotencyRecord,
+    ) -> Result<bool, DatabaseError> {
+        self.write(|txn| txn.put_idempotency_record(record))
+    }
+
+    /// Claim a key for a request. Returns whether the claim was stored: not
+    /// if the key has a response or a claim that hasn't lapsed by the time of
+    /// this one.
+    pub fn claim_idempotency_key(&self, record: &IdempotencyRecord) -> Result<bool, DatabaseError> {
+        self.write(|txn| txn.claim_idempotency_key(record))
+    }
+
+    /// Remove a claim on a key. Returns whether the key held that claim.
+    pub fn release_idempotency_key(
+        &self,
+        namespace: &str,
+        key: &str,
+        claim: &str,
+    ) -> Result<bool, DatabaseError> {
+        self.write(|txn| txn.release_idempotency_key(namespace, key, claim))
+    }
+
+    pub fn get_idempotency_record(
+        &self,
+        namespace: &str,
+        key: &str,
+    ) -> Result<Option<IdempotencyRecord>, DatabaseError> {
+        let read_txn = self.begin_read()?;
+        let table = read_txn.open_table(IDEMPOTENCY_KEYS)?;
+        let record = match table.get((namespace, key))? {
+            Some(data) => Some(rmp_serde::from_slice(data.value())?),
+            None => None,
+        };
+        Ok(record)
+    }
+
+    /// Creation time of the oldest stored response, if any
+    pub fn oldest_idempotency_timestamp(&self) -> Result<Option<DateTime<Utc>>, DatabaseError> {
+        let mut oldest = None;
+        for record in self.get_idempotency_records()? {
+            if oldest.is_none_or(|t| record.created_at < t) {
+                oldest = Some(record.created_at);
+            }
+        }
+        Ok(oldest)
+    }
+
+    /// Forget responses stored before a time. Returns the number forgotten.
+    pub fn prune_idempotency_records(&self, before: DateTime<Utc>) -> Result<u64, DatabaseError> {
+        self.write(|txn| txn.prune_idempotency_records(before))
+    }
+
+    /// Get every stored response, for snapshots
+    pub fn get_idempotency_records(&self) -> Result<Vec<IdempotencyRecord>, DatabaseError> {
+        let read_txn = self.begin_read()?;
+        let mut records = Vec::new();
+        for entry in read_txn.open_table(IDEMPOTENCY_KEYS)?.iter()? {
+            let (_, data) = entry?;
+            records.push(rmp_serde::from_slice(data.value())?);
+        }
+        Ok(records)
+    }
+
+    /// Replace every stored response with those from a snapshot
+    pub fn replace_idempotency_records(
+        &self,
+        records: &[IdempotencyRecord],
+    ) -> Result<(), DatabaseError> {
+        self.write(|txn| txn.replace_idempotency_records(records))
+    }
+}
+
+impl Transaction {
+    /// Replace every stored response with those from a snapshot
+    pub fn replace_idempotency_records(
+        &self,
+        records: &[IdempotencyRecord],
+    ) -> Result<(), DatabaseError> {
+        let mut table = self.write_txn.open_table(IDEMPOTENCY_KEYS)?;
+        table.retain(|_, _| false)?;
+        for record in records {
+            let data = rmp_serde::to_vec_named(record)?;
+            table.insert(
+                (record.namespace.as_str(), record.key.as_str()),
+                data.as_slice(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Store the response to a request, replacing a claim on its key. A
+    /// response already stored for the key is kept. Returns whether this one
+    /// was stored.
+    pub fn put_idempotency_record(
+        &self,
+        record: &IdempotencyRecord,
+    ) -> Result<bool, DatabaseError> {
+        self.store_idempotency_record(record, |stored| stored.is_claim())
+    }
+
+    /// Claim a key for a request. Returns whether the claim was stored: not
+    /// if the key has a response or a claim that hasn't lapsed by the time of
+    /// this one.
+    pub fn claim_idempotency_key(&self, record: &IdempotencyRecord) -> Result<bool, DatabaseError> {
+        self.store_idempotency_record(record, |stored| {
+            stored.is_claim() && stored.created_at + CLAIM_LEASE <= record.created_at
+        })
+    }
+
+    /// Remove a claim on a key. Returns whether the key held that claim.
+    pub fn release_idempotency_key(
+        &self,
+        namespace: &str,
+        key: &str,
+        claim: &str,
+    ) -> Result<bool, DatabaseError> {
+        let mut table = self.write_txn.open_table(IDEMPOTENCY_KEYS)?;
+        let held = match table.get((namespace, key))? {
+            Some(data) => rmp_serde::from_slice::<IdempotencyRecord>(data.value())?
+                .claim
+                .is_some_and(|c| c == claim),
+            None => false,
+        };
+        if held {
+            table.remove((namespace, key))?;
+        }
+        Ok(held)
+    }
+
+    /// Store a record unless its key already has one that `replaceable`
+    /// rejects. Returns whether it was stored.
+    fn store_idempotency_record(
+        &self,
+        record: &IdempotencyRecord,
+        replaceable: impl FnOnce(&IdempotencyRecord) -> bool,
+    ) -> Result<bool, DatabaseError> {
+        let mut table = self.write_txn.open_table(IDEMPOTENCY_KEYS)?;
+        let key = (record.namespace.as_str(), record.key.as_str());
+        let stored = match table.get(key)? {
+            Some(data) => Some(rmp_serde::from_slice::<IdempotencyRecord>(data.value())?),
+            None => None,
+        };
+        if stored.is_some_and(|stored| !replaceable(&stored)) {
+            return Ok(false);
+        }
+        let data = rmp_serde::to_vec_named(record)?;
+        table.insert(key, data.as_slice())?;
+        Ok(true)
+    }
+
+    /// Forget responses stored before a time. Returns the number forgotten.
+    pub fn prune_idempotency_records(&self, before: DateTime<Utc>) -> Result<u64, DatabaseError> {
+        let write_txn = &self.write_txn;
+        let pruned = {
+            let mut table = write_txn.open_table(IDEMPOTENCY_KEYS)?;
+            let count = table.len()?;
+            table.retain(|_, data| {
+                rmp_serde::from_slice::<IdempotencyRecord>(data)
+                    .is_ok_and(|r| r.created_at >= before)
+            })?;
+            count - table.len()?
+        };
+        Ok(pruned)
+    }
+}
@@ -0,0 +1,436 @@
+//! Secondary index maintenance. Every index here is derived from the `FILES`
+//! table, so it can be rebuilt from the file records at any time.
+
+use redb::{MultimapTableHandle, ReadableTable, TableDefinition, TableHandle, WriteTransaction};
+
+use super::db::DatabaseError;
+use super::models::FileRecord;
+use super::redirects::scope_legacy_redirects;
+use super::search::{file_terms, SEARCH_INDEX};
+use super::tables::*;
+
+/// Version of the derived index layout. Bump it whenever an index is added or
+/// changed, and the indexes are rebuilt from `FILES` on the next open.
+const INDEX_VERSION: u64 = 9;
+
+pub(crate) const INDEX_VERSION_KEY: &str = "index_version";
+
+/// Tables from earlier index layouts, dropped during migration.
+const LEGACY_TABLES: &[&str] = &["files_by_created", "subject_files"];
+const LEGACY_MULTIMAP_TABLES: &[&str] = &["subject_file_index"];
+
+/// A composite-key index of the form (value, created_at, uuid).
+type ValueIndexDefinition = TableDefinition<'static, (&'static str, i64, &'static str), ()>;
+
+/// Every composite-key index, with the name used for its entries in `INDEX_COUNTS`.
+const VALUE_INDEXES: &[(&str, ValueIndexDefinition)] = &[
+    ("file_type", FILE_TYPE_FILES),
+    ("mime_type", MIME_TYPE_FILES),
+    (NAMESPACE_INDEX, NAMESPACE_FILES),
+    ("subject_id", SUBJECT_FILES),
+];
+
+/// Index name used for namespace entries in `INDEX_COUNTS`.
+pub(crate) const NAMESPACE_INDEX: &str = "namespace";
+
+/// Key of a permalink or index value within a namespace. Namespace names
+/// can't contain `\0`, so each namespace's keys form one contiguous range.
+pub(crate) fn scoped(namespace: &str, value: &str) -> String {
+    format!("{namespace}\0{value}")
+}
+
+/// Split a scoped key into its namespace and value.
+pub(crate) fn unscoped(key: &str) -> (&str, &str) {
+    key.split_once('\0').unwrap_or(("", key))
+}
+
+/// Sort key of the trash and expiry indexes.
+pub(crate) fn time_key(time: &chrono::DateTime<chrono::Utc>) -> i64 {
+    time.timestamp_micros()
+}
+
+/// Sort key used by every creation-ordered index.
+pub(crate) fn created_key(file: &FileRecord) -> i64 {
+    file.created_at.timestamp_micros()
+}
+
+/// Key for an index value in `INDEX_COUNTS`.
+pub(crate) fn count_key(index: &str, value: &str) -> String {
+    format!("{index}:{value}")
+}
+
+/// Index name used for tag entries in `INDEX_COUNTS`.
+pub(crate) const TAG_INDEX: &str = "tag";
+
+/// Index name used for metadata entries in `INDEX_COUNTS`.
+pub(crate) const METADATA_INDEX: &str = "metadata";
+
+/// Entry in `METADATA_FILES` for a file having a key.
+pub(crate) fn metadata_key_entry(key: &str) -> String {
+    key.to_string()
+}
+
+/// Entry in `METADATA_FILES` for a file having a key set to a value.
+pub(crate) fn metadata_value_entry(key: &str, value: &str) -> String {
+    format!("{key}\0{value}")
+}
+
+/// String form of a metadata value used for matching. Only strings, numbers
+/// and booleans can be matched by value.
+pub(crate) fn metadata_match_value(value: &serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::String(s) => Some(s.clone()),
+        serde_json::Value::Number(n) => Some(n.to_string()),
+        serde_json::Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/// Entries a file contributes to `METADATA_FILES` for the indexed keys.
+fn metadata_entries(
+    write_txn: &WriteTransaction,
+    file: &FileRecord,
+) -> Result<Vec<String>, DatabaseError> {
+    let Some(ref metadata) = file.metadata else {
+        return Ok(Vec::new());
+    };
+    let indexed = write_txn.open_table(INDEXED_METADATA_KEYS)?;
+
+    let mut entries = Vec::new();
+    for (key, value) in metadata {
+        if indexed.get(key.as_str())?.is_none() {
+            continue;
+        }
+        entries.push(scoped(&file.namespace, &metadata_key_entry(key)));
+        if let Some(value) = metadata_match_value(value) {
+            entries.push(scoped(&file.namespace, &metadata_value_entry(key, &value)));
+        }
+    }
+    Ok(entries)
+}
+
+/// Values a file contributes to each composite-key index. The namespace index
+/// holds bare namespace names, every other value is scoped.
+fn index_values(file: &FileRecord) -> [Option<String>; 4] {
+    let namespace = file.namespace.as_str();
+    [
+        Some(scoped(namespace, file.file_type.as_str())),
+        Some(scoped(namespace, &file.mime_type)),
+        Some(namespace.to_string()),
+        file.subject_id.as_deref().map(|s| scoped(namespace, s)),
+    ]
+}
+
+fn adjust_count(write_txn: &WriteTransaction, key: &str, delta: i64) -> Result<(), DatabaseError> {
+    let mut counts = write_txn.open_table(INDEX_COUNTS)?;
+    let current = counts.get(key)?.map(|v| v.value()).unwrap_or(0);
+    let next = current.saturating_add_signed(delta);
+    if next == 0 {
+        counts.remove(key)?;
+    } else {
+        counts.insert(key, next)?;
+    }
+    Ok(())
+}
+
+/// Add every index entry for a file record.
+pub(crate) fn index_file(
+    write_txn: &WriteTransaction,
+    file: &FileRecord,
+) -> Result<(), DatabaseError> {
+    let id = file.id.as_str();
+    let created_at = created_key(file);
+    let namespace = file.namespace.as_str();
+
+    write_txn
+        .open_table(FILE_PERMALINKS)?
+        .insert(scoped(namespace, &file.permalink).as_str(), id)?;
+    // Trashed files still expire
+    if let Some(ref expires_at) = file.expires_at {
+        write_txn
+            .open_table(EXPIRING_FILES)?
+            .insert((time_key(expires_at), id), ())?;
+    }
+    if let Some(ref deleted_at) = file.deleted_at {
+        write_txn
+            .open_table(TRASHED_FILES)?
+            .insert((time_key(deleted_at), id), ())?;
+        write_txn
+            .open_table(TRASHED_NAMESPACE_FILES)?
+            .insert((namespace, time_key(deleted_at), id), ())?;
+        if let Some(ref subject_id) = file.subject_id {
+            write_txn.open_table(TRASHED_SUBJECT_FILES)?.insert(
+                (
+                    scoped(namespace, subject_id).as_str(),
+                    time_key(deleted_at),
+                    id,
+                ),
+                (),
+            )?;
+        }
+        return Ok(());
+    }
+    for ((name, definition), value) in VALUE_INDEXES.iter().zip(index_values(file)) {
+        if let Some(value) = value {
+            let inserted = write_txn
+                .open_table(*definition)?
+                .insert((value.as_str(), created_at, id), ())?
+                .is_none();
+            if inserted {
+                adjust_count(write_txn, &count_key(name, &value), 1)?;
+            }
+        }
+    }
+
+    for tag in &file.tags {
+        let tag = scoped(namespace, tag);
+        let inserted = write_txn
+            .open_table(TAG_FILES)?
+            .insert((tag.as_str(), created_at, id), ())?
+            .is_none();
+        if inserted {
+            adjust_count(write_txn, &count_key(TAG_INDEX, &tag), 1)?;
+        }
+    }
+
+    for entry in metadata_entries(write_txn, file)? {
+        let inserted = write_txn
+            .open_table(METADATA_FILES)?
+            .insert((entry.as_str(), created_at, id), ())?
+            .is_none();
+        if inserted {
+            adjust_count(write_txn, &count_key(METADATA_INDEX, &entry), 1)?;
+        }
+    }
+
+    for (term, weight) in file_terms(file) {
+        let term = scoped(namespace, &term);
+        let inserted = write_txn
+            .open_table(SEARCH_TERMS)?
+            .insert((term.as_str(), id), weight)?
+            .is_none();
+        if inserted {
+            adjust_count(write_txn, &count_key(SEARCH_INDEX, &term), 1)?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove every index entry for a file record.
+pub(crate) fn unindex_file(
+    write_txn: &WriteTransaction,
+    file: &FileRecord,
+) -> Result<(), DatabaseError> {
+    let id = file.id.as_str();
+    let created_at = created_key(file);
+    let namespace = file.namespace.as_str();
+
+    {
+        // Only drop the permalink if it still points at this file
+        let mut permalink_table = write_txn.open_table(FILE_PERMALINKS)?;
+        let permalink = scoped(namespace, &file.permalink);
+        let owned = permalink_table
+            .get(permalink.as_str())?
+            .is_some_and(|v| v.value() == id);
+        if owned {
+            permalink_table.remove(permalink.as_str())?;
+        }
+    }
+    if let Some(ref expires_at) = file.expires_at {
+        write_txn
+            .open_table(EXPIRING_FILES)?
+            .remove((time_key(expires_at), id))?;
+    }
+    if let Some(ref deleted_at) = file.deleted_at {
+        write_txn
+            .open_table(TRASHED_FILES)?
+            .remove((time_key(deleted_at), id))?;
+        write_txn.open_table(TRASHED_NAMESPACE_FILES)?.remove((
+            namespace,
+            time_key(deleted_at),
+            id,
+        ))?;
+        if let Some(ref subject_id) = file.subject_id {
+            write_txn.open_table(TRASHED_SUBJECT_FILES)?.remove((
+                scoped(namespace, subject_id).as_str(),
+                time_key(deleted_at),
+                id,
+            ))?;
+        }
+        return Ok(());
+    }
+    for ((name, definition), value) in VALUE_INDEXES.iter().zip(index_values(file)) {
+        if let Some(value) = value {
+            let removed = write_txn
+                .open_table(*definition)?
+                .remove((value.as_str(), created_at, id))?
+                .is_some();
+            if removed {
+                adjust_count(write_txn, &count_key(name, &value), -1)?;
+            }
+        }
+    }
+
+    for tag in &file.tags {
+        let tag = scoped(namespace, tag);
+        let removed = write_txn
+            .open_table(TAG_FILES)?
+            .remove((tag.as_str(), created_at, id))?
+            .is_some();
+        if removed {
+            adjust_count(write_txn, &count_key(TAG_INDEX, &tag), -1)?;
+        }
+    }
+
+    for entry in metadata_entries(write_txn, file)? {
+        let removed = write_txn
+            .open_table(METADATA_FILES)?
+            .remove((entry.as_str(), created_at, id))?
+            .is_some();
+        if removed {
+            adjust_count(write_txn, &count_key(METADATA_INDEX, &entry), -1)?;
+        }
+    }
+
+    for term in file_terms(file).into_keys() {
+        let term = scoped(namespace, &term);
+        let removed = write_txn
+            .open_table(SEARCH_TERMS)?
+            .remove((term.as_str(), id))?
+            .is_some();
+        if removed {
+            adjust_count(write_txn, &count_key(SEARCH_INDEX, &term), -1)?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove every entry from every derived index.
+pub(crate) fn clear_indexes(write_txn: &WriteTransaction) -> Result<(), DatabaseError> {
+    write_txn
+        .open_table(FILE_PERMALINKS)?
+        .retain(|_, _| false)?;
+    write_txn.open_table(TRASHED_FILES)?.retain(|_, _| false)?;
+    write_txn
+        .open_table(TRASHED_NAMESPACE_FILES)?
+        .retain(|_, _| false)?;
+    write_txn
+        .open_table(TRASHED_SUBJECT_FILES)?
+        .retain(|_, _| false)?;
+    write_txn.open_table(EXPIRING_FILES)?.retain(|_, _| false)?;
+    for (_, definition) in VALUE_INDEXES {
+        write_txn.open_table(*definition)?.retain(|_, _| false)?;
+    }
+    write_txn.open_table(TAG_FILES)?.retain(|_, _| false)?;
+    write_txn.open_table(METADATA_FILES)?.retain(|_, _| false)?;
+    write_txn.open_table(SEARCH_TERMS)?.retain(|_, _| false)?;
+    write_txn.open_table(INDEX_COUNTS)?.retain(|_, _| false)?;
+    Ok(())
+}
+
+/// Rebuild every derived index from the file records.
+pub(crate) fn rebuild_indexes(write_txn: &WriteTransaction) -> Result<u64, DatabaseError> {
+    clear_indexes(write_txn)?;
+
+    let files = write_txn.open_table(FILES)?;
+    let mut count = 0;
+    for result in files.iter()? {
+        let (_, value) = result?;
+        let file: FileRecord = rmp_serde::from_slice(value.value())?;
+        index_file(write_txn, &file)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Create the index tables and rebuild them if they predate `INDEX_VERSION`
+/// or the set of indexed metadata keys has changed.
+pub(crate) fn migrate(
+    write_txn: &WriteTransaction,
+    metadata_keys: &[String],
+) -> Result<(), DatabaseError> {
+    let _ = write_txn.open_table(TRASHED_FILES)?;
+    let _ = write_txn.open_table(TRASHED_NAMESPACE_FILES)?;
+    let _ = write_txn.open_table(TRASHED_SUBJECT_FILES)?;
+    let _ = write_txn.open_table(EXPIRING_FILES)?;
+    for (_, definition) in VALUE_INDEXES {
+        let _ = write_txn.open_table(*definition)?;
+    }
+    let _ = write_txn.open_table(TAG_FILES)?;
+    let _ = write_txn.open_table(METADATA_FILES)?;
+    let _ = write_txn.open_table(SEARCH_TERMS)?;
+    let _ = write_txn.open_table(INDEX_COUNTS)?;
+
+    let keys_changed = sync_metadata_keys(write_txn, metadata_keys)?;
+    let version = write_txn
+        .open_table(META)?
+        .get(INDEX_VERSION_KEY)?
+        .map(|v| v.value())
+        .unwrap_or(0);
+    if version >= INDEX_VERSION {
+        if keys_changed {
+            let count = rebuild_indexes(write_txn)?;
+            tracing::info!(
+                keys = ?metadata_keys,
+                files = count,
+                "Rebuilt indexes for changed metadata keys"
+            );
+        }
+        return Ok(());
+    }
+
+    let legacy: Vec<_> = write_txn
+        .list_tables()?
+        .filter(|t| LEGACY_TABLES.contains(&t.name()))
+        .collect();
+    for table in legacy {
+        write_txn.delete_table(table)?;
+    }
+    let legacy: Vec<_> = write_txn
+        .list_multimap_tables()?
+        .filter(|t| LEGACY_MULTIMAP_TABLES.contains(&t.name()))
+        .collect();
+    for table in legacy {
+        write_txn.delete_multimap_table(table)?;
+    }
+    scope_legacy_redirects(write_txn)?;
+
+    let count = rebuild_indexes(write_txn)?;
+    write_txn
+        .open_table(META)?
+        .insert(INDEX_VERSION_KEY, INDEX_VERSION)?;
+
+    tracing::info!(
+        from = version,
+        to = INDEX_VERSION,
+        files = count,
+        "Rebuilt secondary indexes"
+    );
+    Ok(())
+}
+
+/// Record the configured metadata keys. Returns whether they changed.
+fn sync_metadata_keys(
+    write_txn: &WriteTransaction,
+    metadata_keys: &[String],
+) -> Result<bool, DatabaseError> {
+    let mut table = write_txn.open_table(INDEXED_METADATA_KEYS)?;
+
+    let mut current = Vec::new();
+    for result in table.iter()? {
+        let (key, _) = result?;
+        current.push(key.value().to_string());
+    }
+    let mut wanted: Vec<String> = metadata_keys.to_vec();
+    wanted.sort();
+    wanted.dedup();
+    if current == wanted {
+        return Ok(false);
+    }
+
+    table.retain(|_, _| false)?;
+    for key in &wanted {
+        table.insert(key.as_str(), ())?;
+    }
+    Ok(true)
+}
@@ -1,7 +1,18 @@
+pub mod audit;
+pub mod changes;
 pub mod db;
+mod erasures;
 mod files;
+pub mod folders;
+mod idempotency;
+mod indexes;
 pub mod models;
+pub mod query;
+mod redirects;
+pub mod search;
+pub mod snapshot;
 mod tables;
+pub mod webhooks;
 
-pub use db::{Database, DatabaseError};
+pub use db::{Database, DatabaseError, DatabaseOptions, Transaction};
 pub use tables::*;
@@ -53,6 +53,17 @@ pub enum FileType {
 }
 
 impl FileType {
+    /// The lowercase name used in the API and in indexes.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FileType::Audio => "audio",
+            FileType::Binary => "binary",
+            FileType::Document => "document",
+            FileType::Image => "image",
+            FileType::Video => "video",
+        }
+    }
+
     /// Derive a file type classification from a MIME type string.
     pub fn from_mime(mime_type: &str) -> Self {
         let primary = mime_type.split('/').next().unwrap_or("");
@@ -81,35 +92,378 @@ impl FileType {
     }
 }
 
+impl std::str::FromStr for FileType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "audio" => Ok(FileType::Audio),
+            "binary" => Ok(FileType::Binary),
+            "document" => Ok(FileType::Document),
+            "image" => Ok(FileType::Image),
+            "video" => Ok(FileType::Video),
+            _ => Err(format!("unknown file type '{s}'")),
+        }
+    }
+}
+
+/// Namespace of files created without selecting one, and of every file stored
+/// before namespaces existed.
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+fn default_namespace() -> String {
+    DEFAULT_NAMESPACE.to_string()
+}
+
+/// Whether a file's content is served on `/static`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    /// Served to anyone with the permalink
+    #[default]
+    Public,
+    /// Only available through the API
+    Private,
+}
+
+impl std::str::FromStr for Visibility {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "public" => Ok(Visibility::Public),
+            "private" => Ok(Visibility::Private),
+            _ => Err(format!(
+                "unknown visibility '{s}', expected public or private"
+            )),
+        }
+    }
+}
+
+/// Maximum length of a tag in bytes.
+pub const MAX_TAG_LEN: usize = 64;
+
+/// Normalize a tag to lowercase, rejecting empty, overlong or malformed tags.
+/// Tags may contain letters, digits, `-`, `_`, `:` and `.`.
+pub fn normalize_tag(tag: &str) -> Result<String, String> {
+    let tag = tag.trim().to_lowercase();
+    if tag.is_empty() {
+        return Err("tags must not be empty".to_string());
+    }
+    if tag.len() > MAX_TAG_LEN {
+        return Err(format!("tag '{tag}' is longer than {MAX_TAG_LEN} bytes"));
+    }
+    if let Some(c) = tag
+        .chars()
+        .find(|c| !(c.is_alphanumeric() || matches!(c, '-' | '_' | ':' | '.')))
+    {
+        return Err(format!("tag '{tag}' contains invalid character '{c}'"));
+    }
+    Ok(tag)
+}
+
 /// A file record stored in redb
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileRecord {
     // System fields
     pub id: String,
+    /// Tenant the file belongs to. Permalinks and indexes are scoped by it.
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
     pub mime_type: String,
     pub file_type: FileType,
     pub byte_size: u64,
     pub permalink: String,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    /// Bumped on every write to the record, for optimistic concurrency. Zero
+    /// for records written before revisions.
+    #[serde(default)]
+    pub revision: u64,
 
     // CMS fields (all optional)
     #[serde(default)]
     pub alt: Option<String>,
     #[serde(default)]
     pub description: Option<String>,
+    /// When the file is deleted automatically
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
     #[serde(default)]
     pub metadata: Option<HashMap<String, serde_json::Value>>,
     #[serde(default)]
     pub name: Option<String>,
     #[serde(default)]
     pub subject_id: Option<String>,
+    /// Sorted, deduplicated labels
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub visibility: Visibility,
+
+    /// Content versions, oldest first, the last being current. Empty for files
+    /// uploaded before versioning, whose only content is stored under the ID.
+    #[serde(default)]
+    pub versions: Vec<FileVersion>,
+
+    /// When the file was moved to the trash. Trashed files are hidden but keep
+    /// their permalink until restored or purged.
+    #[serde(default)]
+    pub deleted_at: Option<DateTime<Utc>>,
+
+    // Retention (admin only)
+    /// Locks the file until the hold is released
+    #[serde(default)]
+    pub legal_hold: bool,
+    /// Locks the file until this time. Can only be extended while in effect.
+    #[serde(default)]
+    pub retain_until: Option<DateTime<Utc>>,
+}
+
+impl FileRecord {
+    /// What prevents the file from being deleted, having its content replaced
+    /// or its permalink changed, if anything
+    pub fn lock(&self, now: DateTime<Utc>) -> Option<FileLock> {
+        if self.legal_hold {
+            return Some(FileLock::LegalHold);
+        }
+        self.retain_until
+            .filter(|t| *t > now)
+            .map(FileLock::Retention)
+    }
+
+    /// Whether the file has passed its expiry time, even if not yet reaped
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|t| t <= now)
+    }
+
+    /// Every content version, oldest first
+    pub fn all_versions(&self) -> Vec<FileVersion> {
+        if self.versions.is_empty() {
+            vec![self.original_version()]
+        } else {
+            self.versions.clone()
+        }
+    }
+
+    /// The version currently served
+    pub fn current_version(&self) -> FileVersion {
+        self.versions
+            .last()
+            .cloned()
+            .unwrap_or_else(|| self.original_version())
+    }
+
+    /// Look up a content version by number
+    pub fn version(&self, version: u32) -> Option<FileVersion> {
+        self.all_versions()
+            .into_iter()
+            .find(|v| v.version == version)
+    }
+
+    /// Object storage keys of all versions, without duplicates
+    pub fn blob_keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self
+            .all_versions()
+            .into_iter()
+            .map(|v| v.blob_key)
+            .collect();
+        keys.sort();
+        keys.dedup();
+        keys
+    }
+
+    /// The implicit first version of a file uploaded before versioning
+    fn original_version(&self) -> FileVersion {
+        FileVersion {
+            version: 1,
+            blob_key: self.id.clone(),
+            byte_size: self.byte_size,
+            mime_type: self.mime_type.clone(),
+            sha256: None,
+            created_at: self.created_at,
+        }
+    }
+}
+
+/// Why a file is locked against deletion and changes to its content or permalink
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileLock {
+    LegalHold,
+    Retention(DateTime<Utc>),
+}
+
+impl std::fmt::Display for FileLock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileLock::LegalHold => write!(f, "under legal hold"),
+            FileLock::Retention(until) => write!(f, "retained until {}", until.to_rfc3339()),
+        }
+    }
+}
+
+/// One uploaded revision of a file's content
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileVersion {
+    /// Sequential version number, starting at 1
+    pub version: u32,
+    /// Object storage key holding the content
+    pub blob_key: String,
+    pub byte_size: u64,
+    pub mime_type: String,
+    /// Hex SHA-256 of the content, unknown for files uploaded before versioning
+    #[serde(default)]
+    pub sha256: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A former permalink that now redirects to a file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Redirect {
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+    pub permalink: String,
+    pub file_id: String,
+}
+
+/// A permalink change for one file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PermalinkRename {
+    pub id: String,
+    pub from: String,
+    pub to: String,
+}
+
+/// A renamed permalink that is already used or redirected by another file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PermalinkConflict {
+    pub permalink: String,
+    pub file_id: String,
+}
+
+/// The effect of renaming a permalink prefix
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RenamePlan {
+    pub renames: Vec<PermalinkRename>,
+    pub conflicts: Vec<PermalinkConflict>,
+}
+
+/// The response to a request made with an `Idempotency-Key`, replayed when
+/// the request is retried with the same key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdempotencyRecord {
+    pub namespace: String,
+    pub key: String,
+    /// SHA-256 of the request method, path and body, to tell a retry from a
+    /// different request reusing the key
+    pub fingerprint: String,
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    pub created_at: DateTime<Utc>,
+    /// Set while the request is still being handled, to the id of the claim
+    /// it holds the key with. Such a record has no response yet.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub claim: Option<String>,
+}
+
+/// How long a claim on an idempotency key holds without a response
+pub const CLAIM_LEASE: chrono::Duration = chrono::Duration::minutes(10);
+
+impl IdempotencyRecord {
+    /// Whether this is a claim on the key, without a response
+    pub fn is_claim(&self) -> bool {
+        self.claim.is_some()
+    }
+}
+
+/// A subscription to file lifecycle events of a namespace
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Webhook {
+    pub id: String,
+    pub namespace: String,
+    /// Where deliveries are POSTed
+    pub url: String,
+    /// Key the payload signature is computed with
+    pub secret: String,
+    pub events: Vec<WebhookEvent>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// File lifecycle events webhooks can subscribe to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WebhookEvent {
+    #[serde(rename = "file.created")]
+    FileCreated,
+    #[serde(rename = "file.updated")]
+    FileUpdated,
+    #[serde(rename = "file.deleted")]
+    FileDeleted,
+    #[serde(rename = "file.content_replaced")]
+    FileContentReplaced,
+}
+
+impl WebhookEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WebhookEvent::FileCreated => "file.created",
+            WebhookEvent::FileUpdated => "file.updated",
+            WebhookEvent::FileDeleted => "file.deleted",
+            WebhookEvent::FileContentReplaced => "file.content_replaced",
+        }
+    }
+}
+
+/// A right-to-erasure request deleting every file of a subject. Large
+/// subjects are erased over several steps, each adding to the report.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Erasure {
+    pub id: String,
+    pub namespace: String,
+    pub subject_id: String,
+    /// Also erase the subject's files in the trash
+    pub include_trashed: bool,
+    pub started_at: DateTime<Utc>,
+    /// Set once every erasable file is deleted
+    pub completed_at: Option<DateTime<Utc>>,
+    /// Files deleted so far
+    pub erased: Vec<ErasedFile>,
+    /// Files kept because they are under legal hold or retention
+    pub retained: Vec<String>,
+    /// Content of erased files not yet confirmed deleted from object storage,
+    /// by blob key. An interrupted erasure deletes it when resumed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pending_content: Vec<String>,
+}
+
+/// A file deleted by an erasure, with every version whose content went
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ErasedFile {
+    pub id: String,
+    pub permalink: String,
+    /// Whether the file was in the trash
+    pub trashed: bool,
+    pub versions: Vec<FileVersion>,
+    pub erased_at: DateTime<Utc>,
+}
+
+/// Who made a change and where, for the audit log. Carried with the operation
+/// so that every node records the same entry.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditContext {
+    /// Authenticated caller, as reported by the gateway in front of the API
+    pub actor: Option<String>,
+    pub request_id: Option<String>,
+    /// Node that accepted the request
+    pub source_node: Option<String>,
+    pub timestamp: DateTime<Utc>,
 }
 
 /// Types of write operations (replicated via muster)
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum WriteOp {
     CreateFile(FileRecord),
+    /// Permanently delete a file record.
     DeleteFile {
         id: String,
     },
@@ -120,11 +474,238 @@ pub enum WriteOp {
         #[serde(default)]
         description: Patch<String>,
         #[serde(default)]
+        expires_at: Patch<DateTime<Utc>>,
+        #[serde(default)]
         metadata: Patch<HashMap<String, serde_json::Value>>,
         #[serde(default)]
         name: Patch<String>,
         permalink: Option<String>,
         #[serde(default)]
         subject_id: Patch<String>,
+        #[serde(default)]
+        visibility: Option<Visibility>,
+    },
+    /// Remove every file record and index entry (test mode only).
+    PurgeAll,
+    /// Add tags to a file, ignoring ones it already has.
+    AddTags {
+        id: String,
+        tags: Vec<String>,
+    },
+    /// Remove tags from a file, ignoring ones it doesn't have.
+    RemoveTags {
+        id: String,
+        tags: Vec<String>,
+    },
+    /// Replace the `from` prefix of every matching permalink with `to`, all or
+    /// nothing. Not applied if any new permalink is taken.
+    RenamePrefix {
+        #[serde(default = "default_namespace")]
+        namespace: String,
+        from: String,
+        to: String,
+        renamed_at: DateTime<Utc>,
+    },
+    /// Stop redirecting a former permalink.
+    DeleteRedirect {
+        #[serde(default = "default_namespace")]
+        namespace: String,
+        permalink: String,
+    },
+    /// Make uploaded content the file's current version. Not applied unless
+    /// `version.version` is the next version number.
+    AddVersion {
+        id: String,
+        version: FileVersion,
+    },
+    /// Add a new current version with the content of an earlier one.
+    RestoreVersion {
+        id: String,
+        version: u32,
+        restored_at: DateTime<Utc>,
     },
+    /// Move a file to the trash.
+    TrashFile {
+        id: String,
+        deleted_at: DateTime<Utc>,
+    },
+    /// Take a file back out of the trash.
+    RestoreFile {
+        id: String,
+    },
+    /// Change a file's legal hold or retention period. Not applied if it would
+    /// shorten a retention period in effect.
+    SetRetention {
+        id: String,
+        legal_hold: Option<bool>,
+        #[serde(default)]
+        retain_until: Patch<DateTime<Utc>>,
+    },
+    /// Changes to many files applied in one transaction, all or nothing. See
+    /// `WriteOp::is_batchable` for the operations a batch can hold.
+    Batch {
+        ops: Vec<WriteOp>,
+    },
+    /// An operation together with the request that made it, for the audit log.
+    Audited {
+        context: AuditContext,
+        op: Box<WriteOp>,
+    },
+    /// An operation applied only while a file is at one of `revisions`, so
+    /// concurrent writers can't overwrite each other's changes.
+    IfMatch {
+        id: String,
+        revisions: Vec<u64>,
+        op: Box<WriteOp>,
+    },
+    /// Delete audit log entries recorded before a time.
+    PruneAudit {
+        before: DateTime<Utc>,
+    },
+    /// Delete change feed entries applied before a time.
+    PruneChanges {
+        before: DateTime<Utc>,
+    },
+    /// Create or replace a webhook subscription.
+    PutWebhook(Webhook),
+    /// Delete a webhook subscription and its pending and dead-lettered
+    /// deliveries.
+    DeleteWebhook {
+        id: String,
+    },
+    /// Remove a delivered webhook delivery from the outbox.
+    WebhookDelivered {
+        delivery_id: u64,
+    },
+    /// Record a failed webhook delivery attempt, retrying at `retry_at` or
+    /// dead-lettering the delivery without one.
+    WebhookFailed {
+        delivery_id: u64,
+        attempted_at: DateTime<Utc>,
+        error: String,
+        retry_at: Option<DateTime<Utc>>,
+    },
+    /// Move a dead-lettered webhook delivery back to the outbox, or retry a
+    /// pending one, at a time.
+    RedeliverWebhook {
+        delivery_id: u64,
+        at: DateTime<Utc>,
+    },
+    /// Remember the response to a request made with an idempotency key. The
+    /// first response stored for a key wins, replacing its claim.
+    PutIdempotencyKey(IdempotencyRecord),
+    /// Claim an idempotency key for a request about to be handled. Not applied
+    /// if the key has a response, or a claim younger than `CLAIM_LEASE`.
+    ClaimIdempotencyKey(IdempotencyRecord),
+    /// Give up a claim on an idempotency key, so the request can be retried.
+    ReleaseIdempotencyKey {
+        namespace: String,
+        key: String,
+        claim: String,
+    },
+    /// Forget idempotency keys stored before a time.
+    PruneIdempotencyKeys {
+        before: DateTime<Utc>,
+    },
+    /// Start erasing a subject's files. Not applied if the erasure exists.
+    StartErasure(Erasure),
+    /// Permanently delete files of an erasure's subject, adding them to its
+    /// report in the same transaction. Files that meanwhile left the subject,
+    /// went to the trash the erasure leaves alone or were locked are skipped.
+    EraseFiles {
+        erasure_id: String,
+        ids: Vec<String>,
+        erased_at: DateTime<Utc>,
+    },
+    /// Record that the content of erased files is gone from object storage.
+    ErasureContentDeleted {
+        erasure_id: String,
+        blob_keys: Vec<String>,
+    },
+    /// Complete an erasure, recording the files it had to keep.
+    CompleteErasure {
+        erasure_id: String,
+        retained: Vec<String>,
+        completed_at: DateTime<Utc>,
+    },
+}
+
+impl WriteOp {
+    /// Attach the context of the request making this change.
+    pub fn audited(self, context: &AuditContext) -> WriteOp {
+        WriteOp::Audited {
+            context: context.clone(),
+            op: Box::new(self),
+        }
+    }
+
+    /// Apply this change only while the file is at one of `revisions`.
+    pub fn if_match(self, id: &str, revisions: Vec<u64>) -> WriteOp {
+        WriteOp::IfMatch {
+            id: id.to_string(),
+            revisions,
+            op: Box::new(self),
+        }
+    }
+
+    /// Whether the operation can be part of a batch: a change to one file's
+    /// record that needs nothing outside it.
+    pub fn is_batchable(&self) -> bool {
+        matches!(
+            self,
+            WriteOp::UpdateFile { .. }
+                | WriteOp::AddTags { .. }
+                | WriteOp::RemoveTags { .. }
+                | WriteOp::TrashFile { .. }
+                | WriteOp::DeleteFile { .. }
+        )
+    }
+
+    /// The file a batchable operation changes
+    pub fn file_id(&self) -> Option<&str> {
+        match self {
+            WriteOp::UpdateFile { id, .. }
+            | WriteOp::AddTags { id, .. }
+            | WriteOp::RemoveTags { id, .. }
+            | WriteOp::TrashFile { id, .. }
+            | WriteOp::DeleteFile { id } => Some(id),
+            _ => None,
+        }
+    }
+
+    /// Name of the operation, as recorded in the audit log and change feed
+    pub fn name(&self) -> &'static str {
+        match self {
+            WriteOp::CreateFile(_) => "create_file",
+            WriteOp::DeleteFile { .. } => "delete_file",
+            WriteOp::UpdateFile { .. } => "update_file",
+            WriteOp::PurgeAll => "purge_all",
+            WriteOp::AddTags { .. } => "add_tags",
+            WriteOp::RemoveTags { .. } => "remove_tags",
+            WriteOp::RenamePrefix { .. } => "rename_prefix",
+            WriteOp::DeleteRedirect { .. } => "delete_redirect",
+            WriteOp::AddVersion { .. } => "add_version",
+            WriteOp::RestoreVersion { .. } => "restore_version",
+            WriteOp::TrashFile { .. } => "trash_file",
+            WriteOp::RestoreFile { .. } => "restore_file",
+            WriteOp::SetRetention { .. } => "set_retention",
+            WriteOp::Batch { .. } => "batch",
+            WriteOp::Audited { op, .. } | WriteOp::IfMatch { op, .. } => op.name(),
+            WriteOp::PruneAudit { .. } => "prune_audit",
+            WriteOp::PruneChanges { .. } => "prune_changes",
+            WriteOp::PutWebhook(_) => "put_webhook",
+            WriteOp::DeleteWebhook { .. } => "delete_webhook",
+            WriteOp::WebhookDelivered { .. } => "webhook_delivered",
+            WriteOp::WebhookFailed { .. } => "webhook_failed",
+            WriteOp::RedeliverWebhook { .. } => "redeliver_webhook",
+            WriteOp::PutIdempotencyKey(_) => "put_idempotency_key",
+            WriteOp::ClaimIdempotencyKey(_) => "claim_idempotency_key",
+            WriteOp::ReleaseIdempotencyKey { .. } => "release_idempotency_key",
+            WriteOp::PruneIdempotencyKeys { .. } => "prune_idempotency_keys",
+            WriteOp::StartErasure(_) => "start_erasure",
+            WriteOp::EraseFiles { .. } => "erase_files",
+            WriteOp::ErasureContentDeleted { .. } => "erasure_content_deleted",
+            WriteOp::CompleteErasure { .. } => "complete_erasure",
+        }
+    }
 }
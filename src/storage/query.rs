@@ -0,0 +1,732 @@
+use std::cmp::Ordering;
+use std::ops::Bound;
+
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use redb::ReadOnlyTable;
+use serde::{Deserialize, Deserializer};
+
+use super::db::{Database, DatabaseError};
+use super::indexes::{
+    count_key, created_key, metadata_key_entry, metadata_match_value, metadata_value_entry, scoped,
+    METADATA_INDEX, NAMESPACE_INDEX, TAG_INDEX,
+};
+use super::models::{FileRecord, FileType, DEFAULT_NAMESPACE};
+use super::tables::*;
+
+// ============================================================================
+// Query types
+// ============================================================================
+
+/// Filters for listing files in a namespace. Every filter that is set must match.
+#[derive(Debug, Clone)]
+pub struct FileFilter {
+    /// Namespace to list, the default one unless set
+    pub namespace: String,
+    pub byte_size: RangeFilter<u64>,
+    pub created_at: RangeFilter<DateTime<Utc>>,
+    /// Matches files of any of these types (empty means any type)
+    pub file_types: Vec<FileType>,
+    /// Conditions on metadata values
+    pub metadata: Vec<MetadataFilter>,
+    pub mime_type: Option<MimeFilter>,
+    /// Case-insensitive substring of the file's name
+    pub name_contains: Option<String>,
+    pub subject_id: Option<String>,
+    /// Tags to match, combined according to `tag_match`
+    pub tags: Vec<String>,
+    pub tag_match: TagMatch,
+    pub updated_at: RangeFilter<DateTime<Utc>>,
+}
+
+impl Default for FileFilter {
+    fn default() -> Self {
+        Self {
+            namespace: DEFAULT_NAMESPACE.to_string(),
+            byte_size: RangeFilter::default(),
+            created_at: RangeFilter::default(),
+            file_types: Vec::new(),
+            metadata: Vec::new(),
+            mime_type: None,
+            name_contains: None,
+            subject_id: None,
+            tags: Vec::new(),
+            tag_match: TagMatch::default(),
+            updated_at: RangeFilter::default(),
+        }
+    }
+}
+
+/// How multiple tag filters combine.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TagMatch {
+    /// Files must have every tag
+    #[default]
+    All,
+    /// Files must have at least one of the tags
+    Any,
+}
+
+/// Bounds on a value. Unset bounds are open.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(bound(deserialize = "T: Deserialize<'de>"))]
+pub struct RangeFilter<T> {
+    #[serde(default)]
+    pub gt: Option<T>,
+    #[serde(default)]
+    pub gte: Option<T>,
+    #[serde(default)]
+    pub lt: Option<T>,
+    #[serde(default)]
+    pub lte: Option<T>,
+}
+
+impl<T> Default for RangeFilter<T> {
+    fn default() -> Self {
+        Self {
+            gt: None,
+            gte: None,
+            lt: None,
+            lte: None,
+        }
+    }
+}
+
+impl<T: PartialOrd> RangeFilter<T> {
+    pub fn is_unbounded(&self) -> bool {
+        self.gt.is_none() && self.gte.is_none() && self.lt.is_none() && self.lte.is_none()
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        self.gt.as_ref().is_none_or(|b| value > b)
+            && self.gte.as_ref().is_none_or(|b| value >= b)
+            && self.lt.as_ref().is_none_or(|b| value < b)
+            && self.lte.as_ref().is_none_or(|b| value <= b)
+    }
+}
+
+/// MIME type filter: an exact type, or a `type/*` wildcard.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MimeFilter {
+    Exact(String),
+    /// Matches every MIME type starting with the given `type/` prefix
+    Prefix(String),
+}
+
+impl MimeFilter {
+    /// Parse `image/png` as an exact match and `image/*` as a prefix match.
+    pub fn parse(value: &str) -> Self {
+        match value.strip_suffix('*') {
+            Some(prefix) => MimeFilter::Prefix(prefix.to_string()),
+            None => MimeFilter::Exact(value.to_string()),
+        }
+    }
+
+    pub fn matches(&self, mime_type: &str) -> bool {
+        match self {
+            MimeFilter::Exact(exact) => mime_type == exact,
+            MimeFilter::Prefix(prefix) => mime_type.starts_with(prefix.as_str()),
+        }
+    }
+}
+
+/// A condition on one metadata key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetadataFilter {
+    pub key: String,
+    pub condition: MetadataCondition,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetadataCondition {
+    /// The value equals the given string, number or boolean
+    Equals(String),
+    /// The value equals any of the given strings, numbers or booleans
+    In(Vec<String>),
+    /// The key is present (`true`) or absent (`false`)
+    Exists(bool),
+}
+
+impl MetadataFilter {
+    pub fn matches(&self, file: &FileRecord) -> bool {
+        let value = file.metadata.as_ref().and_then(|m| m.get(&self.key));
+        match self.condition {
+            MetadataCondition::Equals(ref expected) => {
+                value.and_then(metadata_match_value).as_ref() == Some(expected)
+            }
+            MetadataCondition::In(ref expected) => value
+                .and_then(metadata_match_value)
+                .is_some_and(|v| expected.contains(&v)),
+            MetadataCondition::Exists(exists) => value.is_some() == exists,
+        }
+    }
+}
+
+/// Field to order a listing by.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SortField {
+    ByteSize,
+    #[default]
+    CreatedAt,
+    Name,
+    UpdatedAt,
+}
+
+/// Listing order, written as `field` or `-field` for descending.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Sort {
+    pub field: SortField,
+    pub descending: bool,
+}
+
+impl std::str::FromStr for Sort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (descending, name) = match s.strip_prefix('-') {
+            Some(name) => (true, name),
+            None => (false, s),
+        };
+        let field = match name {
+            "byte_size" => SortField::ByteSize,
+            "created_at" => SortField::CreatedAt,
+            "name" => SortField::Name,
+            "updated_at" => SortField::UpdatedAt,
+            _ => {
+                return Err(format!(
+                    "unknown sort field '{name}', expected one of \
+                     byte_size, created_at, name, updated_at"
+                ))
+            }
+        };
+        Ok(Sort { field, descending })
+    }
+}
+
+impl<'de> Deserialize<'de> for Sort {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// A position in creation order, used for keyset pagination.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cursor {
+    /// `created_at` in microseconds since the epoch
+    pub created_at: i64,
+    pub id: String,
+}
+
+impl Cursor {
+    /// The cursor pointing just past the given file.
+    pub fn for_file(file: &FileRecord) -> Self {
+        Self {
+            created_at: created_key(file),
+            id: file.id.clone(),
+        }
+    }
+
+    /// Encode as an opaque, URL-safe token.
+    pub fn encode(&self) -> String {
+        base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .encode(format!("{}:{}", self.created_at, self.id))
+    }
+
+    /// Decode a token produced by [`Cursor::encode`].
+    pub fn decode(token: &str) -> Option<Self> {
+        let raw = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(token)
+            .ok()?;
+        let raw = String::from_utf8(raw).ok()?;
+        let (created_at, id) = raw.split_once(':')?;
+        Some(Self {
+            created_at: created_at.parse().ok()?,
+            id: id.to_string(),
+        })
+    }
+}
+
+/// One page of a file listing
+#[derive(Debug)]
+pub struct FilePage {
+    pub files: Vec<FileRecord>,
+    /// Number of files matching the filter, ignoring pagination, when known
+    /// without reading past the page: from the index counts, or because no
+    /// more files matched. Not given for pages after a cursor otherwise.
+    pub total: Option<u64>,
+    /// Where the next page starts, if more files match
+    pub next_cursor: Option<Cursor>,
+}
+
+// ============================================================================
+// Query execution
+// ============================================================================
+
+type ValueIndex = ReadOnlyTable<(&'static str, i64, &'static str), ()>;
+type KeyIter = Box<dyn Iterator<Item = Result<(i64, String), DatabaseError>>>;
+
+/// An equality filter answered by a composite-key index: a file matches if it
+/// has an entry under any of the values.
+struct IndexedFilter<'a> {
+    table: &'a ValueIndex,
+    values: Vec<String>,
+    /// Sum of the index counts of every value
+    count: u64,
+    /// Whether a file has at most one of the values, making `count` exact
+    disjoint: bool,
+}
+
+impl IndexedFilter<'_> {
+    fn contains(&self, created_at: i64, id: &str) -> Result<bool, DatabaseError> {
+        for value in &self.values {
+            if self.table.get((value.as_str(), created_at, id))?.is_some() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl Database {
+    /// List files matching a filter, skipping `offset` matches.
+    ///
+    /// Listings sorted by `created_at` are read in index order: the scan is
+    /// driven by the most selective single-valued index, the remaining indexed
+    /// filters are checked by key lookup, and records are only decoded for the
+    /// returned page or when a non-indexed filter needs them. Such listings can
+    /// resume after a cursor, which is ignored for every other sort.
+    ///
+    /// Other sorts decode and sort every matching record.
+    pub fn query_files(
+        &self,
+        filter: &FileFilter,
+        sort: Sort,
+        after: Option<&Cursor>,
+        offset: usize,
+        limit: usize,
+    ) -> Result<FilePage, DatabaseError> {
+        let read_txn = self.begin_read()?;
+        let files_table = read_txn.open_table(FILES)?;
+        let namespace_table = read_txn.open_table(NAMESPACE_FILES)?;
+        let counts_table = read_txn.open_table(INDEX_COUNTS)?;
+        let type_table = read_txn.open_table(FILE_TYPE_FILES)?;
+        let mime_table = read_txn.open_table(MIME_TYPE_FILES)?;
+        let subject_table = read_txn.open_table(SUBJECT_FILES)?;
+        let tag_table = read_txn.open_table(TAG_FILES)?;
+        let metadata_table = read_txn.open_table(METADATA_FILES)?;
+        let indexed_keys = read_txn.open_table(INDEXED_METADATA_KEYS)?;
+
+        let count = |name: &str, value: &str| -> Result<u64, DatabaseError> {
+            Ok(counts_table
+                .get(count_key(name, value).as_str())?
+                .map(|v| v.value())
+                .unwrap_or(0))
+        };
+
+        let namespace = filter.namespace.as_str();
+        let mut indexed: Vec<IndexedFilter> = Vec::new();
+        if !filter.file_types.is_empty() {
+            let values: Vec<String> = filter
+                .file_types
+                .iter()
+                .map(|t| scoped(namespace, t.as_str()))
+                .collect();
+            let mut total = 0;
+            for value in &values {
+                total += count("file_type", value)?;
+            }
+            indexed.push(IndexedFilter {
+                table: &type_table,
+                values,
+                count: total,
+                disjoint: true,
+            });
+        }
+        if let Some(MimeFilter::Exact(ref mime_type)) = filter.mime_type {
+            let value = scoped(namespace, mime_type);
+            indexed.push(IndexedFilter {
+                table: &mime_table,
+                count: count("mime_type", &value)?,
+                values: vec![value],
+                disjoint: true,
+            });
+        }
+        if let Some(ref subject_id) = filter.subject_id {
+            let value = scoped(namespace, subject_id);
+            indexed.push(IndexedFilter {
+                table: &subject_table,
+                count: count("subject_id", &value)?,
+                values: vec![value],
+                disjoint: true,
+            });
+        }
+
+        if !filter.tags.is_empty() {
+            let tags = filter.tags.iter().map(|t| scoped(namespace, t));
+            let groups: Vec<Vec<String>> = match filter.tag_match {
+                TagMatch::All => tags.map(|t| vec![t]).collect(),
+                TagMatch::Any => vec![tags.collect()],
+            };
+            for values in groups {
+                let mut total = 0;
+                for value in &values {
+                    total += count(TAG_INDEX, value)?;
+                }
+                indexed.push(IndexedFilter {
+                    table: &tag_table,
+                    disjoint: values.len() == 1,
+                    values,
+                    count: total,
+                });
+            }
+        }
+
+        // Metadata conditions on indexed keys are answered by the index,
+        // everything else is checked against the decoded record
+        let mut unindexed_metadata = Vec::new();
+        for condition in &filter.metadata {
+            let key = condition.key.as_str();
+            let values = if indexed_keys.get(key)?.is_none() {
+                None
+            } else {
+                match condition.condition {
+                    MetadataCondition::Equals(ref value) => {
+                        Some(vec![scoped(namespace, &metadata_value_entry(key, value))])
+                    }
+                    MetadataCondition::In(ref values) => Some(
+                        values
+                            .iter()
+                            .map(|value| scoped(namespace, &metadata_value_entry(key, value)))
+                            .collect(),
+                    ),
+                    MetadataCondition::Exists(true) => {
+                        Some(vec![scoped(namespace, &metadata_key_entry(key))])
+                    }
+                    MetadataCondition::Exists(false) => None,
+                }
+            };
+            match values {
+                Some(values) => {
+                    let mut total = 0;
+                    for value in &values {
+                        total += count(METADATA_INDEX, value)?;
+                    }
+                    // A key has one value per file
+                    indexed.push(IndexedFilter {
+                        table: &metadata_table,
+                        values,
+                        count: total,
+                        disjoint: true,
+                    });
+                }
+                None => unindexed_metadata.push(condition),
+            }
+        }
+
+        // Drive the scan from the smallest single-valued index. Their values
+        // are scoped, so the namespace index is only needed without one.
+        indexed.sort_by_key(|f| f.count);
+        let (driver, namespace_driven) = match indexed.iter().position(|f| f.values.len() == 1) {
+            Some(i) => (indexed.remove(i), false),
+            None => (
+                IndexedFilter {
+                    table: &namespace_table,
+                    values: vec![namespace.to_string()],
+                    count: count(NAMESPACE_INDEX, namespace)?,
+                    disjoint: true,
+                },
+                true,
+            ),
+        };
+        let checks = indexed;
+
+        let needs_record = matches!(filter.mime_type, Some(MimeFilter::Prefix(_)))
+            || !filter.byte_size.is_unbounded()
+            || !filter.updated_at.is_unbounded()
+            || filter.name_contains.is_some()
+            || !unindexed_metadata.is_empty();
+        let name_needle = filter.name_contains.as_deref().map(str::to_lowercase);
+        let record_matches = |file: &FileRecord| {
+            filter
+                .mime_type
+                .as_ref()
+                .is_none_or(|m| m.matches(&file.mime_type))
+                && filter.byte_size.contains(&file.byte_size)
+                && filter.updated_at.contains(&file.updated_at)
+                && name_needle.as_deref().is_none_or(|needle| {
+                    file.name
+                        .as_deref()
+                        .is_some_and(|name| name.to_lowercase().contains(needle))
+                })
+                && unindexed_metadata.iter().all(|m| m.matches(file))
+        };
+
+        // Returns the decoded record when the key matches every filter
+        let load = |id: &str| -> Result<Option<FileRecord>, DatabaseError> {
+            match files_table.get(id)? {
+                Some(data) => Ok(Some(rmp_serde::from_slice(data.value())?)),
+                None => Ok(None),
+            }
+        };
+        let evaluate = |created_at: i64, id: &str| -> Result<Match, DatabaseError> {
+            for check in &checks {
+                if !check.contains(created_at, id)? {
+                    return Ok(Match::No);
+                }
+            }
+            if !needs_record {
+                return Ok(Match::Yes(None));
+            }
+            match load(id)? {
+                Some(file) if record_matches(&file) => Ok(Match::Yes(Some(Box::new(file)))),
+                _ => Ok(Match::No),
+            }
+        };
+
+        let descending = sort.descending && sort.field == SortField::CreatedAt;
+        let created_bounds = KeyBounds::from_range(&filter.created_at);
+        let scan = |after: Option<&Cursor>| -> Result<KeyIter, DatabaseError> {
+            let mut bounds = created_bounds.clone();
+            if let Some(cursor) = after {
+                bounds.resume_after(cursor, descending);
+            }
+            scan_value_index(driver.table, &driver.values[0], &bounds, descending)
+        };
+
+        if sort.field != SortField::CreatedAt {
+            let mut files = Vec::new();
+            for key in scan(None)? {
+                let (created_at, id) = key?;
+                match evaluate(created_at, &id)? {
+                    Match::No => {}
+                    Match::Yes(Some(file)) => files.push(*file),
+                    Match::Yes(None) => files.extend(load(&id)?),
+                }
+            }
+            files.sort_by(|a, b| compare_files(a, b, sort));
+            let total = Some(files.len() as u64);
+            let files = files.into_iter().skip(offset).take(limit).collect();
+            return Ok(FilePage {
+                files,
+                total,
+                next_cursor: None,
+            });
+        }
+
+        let known_total = if needs_record || !filter.created_at.is_unbounded() {
+            None
+        } else {
+            match checks.as_slice() {
+                [] => Some(driver.count),
+                [only] if namespace_driven && only.disjoint => Some(only.count),
+                _ => None,
+            }
+        };
+
+        let mut files = Vec::new();
+        let mut skipped = 0;
+        let mut matched = 0u64;
+        let mut has_more = false;
+        for key in scan(after)? {
+            let (created_at, id) = key?;
+            let decoded = match evaluate(created_at, &id)? {
+                Match::No => continue,
+                Match::Yes(decoded) => decoded,
+            };
+            matched += 1;
+            if skipped < offset {
+                skipped += 1;
+            } else if files.len() < limit {
+                match decoded {
+                    Some(file) => files.push(*file),
+                    None => files.extend(load(&id)?),
+                }
+            } else {
+                has_more = true;
+                break;
+            }
+        }
+
+        // Counting the rest would mean reading every match past the page, and
+        // from a cursor only the tail is seen
+        let total = match (known_total, after) {
+            (Some(total), _) => Some(total),
+            (None, None) if !has_more => Some(matched),
+            (None, _) => None,
+        };
+
+        let next_cursor = if has_more {
+            files.last().map(Cursor::for_file)
+        } else {
+            None
+        };
+
+        Ok(FilePage {
+            files,
+            total,
+            next_cursor,
+        })
+    }
+}
+
+enum Match {
+    No,
+    /// Matched, with the record if it had to be decoded
+    Yes(Option<Box<FileRecord>>),
+}
+
+/// Order files by a sort field, falling back to creation order.
+fn compare_files(a: &FileRecord, b: &FileRecord, sort: Sort) -> Ordering {
+    let ordering = match sort.field {
+        SortField::ByteSize => a.byte_size.cmp(&b.byte_size),
+        SortField::CreatedAt => Ordering::Equal,
+        // Unnamed files sort after named ones
+        SortField::Name => match (&a.name, &b.name) {
+            (Some(a), Some(b)) => a.to_lowercase().cmp(&b.to_lowercase()),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        },
+        SortField::UpdatedAt => a.updated_at.cmp(&b.updated_at),
+    }
+    .then_with(|| created_key(a).cmp(&created_key(b)))
+    .then_with(|| a.id.cmp(&b.id));
+
+    if sort.descending {
+        ordering.reverse()
+    } else {
+        ordering
+    }
+}
+
+// ============================================================================
+// Index scans
+// ============================================================================
+
+/// Bounds over (created_at, uuid) positions in a creation-ordered index.
+#[derive(Debug, Clone)]
+struct KeyBounds {
+    start: Bound<(i64, String)>,
+    end: Bound<(i64, String)>,
+}
+
+impl KeyBounds {
+    fn from_range(range: &RangeFilter<DateTime<Utc>>) -> Self {
+        let at = |t: &DateTime<Utc>, offset: i64| {
+            (t.timestamp_micros().saturating_add(offset), String::new())
+        };
+        let mut bounds = KeyBounds {
+            start: Bound::Unbounded,
+            end: Bound::Unbounded,
+        };
+        if let Some(ref t) = range.gte {
+            bounds.raise_start(Bound::Included(at(t, 0)));
+        }
+        if let Some(ref t) = range.gt {
+            bounds.raise_start(Bound::Included(at(t, 1)));
+        }
+        if let Some(ref t) = range.lte {
+            bounds.lower_end(Bound::Excluded(at(t, 1)));
+        }
+        if let Some(ref t) = range.lt {
+            bounds.lower_end(Bound::Excluded(at(t, 0)));
+        }
+        bounds
+    }
+
+    /// Narrow the bounds to the positions after a cursor in scan order.
+    fn resume_after(&mut self, cursor: &Cursor, descending: bool) {
+        let position = (cursor.created_at, cursor.id.clone());
+        if descending {
+            self.lower_end(Bound::Excluded(position));
+        } else {
+            self.raise_start(Bound::Excluded(position));
+        }
+    }
+
+    fn raise_start(&mut self, bound: Bound<(i64, String)>) {
+        // Rank start bounds so that a higher rank is tighter
+        fn rank(b: &Bound<(i64, String)>) -> Option<(&(i64, String), bool)> {
+            match b {
+                Bound::Included(x) => Some((x, false)),
+                Bound::Excluded(x) => Some((x, true)),
+                Bound::Unbounded => None,
+            }
+        }
+        if rank(&bound) > rank(&self.start) {
+            self.start = bound;
+        }
+    }
+
+    fn lower_end(&mut self, bound: Bound<(i64, String)>) {
+        // Rank end bounds so that a lower rank is tighter
+        fn rank(b: &Bound<(i64, String)>) -> Option<(&(i64, String), bool)> {
+            match b {
+                Bound::Included(x) => Some((x, true)),
+                Bound::Excluded(x) => Some((x, false)),
+                Bound::Unbounded => None,
+            }
+        }
+        let tighter = match (rank(&bound), rank(&self.end)) {
+            (Some(new), Some(current)) => new < current,
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+        if tighter {
+            self.end = bound;
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match (&self.start, &self.end) {
+            (Bound::Included(s), Bound::Included(e)) => s > e,
+            (Bound::Included(s) | Bound::Excluded(s), Bound::Excluded(e))
+            | (Bound::Excluded(s), Bound::Included(e)) => s >= e,
+            _ => false,
+        }
+    }
+}
+
+fn borrow_bound(bound: &Bound<(i64, String)>) -> Bound<(i64, &str)> {
+    match bound {
+        Bound::Included((t, id)) => Bound::Included((*t, id.as_str())),
+        Bound::Excluded((t, id)) => Bound::Excluded((*t, id.as_str())),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// Keys of a composite-key index for one value within bounds.
+fn scan_value_index(
+    table: &ValueIndex,
+    value: &str,
+    bounds: &KeyBounds,
+    descending: bool,
+) -> Result<KeyIter, DatabaseError> {
+    if bounds.is_empty() {
+        return Ok(Box::new(std::iter::empty()));
+    }
+    let start = match borrow_bound(&bounds.start) {
+        Bound::Included((t, id)) => Bound::Included((value, t, id)),
+        Bound::Excluded((t, id)) => Bound::Excluded((value, t, id)),
+        Bound::Unbounded => Bound::Included((value, i64::MIN, "")),
+    };
+    let end = match borrow_bound(&bounds.end) {
+        Bound::Included((t, id)) => Bound::Included((value, t, id)),
+        Bound::Excluded((t, id)) => Bound::Excluded((value, t, id)),
+        Bound::Unbounded => Bound::Excluded((value, i64::MAX, "")),
+    };
+    let range = table.range::<(&str, i64, &str)>((start, end))?;
+    let keys = range.map(|entry| {
+        let (key, _) = entry?;
+        let (_, created_at, id) = key.value();
+        Ok((created_at, id.to_string()))
+    });
+
+    if descending {
+        Ok(Box::new(keys.rev()))
+    } else {
+        Ok(Box::new(keys))
+    }
+}
@@ -0,0 +1,224 @@
+//! Redirects from permalinks a file used to have. Unlike the secondary indexes
+//! these can't be rebuilt from `FILES`, so they are part of snapshots.
+
+use redb::{ReadableTable, WriteTransaction};
+
+use super::db::{Database, DatabaseError, Transaction};
+use super::indexes::{scoped, unscoped};
+use super::models::{Redirect, DEFAULT_NAMESPACE};
+use super::tables::*;
+
+/// Redirect a former permalink to the file that used to have it.
+pub(crate) fn add_redirect(
+    write_txn: &WriteTransaction,
+    namespace: &str,
+    permalink: &str,
+    file_id: &str,
+) -> Result<(), DatabaseError> {
+    remove_redirect(write_txn, namespace, permalink)?;
+    let key = scoped(namespace, permalink);
+    write_txn
+        .open_table(PERMALINK_REDIRECTS)?
+        .insert(key.as_str(), file_id)?;
+    write_txn
+        .open_table(FILE_REDIRECTS)?
+        .insert((file_id, key.as_str()), ())?;
+    Ok(())
+}
+
+/// Remove the redirect for a permalink. Returns the file it pointed at.
+pub(crate) fn remove_redirect(
+    write_txn: &WriteTransaction,
+    namespace: &str,
+    permalink: &str,
+) -> Result<Option<String>, DatabaseError> {
+    let key = scoped(namespace, permalink);
+    let file_id = write_txn
+        .open_table(PERMALINK_REDIRECTS)?
+        .remove(key.as_str())?
+        .map(|v| v.value().to_string());
+    if let Some(ref file_id) = file_id {
+        write_txn
+            .open_table(FILE_REDIRECTS)?
+            .remove((file_id.as_str(), key.as_str()))?;
+    }
+    Ok(file_id)
+}
+
+/// Remove every redirect to a file.
+pub(crate) fn remove_file_redirects(
+    write_txn: &WriteTransaction,
+    file_id: &str,
+) -> Result<(), DatabaseError> {
+    let mut by_file = write_txn.open_table(FILE_REDIRECTS)?;
+    let mut keys = Vec::new();
+    for entry in by_file.range::<(&str, &str)>((file_id, "")..)? {
+        let (key, _) = entry?;
+        let (id, key) = key.value();
+        if id != file_id {
+            break;
+        }
+        keys.push(key.to_string());
+    }
+
+    let mut redirects = write_txn.open_table(PERMALINK_REDIRECTS)?;
+    for key in &keys {
+        redirects.remove(key.as_str())?;
+        by_file.remove((file_id, key.as_str()))?;
+    }
+    Ok(())
+}
+
+/// Remove every redirect.
+pub(crate) fn clear_redirects(write_txn: &WriteTransaction) -> Result<(), DatabaseError> {
+    write_txn
+        .open_table(PERMALINK_REDIRECTS)?
+        .retain(|_, _| false)?;
+    write_txn.open_table(FILE_REDIRECTS)?.retain(|_, _| false)?;
+    Ok(())
+}
+
+/// Move redirects stored before namespaces existed into the default namespace.
+pub(crate) fn scope_legacy_redirects(write_txn: &WriteTransaction) -> Result<(), DatabaseError> {
+    let mut legacy = Vec::new();
+    for entry in write_txn.open_table(PERMALINK_REDIRECTS)?.iter()? {
+        let (key, file_id) = entry?;
+        if !key.value().contains('\0') {
+            legacy.push((key.value().to_string(), file_id.value().to_string()));
+        }
+    }
+
+    let mut redirects = write_txn.open_table(PERMALINK_REDIRECTS)?;
+    let mut by_file = write_txn.open_table(FILE_REDIRECTS)?;
+    for (permalink, file_id) in &legacy {
+        let key = scoped(DEFAULT_NAMESPACE, permalink);
+        redirects.remove(permalink.as_str())?;
+        redirects.insert(key.as_str(), file_id.as_str())?;
+        by_file.remove((file_id.as_str(), permalink.as_str()))?;
+        by_file.insert((file_id.as_str(), key.as_str()), ())?;
+    }
+    Ok(())
+}
+
+fn to_redirect(key: &str, file_id: &str) -> Redirect {
+    let (namespace, permalink) = unscoped(key);
+    Redirect {
+        namespace: namespace.to_string(),
+        permalink: permalink.to_string(),
+        file_id: file_id.to_string(),
+    }
+}
+
+impl Database {
+    /// Get the ID of the file a former permalink redirects to
+    pub fn get_redirect(
+        &self,
+        namespace: &str,
+        permalink: &str,
+    ) -> Result<Option<String>, DatabaseError> {
+        let read_txn = self.begin_read()?;
+        let table = read_txn.open_table(PERMALINK_REDIRECTS)?;
+        Ok(table
+            .get(scoped(namespace, permalink).as_str())?
+            .map(|v| v.value().to_string()))
+    }
+
+    /// List a namespace's redirects in permalink order, optionally only those
+    /// to one file, starting after the given permalink
+    pub fn list_redirects(
+        &self,
+        namespace: &str,
+        file_id: Option<&str>,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<Redirect>, DatabaseError> {
+        let read_txn = self.begin_read()?;
+        let scope = scoped(namespace, "");
+        let after = scoped(namespace, after.unwrap_or(""));
+
+        let mut redirects = Vec::new();
+        match file_id {
+            Some(file_id) => {
+                let table = read_txn.open_table(FILE_REDIRECTS)?;
+                for entry in table.range::<(&str, &str)>((file_id, after.as_str())..)? {
+                    let (key, _) = entry?;
+                    let (id, key) = key.value();
+                    if id != file_id || !key.starts_with(&scope) || redirects.len() == limit {
+                        break;
+                    }
+                    if key != after {
+                        redirects.push(to_redirect(key, id));
+                    }
+                }
+            }
+            None => {
+                let table = read_txn.open_table(PERMALINK_REDIRECTS)?;
+                for entry in table.range(after.as_str()..)? {
+                    let (key, id) = entry?;
+                    if !key.value().starts_with(&scope) || redirects.len() == limit {
+                        break;
+                    }
+                    if key.value() != after {
+                        redirects.push(to_redirect(key.value(), id.value()));
+                    }
+                }
+            }
+        }
+        Ok(redirects)
+    }
+
+    /// Delete the redirect for a permalink. Returns false if there was none.
+    pub fn delete_redirect(&self, namespace: &str, permalink: &str) -> Result<bool, DatabaseError> {
+        self.write(|txn| txn.delete_redirect(namespace, permalink))
+    }
+
+    /// Get every redirect in every namespace, for snapshots
+    pub fn get_all_redirects(&self) -> Result<Vec<Redirect>, DatabaseError> {
+        let read_txn = self.begin_read()?;
+        let table = read_txn.open_table(PERMALINK_REDIRECTS)?;
+        let mut redirects = Vec::new();
+        for entry in table.iter()? {
+            let (key, id) = entry?;
+            redirects.push(to_redirect(key.value(), id.value()));
+        }
+        Ok(redirects)
+    }
+
+    /// Check if a permalink is used by a file or redirects to one, other than
+    /// the file `owner`
+    pub fn permalink_in_use(
+        &self,
+        namespace: &str,
+        permalink: &str,
+        owner: Option<&str>,
+    ) -> Result<bool, DatabaseError> {
+        let read_txn = self.begin_read()?;
+        let permalinks = read_txn.open_table(FILE_PERMALINKS)?;
+        let redirects = read_txn.open_table(PERMALINK_REDIRECTS)?;
+
+        let key = scoped(namespace, permalink);
+        let taken =
+            |id: Option<redb::AccessGuard<&str>>| id.is_some_and(|id| owner != Some(id.value()));
+        Ok(taken(permalinks.get(key.as_str())?) || taken(redirects.get(key.as_str())?))
+    }
+}
+
+impl Transaction {
+    /// Get the ID of the file a former permalink redirects to
+    pub fn get_redirect(
+        &self,
+        namespace: &str,
+        permalink: &str,
+    ) -> Result<Option<String>, DatabaseError> {
+        let table = self.write_txn.open_table(PERMALINK_REDIRECTS)?;
+        let file_id = table
+            .get(scoped(namespace, permalink).as_str())?
+            .map(|v| v.value().to_string());
+        Ok(file_id)
+    }
+
+    /// Delete the redirect for a permalink. Returns false if there was none.
+    pub fn delete_redirect(&self, namespace: &str, permalink: &str) -> Result<bool, DatabaseError> {
+        Ok(remove_redirect(&self.write_txn, namespace, permalink)?.is_some())
+    }
+}
@@ -0,0 +1,198 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+
+use super::db::{Database, DatabaseError};
+use super::indexes::{count_key, created_key, scoped, NAMESPACE_INDEX};
+use super::models::{FileRecord, FileType, DEFAULT_NAMESPACE};
+use super::tables::*;
+
+/// Index name used for search term document counts in `INDEX_COUNTS`.
+pub(crate) const SEARCH_INDEX: &str = "search";
+
+/// Terms longer than this are not indexed.
+const MAX_TERM_LEN: usize = 64;
+
+/// Relative weight of a term by the field it appears in.
+const NAME_WEIGHT: u32 = 4;
+const ALT_WEIGHT: u32 = 2;
+const PERMALINK_WEIGHT: u32 = 2;
+const DESCRIPTION_WEIGHT: u32 = 1;
+const METADATA_WEIGHT: u32 = 1;
+
+/// Score multiplier for a term that only matches a query word as a prefix.
+const PREFIX_MATCH_BOOST: f64 = 0.5;
+
+/// Split text into lowercase alphanumeric terms.
+pub fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(str::to_lowercase)
+        .filter(|term| term.len() <= MAX_TERM_LEN)
+}
+
+/// Weighted term frequencies for every searchable field of a file.
+pub(crate) fn file_terms(file: &FileRecord) -> BTreeMap<String, u32> {
+    let mut terms = BTreeMap::new();
+    let mut add = |text: &str, weight: u32| {
+        for term in tokenize(text) {
+            *terms.entry(term).or_insert(0) += weight;
+        }
+    };
+
+    if let Some(ref name) = file.name {
+        add(name, NAME_WEIGHT);
+    }
+    if let Some(ref alt) = file.alt {
+        add(alt, ALT_WEIGHT);
+    }
+    if let Some(ref description) = file.description {
+        add(description, DESCRIPTION_WEIGHT);
+    }
+    add(&file.permalink, PERMALINK_WEIGHT);
+    if let Some(ref metadata) = file.metadata {
+        for value in metadata.values() {
+            if let serde_json::Value::String(s) = value {
+                add(s, METADATA_WEIGHT);
+            }
+        }
+    }
+    terms
+}
+
+/// A full-text search over the files of a namespace
+#[derive(Debug, Clone)]
+pub struct SearchQuery {
+    /// Namespace to search, the default one unless set
+    pub namespace: String,
+    /// Free text. Every word must match a term, either exactly or as a prefix.
+    pub text: String,
+    /// Matches files of any of these types (empty means any type)
+    pub file_types: Vec<FileType>,
+    pub subject_id: Option<String>,
+}
+
+impl Default for SearchQuery {
+    fn default() -> Self {
+        Self {
+            namespace: DEFAULT_NAMESPACE.to_string(),
+            text: String::new(),
+            file_types: Vec::new(),
+            subject_id: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SearchHit {
+    pub file: FileRecord,
+    pub score: f64,
+}
+
+/// One page of search results, most relevant first
+#[derive(Debug)]
+pub struct SearchPage {
+    pub hits: Vec<SearchHit>,
+    /// Number of files matching the query, ignoring pagination
+    pub total: u64,
+}
+
+impl Database {
+    /// Search files by the words in their name, alt text, description,
+    /// permalink and string metadata values.
+    ///
+    /// Matches are scored by TF-IDF, with exact term matches ranked above
+    /// prefix matches. Ties are broken newest first.
+    pub fn search_files(
+        &self,
+        query: &SearchQuery,
+        offset: usize,
+        limit: usize,
+    ) -> Result<SearchPage, DatabaseError> {
+        let mut words: Vec<String> = tokenize(&query.text)
+            .map(|word| scoped(&query.namespace, &word))
+            .collect();
+        words.sort();
+        words.dedup();
+        if words.is_empty() {
+            return Ok(SearchPage {
+                hits: Vec::new(),
+                total: 0,
+            });
+        }
+
+        let read_txn = self.begin_read()?;
+        let files_table = read_txn.open_table(FILES)?;
+        let terms_table = read_txn.open_table(SEARCH_TERMS)?;
+        let counts_table = read_txn.open_table(INDEX_COUNTS)?;
+        let file_count = counts_table
+            .get(count_key(NAMESPACE_INDEX, &query.namespace).as_str())?
+            .map(|v| v.value())
+            .unwrap_or(0) as f64;
+
+        // Every word must match, so intersect the per-word scores
+        let mut scores: Option<HashMap<String, f64>> = None;
+        for word in &words {
+            let mut word_scores: HashMap<String, f64> = HashMap::new();
+            for entry in terms_table.range::<(&str, &str)>((word.as_str(), "")..)? {
+                let (key, weight) = entry?;
+                let (term, id) = key.value();
+                if !term.starts_with(word.as_str()) {
+                    break;
+                }
+
+                let documents = counts_table
+                    .get(count_key(SEARCH_INDEX, term).as_str())?
+                    .map(|v| v.value())
+                    .unwrap_or(1) as f64;
+                let idf = (1.0 + file_count / documents).ln();
+                let boost = if term == word {
+                    1.0
+                } else {
+                    PREFIX_MATCH_BOOST
+                };
+                *word_scores.entry(id.to_string()).or_insert(0.0) +=
+                    f64::from(weight.value()) * idf * boost;
+            }
+
+            scores = Some(match scores {
+                None => word_scores,
+                Some(mut scores) => {
+                    scores.retain(|id, _| word_scores.contains_key(id));
+                    for (id, score) in scores.iter_mut() {
+                        *score += word_scores[id];
+                    }
+                    scores
+                }
+            });
+        }
+
+        let mut hits = Vec::new();
+        for (id, score) in scores.unwrap_or_default() {
+            let Some(data) = files_table.get(id.as_str())? else {
+                continue;
+            };
+            let file: FileRecord = rmp_serde::from_slice(data.value())?;
+            let type_matches =
+                query.file_types.is_empty() || query.file_types.contains(&file.file_type);
+            let subject_matches = query
+                .subject_id
+                .as_ref()
+                .is_none_or(|s| file.subject_id.as_ref() == Some(s));
+            if type_matches && subject_matches {
+                hits.push(SearchHit { file, score });
+            }
+        }
+
+        hits.sort_by(|a, b| {
+            b.score
+                .partial_cmp(&a.score)
+                .unwrap_or(Ordering::Equal)
+                .then_with(|| created_key(&b.file).cmp(&created_key(&a.file)))
+                .then_with(|| a.file.id.cmp(&b.file.id))
+        });
+        let total = hits.len() as u64;
+        let hits = hits.into_iter().skip(offset).take(limit).collect();
+
+        Ok(SearchPage { hits, total })
+    }
+}
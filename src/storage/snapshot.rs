@@ -0,0 +1,136 @@
+//! File records and logs in snapshots. A snapshot being taken reads them from
+//! a read transaction while it is written out, so the leader never holds a
+//! whole catalog or log in memory. Muster hands `restore` the snapshot already
+//! decoded, so a follower does hold what it received until it is installed.
+
+use std::fmt;
+use std::sync::Arc;
+
+use redb::{Key, ReadTransaction, ReadableTable, ReadableTableMetadata, TableDefinition};
+use serde::de::{DeserializeOwned, Deserializer};
+use serde::ser::{Error as _, SerializeSeq, Serializer};
+use serde::{Deserialize, Serialize};
+
+use super::audit::AuditEntry;
+use super::changes::Change;
+use super::db::DatabaseError;
+use super::models::FileRecord;
+use super::tables::*;
+
+/// A record stored msgpack-encoded in a table of its own
+pub trait StoredRecord: Serialize + DeserializeOwned {
+    type Key: Key + 'static;
+
+    /// The table holding every record
+    const TABLE: TableDefinition<'static, Self::Key, &'static [u8]>;
+}
+
+impl StoredRecord for FileRecord {
+    type Key = &'static str;
+    const TABLE: TableDefinition<'static, &'static str, &'static [u8]> = FILES;
+}
+
+impl StoredRecord for AuditEntry {
+    type Key = u64;
+    const TABLE: TableDefinition<'static, u64, &'static [u8]> = AUDIT_LOG;
+}
+
+impl StoredRecord for Change {
+    type Key = u64;
+    const TABLE: TableDefinition<'static, u64, &'static [u8]> = CHANGE_LOG;
+}
+
+/// The records of one table in a snapshot
+pub enum SnapshotRecords<T> {
+    /// Read from the table as the snapshot is written out
+    Stored(Arc<ReadTransaction>),
+    /// Read back from a written snapshot
+    Loaded(Vec<T>),
+}
+
+impl<T: StoredRecord> SnapshotRecords<T> {
+    /// Visit every record in turn, without collecting stored ones
+    pub fn try_for_each<F>(self, mut visit: F) -> Result<(), DatabaseError>
+    where
+        F: FnMut(T) -> Result<(), DatabaseError>,
+    {
+        match self {
+            Self::Stored(read_txn) => {
+                for entry in read_txn.open_table(T::TABLE)?.iter()? {
+                    let (_, data) = entry?;
+                    visit(rmp_serde::from_slice(data.value())?)?;
+                }
+                Ok(())
+            }
+            Self::Loaded(records) => records.into_iter().try_for_each(visit),
+        }
+    }
+}
+
+impl<T> Default for SnapshotRecords<T> {
+    fn default() -> Self {
+        Self::Loaded(Vec::new())
+    }
+}
+
+impl<T> From<Vec<T>> for SnapshotRecords<T> {
+    fn from(records: Vec<T>) -> Self {
+        Self::Loaded(records)
+    }
+}
+
+impl<T> fmt::Debug for SnapshotRecords<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Stored(_) => f.write_str("Stored"),
+            Self::Loaded(records) => write!(f, "Loaded({} records)", records.len()),
+        }
+    }
+}
+
+impl<T: StoredRecord> Serialize for SnapshotRecords<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let read_txn = match self {
+            Self::Stored(read_txn) => read_txn,
+            Self::Loaded(records) => return records.serialize(serializer),
+        };
+        let table = read_txn.open_table(T::TABLE).map_err(S::Error::custom)?;
+        let len = table.len().map_err(S::Error::custom)?;
+        let mut seq = serializer.serialize_seq(Some(len as usize))?;
+        for entry in table.iter().map_err(S::Error::custom)? {
+            let (_, data) = entry.map_err(S::Error::custom)?;
+            let record: T = rmp_serde::from_slice(data.value()).map_err(S::Error::custom)?;
+            seq.serialize_element(&record)?;
+        }
+        seq.end()
+    }
+}
+
+impl<'de, T: DeserializeOwned> Deserialize<'de> for SnapshotRecords<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::deserialize(deserializer).map(Self::Loaded)
+    }
+}
+
+/// Read the file records of a snapshot, listed one by one or, as some earlier
+/// versions wrote them, in chunks.
+pub fn deserialize_files<'de, D>(deserializer: D) -> Result<SnapshotRecords<FileRecord>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Item {
+        File(Box<FileRecord>),
+        Chunk(Vec<FileRecord>),
+    }
+
+    let mut files = Vec::new();
+    for item in Vec::<Item>::deserialize(deserializer)? {
+        match item {
+            Item::File(file) => files.push(*file),
+            Item::Chunk(chunk) => files.extend(chunk),
+        }
+    }
+    Ok(SnapshotRecords::Loaded(files))
+}
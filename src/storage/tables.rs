@@ -1,10 +1,116 @@
+//! Table definitions. Permalinks and index values are scoped by namespace:
+//! string keys below marked "scoped" are `"<namespace>\0<value>"`.
+
 use redb::TableDefinition;
 
 /// File records: uuid -> FileRecord (msgpack)
 pub const FILES: TableDefinition<&str, &[u8]> = TableDefinition::new("files");
 
-/// Permalink index: permalink -> uuid (for /static/ route lookups)
+/// Permalink index: scoped permalink -> uuid (for /static/ route lookups)
 pub const FILE_PERMALINKS: TableDefinition<&str, &str> = TableDefinition::new("file_permalinks");
 
-/// Subject index: subject_id -> msgpack Vec of file UUIDs
-pub const SUBJECT_FILES: TableDefinition<&str, &[u8]> = TableDefinition::new("subject_files");
+/// Redirects from former permalinks: scoped old permalink -> uuid
+pub const PERMALINK_REDIRECTS: TableDefinition<&str, &str> =
+    TableDefinition::new("permalink_redirects");
+
+/// Redirects by file: (uuid, scoped old permalink) -> ()
+pub const FILE_REDIRECTS: TableDefinition<(&str, &str), ()> =
+    TableDefinition::new("file_redirects");
+
+/// Namespace index, in creation order: (namespace, created_at micros, uuid) -> ()
+pub const NAMESPACE_FILES: TableDefinition<(&str, i64, &str), ()> =
+    TableDefinition::new("namespace_files_by_created");
+
+/// Subject index: (scoped subject_id, created_at micros, uuid) -> ()
+pub const SUBJECT_FILES: TableDefinition<(&str, i64, &str), ()> =
+    TableDefinition::new("subject_files_by_created");
+
+/// Trash index: (deleted_at micros, uuid) -> ()
+///
+/// Trashed files keep their permalink but are left out of every other index.
+pub const TRASHED_FILES: TableDefinition<(i64, &str), ()> =
+    TableDefinition::new("trashed_files_by_deleted");
+
+/// Trash index by namespace: (namespace, deleted_at micros, uuid) -> ()
+pub const TRASHED_NAMESPACE_FILES: TableDefinition<(&str, i64, &str), ()> =
+    TableDefinition::new("trashed_namespace_files_by_deleted");
+
+/// Trash index by subject: (scoped subject_id, deleted_at micros, uuid) -> ()
+pub const TRASHED_SUBJECT_FILES: TableDefinition<(&str, i64, &str), ()> =
+    TableDefinition::new("trashed_subject_files_by_deleted");
+
+/// Expiry index: (expires_at micros, uuid) -> ()
+pub const EXPIRING_FILES: TableDefinition<(i64, &str), ()> =
+    TableDefinition::new("expiring_files_by_expiry");
+
+/// File type index: (scoped file_type, created_at micros, uuid) -> ()
+pub const FILE_TYPE_FILES: TableDefinition<(&str, i64, &str), ()> =
+    TableDefinition::new("file_type_files_by_created");
+
+/// MIME type index: (scoped mime_type, created_at micros, uuid) -> ()
+pub const MIME_TYPE_FILES: TableDefinition<(&str, i64, &str), ()> =
+    TableDefinition::new("mime_type_files_by_created");
+
+/// Tag index: (scoped tag, created_at micros, uuid) -> ()
+pub const TAG_FILES: TableDefinition<(&str, i64, &str), ()> =
+    TableDefinition::new("tag_files_by_created");
+
+/// Metadata index for configured keys: (scoped entry, created_at micros, uuid) -> ()
+///
+/// Each file has a `"<key>"` entry for every indexed key it has, plus a
+/// `"<key>\0<value>"` entry when the value is a string, number or boolean.
+pub const METADATA_FILES: TableDefinition<(&str, i64, &str), ()> =
+    TableDefinition::new("metadata_files_by_created");
+
+/// Metadata keys covered by `METADATA_FILES`: key -> ()
+pub const INDEXED_METADATA_KEYS: TableDefinition<&str, ()> =
+    TableDefinition::new("indexed_metadata_keys");
+
+/// Full-text index: (scoped term, uuid) -> field-weighted term frequency
+pub const SEARCH_TERMS: TableDefinition<(&str, &str), u32> = TableDefinition::new("search_terms");
+
+/// Index entry counts: "<index>:<scoped value>" -> number of files, and
+/// "namespace:<namespace>" -> number of live files in the namespace
+pub const INDEX_COUNTS: TableDefinition<&str, u64> = TableDefinition::new("index_counts");
+
+/// Audit log, in apply order: sequence number -> AuditEntry (msgpack)
+pub const AUDIT_LOG: TableDefinition<u64, &[u8]> = TableDefinition::new("audit_log");
+
+/// Audit log by file: (uuid, sequence number) -> ()
+pub const FILE_AUDIT: TableDefinition<(&str, u64), ()> = TableDefinition::new("audit_log_by_file");
+
+/// Change feed, in apply order: sequence number -> Change (msgpack)
+pub const CHANGE_LOG: TableDefinition<u64, &[u8]> = TableDefinition::new("change_log");
+
+/// Change feed by file: (uuid, sequence number) -> ()
+pub const FILE_CHANGES: TableDefinition<(&str, u64), ()> =
+    TableDefinition::new("change_log_by_file");
+
+/// Webhook subscriptions: id -> Webhook (msgpack)
+pub const WEBHOOKS: TableDefinition<&str, &[u8]> = TableDefinition::new("webhooks");
+
+/// Webhook deliveries waiting to be sent: delivery id -> WebhookDelivery (msgpack)
+pub const WEBHOOK_OUTBOX: TableDefinition<u64, &[u8]> = TableDefinition::new("webhook_outbox");
+
+/// Webhook outbox by webhook, in delivery order: (webhook id, delivery id) -> ()
+pub const WEBHOOK_QUEUES: TableDefinition<(&str, u64), ()> =
+    TableDefinition::new("webhook_outbox_by_webhook");
+
+/// Webhook deliveries that ran out of attempts: delivery id -> WebhookDelivery (msgpack)
+pub const WEBHOOK_DEAD_LETTERS: TableDefinition<u64, &[u8]> =
+    TableDefinition::new("webhook_dead_letters");
+
+/// Responses to requests made with an idempotency key:
+/// (namespace, key) -> IdempotencyRecord (msgpack)
+pub const IDEMPOTENCY_KEYS: TableDefinition<(&str, &str), &[u8]> =
+    TableDefinition::new("idempotency_keys");
+
+/// Right-to-erasure requests and their reports: id -> Erasure (msgpack)
+pub const ERASURES: TableDefinition<&str, &[u8]> = TableDefinition::new("erasures");
+
+/// Erasures not yet complete, by subject: (scoped subject_id, id) -> ()
+pub const OPEN_ERASURES: TableDefinition<(&str, &str), ()> =
+    TableDefinition::new("open_erasures_by_subject");
+
+/// Database bookkeeping: key -> value (e.g. index schema version)
+pub const META: TableDefinition<&str, u64> = TableDefinition::new("meta");
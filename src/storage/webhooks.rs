@@ -0,0 +1,472 @@
+//! Webhook subscriptions and their delivery outbox. Deliveries are queued by
+//! the state machine as changes are applied, so every node holds the same
+//! outbox and a new leader carries on sending where the old one stopped.
+//! Like redirects they can't be rebuilt from `FILES`, so they are part of
+//! snapshots.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use redb::{ReadableTable, ReadableTableMetadata, TableDefinition, WriteTransaction};
+use serde::{Deserialize, Serialize};
+
+use super::audit::FieldChange;
+use super::db::{Database, DatabaseError, Transaction};
+use super::models::{FileRecord, Webhook, WebhookEvent};
+use super::tables::*;
+
+/// `META` key holding the last assigned delivery id
+const WEBHOOK_DELIVERY_SEQ: &str = "webhook_delivery_seq";
+
+/// One event to send to one webhook
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WebhookDelivery {
+    pub id: u64,
+    pub webhook_id: String,
+    pub event: WebhookEvent,
+    /// JSON body, signed and sent as is
+    pub payload: String,
+    pub created_at: DateTime<Utc>,
+    /// Failed attempts so far
+    pub attempts: u32,
+    /// When the next attempt is due. Meaningless once dead-lettered.
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_attempt_at: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+}
+
+/// Body of a webhook delivery
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookPayload {
+    /// Changed file record fields
+    pub changes: BTreeMap<String, FieldChange>,
+    pub event: WebhookEvent,
+    /// The file record after the change, or before it for deletions
+    pub file: Option<FileRecord>,
+    pub namespace: String,
+    /// Sequence number of the change in the change feed
+    pub seq: u64,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Every webhook table, for snapshots
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WebhookState {
+    pub webhooks: Vec<Webhook>,
+    pub outbox: Vec<WebhookDelivery>,
+    pub dead_letters: Vec<WebhookDelivery>,
+    /// Last assigned delivery id
+    pub delivery_seq: u64,
+}
+
+fn put_delivery(
+    write_txn: &WriteTransaction,
+    table: TableDefinition<u64, &[u8]>,
+    delivery: &WebhookDelivery,
+) -> Result<(), DatabaseError> {
+    let data = rmp_serde::to_vec_named(delivery)?;
+    write_txn
+        .open_table(table)?
+        .insert(delivery.id, data.as_slice())?;
+    Ok(())
+}
+
+fn take_delivery(
+    write_txn: &WriteTransaction,
+    table: TableDefinition<u64, &[u8]>,
+    id: u64,
+) -> Result<Option<WebhookDelivery>, DatabaseError> {
+    let mut table = write_txn.open_table(table)?;
+    let delivery = match table.remove(id)? {
+        Some(data) => Some(rmp_serde::from_slice(data.value())?),
+        None => None,
+    };
+    Ok(delivery)
+}
+
+/// Add a delivery to the outbox, at the end of its webhook's queue
+fn queue_delivery(
+    write_txn: &WriteTransaction,
+    delivery: &WebhookDelivery,
+) -> Result<(), DatabaseError> {
+    put_delivery(write_txn, WEBHOOK_OUTBOX, delivery)?;
+    write_txn
+        .open_table(WEBHOOK_QUEUES)?
+        .insert((delivery.webhook_id.as_str(), delivery.id), ())?;
+    Ok(())
+}
+
+/// Take a delivery out of the outbox and its webhook's queue
+fn unqueue_delivery(
+    write_txn: &WriteTransaction,
+    id: u64,
+) -> Result<Option<WebhookDelivery>, DatabaseError> {
+    let delivery = take_delivery(write_txn, WEBHOOK_OUTBOX, id)?;
+    if let Some(delivery) = &delivery {
+        write_txn
+            .open_table(WEBHOOK_QUEUES)?
+            .remove((delivery.webhook_id.as_str(), id))?;
+    }
+    Ok(delivery)
+}
+
+/// Build the webhook queues of an outbox written before they existed.
+pub(super) fn migrate_queues(write_txn: &WriteTransaction) -> Result<(), DatabaseError> {
+    let mut queues = write_txn.open_table(WEBHOOK_QUEUES)?;
+    if !queues.is_empty()? {
+        return Ok(());
+    }
+    for entry in write_txn.open_table(WEBHOOK_OUTBOX)?.iter()? {
+        let (id, data) = entry?;
+        let delivery: WebhookDelivery = rmp_serde::from_slice(data.value())?;
+        queues.insert((delivery.webhook_id.as_str(), id.value()), ())?;
+    }
+    Ok(())
+}
+
+impl Database {
+    /// Create or replace a webhook
+    pub fn put_webhook(&self, webhook: &Webhook) -> Result<(), DatabaseError> {
+        self.write(|txn| txn.put_webhook(webhook))
+    }
+
+    pub fn get_webhook(&self, id: &str) -> Result<Option<Webhook>, DatabaseError> {
+        let read_txn = self.begin_read()?;
+        let table = read_txn.open_table(WEBHOOKS)?;
+        let webhook = match table.get(id)? {
+            Some(data) => Some(rmp_serde::from_slice(data.value())?),
+            None => None,
+        };
+        Ok(webhook)
+    }
+
+    /// List a namespace's webhooks, oldest first
+    pub fn list_webhooks(&self, namespace: &str) -> Result<Vec<Webhook>, DatabaseError> {
+        let read_txn = self.begin_read()?;
+        let table = read_txn.open_table(WEBHOOKS)?;
+        let mut webhooks = Vec::new();
+        for entry in table.iter()? {
+            let (_, data) = entry?;
+            let webhook: Webhook = rmp_serde::from_slice(data.value())?;
+            if webhook.namespace == namespace {
+                webhooks.push(webhook);
+            }
+        }
+        webhooks.sort_by(|a, b| a.created_at.cmp(&b.created_at).then(a.id.cmp(&b.id)));
+        Ok(webhooks)
+    }
+
+    /// Delete a webhook and its deliveries. Returns whether it existed.
+    pub fn delete_webhook(&self, id: &str) -> Result<bool, DatabaseError> {
+        self.write(|txn| txn.delete_webhook(id))
+    }
+
+    /// Queue a delivery of an event to every webhook of the namespace
+    /// subscribed to it. Returns the number queued.
+    pub fn enqueue_webhook_deliveries(
+        &self,
+        namespace: &str,
+        event: WebhookEvent,
+        payload: &str,
+        created_at: DateTime<Utc>,
+    ) -> Result<u64, DatabaseError> {
+        self.write(|txn| txn.enqueue_webhook_deliveries(namespace, event, payload, created_at))
+    }
+
+    /// Deliveries due for an attempt, by webhook. Each webhook's come from
+    /// the head of its queue in order, up to `limit` and stopping at the
+    /// first that isn't due, so a webhook waiting to retry its oldest
+    /// delivery gets none.
+    pub fn due_webhook_deliveries(
+        &self,
+        now: DateTime<Utc>,
+        limit: usize,
+    ) -> Result<Vec<Vec<WebhookDelivery>>, DatabaseError> {
+        let read_txn = self.begin_read()?;
+        let webhooks = read_txn.open_table(WEBHOOKS)?;
+        let queues = read_txn.open_table(WEBHOOK_QUEUES)?;
+        let outbox = read_txn.open_table(WEBHOOK_OUTBOX)?;
+        let mut due = Vec::new();
+        for entry in webhooks.iter()? {
+            let (webhook_id, _) = entry?;
+            let webhook_id = webhook_id.value();
+            let mut deliveries = Vec::new();
+            for entry in queues.range((webhook_id, 0)..=(webhook_id, u64::MAX))? {
+                if deliveries.len() >= limit {
+                    break;
+                }
+                let (key, _) = entry?;
+                let Some(data) = outbox.get(key.value().1)? else {
+                    continue;
+                };
+                let delivery: WebhookDelivery = rmp_serde::from_slice(data.value())?;
+                if delivery.next_attempt_at > now {
+                    break;
+                }
+                deliveries.push(delivery);
+            }
+            if !deliveries.is_empty() {
+                due.push(deliveries);
+            }
+        }
+        Ok(due)
+    }
+
+    /// List a webhook's pending or dead-lettered deliveries, newest first
+    pub fn list_webhook_deliveries(
+        &self,
+        webhook_id: &str,
+        dead_letters: bool,
+        limit: usize,
+    ) -> Result<Vec<WebhookDelivery>, DatabaseError> {
+        let read_txn = self.begin_read()?;
+        let table = read_txn.open_table(if dead_letters {
+            WEBHOOK_DEAD_LETTERS
+        } else {
+            WEBHOOK_OUTBOX
+        })?;
+        let mut deliveries = Vec::new();
+        for entry in table.iter()?.rev() {
+            if deliveries.len() >= limit {
+                break;
+            }
+            let (_, data) = entry?;
+            let delivery: WebhookDelivery = rmp_serde::from_slice(data.value())?;
+            if delivery.webhook_id == webhook_id {
+                deliveries.push(delivery);
+            }
+        }
+        Ok(deliveries)
+    }
+
+    /// Get a delivery and whether it is dead-lettered
+    pub fn get_webhook_delivery(
+        &self,
+        id: u64,
+    ) -> Result<Option<(WebhookDelivery, bool)>, DatabaseError> {
+        let read_txn = self.begin_read()?;
+        for (table, dead) in [(WEBHOOK_OUTBOX, false), (WEBHOOK_DEAD_LETTERS, true)] {
+            if let Some(data) = read_txn.open_table(table)?.get(id)? {
+                return Ok(Some((rmp_serde::from_slice(data.value())?, dead)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Remove a delivered delivery from the outbox
+    pub fn complete_webhook_delivery(&self, id: u64) -> Result<(), DatabaseError> {
+        self.write(|txn| txn.complete_webhook_delivery(id))
+    }
+
+    /// Record a failed attempt, keeping the delivery in the outbox until
+    /// `retry_at`, or dead-lettering it without one
+    pub fn fail_webhook_delivery(
+        &self,
+        id: u64,
+        attempted_at: DateTime<Utc>,
+        error: &str,
+        retry_at: Option<DateTime<Utc>>,
+    ) -> Result<(), DatabaseError> {
+        self.write(|txn| txn.fail_webhook_delivery(id, attempted_at, error, retry_at))
+    }
+
+    /// Schedule a delivery for another attempt at a time. Dead-lettered
+    /// deliveries go back to the outbox with their attempts reset. Returns
+    /// whether the delivery exists.
+    pub fn redeliver_webhook_delivery(
+        &self,
+        id: u64,
+        at: DateTime<Utc>,
+    ) -> Result<bool, DatabaseError> {
+        self.write(|txn| txn.redeliver_webhook_delivery(id, at))
+    }
+
+    /// Get every webhook table, for snapshots
+    pub fn get_webhook_state(&self) -> Result<WebhookState, DatabaseError> {
+        let read_txn = self.begin_read()?;
+        let mut state = WebhookState::default();
+        for entry in read_txn.open_table(WEBHOOKS)?.iter()? {
+            let (_, data) = entry?;
+            state.webhooks.push(rmp_serde::from_slice(data.value())?);
+        }
+        for entry in read_txn.open_table(WEBHOOK_OUTBOX)?.iter()? {
+            let (_, data) = entry?;
+            state.outbox.push(rmp_serde::from_slice(data.value())?);
+        }
+        for entry in read_txn.open_table(WEBHOOK_DEAD_LETTERS)?.iter()? {
+            let (_, data) = entry?;
+            state
+                .dead_letters
+                .push(rmp_serde::from_slice(data.value())?);
+        }
+        state.delivery_seq = read_txn
+            .open_table(META)?
+            .get(WEBHOOK_DELIVERY_SEQ)?
+            .map(|v| v.value())
+            .unwrap_or(0);
+        Ok(state)
+    }
+
+    /// Replace every webhook table with ones from a snapshot
+    pub fn replace_webhook_state(&self, state: &WebhookState) -> Result<(), DatabaseError> {
+        self.write(|txn| txn.replace_webhook_state(state))
+    }
+}
+
+impl Transaction {
+    /// Replace every webhook table with ones from a snapshot
+    pub fn replace_webhook_state(&self, state: &WebhookState) -> Result<(), DatabaseError> {
+        let write_txn = &self.write_txn;
+        {
+            let mut webhooks = write_txn.open_table(WEBHOOKS)?;
+            webhooks.retain(|_, _| false)?;
+            for webhook in &state.webhooks {
+                let data = rmp_serde::to_vec_named(webhook)?;
+                webhooks.insert(webhook.id.as_str(), data.as_slice())?;
+            }
+        }
+        write_txn.open_table(WEBHOOK_OUTBOX)?.retain(|_, _| false)?;
+        write_txn.open_table(WEBHOOK_QUEUES)?.retain(|_, _| false)?;
+        write_txn
+            .open_table(WEBHOOK_DEAD_LETTERS)?
+            .retain(|_, _| false)?;
+        for delivery in &state.outbox {
+            queue_delivery(write_txn, delivery)?;
+        }
+        for delivery in &state.dead_letters {
+            put_delivery(write_txn, WEBHOOK_DEAD_LETTERS, delivery)?;
+        }
+        write_txn
+            .open_table(META)?
+            .insert(WEBHOOK_DELIVERY_SEQ, state.delivery_seq)?;
+        Ok(())
+    }
+
+    /// Create or replace a webhook
+    pub fn put_webhook(&self, webhook: &Webhook) -> Result<(), DatabaseError> {
+        let data = rmp_serde::to_vec_named(webhook)?;
+        let write_txn = &self.write_txn;
+        write_txn
+            .open_table(WEBHOOKS)?
+            .insert(webhook.id.as_str(), data.as_slice())?;
+        Ok(())
+    }
+
+    /// Delete a webhook and its deliveries. Returns whether it existed.
+    pub fn delete_webhook(&self, id: &str) -> Result<bool, DatabaseError> {
+        let write_txn = &self.write_txn;
+        let existed = write_txn.open_table(WEBHOOKS)?.remove(id)?.is_some();
+        for table in [WEBHOOK_OUTBOX, WEBHOOK_DEAD_LETTERS] {
+            write_txn.open_table(table)?.retain(|_, data| {
+                rmp_serde::from_slice::<WebhookDelivery>(data).is_ok_and(|d| d.webhook_id != id)
+            })?;
+        }
+        write_txn
+            .open_table(WEBHOOK_QUEUES)?
+            .retain_in((id, 0)..=(id, u64::MAX), |_, _| false)?;
+        Ok(existed)
+    }
+
+    /// Queue a delivery of an event to every webhook of the namespace
+    /// subscribed to it. Returns the number queued.
+    pub fn enqueue_webhook_deliveries(
+        &self,
+        namespace: &str,
+        event: WebhookEvent,
+        payload: &str,
+        created_at: DateTime<Utc>,
+    ) -> Result<u64, DatabaseError> {
+        let write_txn = &self.write_txn;
+        let mut subscribers = Vec::new();
+        for entry in write_txn.open_table(WEBHOOKS)?.iter()? {
+            let (_, data) = entry?;
+            let webhook: Webhook = rmp_serde::from_slice(data.value())?;
+            if webhook.namespace == namespace && webhook.events.contains(&event) {
+                subscribers.push(webhook);
+            }
+        }
+        if subscribers.is_empty() {
+            return Ok(0);
+        }
+
+        let mut seq = write_txn
+            .open_table(META)?
+            .get(WEBHOOK_DELIVERY_SEQ)?
+            .map(|v| v.value())
+            .unwrap_or(0);
+        for webhook in &subscribers {
+            seq += 1;
+            let delivery = WebhookDelivery {
+                id: seq,
+                webhook_id: webhook.id.clone(),
+                event,
+                payload: payload.to_string(),
+                created_at,
+                attempts: 0,
+                next_attempt_at: created_at,
+                last_attempt_at: None,
+                last_error: None,
+            };
+            queue_delivery(write_txn, &delivery)?;
+        }
+        write_txn
+            .open_table(META)?
+            .insert(WEBHOOK_DELIVERY_SEQ, seq)?;
+        Ok(subscribers.len() as u64)
+    }
+
+    /// Remove a delivered delivery from the outbox
+    pub fn complete_webhook_delivery(&self, id: u64) -> Result<(), DatabaseError> {
+        unqueue_delivery(&self.write_txn, id)?;
+        Ok(())
+    }
+
+    /// Record a failed attempt, keeping the delivery in the outbox until
+    /// `retry_at`, or dead-lettering it without one
+    pub fn fail_webhook_delivery(
+        &self,
+        id: u64,
+        attempted_at: DateTime<Utc>,
+        error: &str,
+        retry_at: Option<DateTime<Utc>>,
+    ) -> Result<(), DatabaseError> {
+        let write_txn = &self.write_txn;
+        if let Some(mut delivery) = unqueue_delivery(write_txn, id)? {
+            delivery.attempts += 1;
+            delivery.last_attempt_at = Some(attempted_at);
+            delivery.last_error = Some(error.to_string());
+            match retry_at {
+                Some(at) => {
+                    delivery.next_attempt_at = at;
+                    queue_delivery(write_txn, &delivery)?;
+                }
+                None => put_delivery(write_txn, WEBHOOK_DEAD_LETTERS, &delivery)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Schedule a delivery for another attempt at a time. Dead-lettered
+    /// deliveries go back to the outbox with their attempts reset. Returns
+    /// whether the delivery exists.
+    pub fn redeliver_webhook_delivery(
+        &self,
+        id: u64,
+        at: DateTime<Utc>,
+    ) -> Result<bool, DatabaseError> {
+        let write_txn = &self.write_txn;
+        let delivery = match take_delivery(write_txn, WEBHOOK_DEAD_LETTERS, id)? {
+            Some(mut delivery) => {
+                delivery.attempts = 0;
+                Some(delivery)
+            }
+            None => unqueue_delivery(write_txn, id)?,
+        };
+        let found = delivery.is_some();
+        if let Some(mut delivery) = delivery {
+            delivery.next_attempt_at = at;
+            queue_delivery(write_txn, &delivery)?;
+        }
+        Ok(found)
+    }
+}
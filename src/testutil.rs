@@ -1,10 +1,14 @@
 //! Shared test helpers for file-manager integration tests.
 
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 
-use crate::config::{ClusterConfig, Config, NodeConfig, StorageConfig};
+use chrono::Utc;
+
+use crate::config::{ClusterConfig, Config, NamespaceConfig, NodeConfig, StorageConfig};
 use crate::object_store::LocalStore;
 use crate::state_machine::FileStateMachine;
+use crate::storage::models::{FileRecord, FileType, Visibility, DEFAULT_NAMESPACE};
 use crate::storage::Database;
 use crate::AppState;
 
@@ -14,15 +18,30 @@ pub fn test_state(temp_dir: &tempfile::TempDir) -> Arc<AppState> {
     let files_dir = temp_dir.path().join("files");
 
     let config = Config {
+        admin_token: None,
+        audit_retention_days: 365,
+        change_retention_days: 7,
         node: NodeConfig {
             id: uuid::Uuid::new_v4().to_string(),
             bind_address: "127.0.0.1:0".to_string(),
             data_dir: data_dir.to_string_lossy().to_string(),
         },
         cluster: ClusterConfig::default(),
+        erasure_signing_key: None,
+        idempotency_ttl_hours: 24,
+        indexed_metadata_keys: Vec::new(),
+        namespaces: vec![NamespaceConfig {
+            name: DEFAULT_NAMESPACE.to_string(),
+            allowed_mime_types: Vec::new(),
+            default_visibility: Visibility::Public,
+            max_upload_size: 10 * 1024 * 1024,
+            storage: None,
+        }],
         storage: StorageConfig::default(),
         test_mode: true,
+        max_import_size: 100 * 1024 * 1024,
         max_upload_size: 10 * 1024 * 1024, // 10MB for tests
+        trash_retention_days: 30,
     };
 
     let db = Database::open(&data_dir).expect("Failed to open test database");
@@ -31,6 +50,7 @@ pub fn test_state(temp_dir: &tempfile::TempDir) -> Arc<AppState> {
     let muster_storage =
         muster::RedbStorage::new(db.inner()).expect("Failed to create muster storage");
     let state_machine = FileStateMachine::new(db.clone());
+    let changes = state_machine.subscribe();
     let muster_config = muster::Config {
         node_id: config.node.id.clone(),
         cluster_port: 0,
@@ -50,5 +70,36 @@ pub fn test_state(temp_dir: &tempfile::TempDir) -> Arc<AppState> {
         db,
         node: Arc::clone(&node),
         object_store: Arc::new(object_store),
+        namespace_stores: HashMap::new(),
+        changes,
+        idempotency_in_flight: Mutex::default(),
     })
 }
+
+/// A live, unlocked file in the default namespace.
+pub fn sample_file(id: &str) -> FileRecord {
+    let now = Utc::now();
+    FileRecord {
+        id: id.to_string(),
+        namespace: DEFAULT_NAMESPACE.to_string(),
+        mime_type: "application/octet-stream".to_string(),
+        file_type: FileType::Binary,
+        byte_size: 1024,
+        permalink: format!("files/{id}"),
+        created_at: now,
+        updated_at: now,
+        revision: 0,
+        alt: None,
+        description: None,
+        expires_at: None,
+        metadata: None,
+        name: None,
+        subject_id: None,
+        tags: Vec::new(),
+        visibility: Visibility::Public,
+        versions: Vec::new(),
+        deleted_at: None,
+        legal_hold: false,
+        retain_until: None,
+    }
+}
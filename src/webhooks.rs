@@ -0,0 +1,211 @@
+//! Background task that sends webhook deliveries from the outbox. Runs on
+//! every node but only acts on the leader, replicating the outcome of each
+//! attempt so the outbox stays the same everywhere. Failed deliveries are
+//! retried with exponential backoff and dead-lettered after `MAX_ATTEMPTS`.
+//!
+//! Deliveries are POSTed as JSON with an `X-Webhook-Signature` header of
+//! `sha256=<hex HMAC-SHA256>` over `<X-Webhook-Timestamp>.<body>`, keyed with
+//! the webhook's secret.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt;
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::storage::models::{Webhook, WriteOp};
+use crate::storage::webhooks::WebhookDelivery;
+use crate::AppState;
+
+/// How often to check for deliveries due for a retry. New deliveries are sent
+/// as soon as they are queued.
+pub const DISPATCH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Attempts before a delivery is dead-lettered
+pub const MAX_ATTEMPTS: u32 = 8;
+
+/// Delay before the first retry, doubling with every failed attempt
+const INITIAL_BACKOFF: chrono::Duration = chrono::Duration::seconds(30);
+
+/// Longest delay between two attempts
+const MAX_BACKOFF: chrono::Duration = chrono::Duration::hours(6);
+
+/// How long a receiver has to answer
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum number of deliveries sent to each webhook per check
+const BATCH_SIZE: usize = 100;
+
+/// Webhooks sent to at the same time
+const CONCURRENT_WEBHOOKS: usize = 8;
+
+pub const EVENT_HEADER: &str = "x-webhook-event";
+pub const DELIVERY_HEADER: &str = "x-webhook-delivery";
+pub const TIMESTAMP_HEADER: &str = "x-webhook-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-webhook-signature";
+
+/// Run the dispatcher until the task is aborted.
+pub async fn run(state: Arc<AppState>) {
+    let client = match http_client() {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to create webhook HTTP client");
+            return;
+        }
+    };
+    let mut changes = state.changes.clone();
+    let mut interval = tokio::time::interval(DISPATCH_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            Ok(()) = changes.changed() => {}
+        }
+        if !state.is_leader().await {
+            continue;
+        }
+        match dispatch(&state, &client).await {
+            Ok(0) => {}
+            Ok(count) => tracing::debug!(deliveries = count, "Delivered webhooks"),
+            Err(e) => tracing::warn!(error = %e, "Failed to dispatch webhooks"),
+        }
+    }
+}
+
+/// HTTP client for sending deliveries
+pub fn http_client() -> reqwest::Result<reqwest::Client> {
+    reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build()
+}
+
+/// Attempt one batch of due deliveries. Returns the number delivered.
+///
+/// Webhooks are sent to concurrently, so a slow or unreachable receiver only
+/// holds up its own deliveries. Each webhook's deliveries go out in order: a
+/// failed one is retried before any later one is sent.
+pub async fn dispatch(state: &AppState, client: &reqwest::Client) -> anyhow::Result<u64> {
+    let due = state.db.due_webhook_deliveries(Utc::now(), BATCH_SIZE)?;
+    let mut delivered = 0;
+    let mut results = futures_util::stream::iter(due)
+        .map(|deliveries| dispatch_webhook(state, client, deliveries))
+        .buffer_unordered(CONCURRENT_WEBHOOKS);
+    while let Some(result) = results.next().await {
+        delivered += result?;
+    }
+    Ok(delivered)
+}
+
+/// Attempt one webhook's deliveries in order, stopping at the first failure.
+async fn dispatch_webhook(
+    state: &AppState,
+    client: &reqwest::Client,
+    deliveries: Vec<WebhookDelivery>,
+) -> anyhow::Result<u64> {
+    let Some(first) = deliveries.first() else {
+        return Ok(0);
+    };
+    // Deliveries are deleted with their webhook
+    let Some(webhook) = state.db.get_webhook(&first.webhook_id)? else {
+        return Ok(0);
+    };
+    let mut delivered = 0;
+    for delivery in deliveries {
+        match send(client, &webhook, &delivery).await {
+            Ok(()) => {
+                delivered += 1;
+                state
+                    .node
+                    .replicate(WriteOp::WebhookDelivered {
+                        delivery_id: delivery.id,
+                    })
+                    .await?;
+            }
+            Err(error) => {
+                let attempted_at = Utc::now();
+                tracing::debug!(
+                    delivery_id = delivery.id,
+                    webhook_id = %webhook.id,
+                    error,
+                    "Webhook delivery failed"
+                );
+                state
+                    .node
+                    .replicate(WriteOp::WebhookFailed {
+                        delivery_id: delivery.id,
+                        attempted_at,
+                        error,
+                        retry_at: retry_at(delivery.attempts + 1, attempted_at),
+                    })
+                    .await?;
+                break;
+            }
+        }
+    }
+    Ok(delivered)
+}
+
+/// When to retry a delivery after its `attempts`th failed attempt, or `None`
+/// to dead-letter it.
+pub fn retry_at(attempts: u32, attempted_at: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    if attempts >= MAX_ATTEMPTS {
+        return None;
+    }
+    let backoff = INITIAL_BACKOFF
+        .checked_mul(1 << attempts.saturating_sub(1).min(16))
+        .unwrap_or(MAX_BACKOFF)
+        .min(MAX_BACKOFF);
+    Some(attempted_at + backoff)
+}
+
+/// POST a delivery to its webhook. Any 2xx answer counts as delivered.
+pub async fn send(
+    client: &reqwest::Client,
+    webhook: &Webhook,
+    delivery: &WebhookDelivery,
+) -> Result<(), String> {
+    let timestamp = Utc::now().timestamp();
+    let response = client
+        .post(&webhook.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(EVENT_HEADER, delivery.event.as_str())
+        .header(DELIVERY_HEADER, delivery.id)
+        .header(TIMESTAMP_HEADER, timestamp)
+        .header(
+            SIGNATURE_HEADER,
+            sign(&webhook.secret, timestamp, &delivery.payload),
+        )
+        .body(delivery.payload.clone())
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("Receiver answered {}", response.status()))
+    }
+}
+
+/// Signature of a delivery body sent at a Unix timestamp
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let mut context = hmac::Context::with_key(&key);
+    context.update(timestamp.to_string().as_bytes());
+    context.update(b".");
+    context.update(body.as_bytes());
+    format!("sha256={}", hex(context.sign().as_ref()))
+}
+
+/// A random webhook secret
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("system random number generator failed");
+    hex(&bytes)
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
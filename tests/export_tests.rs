@@ -0,0 +1,115 @@
+use async_zip::base::read::mem::ZipFileReader;
+use bytes::Bytes;
+use chrono::Utc;
+use file_manager::api::export::{write_export, MANIFEST_PATH};
+use file_manager::object_store::{LocalStore, ObjectStore};
+use file_manager::storage::models::{FileRecord, FileType, Visibility, DEFAULT_NAMESPACE};
+
+fn sample_file(id: &str, permalink: &str, subject_id: &str) -> FileRecord {
+    let now = Utc::now();
+    FileRecord {
+        id: id.to_string(),
+        namespace: DEFAULT_NAMESPACE.to_string(),
+        mime_type: "text/plain".to_string(),
+        file_type: FileType::Document,
+        byte_size: 5,
+        permalink: permalink.to_string(),
+        created_at: now,
+        updated_at: now,
+        revision: 0,
+        alt: None,
+        description: None,
+        expires_at: None,
+        metadata: None,
+        name: None,
+        subject_id: Some(subject_id.to_string()),
+        tags: Vec::new(),
+        visibility: Visibility::Public,
+        versions: Vec::new(),
+        deleted_at: None,
+        legal_hold: false,
+        retain_until: None,
+    }
+}
+
+#[tokio::test]
+async fn test_export_archive() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = LocalStore::new(dir.path()).unwrap();
+    store.put("a", Bytes::from_static(b"hello")).await.unwrap();
+    let files = [
+        sample_file("a", "notes/a.txt", "user-1"),
+        sample_file("gone", "gone.txt", "user-1"),
+    ];
+
+    let mut archive = Vec::new();
+    write_export(&store, DEFAULT_NAMESPACE, "user-1", &files, &mut archive)
+        .await
+        .unwrap();
+
+    let zip = ZipFileReader::new(archive).await.unwrap();
+    let names: Vec<_> = zip
+        .file()
+        .entries()
+        .iter()
+        .map(|e| e.filename().as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(names, vec!["files/notes/a.txt", MANIFEST_PATH]);
+
+    let mut content = Vec::new();
+    let mut reader = zip.reader_with_entry(0).await.unwrap();
+    reader.read_to_end_checked(&mut content).await.unwrap();
+    assert_eq!(content, b"hello");
+
+    let mut manifest = String::new();
+    let mut reader = zip.reader_with_entry(1).await.unwrap();
+    reader.read_to_string_checked(&mut manifest).await.unwrap();
+    let manifest: serde_json::Value = serde_json::from_str(&manifest).unwrap();
+    assert_eq!(manifest["subject_id"], "user-1");
+    let exported = manifest["files"].as_array().unwrap();
+    assert_eq!(exported.len(), 2);
+    assert_eq!(exported[0]["file"]["id"], "a");
+    assert_eq!(exported[0]["path"], "files/notes/a.txt");
+    // Content missing from object storage is left out
+    assert_eq!(exported[1]["file"]["id"], "gone");
+    assert!(exported[1]["path"].is_null());
+}
+
+#[tokio::test]
+async fn test_export_paths_stay_inside_the_archive() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = LocalStore::new(dir.path()).unwrap();
+    for id in ["a", "b", "c", "d"] {
+        store.put(id, Bytes::from_static(b"hello")).await.unwrap();
+    }
+    let files = [
+        sample_file("a", "../../.bashrc", "user-1"),
+        sample_file("b", "/docs/.\\y.txt", "user-1"),
+        sample_file("c", "..", "user-1"),
+        sample_file("d", "docs/y.txt", "user-1"),
+    ];
+
+    let mut archive = Vec::new();
+    write_export(&store, DEFAULT_NAMESPACE, "user-1", &files, &mut archive)
+        .await
+        .unwrap();
+
+    let zip = ZipFileReader::new(archive).await.unwrap();
+    let names: Vec<_> = zip
+        .file()
+        .entries()
+        .iter()
+        .map(|e| e.filename().as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(
+        names,
+        vec![
+            "files/.bashrc",
+            "files/docs/y.txt",
+            "files/c",
+            // Cleaned permalinks that collide are kept apart by file id
+            "files/d/docs/y.txt",
+            MANIFEST_PATH
+        ]
+    );
+}
@@ -0,0 +1,63 @@
+use axum::body::Bytes;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::request::Parts;
+use axum::http::Request;
+use file_manager::api::idempotency::fingerprint;
+
+fn parts(method: &str, uri: &str, content_type: &str) -> Parts {
+    Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(CONTENT_TYPE, content_type)
+        .body(())
+        .unwrap()
+        .into_parts()
+        .0
+}
+
+fn upload(boundary: &str, permalink: &str) -> (Parts, Bytes) {
+    let body = format!(
+        "--{boundary}\r\n\
+         Content-Disposition: form-data; name=\"permalink\"\r\n\r\n\
+         {permalink}\r\n\
+         --{boundary}\r\n\
+         Content-Disposition: form-data; name=\"file\"; filename=\"logo.png\"\r\n\
+         Content-Type: image/png\r\n\r\n\
+         PNGDATA\r\n\
+         --{boundary}--\r\n"
+    );
+    (
+        parts(
+            "POST",
+            "/files",
+            &format!("multipart/form-data; boundary={boundary}"),
+        ),
+        Bytes::from(body),
+    )
+}
+
+#[tokio::test]
+async fn test_fingerprint_ignores_multipart_boundary() {
+    let (parts_a, body_a) = upload("aaaa1111", "images/logo.png");
+    let (parts_b, body_b) = upload("bbbb2222", "images/logo.png");
+    let (parts_c, body_c) = upload("aaaa1111", "images/other.png");
+
+    let a = fingerprint(&parts_a, &body_a).await;
+    assert_eq!(a, fingerprint(&parts_b, &body_b).await);
+    assert_ne!(a, fingerprint(&parts_c, &body_c).await);
+}
+
+#[tokio::test]
+async fn test_fingerprint_normalizes_json() {
+    let update = parts("PUT", "/files/f1", "application/json");
+    let a = fingerprint(&update, &Bytes::from(r#"{"alt": "A", "name": "N"}"#)).await;
+    let b = fingerprint(&update, &Bytes::from(r#"{"name":"N","alt":"A"}"#)).await;
+    assert_eq!(a, b);
+
+    // The same body sent elsewhere is a different request
+    let other = parts("PUT", "/files/f2", "application/json");
+    assert_ne!(
+        a,
+        fingerprint(&other, &Bytes::from(r#"{"alt": "A", "name": "N"}"#)).await
+    );
+}
@@ -0,0 +1,107 @@
+use async_zip::tokio::write::ZipFileWriter;
+use async_zip::{Compression, ZipEntryBuilder};
+use file_manager::api::import::{entry_permalink, ArchiveEntry, ArchiveReader, EntryContent};
+use tokio_tar::{Builder, EntryType, Header};
+
+async fn read_all(path: &std::path::Path, max_size: u64) -> Vec<ArchiveEntry> {
+    let mut reader = ArchiveReader::open(path).await.unwrap();
+    let mut entries = Vec::new();
+    while let Some(entry) = reader.next_entry(max_size).await.unwrap() {
+        entries.push(entry);
+    }
+    entries
+}
+
+fn tar_header(entry_type: EntryType, size: u64) -> Header {
+    let mut header = Header::new_gnu();
+    header.set_entry_type(entry_type);
+    header.set_mode(0o644);
+    header.set_size(size);
+    header
+}
+
+#[tokio::test]
+async fn test_import_zip() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("upload.zip");
+    let mut archive = Vec::new();
+    let mut zip = ZipFileWriter::with_tokio(&mut archive);
+    for (name, data, compression) in [
+        ("docs/", &b""[..], Compression::Stored),
+        ("docs/a.txt", b"hello", Compression::Deflate),
+        ("big.bin", &[0; 32], Compression::Stored),
+    ] {
+        let entry = ZipEntryBuilder::new(name.to_string().into(), compression);
+        zip.write_entry_whole(entry, data).await.unwrap();
+    }
+    zip.close().await.unwrap();
+    tokio::fs::write(&path, &archive).await.unwrap();
+
+    // Directories are skipped, and large files aren't read
+    let entries = read_all(&path, 16).await;
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].path, "docs/a.txt");
+    assert!(matches!(&entries[0].content, EntryContent::File(data) if data.as_ref() == b"hello"));
+    assert_eq!(entries[1].path, "big.bin");
+    assert!(matches!(entries[1].content, EntryContent::TooLarge(32)));
+}
+
+#[tokio::test]
+async fn test_import_tar() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("upload.tar");
+    let mut tar = Builder::new(Vec::new());
+    let mut header = tar_header(EntryType::Directory, 0);
+    tar.append_data(&mut header, "docs/", &b""[..])
+        .await
+        .unwrap();
+    let mut header = tar_header(EntryType::Regular, 32);
+    tar.append_data(&mut header, "big.bin", &[0; 32][..])
+        .await
+        .unwrap();
+    let mut header = tar_header(EntryType::Symlink, 0);
+    header.set_link_name("docs/a.txt").unwrap();
+    tar.append_data(&mut header, "link.txt", &b""[..])
+        .await
+        .unwrap();
+    let mut header = tar_header(EntryType::Regular, 5);
+    tar.append_data(&mut header, "docs/a.txt", &b"hello"[..])
+        .await
+        .unwrap();
+    let archive = tar.into_inner().await.unwrap();
+    tokio::fs::write(&path, &archive).await.unwrap();
+
+    // The content of a large file is skipped to reach the entries after it
+    let entries = read_all(&path, 16).await;
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries[0].path, "big.bin");
+    assert!(matches!(entries[0].content, EntryContent::TooLarge(32)));
+    assert_eq!(entries[1].path, "link.txt");
+    assert!(matches!(entries[1].content, EntryContent::NotAFile));
+    assert_eq!(entries[2].path, "docs/a.txt");
+    assert!(matches!(&entries[2].content, EntryContent::File(data) if data.as_ref() == b"hello"));
+}
+
+#[tokio::test]
+async fn test_import_rejects_other_formats() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("upload.txt");
+    tokio::fs::write(&path, b"not an archive").await.unwrap();
+
+    assert!(ArchiveReader::open(&path).await.is_err());
+}
+
+#[test]
+fn test_entry_permalink() {
+    assert_eq!(
+        entry_permalink("customers/acme/", "./logos/main.png").unwrap(),
+        "customers/acme/logos/main.png"
+    );
+    assert_eq!(
+        entry_permalink("/customers/acme", "logos\\main.png").unwrap(),
+        "customers/acme/logos/main.png"
+    );
+    assert_eq!(entry_permalink("", "/main.png").unwrap(), "main.png");
+    assert!(entry_permalink("customers", "../main.png").is_err());
+    assert!(entry_permalink("customers", "./").is_err());
+}
@@ -69,3 +69,32 @@ async fn test_local_store_overwrite() {
     let data = store.get("key").await.unwrap();
     assert_eq!(data, Bytes::from("second"));
 }
+
+#[tokio::test]
+async fn test_local_store_list() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = LocalStore::new(dir.path()).unwrap();
+
+    assert!(store.list().await.unwrap().is_empty());
+
+    store.put("a", Bytes::from("1")).await.unwrap();
+    store.put("b", Bytes::from("2")).await.unwrap();
+
+    let mut keys = store.list().await.unwrap();
+    keys.sort();
+    assert_eq!(keys, vec!["a".to_string(), "b".to_string()]);
+}
+
+#[tokio::test]
+async fn test_local_store_nested_keys() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = LocalStore::new(dir.path()).unwrap();
+
+    store.put("top", Bytes::from("a")).await.unwrap();
+    store.put("acme/nested", Bytes::from("b")).await.unwrap();
+    assert_eq!(store.get("acme/nested").await.unwrap(), Bytes::from("b"));
+
+    let mut keys = store.list().await.unwrap();
+    keys.sort();
+    assert_eq!(keys, ["acme/nested", "top"]);
+}
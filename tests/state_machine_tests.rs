@@ -0,0 +1,1201 @@
+use chrono::Utc;
+use file_manager::state_machine::{FileSnapshot, FileStateMachine};
+use file_manager::storage::audit::{AuditEntry, AuditFilter, FieldChange};
+use file_manager::storage::changes::{Change, ChangeKind};
+use file_manager::storage::models::{
+    AuditContext, Erasure, FileRecord, FileType, FileVersion, IdempotencyRecord, Patch, Visibility,
+    Webhook, WebhookEvent, WriteOp, DEFAULT_NAMESPACE,
+};
+use file_manager::storage::snapshot::SnapshotRecords;
+use file_manager::storage::webhooks::{WebhookDelivery, WebhookPayload};
+use file_manager::storage::Database;
+use muster::StateMachine;
+
+fn test_machine() -> (tempfile::TempDir, Database, FileStateMachine) {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("data")).unwrap();
+    let machine = FileStateMachine::new(db.clone());
+    (dir, db, machine)
+}
+
+fn sample_file(id: &str, permalink: &str) -> FileRecord {
+    let now = Utc::now();
+    FileRecord {
+        id: id.to_string(),
+        namespace: DEFAULT_NAMESPACE.to_string(),
+        mime_type: "image/png".to_string(),
+        file_type: FileType::Image,
+        byte_size: 1024,
+        permalink: permalink.to_string(),
+        created_at: now,
+        updated_at: now,
+        revision: 0,
+        alt: None,
+        description: None,
+        expires_at: None,
+        metadata: None,
+        name: None,
+        subject_id: Some("user-1".to_string()),
+        tags: Vec::new(),
+        visibility: Visibility::Public,
+        versions: Vec::new(),
+        deleted_at: None,
+        legal_hold: false,
+        retain_until: None,
+    }
+}
+
+#[test]
+fn test_apply_purge_all() {
+    let (_dir, db, machine) = test_machine();
+    machine
+        .apply(&WriteOp::CreateFile(sample_file("a", "a.png")))
+        .unwrap();
+    machine
+        .apply(&WriteOp::CreateFile(sample_file("b", "b.png")))
+        .unwrap();
+
+    machine.apply(&WriteOp::PurgeAll).unwrap();
+
+    assert_eq!(db.count_files().unwrap(), 0);
+    assert!(!db.permalink_exists(DEFAULT_NAMESPACE, "a.png").unwrap());
+    assert!(db
+        .get_files_by_subject(DEFAULT_NAMESPACE, "user-1")
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn test_apply_tag_changes() {
+    let (_dir, db, machine) = test_machine();
+    machine
+        .apply(&WriteOp::CreateFile(sample_file("t", "t.png")))
+        .unwrap();
+
+    machine
+        .apply(&WriteOp::AddTags {
+            id: "t".to_string(),
+            tags: vec![
+                "hero".to_string(),
+                "archived".to_string(),
+                "hero".to_string(),
+            ],
+        })
+        .unwrap();
+    assert_eq!(
+        db.get_file("t").unwrap().unwrap().tags,
+        ["archived", "hero"]
+    );
+
+    machine
+        .apply(&WriteOp::RemoveTags {
+            id: "t".to_string(),
+            tags: vec!["archived".to_string(), "missing".to_string()],
+        })
+        .unwrap();
+    assert_eq!(db.get_file("t").unwrap().unwrap().tags, ["hero"]);
+    assert_eq!(
+        db.list_tags(DEFAULT_NAMESPACE).unwrap(),
+        [("hero".to_string(), 1)]
+    );
+}
+
+#[test]
+fn test_snapshot_round_trip() {
+    let (_dir, _db, leader) = test_machine();
+    for i in 0..5 {
+        leader
+            .apply(&WriteOp::CreateFile(sample_file(
+                &format!("f{i}"),
+                &format!("f{i}.png"),
+            )))
+            .unwrap();
+    }
+
+    let snapshot = leader.snapshot().unwrap();
+    // Written out and read back, as muster sends it to followers
+    let snapshot: FileSnapshot =
+        rmp_serde::from_slice(&rmp_serde::to_vec_named(&snapshot).unwrap()).unwrap();
+    match &snapshot.files {
+        SnapshotRecords::Loaded(files) => assert_eq!(files.len(), 5),
+        SnapshotRecords::Stored(_) => panic!("snapshot files were not read back"),
+    }
+
+    let (_dir2, follower_db, follower) = test_machine();
+    follower.restore(snapshot).unwrap();
+
+    assert_eq!(follower_db.count_files().unwrap(), 5);
+    assert_eq!(
+        follower_db
+            .get_files_by_subject(DEFAULT_NAMESPACE, "user-1")
+            .unwrap()
+            .len(),
+        5
+    );
+    assert!(follower_db
+        .permalink_exists(DEFAULT_NAMESPACE, "f3.png")
+        .unwrap());
+}
+
+#[test]
+fn test_restore_earlier_snapshot_formats() {
+    #[derive(serde::Serialize)]
+    struct Flat {
+        files: Vec<FileRecord>,
+    }
+    #[derive(serde::Serialize)]
+    struct Chunked {
+        chunks: Vec<Vec<FileRecord>>,
+    }
+
+    let flat = rmp_serde::to_vec_named(&Flat {
+        files: vec![sample_file("a", "a.png"), sample_file("b", "b.png")],
+    })
+    .unwrap();
+    let chunked = rmp_serde::to_vec_named(&Chunked {
+        chunks: vec![
+            vec![sample_file("a", "a.png"), sample_file("b", "b.png")],
+            vec![sample_file("c", "c.png")],
+        ],
+    })
+    .unwrap();
+    for (data, count) in [(flat, 2), (chunked, 3)] {
+        let (_dir, db, machine) = test_machine();
+        machine
+            .restore(rmp_serde::from_slice(&data).unwrap())
+            .unwrap();
+        assert_eq!(db.count_files().unwrap(), count);
+        assert!(db.permalink_exists(DEFAULT_NAMESPACE, "b.png").unwrap());
+    }
+}
+
+#[test]
+fn test_snapshot_includes_redirects() {
+    let (_dir, _db, leader) = test_machine();
+    leader
+        .apply(&WriteOp::CreateFile(sample_file("r", "old.png")))
+        .unwrap();
+    leader
+        .apply(&WriteOp::UpdateFile {
+            id: "r".to_string(),
+            alt: Patch::Absent,
+            description: Patch::Absent,
+            expires_at: Patch::Absent,
+            metadata: Patch::Absent,
+            name: Patch::Absent,
+            permalink: Some("new.png".to_string()),
+            subject_id: Patch::Absent,
+            visibility: None,
+        })
+        .unwrap();
+
+    // The follower has a redirect the leader has since deleted
+    let (_dir2, follower_db, follower) = test_machine();
+    follower
+        .apply(&WriteOp::CreateFile(sample_file("s", "stale.png")))
+        .unwrap();
+    follower
+        .apply(&WriteOp::UpdateFile {
+            id: "s".to_string(),
+            alt: Patch::Absent,
+            description: Patch::Absent,
+            expires_at: Patch::Absent,
+            metadata: Patch::Absent,
+            name: Patch::Absent,
+            permalink: Some("fresh.png".to_string()),
+            subject_id: Patch::Absent,
+            visibility: None,
+        })
+        .unwrap();
+
+    follower.restore(leader.snapshot().unwrap()).unwrap();
+
+    assert_eq!(
+        follower_db
+            .get_redirect(DEFAULT_NAMESPACE, "old.png")
+            .unwrap()
+            .as_deref(),
+        Some("r")
+    );
+    assert_eq!(
+        follower_db
+            .get_redirect(DEFAULT_NAMESPACE, "stale.png")
+            .unwrap(),
+        None
+    );
+
+    follower
+        .apply(&WriteOp::DeleteRedirect {
+            namespace: DEFAULT_NAMESPACE.to_string(),
+            permalink: "old.png".to_string(),
+        })
+        .unwrap();
+    assert!(follower_db.get_all_redirects().unwrap().is_empty());
+}
+
+#[test]
+fn test_restore_replaces_stale_records() {
+    let (_dir, _db, leader) = test_machine();
+    leader
+        .apply(&WriteOp::CreateFile(sample_file("keep", "keep.png")))
+        .unwrap();
+
+    // The follower still holds a file the leader has since deleted
+    let (_dir2, follower_db, follower) = test_machine();
+    follower
+        .apply(&WriteOp::CreateFile(sample_file("keep", "keep.png")))
+        .unwrap();
+    follower
+        .apply(&WriteOp::CreateFile(sample_file("stale", "stale.png")))
+        .unwrap();
+
+    follower.restore(leader.snapshot().unwrap()).unwrap();
+
+    assert!(follower_db.get_file("stale").unwrap().is_none());
+    assert!(!follower_db
+        .permalink_exists(DEFAULT_NAMESPACE, "stale.png")
+        .unwrap());
+    let subject_files = follower_db
+        .get_files_by_subject(DEFAULT_NAMESPACE, "user-1")
+        .unwrap();
+    assert_eq!(subject_files.len(), 1);
+    assert_eq!(subject_files[0].id, "keep");
+}
+
+#[test]
+fn test_apply_rejects_changes_to_locked_files() {
+    let (_dir, db, machine) = test_machine();
+    machine
+        .apply(&WriteOp::CreateFile(sample_file("h", "docs/h.png")))
+        .unwrap();
+    machine
+        .apply(&WriteOp::SetRetention {
+            id: "h".to_string(),
+            legal_hold: Some(true),
+            retain_until: Patch::Absent,
+        })
+        .unwrap();
+
+    let rejected = [
+        WriteOp::DeleteFile {
+            id: "h".to_string(),
+        },
+        WriteOp::TrashFile {
+            id: "h".to_string(),
+            deleted_at: Utc::now(),
+        },
+        WriteOp::RestoreVersion {
+            id: "h".to_string(),
+            version: 1,
+            restored_at: Utc::now(),
+        },
+        WriteOp::UpdateFile {
+            id: "h".to_string(),
+            alt: Patch::Absent,
+            description: Patch::Absent,
+            expires_at: Patch::Absent,
+            metadata: Patch::Absent,
+            name: Patch::Absent,
+            permalink: Some("moved.png".to_string()),
+            subject_id: Patch::Absent,
+            visibility: None,
+        },
+        WriteOp::RenamePrefix {
+            namespace: DEFAULT_NAMESPACE.to_string(),
+            from: "docs/".to_string(),
+            to: "archive/".to_string(),
+            renamed_at: Utc::now(),
+        },
+    ];
+    for op in &rejected {
+        assert!(machine.apply(op).is_err(), "{op:?} should be rejected");
+    }
+    let file = db.get_file("h").unwrap().unwrap();
+    assert_eq!(file.permalink, "docs/h.png");
+    assert!(file.deleted_at.is_none());
+
+    // Metadata changes are still allowed
+    machine
+        .apply(&WriteOp::UpdateFile {
+            id: "h".to_string(),
+            alt: Patch::Value("Held".to_string()),
+            description: Patch::Absent,
+            expires_at: Patch::Absent,
+            metadata: Patch::Absent,
+            name: Patch::Absent,
+            permalink: None,
+            subject_id: Patch::Absent,
+            visibility: None,
+        })
+        .unwrap();
+
+    // Retention can be extended but not shortened or cleared
+    let until = Utc::now() + chrono::Duration::days(10);
+    machine
+        .apply(&WriteOp::SetRetention {
+            id: "h".to_string(),
+            legal_hold: Some(false),
+            retain_until: Patch::Value(until),
+        })
+        .unwrap();
+    for retain_until in [Patch::Value(until - chrono::Duration::days(1)), Patch::Null] {
+        let op = WriteOp::SetRetention {
+            id: "h".to_string(),
+            legal_hold: None,
+            retain_until,
+        };
+        assert!(machine.apply(&op).is_err());
+    }
+    assert!(machine
+        .apply(&WriteOp::DeleteFile {
+            id: "h".to_string()
+        })
+        .is_err());
+    assert_eq!(db.get_file("h").unwrap().unwrap().retain_until, Some(until));
+}
+
+#[test]
+fn test_apply_checks_locks_at_decision_time() {
+    let (_dir, db, machine) = test_machine();
+    machine
+        .apply(&WriteOp::CreateFile(sample_file("h", "docs/h.png")))
+        .unwrap();
+    let until = Utc::now() - chrono::Duration::days(1);
+    machine
+        .apply(&WriteOp::SetRetention {
+            id: "h".to_string(),
+            legal_hold: None,
+            retain_until: Patch::Value(until),
+        })
+        .unwrap();
+
+    // Decided while the file was retained, however late it's applied
+    let delete = WriteOp::DeleteFile {
+        id: "h".to_string(),
+    };
+    let mut context = audit_context("alice");
+    context.timestamp = until - chrono::Duration::hours(1);
+    assert!(machine.apply(&delete.clone().audited(&context)).is_err());
+    assert!(db.get_file("h").unwrap().is_some());
+
+    context.timestamp = until + chrono::Duration::hours(1);
+    machine.apply(&delete.audited(&context)).unwrap();
+    assert!(db.get_file("h").unwrap().is_none());
+}
+
+#[test]
+fn test_apply_checks_if_match_revisions() {
+    let (_dir, db, machine) = test_machine();
+    machine
+        .apply(&WriteOp::CreateFile(sample_file("m", "m.png")))
+        .unwrap();
+    let update = |alt: &str| WriteOp::UpdateFile {
+        id: "m".to_string(),
+        alt: Patch::Value(alt.to_string()),
+        description: Patch::Absent,
+        expires_at: Patch::Absent,
+        metadata: Patch::Absent,
+        name: Patch::Absent,
+        permalink: None,
+        subject_id: Patch::Absent,
+        visibility: None,
+    };
+
+    // Two editors read revision 1; the first write wins
+    machine
+        .apply(
+            &update("First")
+                .if_match("m", vec![1])
+                .audited(&audit_context("ann")),
+        )
+        .unwrap();
+    assert!(machine
+        .apply(
+            &update("Second")
+                .if_match("m", vec![1])
+                .audited(&audit_context("bob"))
+        )
+        .is_err());
+    let file = db.get_file("m").unwrap().unwrap();
+    assert_eq!(file.alt.as_deref(), Some("First"));
+    assert_eq!(file.revision, 2);
+
+    // Rejected operations are neither audited nor in the change feed
+    assert_eq!(file_audit(&db, "m").len(), 2);
+    assert_eq!(changes(&db, DEFAULT_NAMESPACE, 0).len(), 2);
+
+    machine
+        .apply(
+            &WriteOp::TrashFile {
+                id: "m".to_string(),
+                deleted_at: Utc::now(),
+            }
+            .if_match("m", vec![1, 2]),
+        )
+        .unwrap();
+    assert!(db.get_file("m").unwrap().unwrap().deleted_at.is_some());
+
+    assert!(machine
+        .apply(
+            &WriteOp::DeleteFile {
+                id: "missing".to_string()
+            }
+            .if_match("missing", vec![1])
+        )
+        .is_err());
+}
+
+#[test]
+fn test_apply_rename_prefix_stays_in_namespace() {
+    let (_dir, db, machine) = test_machine();
+    let mut acme = sample_file("a", "docs/a.png");
+    acme.namespace = "acme".to_string();
+    machine.apply(&WriteOp::CreateFile(acme)).unwrap();
+    machine
+        .apply(&WriteOp::CreateFile(sample_file("d", "docs/d.png")))
+        .unwrap();
+
+    machine
+        .apply(&WriteOp::RenamePrefix {
+            namespace: "acme".to_string(),
+            from: "docs/".to_string(),
+            to: "archive/".to_string(),
+            renamed_at: Utc::now(),
+        })
+        .unwrap();
+
+    assert_eq!(
+        db.get_file("a").unwrap().unwrap().permalink,
+        "archive/a.png"
+    );
+    assert_eq!(db.get_file("d").unwrap().unwrap().permalink, "docs/d.png");
+    assert_eq!(
+        db.get_redirect("acme", "docs/a.png").unwrap().as_deref(),
+        Some("a")
+    );
+    assert!(!db.permalink_exists("acme", "docs/d.png").unwrap());
+}
+
+#[test]
+fn test_apply_rename_prefix_rejects_conflicts() {
+    let (_dir, db, machine) = test_machine();
+    for (id, permalink) in [("a", "docs/a.png"), ("b", "docs/b.png")] {
+        machine
+            .apply(&WriteOp::CreateFile(sample_file(id, permalink)))
+            .unwrap();
+    }
+    // Taken after the handler planned the rename
+    machine
+        .apply(&WriteOp::CreateFile(sample_file("x", "archive/b.png")))
+        .unwrap();
+
+    let rename = WriteOp::RenamePrefix {
+        namespace: DEFAULT_NAMESPACE.to_string(),
+        from: "docs/".to_string(),
+        to: "archive/".to_string(),
+        renamed_at: Utc::now(),
+    };
+    assert!(machine.apply(&rename).is_err());
+    assert_eq!(db.get_file("a").unwrap().unwrap().permalink, "docs/a.png");
+    assert_eq!(db.get_file("b").unwrap().unwrap().permalink, "docs/b.png");
+}
+
+fn audit_context(actor: &str) -> AuditContext {
+    AuditContext {
+        actor: Some(actor.to_string()),
+        request_id: Some("req-1".to_string()),
+        source_node: Some("node-1".to_string()),
+        timestamp: Utc::now(),
+    }
+}
+
+fn file_audit(db: &Database, id: &str) -> Vec<AuditEntry> {
+    let filter = AuditFilter {
+        namespace: DEFAULT_NAMESPACE.to_string(),
+        file_id: Some(id.to_string()),
+        ..Default::default()
+    };
+    db.list_audit(&filter, None, 100).unwrap()
+}
+
+#[test]
+fn test_apply_records_audit_entries() {
+    let (_dir, db, machine) = test_machine();
+    let context = audit_context("alice");
+    machine
+        .apply(&WriteOp::CreateFile(sample_file("a", "a.png")).audited(&context))
+        .unwrap();
+    machine
+        .apply(
+            &WriteOp::AddTags {
+                id: "a".to_string(),
+                tags: vec!["hero".to_string()],
+            }
+            .audited(&audit_context("bob")),
+        )
+        .unwrap();
+    // Changes nothing, so nothing is recorded
+    machine
+        .apply(&WriteOp::RestoreFile {
+            id: "a".to_string(),
+        })
+        .unwrap();
+
+    let entries = file_audit(&db, "a");
+    assert_eq!(entries.len(), 2);
+
+    let tagged = &entries[0];
+    assert_eq!(tagged.op.name(), "add_tags");
+    assert_eq!(tagged.actor.as_deref(), Some("bob"));
+    assert_eq!(tagged.request_id.as_deref(), Some("req-1"));
+    assert_eq!(tagged.source_node.as_deref(), Some("node-1"));
+    assert_eq!(
+        tagged.changes["tags"],
+        FieldChange {
+            before: serde_json::json!([]),
+            after: serde_json::json!(["hero"]),
+        }
+    );
+    assert!(!tagged.changes.contains_key("permalink"));
+
+    let created = &entries[1];
+    assert_eq!(created.op.name(), "create_file");
+    assert_eq!(created.timestamp, context.timestamp);
+    assert!(created.seq < tagged.seq);
+    assert_eq!(
+        created.changes["permalink"].after,
+        serde_json::json!("a.png")
+    );
+    assert_eq!(created.changes["permalink"].before, serde_json::Value::Null);
+}
+
+#[test]
+fn test_snapshot_includes_audit_log() {
+    let (_dir, _db, leader) = test_machine();
+    leader
+        .apply(&WriteOp::CreateFile(sample_file("a", "a.png")).audited(&audit_context("alice")))
+        .unwrap();
+
+    let (_dir2, follower_db, follower) = test_machine();
+    follower.restore(leader.snapshot().unwrap()).unwrap();
+    assert_eq!(file_audit(&follower_db, "a").len(), 1);
+
+    // Numbering continues where the leader left off
+    follower
+        .apply(&WriteOp::DeleteFile {
+            id: "a".to_string(),
+        })
+        .unwrap();
+    let entries = file_audit(&follower_db, "a");
+    assert_eq!(entries.iter().map(|e| e.seq).collect::<Vec<_>>(), [2, 1]);
+    assert_eq!(entries[0].changes["id"].after, serde_json::Value::Null);
+}
+
+fn changes(db: &Database, namespace: &str, since: u64) -> Vec<Change> {
+    db.list_changes(namespace, since, 100).unwrap().changes
+}
+
+#[test]
+fn test_apply_records_change_feed() {
+    let (_dir, db, machine) = test_machine();
+    let mut other = sample_file("b", "b.png");
+    other.namespace = "acme".to_string();
+    let ops = [
+        WriteOp::CreateFile(sample_file("a", "a.png")),
+        WriteOp::CreateFile(other),
+        WriteOp::RenamePrefix {
+            namespace: DEFAULT_NAMESPACE.to_string(),
+            from: "a".to_string(),
+            to: "docs/a".to_string(),
+            renamed_at: Utc::now(),
+        },
+        // Changes nothing, but still takes a sequence number
+        WriteOp::RestoreFile {
+            id: "a".to_string(),
+        },
+        WriteOp::TrashFile {
+            id: "a".to_string(),
+            deleted_at: Utc::now(),
+        },
+        WriteOp::RestoreFile {
+            id: "a".to_string(),
+        },
+        WriteOp::DeleteFile {
+            id: "a".to_string(),
+        },
+    ];
+    for op in &ops {
+        machine.apply(op).unwrap();
+    }
+
+    let feed = changes(&db, DEFAULT_NAMESPACE, 0);
+    assert_eq!(
+        feed.iter().map(|c| c.seq).collect::<Vec<_>>(),
+        [1, 3, 5, 6, 7]
+    );
+    assert_eq!(
+        feed.iter().map(|c| c.events[0].kind).collect::<Vec<_>>(),
+        [
+            ChangeKind::Created,
+            ChangeKind::Updated,
+            ChangeKind::Trashed,
+            ChangeKind::Restored,
+            ChangeKind::Deleted,
+        ]
+    );
+    assert_eq!(feed[1].operation, "rename_prefix");
+    assert_eq!(feed[1].events[0].permalink.as_deref(), Some("docs/a.png"));
+    assert_eq!(
+        feed[1].events[0].previous_permalink.as_deref(),
+        Some("a.png")
+    );
+    assert_eq!(feed[4].events[0].permalink.as_deref(), Some("docs/a.png"));
+
+    let acme = changes(&db, "acme", 0);
+    assert_eq!(acme.len(), 1);
+    assert_eq!(acme[0].seq, 2);
+    assert_eq!(acme[0].events[0].file_id.as_deref(), Some("b"));
+
+    assert_eq!(changes(&db, DEFAULT_NAMESPACE, 5).len(), 2);
+    assert_eq!(
+        db.list_changes(DEFAULT_NAMESPACE, 0, 1).unwrap().last_seq,
+        7
+    );
+    assert_eq!(*machine.subscribe().borrow(), 7);
+}
+
+#[test]
+fn test_rejected_operations_take_a_sequence_number() {
+    let (_dir, db, machine) = test_machine();
+    let watcher = machine.subscribe();
+    machine
+        .apply(&WriteOp::CreateFile(sample_file("a", "a.png")).audited(&audit_context("alice")))
+        .unwrap();
+
+    let rejected = WriteOp::DeleteFile {
+        id: "a".to_string(),
+    }
+    .if_match("a", vec![7])
+    .audited(&audit_context("bob"));
+    assert!(machine.apply(&rejected).is_err());
+
+    // Nothing of the rejected operation is written, but the feed still counts it
+    assert!(db.get_file("a").unwrap().is_some());
+    assert_eq!(file_audit(&db, "a").len(), 1);
+    let page = db.list_changes(DEFAULT_NAMESPACE, 0, 100).unwrap();
+    assert_eq!(page.changes.len(), 1);
+    assert_eq!(page.last_seq, 2);
+    assert_eq!(*watcher.borrow(), 2);
+
+    machine
+        .apply(&WriteOp::CreateFile(sample_file("b", "b.png")))
+        .unwrap();
+    assert_eq!(changes(&db, DEFAULT_NAMESPACE, 1)[0].seq, 3);
+}
+
+#[test]
+fn test_snapshot_includes_change_feed() {
+    let (_dir, _db, leader) = test_machine();
+    leader
+        .apply(&WriteOp::CreateFile(sample_file("a", "a.png")))
+        .unwrap();
+    leader.apply(&WriteOp::PurgeAll).unwrap();
+    leader
+        .apply(&WriteOp::CreateFile(sample_file("c", "c.png")))
+        .unwrap();
+
+    let (_dir2, follower_db, follower) = test_machine();
+    let watcher = follower.subscribe();
+    follower.restore(leader.snapshot().unwrap()).unwrap();
+    assert_eq!(*watcher.borrow(), 2);
+
+    // Purges clear the feed, and show up in every namespace
+    let feed = changes(&follower_db, "acme", 0);
+    assert_eq!(feed.len(), 1);
+    assert_eq!(feed[0].seq, 1);
+    assert_eq!(feed[0].events[0].kind, ChangeKind::Purged);
+    assert_eq!(changes(&follower_db, DEFAULT_NAMESPACE, 0).len(), 2);
+
+    follower
+        .apply(&WriteOp::CreateFile(sample_file("b", "b.png")))
+        .unwrap();
+    assert_eq!(changes(&follower_db, DEFAULT_NAMESPACE, 2)[0].seq, 3);
+}
+
+fn sample_webhook(id: &str, events: Vec<WebhookEvent>) -> Webhook {
+    Webhook {
+        id: id.to_string(),
+        namespace: DEFAULT_NAMESPACE.to_string(),
+        url: "http://127.0.0.1:9/hook".to_string(),
+        secret: "secret".to_string(),
+        events,
+        created_at: Utc::now(),
+    }
+}
+
+fn outbox(db: &Database, webhook_id: &str) -> Vec<WebhookDelivery> {
+    db.list_webhook_deliveries(webhook_id, false, 100).unwrap()
+}
+
+#[test]
+fn test_apply_queues_webhook_deliveries() {
+    let (_dir, db, machine) = test_machine();
+    machine
+        .apply(&WriteOp::PutWebhook(sample_webhook(
+            "w",
+            vec![WebhookEvent::FileCreated, WebhookEvent::FileContentReplaced],
+        )))
+        .unwrap();
+
+    machine
+        .apply(&WriteOp::CreateFile(sample_file("a", "a.png")))
+        .unwrap();
+    // Not subscribed to updates
+    machine
+        .apply(&WriteOp::AddTags {
+            id: "a".to_string(),
+            tags: vec!["hero".to_string()],
+        })
+        .unwrap();
+    machine
+        .apply(&WriteOp::AddVersion {
+            id: "a".to_string(),
+            version: FileVersion {
+                version: 2,
+                blob_key: "a/2".to_string(),
+                byte_size: 2048,
+                mime_type: "image/png".to_string(),
+                sha256: None,
+                created_at: Utc::now(),
+            },
+        })
+        .unwrap();
+    // Another namespace's files aren't delivered
+    let mut other = sample_file("b", "b.png");
+    other.namespace = "acme".to_string();
+    machine.apply(&WriteOp::CreateFile(other)).unwrap();
+
+    let deliveries = outbox(&db, "w");
+    assert_eq!(
+        deliveries.iter().map(|d| d.event).collect::<Vec<_>>(),
+        [WebhookEvent::FileContentReplaced, WebhookEvent::FileCreated]
+    );
+    let payload: WebhookPayload = serde_json::from_str(&deliveries[1].payload).unwrap();
+    assert_eq!(payload.event, WebhookEvent::FileCreated);
+    assert_eq!(payload.seq, 2);
+    assert_eq!(payload.file.unwrap().id, "a");
+    assert!(payload.changes.contains_key("permalink"));
+
+    // Deleting the webhook drops its deliveries
+    machine
+        .apply(&WriteOp::DeleteWebhook {
+            id: "w".to_string(),
+        })
+        .unwrap();
+    assert!(db.get_webhook("w").unwrap().is_none());
+    assert!(outbox(&db, "w").is_empty());
+}
+
+#[test]
+fn test_apply_webhook_delivery_outcomes() {
+    let (_dir, db, machine) = test_machine();
+    machine
+        .apply(&WriteOp::PutWebhook(sample_webhook(
+            "w",
+            vec![WebhookEvent::FileDeleted],
+        )))
+        .unwrap();
+    machine
+        .apply(&WriteOp::CreateFile(sample_file("a", "a.png")))
+        .unwrap();
+    machine
+        .apply(&WriteOp::CreateFile(sample_file("b", "b.png")))
+        .unwrap();
+    machine
+        .apply(&WriteOp::TrashFile {
+            id: "a".to_string(),
+            deleted_at: Utc::now(),
+        })
+        .unwrap();
+    machine
+        .apply(&WriteOp::DeleteFile {
+            id: "b".to_string(),
+        })
+        .unwrap();
+    let ids: Vec<u64> = outbox(&db, "w").iter().map(|d| d.id).collect();
+    assert_eq!(ids, [2, 1]);
+
+    let now = Utc::now();
+    machine
+        .apply(&WriteOp::WebhookFailed {
+            delivery_id: 1,
+            attempted_at: now,
+            error: "Receiver answered 500".to_string(),
+            retry_at: Some(now + chrono::Duration::minutes(1)),
+        })
+        .unwrap();
+    machine
+        .apply(&WriteOp::WebhookFailed {
+            delivery_id: 2,
+            attempted_at: now,
+            error: "connection refused".to_string(),
+            retry_at: None,
+        })
+        .unwrap();
+
+    let due = db.due_webhook_deliveries(now, 10).unwrap();
+    assert!(due.is_empty());
+    let (retrying, dead) = db.get_webhook_delivery(1).unwrap().unwrap();
+    assert!(!dead);
+    assert_eq!(retrying.attempts, 1);
+    assert_eq!(
+        retrying.last_error.as_deref(),
+        Some("Receiver answered 500")
+    );
+    let dead_letters = db.list_webhook_deliveries("w", true, 10).unwrap();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0].id, 2);
+
+    // A follower restored from a snapshot holds the same outbox
+    let (_dir2, follower_db, follower) = test_machine();
+    follower.restore(machine.snapshot().unwrap()).unwrap();
+    assert_eq!(
+        follower_db.get_webhook_state().unwrap().dead_letters,
+        dead_letters
+    );
+    assert_eq!(outbox(&follower_db, "w"), [retrying]);
+
+    machine
+        .apply(&WriteOp::RedeliverWebhook {
+            delivery_id: 2,
+            at: now,
+        })
+        .unwrap();
+    // Delivery 2 waits for delivery 1, ahead of it in the webhook's queue
+    assert!(db.due_webhook_deliveries(now, 10).unwrap().is_empty());
+    machine
+        .apply(&WriteOp::RedeliverWebhook {
+            delivery_id: 1,
+            at: now,
+        })
+        .unwrap();
+    let due = db.due_webhook_deliveries(now, 10).unwrap();
+    let ids: Vec<u64> = due[0].iter().map(|d| d.id).collect();
+    assert_eq!((due.len(), ids), (1, vec![1, 2]));
+    assert_eq!(due[0][1].attempts, 0);
+
+    machine
+        .apply(&WriteOp::WebhookDelivered { delivery_id: 2 })
+        .unwrap();
+    assert!(db.get_webhook_delivery(2).unwrap().is_none());
+}
+
+#[test]
+fn test_apply_idempotency_keys() {
+    let (_dir, db, leader) = test_machine();
+    let record = |fingerprint: &str| IdempotencyRecord {
+        namespace: DEFAULT_NAMESPACE.to_string(),
+        key: "retry-1".to_string(),
+        fingerprint: fingerprint.to_string(),
+        status: 200,
+        headers: Vec::new(),
+        body: "{}".to_string(),
+        created_at: Utc::now(),
+        claim: None,
+    };
+    // A retry on another node while the first request is handled finds its
+    // claim
+    let claim = IdempotencyRecord {
+        claim: Some("c1".to_string()),
+        ..record("aaa")
+    };
+    leader
+        .apply(&WriteOp::ClaimIdempotencyKey(claim.clone()))
+        .unwrap();
+    leader
+        .apply(&WriteOp::ClaimIdempotencyKey(IdempotencyRecord {
+            claim: Some("c2".to_string()),
+            ..record("aaa")
+        }))
+        .unwrap();
+    assert_eq!(
+        db.get_idempotency_record(DEFAULT_NAMESPACE, "retry-1")
+            .unwrap(),
+        Some(claim)
+    );
+    leader
+        .apply(&WriteOp::PutIdempotencyKey(record("aaa")))
+        .unwrap();
+    // Two nodes racing to store a response for the same key keep the first
+    leader
+        .apply(&WriteOp::PutIdempotencyKey(record("bbb")))
+        .unwrap();
+    let stored = db
+        .get_idempotency_record(DEFAULT_NAMESPACE, "retry-1")
+        .unwrap()
+        .unwrap();
+    assert_eq!(stored.fingerprint, "aaa");
+
+    let (_dir2, follower_db, follower) = test_machine();
+    follower.restore(leader.snapshot().unwrap()).unwrap();
+    assert_eq!(follower_db.get_idempotency_records().unwrap(), [stored]);
+
+    follower
+        .apply(&WriteOp::PruneIdempotencyKeys {
+            before: Utc::now() + chrono::Duration::seconds(1),
+        })
+        .unwrap();
+    assert!(follower_db.get_idempotency_records().unwrap().is_empty());
+
+    // A failed request gives its claim up
+    follower
+        .apply(&WriteOp::ClaimIdempotencyKey(IdempotencyRecord {
+            claim: Some("c3".to_string()),
+            ..record("ccc")
+        }))
+        .unwrap();
+    follower
+        .apply(&WriteOp::ReleaseIdempotencyKey {
+            namespace: DEFAULT_NAMESPACE.to_string(),
+            key: "retry-1".to_string(),
+            claim: "c3".to_string(),
+        })
+        .unwrap();
+    assert!(follower_db.get_idempotency_records().unwrap().is_empty());
+}
+
+#[test]
+fn test_apply_updates_files_at_decision_time() {
+    let context = AuditContext {
+        timestamp: Utc::now() - chrono::Duration::hours(1),
+        ..audit_context("ann")
+    };
+    let ops = [
+        WriteOp::CreateFile(sample_file("a", "a.png")),
+        WriteOp::AddTags {
+            id: "a".to_string(),
+            tags: vec!["hero".to_string()],
+        }
+        .audited(&context),
+        WriteOp::UpdateFile {
+            id: "a".to_string(),
+            alt: Patch::Absent,
+            description: Patch::Absent,
+            expires_at: Patch::Absent,
+            metadata: Patch::Absent,
+            name: Patch::Value("A".to_string()),
+            permalink: None,
+            subject_id: Patch::Absent,
+            visibility: None,
+        }
+        .audited(&context),
+        WriteOp::SetRetention {
+            id: "a".to_string(),
+            legal_hold: Some(true),
+            retain_until: Patch::Absent,
+        }
+        .audited(&context),
+    ];
+    // Every node replaying an operation stamps the file with the time the
+    // request was made, not the time it applies the operation
+    for op in ops.iter().skip(1) {
+        let (_dir, db, machine) = test_machine();
+        machine.apply(&ops[0]).unwrap();
+        machine.apply(op).unwrap();
+        let file = db.get_file("a").unwrap().unwrap();
+        assert_eq!(file.updated_at, context.timestamp, "{}", op.name());
+    }
+}
+
+#[test]
+fn test_apply_batch_is_all_or_nothing() {
+    let (_dir, db, machine) = test_machine();
+    for id in ["a", "b", "c"] {
+        machine
+            .apply(&WriteOp::CreateFile(sample_file(id, &format!("{id}.png"))))
+            .unwrap();
+    }
+    let mut locked = sample_file("h", "h.png");
+    locked.legal_hold = true;
+    machine.apply(&WriteOp::CreateFile(locked)).unwrap();
+    let add_tag = |id: &str| WriteOp::AddTags {
+        id: id.to_string(),
+        tags: vec!["hero".to_string()],
+    };
+    let trash = |id: &str| WriteOp::TrashFile {
+        id: id.to_string(),
+        deleted_at: Utc::now(),
+    };
+
+    // A locked file, a missing file, a repeated file or a non-batchable
+    // operation rejects the whole batch
+    let rejected = [
+        vec![add_tag("a"), trash("h")],
+        vec![add_tag("a"), add_tag("missing")],
+        vec![add_tag("a"), trash("a")],
+        vec![add_tag("a"), WriteOp::PurgeAll],
+    ];
+    for ops in rejected {
+        assert!(machine.apply(&WriteOp::Batch { ops }).is_err());
+    }
+    assert!(db.get_file("a").unwrap().unwrap().tags.is_empty());
+    let seq = db.list_changes(DEFAULT_NAMESPACE, 0, 100).unwrap().last_seq;
+
+    machine
+        .apply(
+            &WriteOp::Batch {
+                ops: vec![add_tag("a"), trash("b"), add_tag("c")],
+            }
+            .audited(&audit_context("alice")),
+        )
+        .unwrap();
+    assert_eq!(db.get_file("a").unwrap().unwrap().tags, vec!["hero"]);
+    assert!(db.get_file("b").unwrap().unwrap().deleted_at.is_some());
+
+    // Each item is audited and fed as its own operation, in one change
+    let entries = file_audit(&db, "b");
+    assert_eq!(entries[0].op.name(), "trash_file");
+    assert_eq!(entries[0].actor.as_deref(), Some("alice"));
+    let feed = changes(&db, DEFAULT_NAMESPACE, seq);
+    assert_eq!(feed.len(), 1);
+    assert_eq!(feed[0].operation, "batch");
+    assert_eq!(
+        feed[0].events.iter().map(|e| e.kind).collect::<Vec<_>>(),
+        vec![
+            ChangeKind::Updated,
+            ChangeKind::Trashed,
+            ChangeKind::Updated
+        ]
+    );
+}
+
+#[test]
+fn test_apply_erasure() {
+    let (_dir, db, machine) = test_machine();
+    for id in ["a", "t", "h"] {
+        machine
+            .apply(&WriteOp::CreateFile(sample_file(id, &format!("{id}.png"))))
+            .unwrap();
+    }
+    let mut other = sample_file("o", "o.png");
+    other.subject_id = Some("user-2".to_string());
+    machine.apply(&WriteOp::CreateFile(other)).unwrap();
+    machine
+        .apply(&WriteOp::TrashFile {
+            id: "t".to_string(),
+            deleted_at: Utc::now(),
+        })
+        .unwrap();
+    machine
+        .apply(&WriteOp::SetRetention {
+            id: "h".to_string(),
+            legal_hold: Some(true),
+            retain_until: Patch::Absent,
+        })
+        .unwrap();
+
+    let erase = |ids: &[&str]| WriteOp::EraseFiles {
+        erasure_id: "e1".to_string(),
+        ids: ids.iter().map(|id| id.to_string()).collect(),
+        erased_at: Utc::now(),
+    };
+    // Nothing is erased without a started erasure
+    assert!(machine.apply(&erase(&["a"])).is_err());
+
+    machine
+        .apply(&WriteOp::StartErasure(Erasure {
+            id: "e1".to_string(),
+            namespace: DEFAULT_NAMESPACE.to_string(),
+            subject_id: "user-1".to_string(),
+            include_trashed: false,
+            started_at: Utc::now(),
+            completed_at: None,
+            erased: Vec::new(),
+            retained: Vec::new(),
+            pending_content: Vec::new(),
+        }))
+        .unwrap();
+    machine
+        .apply(&WriteOp::PutWebhook(sample_webhook(
+            "w",
+            vec![WebhookEvent::FileDeleted],
+        )))
+        .unwrap();
+    let seq = db.list_changes(DEFAULT_NAMESPACE, 0, 100).unwrap().last_seq;
+
+    // Another subject's files, locked files and trashed files the erasure
+    // doesn't include are skipped, leaving no trace in the report or feed
+    machine
+        .apply(&erase(&["o", "h", "a", "t"]).audited(&audit_context("dpo")))
+        .unwrap();
+    assert!(db.get_file("a").unwrap().is_none());
+    for id in ["o", "h", "t"] {
+        assert!(db.get_file(id).unwrap().is_some());
+    }
+    let erasure = db.get_erasure("e1").unwrap().unwrap();
+    let erased: Vec<&str> = erasure.erased.iter().map(|f| f.id.as_str()).collect();
+    assert_eq!(erased, ["a"]);
+    assert_eq!(
+        erasure.pending_content,
+        erasure.erased[0]
+            .versions
+            .iter()
+            .map(|v| v.blob_key.clone())
+            .collect::<Vec<_>>()
+    );
+    let feed = changes(&db, DEFAULT_NAMESPACE, seq);
+    assert_eq!(feed.len(), 1);
+    assert_eq!(feed[0].operation, "erase_files");
+    assert_eq!(feed[0].events.len(), 1);
+    assert_eq!(feed[0].events[0].kind, ChangeKind::Deleted);
+
+    // Content confirmed deleted leaves the report's pending list
+    machine
+        .apply(&WriteOp::ErasureContentDeleted {
+            erasure_id: "e1".to_string(),
+            blob_keys: erasure.pending_content.clone(),
+        })
+        .unwrap();
+    assert!(db
+        .get_erasure("e1")
+        .unwrap()
+        .unwrap()
+        .pending_content
+        .is_empty());
+
+    // Nothing of the erased file is left in the audit log, change feed or
+    // webhook payloads
+    let audit = file_audit(&db, "a");
+    assert_eq!(audit.len(), 1);
+    assert_eq!(audit[0].op.name(), "erase_files");
+    assert_eq!(audit[0].actor.as_deref(), Some("dpo"));
+    assert!(audit[0].changes.is_empty());
+    let events: Vec<_> = changes(&db, DEFAULT_NAMESPACE, 0)
+        .into_iter()
+        .flat_map(|c| c.events)
+        .filter(|e| e.file_id.as_deref() == Some("a"))
+        .collect();
+    assert_eq!(events.len(), 2);
+    assert!(events
+        .iter()
+        .all(|e| e.permalink.is_none() && e.previous_permalink.is_none()));
+    let deliveries = outbox(&db, "w");
+    assert_eq!(deliveries.len(), 1);
+    let payload: WebhookPayload = serde_json::from_str(&deliveries[0].payload).unwrap();
+    assert_eq!(payload.event, WebhookEvent::FileDeleted);
+    assert!(payload.file.is_none());
+    assert!(payload.changes.is_empty());
+
+    machine
+        .apply(&WriteOp::CompleteErasure {
+            erasure_id: "e1".to_string(),
+            retained: vec!["h".to_string()],
+            completed_at: Utc::now(),
+        })
+        .unwrap();
+    let erasure = db.get_erasure("e1").unwrap().unwrap();
+    assert!(erasure.completed_at.is_some());
+    assert_eq!(erasure.retained, vec!["h"]);
+    assert_eq!(erasure.erased.len(), 1);
+    assert!(machine.apply(&erase(&["t"])).is_err());
+}
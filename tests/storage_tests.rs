@@ -1,8 +1,24 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
 
 use chrono::Utc;
-use file_manager::storage::models::{FileRecord, FileType};
-use file_manager::storage::Database;
+use file_manager::storage::audit::{diff_records, AuditEntry, AuditFilter};
+use file_manager::storage::changes::{ChangeEvent, ChangeKind};
+use file_manager::storage::models::{
+    Erasure, FileLock, FileRecord, FileType, FileVersion, IdempotencyRecord, Patch,
+    PermalinkConflict, Redirect, Visibility, Webhook, WebhookEvent, WriteOp, DEFAULT_NAMESPACE,
+};
+use file_manager::storage::query::{
+    Cursor, FileFilter, MetadataCondition, MetadataFilter, MimeFilter, RangeFilter, Sort, TagMatch,
+};
+use file_manager::storage::search::SearchQuery;
+use file_manager::storage::snapshot::SnapshotRecords;
+use file_manager::storage::{
+    Database, DatabaseOptions, AUDIT_LOG, CHANGE_LOG, ERASURES, FILES, FILE_AUDIT, FILE_CHANGES,
+    FILE_TYPE_FILES, IDEMPOTENCY_KEYS, INDEX_COUNTS, META, OPEN_ERASURES, SUBJECT_FILES, WEBHOOKS,
+    WEBHOOK_DEAD_LETTERS, WEBHOOK_OUTBOX, WEBHOOK_QUEUES,
+};
+use redb::{ReadableTableMetadata, TableDefinition};
 
 fn test_db() -> (tempfile::TempDir, Database) {
     let dir = tempfile::tempdir().unwrap();
@@ -14,20 +30,36 @@ fn sample_file(id: &str, permalink: &str) -> FileRecord {
     let now = Utc::now();
     FileRecord {
         id: id.to_string(),
+        namespace: DEFAULT_NAMESPACE.to_string(),
         mime_type: "image/png".to_string(),
         file_type: FileType::Image,
         byte_size: 1024,
         permalink: permalink.to_string(),
         created_at: now,
         updated_at: now,
+        revision: 0,
         alt: Some("test alt".to_string()),
         description: None,
+        expires_at: None,
         metadata: None,
         name: Some("Test File".to_string()),
         subject_id: None,
+        tags: Vec::new(),
+        visibility: Visibility::Public,
+        versions: Vec::new(),
+        deleted_at: None,
+        legal_hold: false,
+        retain_until: None,
     }
 }
 
+/// A sample file created `minute` minutes into the past hour.
+fn sample_file_at(id: &str, permalink: &str, minute: i64) -> FileRecord {
+    let mut file = sample_file(id, permalink);
+    file.created_at = Utc::now() - chrono::Duration::hours(1) + chrono::Duration::minutes(minute);
+    file
+}
+
 fn sample_file_with_subject(id: &str, permalink: &str, subject_id: &str) -> FileRecord {
     let mut file = sample_file(id, permalink);
     file.subject_id = Some(subject_id.to_string());
@@ -59,7 +91,7 @@ fn test_get_file_by_permalink() {
     db.put_file(&file).unwrap();
 
     let retrieved = db
-        .get_file_by_permalink("docs/readme.txt")
+        .get_file_by_permalink(DEFAULT_NAMESPACE, "docs/readme.txt")
         .unwrap()
         .expect("file should exist");
     assert_eq!(retrieved.id, "file-2");
@@ -74,7 +106,10 @@ fn test_get_file_not_found() {
 #[test]
 fn test_get_file_by_permalink_not_found() {
     let (_dir, db) = test_db();
-    assert!(db.get_file_by_permalink("no/such/path").unwrap().is_none());
+    assert!(db
+        .get_file_by_permalink(DEFAULT_NAMESPACE, "no/such/path")
+        .unwrap()
+        .is_none());
 }
 
 #[test]
@@ -85,7 +120,10 @@ fn test_delete_file() {
 
     assert!(db.delete_file("file-3").unwrap());
     assert!(db.get_file("file-3").unwrap().is_none());
-    assert!(db.get_file_by_permalink("to-delete.png").unwrap().is_none());
+    assert!(db
+        .get_file_by_permalink(DEFAULT_NAMESPACE, "to-delete.png")
+        .unwrap()
+        .is_none());
 }
 
 #[test]
@@ -105,10 +143,13 @@ fn test_update_file_metadata() {
             "file-4",
             Some(Some("new alt")),
             Some(None), // clear description
+            None,       // keep expires_at
             None,       // keep metadata
             Some(Some("New Name")),
             None, // keep permalink
             None, // keep subject_id
+            None, // keep visibility
+            Utc::now(),
         )
         .unwrap();
     assert!(updated);
@@ -126,15 +167,29 @@ fn test_update_file_permalink() {
     let file = sample_file("file-5", "old-path.png");
     db.put_file(&file).unwrap();
 
-    db.update_file("file-5", None, None, None, None, Some("new-path.png"), None)
-        .unwrap();
+    db.update_file(
+        "file-5",
+        None,
+        None,
+        None,
+        None,
+        None,
+        Some("new-path.png"),
+        None,
+        None,
+        Utc::now(),
+    )
+    .unwrap();
 
     // Old permalink should not resolve
-    assert!(db.get_file_by_permalink("old-path.png").unwrap().is_none());
+    assert!(db
+        .get_file_by_permalink(DEFAULT_NAMESPACE, "old-path.png")
+        .unwrap()
+        .is_none());
 
     // New permalink should resolve
     let file = db
-        .get_file_by_permalink("new-path.png")
+        .get_file_by_permalink(DEFAULT_NAMESPACE, "new-path.png")
         .unwrap()
         .expect("should resolve new permalink");
     assert_eq!(file.id, "file-5");
@@ -151,7 +206,10 @@ fn test_update_file_not_found() {
             None,
             None,
             None,
-            None
+            None,
+            None,
+            None,
+            Utc::now()
         )
         .unwrap());
 }
@@ -162,7 +220,7 @@ fn test_list_files() {
     db.put_file(&sample_file("a", "a.png")).unwrap();
     db.put_file(&sample_file("b", "b.png")).unwrap();
 
-    let files = db.list_files(None, None).unwrap();
+    let files = db.list_files(DEFAULT_NAMESPACE, None, None).unwrap();
     assert_eq!(files.len(), 2);
 }
 
@@ -174,25 +232,38 @@ fn test_list_files_with_filter() {
     let now = Utc::now();
     let doc = FileRecord {
         id: "doc".to_string(),
+        namespace: DEFAULT_NAMESPACE.to_string(),
         mime_type: "application/pdf".to_string(),
         file_type: FileType::Document,
         byte_size: 2048,
         permalink: "doc.pdf".to_string(),
         created_at: now,
         updated_at: now,
+        revision: 0,
         alt: None,
         description: None,
+        expires_at: None,
         metadata: None,
         name: None,
         subject_id: None,
+        tags: Vec::new(),
+        visibility: Visibility::Public,
+        versions: Vec::new(),
+        deleted_at: None,
+        legal_hold: false,
+        retain_until: None,
     };
     db.put_file(&doc).unwrap();
 
-    let images = db.list_files(Some("image"), None).unwrap();
+    let images = db
+        .list_files(DEFAULT_NAMESPACE, Some("image"), None)
+        .unwrap();
     assert_eq!(images.len(), 1);
     assert_eq!(images[0].id, "img");
 
-    let documents = db.list_files(Some("document"), None).unwrap();
+    let documents = db
+        .list_files(DEFAULT_NAMESPACE, Some("document"), None)
+        .unwrap();
     assert_eq!(documents.len(), 1);
     assert_eq!(documents[0].id, "doc");
 }
@@ -203,8 +274,71 @@ fn test_permalink_exists() {
     let file = sample_file("file-6", "check-me.png");
     db.put_file(&file).unwrap();
 
-    assert!(db.permalink_exists("check-me.png").unwrap());
-    assert!(!db.permalink_exists("not-here.png").unwrap());
+    assert!(db
+        .permalink_exists(DEFAULT_NAMESPACE, "check-me.png")
+        .unwrap());
+    assert!(!db
+        .permalink_exists(DEFAULT_NAMESPACE, "not-here.png")
+        .unwrap());
+}
+
+#[test]
+fn test_due_webhook_deliveries_by_webhook() {
+    let (_dir, db) = test_db();
+    let now = Utc::now();
+    for id in ["dead", "live"] {
+        db.put_webhook(&Webhook {
+            id: id.to_string(),
+            namespace: DEFAULT_NAMESPACE.to_string(),
+            url: format!("http://127.0.0.1:9/{id}"),
+            secret: "secret".to_string(),
+            events: vec![WebhookEvent::FileCreated],
+            created_at: now,
+        })
+        .unwrap();
+    }
+    // Deliveries 1, 3 and 5 go to "dead", 2, 4 and 6 to "live"
+    for _ in 0..3 {
+        db.enqueue_webhook_deliveries(DEFAULT_NAMESPACE, WebhookEvent::FileCreated, "{}", now)
+            .unwrap();
+    }
+    let ids = |due: Vec<Vec<file_manager::storage::webhooks::WebhookDelivery>>| -> Vec<Vec<u64>> {
+        due.iter()
+            .map(|deliveries| deliveries.iter().map(|d| d.id).collect())
+            .collect()
+    };
+    assert_eq!(
+        ids(db.due_webhook_deliveries(now, 2).unwrap()),
+        [vec![1, 3], vec![2, 4]]
+    );
+
+    // A webhook waiting to retry its oldest delivery holds back the rest of
+    // its queue, and only its own
+    let retry_at = now + chrono::Duration::minutes(1);
+    db.fail_webhook_delivery(1, now, "refused", Some(retry_at))
+        .unwrap();
+    db.complete_webhook_delivery(2).unwrap();
+    assert_eq!(
+        ids(db.due_webhook_deliveries(now, 10).unwrap()),
+        [vec![4, 6]]
+    );
+    assert_eq!(
+        ids(db.due_webhook_deliveries(retry_at, 10).unwrap()),
+        [vec![1, 3, 5], vec![4, 6]]
+    );
+
+    // Dead-lettering the head unblocks the queue, and deleting the webhook
+    // empties it
+    db.fail_webhook_delivery(1, now, "refused", None).unwrap();
+    assert_eq!(
+        ids(db.due_webhook_deliveries(now, 10).unwrap()),
+        [vec![3, 5], vec![4, 6]]
+    );
+    db.delete_webhook("dead").unwrap();
+    assert_eq!(
+        ids(db.due_webhook_deliveries(now, 10).unwrap()),
+        [vec![4, 6]]
+    );
 }
 
 #[test]
@@ -213,12 +347,101 @@ fn test_purge_all() {
     db.put_file(&sample_file("p1", "p1.png")).unwrap();
     db.put_file(&sample_file("p2", "p2.png")).unwrap();
 
+    db.append_audit(vec![audit_entry("p1", "alice", 0)])
+        .unwrap();
+    db.append_change(Utc::now(), "create_file", vec![change_event("p1")])
+        .unwrap();
+    let webhook = Webhook {
+        id: "w".to_string(),
+        namespace: DEFAULT_NAMESPACE.to_string(),
+        url: "http://127.0.0.1:9/hook".to_string(),
+        secret: "secret".to_string(),
+        events: vec![WebhookEvent::FileCreated],
+        created_at: Utc::now(),
+    };
+    db.put_webhook(&webhook).unwrap();
+    db.enqueue_webhook_deliveries(
+        DEFAULT_NAMESPACE,
+        WebhookEvent::FileCreated,
+        "{}",
+        Utc::now(),
+    )
+    .unwrap();
+    db.enqueue_webhook_deliveries(
+        DEFAULT_NAMESPACE,
+        WebhookEvent::FileCreated,
+        "{}",
+        Utc::now(),
+    )
+    .unwrap();
+    db.fail_webhook_delivery(2, Utc::now(), "refused", None)
+        .unwrap();
+    db.put_idempotency_record(&sample_idempotency_record("k", "aaa", 0))
+        .unwrap();
+    db.put_erasure(&Erasure {
+        id: "e1".to_string(),
+        namespace: DEFAULT_NAMESPACE.to_string(),
+        subject_id: "user-1".to_string(),
+        include_trashed: false,
+        started_at: Utc::now(),
+        completed_at: None,
+        erased: Vec::new(),
+        retained: Vec::new(),
+        pending_content: Vec::new(),
+    })
+    .unwrap();
+
     let stats = db.purge_all().unwrap();
     assert_eq!(stats.files, 2);
 
     assert!(db.get_all_files().unwrap().is_empty());
-    assert!(!db.permalink_exists("p1.png").unwrap());
-    assert!(!db.permalink_exists("p2.png").unwrap());
+    assert!(!db.permalink_exists(DEFAULT_NAMESPACE, "p1.png").unwrap());
+    assert!(!db.permalink_exists(DEFAULT_NAMESPACE, "p2.png").unwrap());
+
+    let read_txn = db.begin_read().unwrap();
+    let len = |table: TableDefinition<u64, &[u8]>| read_txn.open_table(table).unwrap().len();
+    assert_eq!(len(AUDIT_LOG).unwrap(), 0);
+    assert_eq!(len(CHANGE_LOG).unwrap(), 0);
+    assert_eq!(len(WEBHOOK_OUTBOX).unwrap(), 0);
+    assert_eq!(len(WEBHOOK_DEAD_LETTERS).unwrap(), 0);
+    assert_eq!(read_txn.open_table(FILE_AUDIT).unwrap().len().unwrap(), 0);
+    assert_eq!(read_txn.open_table(FILE_CHANGES).unwrap().len().unwrap(), 0);
+    assert_eq!(read_txn.open_table(WEBHOOKS).unwrap().len().unwrap(), 0);
+    assert_eq!(
+        read_txn.open_table(WEBHOOK_QUEUES).unwrap().len().unwrap(),
+        0
+    );
+    assert_eq!(
+        read_txn
+            .open_table(IDEMPOTENCY_KEYS)
+            .unwrap()
+            .len()
+            .unwrap(),
+        0
+    );
+    assert_eq!(read_txn.open_table(ERASURES).unwrap().len().unwrap(), 0);
+    assert_eq!(
+        read_txn.open_table(OPEN_ERASURES).unwrap().len().unwrap(),
+        0
+    );
+    drop(read_txn);
+
+    // Sequence numbers start over
+    assert_eq!(
+        db.append_change(Utc::now(), "purge_all", Vec::new())
+            .unwrap(),
+        1
+    );
+}
+
+fn change_event(file_id: &str) -> ChangeEvent {
+    ChangeEvent {
+        kind: ChangeKind::Created,
+        namespace: Some(DEFAULT_NAMESPACE.to_string()),
+        file_id: Some(file_id.to_string()),
+        permalink: Some(format!("{file_id}.png")),
+        previous_permalink: None,
+    }
 }
 
 #[test]
@@ -262,14 +485,16 @@ fn test_get_files_by_subject() {
         .unwrap();
     db.put_file(&sample_file("no-sub", "nosub.png")).unwrap();
 
-    let org1_files = db.get_files_by_subject("org-1").unwrap();
+    let org1_files = db.get_files_by_subject(DEFAULT_NAMESPACE, "org-1").unwrap();
     assert_eq!(org1_files.len(), 2);
 
-    let org2_files = db.get_files_by_subject("org-2").unwrap();
+    let org2_files = db.get_files_by_subject(DEFAULT_NAMESPACE, "org-2").unwrap();
     assert_eq!(org2_files.len(), 1);
     assert_eq!(org2_files[0].id, "s-c");
 
-    let empty = db.get_files_by_subject("nonexistent").unwrap();
+    let empty = db
+        .get_files_by_subject(DEFAULT_NAMESPACE, "nonexistent")
+        .unwrap();
     assert!(empty.is_empty());
 }
 
@@ -282,11 +507,13 @@ fn test_list_files_by_subject() {
         .unwrap();
     db.put_file(&sample_file("ls-c", "lsc.png")).unwrap();
 
-    let user1_files = db.list_files(None, Some("user-1")).unwrap();
+    let user1_files = db
+        .list_files(DEFAULT_NAMESPACE, None, Some("user-1"))
+        .unwrap();
     assert_eq!(user1_files.len(), 1);
     assert_eq!(user1_files[0].id, "ls-a");
 
-    let all_files = db.list_files(None, None).unwrap();
+    let all_files = db.list_files(DEFAULT_NAMESPACE, None, None).unwrap();
     assert_eq!(all_files.len(), 3);
 }
 
@@ -300,7 +527,9 @@ fn test_delete_file_cleans_subject_index() {
 
     db.delete_file("del-s").unwrap();
 
-    let remaining = db.get_files_by_subject("user-x").unwrap();
+    let remaining = db
+        .get_files_by_subject(DEFAULT_NAMESPACE, "user-x")
+        .unwrap();
     assert_eq!(remaining.len(), 1);
     assert_eq!(remaining[0].id, "keep-s");
 }
@@ -313,7 +542,9 @@ fn test_delete_last_file_removes_subject_entry() {
 
     db.delete_file("only").unwrap();
 
-    let empty = db.get_files_by_subject("user-solo").unwrap();
+    let empty = db
+        .get_files_by_subject(DEFAULT_NAMESPACE, "user-solo")
+        .unwrap();
     assert!(empty.is_empty());
 }
 
@@ -323,18 +554,33 @@ fn test_update_file_subject_id() {
     db.put_file(&sample_file_with_subject("mv", "mv.png", "old-owner"))
         .unwrap();
 
-    db.update_file("mv", None, None, None, None, None, Some(Some("new-owner")))
-        .unwrap();
+    db.update_file(
+        "mv",
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        Some(Some("new-owner")),
+        None,
+        Utc::now(),
+    )
+    .unwrap();
 
     let file = db.get_file("mv").unwrap().unwrap();
     assert_eq!(file.subject_id, Some("new-owner".to_string()));
 
     // Old owner should have no files
-    let old = db.get_files_by_subject("old-owner").unwrap();
+    let old = db
+        .get_files_by_subject(DEFAULT_NAMESPACE, "old-owner")
+        .unwrap();
     assert!(old.is_empty());
 
     // New owner should have the file
-    let new = db.get_files_by_subject("new-owner").unwrap();
+    let new = db
+        .get_files_by_subject(DEFAULT_NAMESPACE, "new-owner")
+        .unwrap();
     assert_eq!(new.len(), 1);
     assert_eq!(new[0].id, "mv");
 }
@@ -345,13 +591,24 @@ fn test_update_file_clear_subject_id() {
     db.put_file(&sample_file_with_subject("clr", "clr.png", "owner"))
         .unwrap();
 
-    db.update_file("clr", None, None, None, None, None, Some(None))
-        .unwrap();
+    db.update_file(
+        "clr",
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        Some(None),
+        None,
+        Utc::now(),
+    )
+    .unwrap();
 
     let file = db.get_file("clr").unwrap().unwrap();
     assert_eq!(file.subject_id, None);
 
-    let owner_files = db.get_files_by_subject("owner").unwrap();
+    let owner_files = db.get_files_by_subject(DEFAULT_NAMESPACE, "owner").unwrap();
     assert!(owner_files.is_empty());
 }
 
@@ -386,8 +643,19 @@ fn test_update_file_metadata_map() {
         serde_json::Value::String("Canon EOS R5".to_string()),
     );
 
-    db.update_file("meta-2", None, None, Some(Some(&meta)), None, None, None)
-        .unwrap();
+    db.update_file(
+        "meta-2",
+        None,
+        None,
+        None,
+        Some(Some(&meta)),
+        None,
+        None,
+        None,
+        None,
+        Utc::now(),
+    )
+    .unwrap();
 
     let file = db.get_file("meta-2").unwrap().unwrap();
     let metadata = file.metadata.unwrap();
@@ -406,8 +674,19 @@ fn test_update_file_clear_metadata() {
     file.metadata = Some(meta);
     db.put_file(&file).unwrap();
 
-    db.update_file("meta-3", None, None, Some(None), None, None, None)
-        .unwrap();
+    db.update_file(
+        "meta-3",
+        None,
+        None,
+        None,
+        Some(None),
+        None,
+        None,
+        None,
+        None,
+        Utc::now(),
+    )
+    .unwrap();
 
     let file = db.get_file("meta-3").unwrap().unwrap();
     assert_eq!(file.metadata, None);
@@ -438,3 +717,1959 @@ fn test_metadata_round_trip_complex() {
     assert_eq!(metadata.get("published").unwrap(), &serde_json::json!(true));
     assert_eq!(metadata.get("author").unwrap(), &serde_json::json!(null));
 }
+
+// ============================================================================
+// snapshot tests
+// ============================================================================
+
+#[test]
+fn test_snapshot_records_from_read_transaction() {
+    let (_dir, db) = test_db();
+    for i in 0..5 {
+        db.put_file(&sample_file(&format!("c{i}"), &format!("c{i}.png")))
+            .unwrap();
+    }
+
+    // Written out as of the read transaction, whatever changed since
+    let records = SnapshotRecords::<FileRecord>::Stored(Arc::new(db.begin_read().unwrap()));
+    db.delete_file("c0").unwrap();
+    db.put_file(&sample_file("c5", "c5.png")).unwrap();
+    let data = rmp_serde::to_vec_named(&records).unwrap();
+    let files: Vec<FileRecord> = rmp_serde::from_slice(&data).unwrap();
+    let ids: Vec<&str> = files.iter().map(|f| f.id.as_str()).collect();
+    assert_eq!(ids, ["c0", "c1", "c2", "c3", "c4"]);
+}
+
+#[test]
+fn test_replace_all_files() {
+    let (_dir, db) = test_db();
+    db.put_file(&sample_file_with_subject("old", "old.png", "user-old"))
+        .unwrap();
+
+    let count = db
+        .replace_all_files(
+            vec![sample_file_with_subject("new", "new.png", "user-new")].into(),
+            &[],
+        )
+        .unwrap();
+    assert_eq!(count, 1);
+
+    assert!(db.get_file("old").unwrap().is_none());
+    assert!(!db.permalink_exists(DEFAULT_NAMESPACE, "old.png").unwrap());
+    assert!(db
+        .get_files_by_subject(DEFAULT_NAMESPACE, "user-old")
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        db.get_files_by_subject(DEFAULT_NAMESPACE, "user-new")
+            .unwrap()
+            .len(),
+        1
+    );
+}
+
+#[test]
+fn test_query_files_offset_pagination() {
+    let (_dir, db) = test_db();
+    for i in 0..5 {
+        db.put_file(&sample_file_at(
+            &format!("pg-{i}"),
+            &format!("pg{i}.png"),
+            i,
+        ))
+        .unwrap();
+    }
+
+    let page = db
+        .query_files(&FileFilter::default(), Sort::default(), None, 1, 2)
+        .unwrap();
+    assert_eq!(page.total, Some(5));
+    let ids: Vec<&str> = page.files.iter().map(|f| f.id.as_str()).collect();
+    assert_eq!(ids, vec!["pg-1", "pg-2"]);
+    assert!(page.next_cursor.is_some());
+
+    let page = db
+        .query_files(&FileFilter::default(), Sort::default(), None, 4, 10)
+        .unwrap();
+    assert_eq!(page.files.len(), 1);
+    assert!(page.next_cursor.is_none());
+}
+
+#[test]
+fn test_query_files_cursor_pagination() {
+    let (_dir, db) = test_db();
+    for i in 0..5 {
+        db.put_file(&sample_file_at(
+            &format!("cur-{i}"),
+            &format!("cur{i}.png"),
+            i,
+        ))
+        .unwrap();
+    }
+
+    let mut seen = Vec::new();
+    let mut after: Option<Cursor> = None;
+    loop {
+        let page = db
+            .query_files(
+                &FileFilter::default(),
+                Sort::default(),
+                after.as_ref(),
+                0,
+                2,
+            )
+            .unwrap();
+        assert_eq!(page.total, Some(5));
+        seen.extend(page.files.into_iter().map(|f| f.id));
+        match page.next_cursor {
+            Some(cursor) => after = Some(cursor),
+            None => break,
+        }
+    }
+    assert_eq!(seen, vec!["cur-0", "cur-1", "cur-2", "cur-3", "cur-4"]);
+}
+
+#[test]
+fn test_cursor_round_trip() {
+    let cursor = Cursor {
+        created_at: 1_700_000_000_000_000,
+        id: "abc:def".to_string(),
+    };
+    assert_eq!(Cursor::decode(&cursor.encode()), Some(cursor));
+    assert_eq!(Cursor::decode("not a cursor"), None);
+}
+
+#[test]
+fn test_query_files_by_indexes() {
+    let (_dir, db) = test_db();
+    db.put_file(&sample_file_with_subject("q-img", "qimg.png", "user-q"))
+        .unwrap();
+    let mut jpeg = sample_file_with_subject("q-jpg", "qjpg.jpg", "user-q");
+    jpeg.mime_type = "image/jpeg".to_string();
+    db.put_file(&jpeg).unwrap();
+    let mut doc = sample_file_with_subject("q-doc", "qdoc.pdf", "user-other");
+    doc.mime_type = "application/pdf".to_string();
+    doc.file_type = FileType::Document;
+    db.put_file(&doc).unwrap();
+
+    let images = db
+        .query_files(
+            &FileFilter {
+                file_types: vec![FileType::Image],
+                ..Default::default()
+            },
+            Sort::default(),
+            None,
+            0,
+            10,
+        )
+        .unwrap();
+    assert_eq!(images.total, Some(2));
+
+    let jpegs = db
+        .query_files(
+            &FileFilter {
+                mime_type: Some(MimeFilter::Exact("image/jpeg".to_string())),
+                ..Default::default()
+            },
+            Sort::default(),
+            None,
+            0,
+            10,
+        )
+        .unwrap();
+    assert_eq!(jpegs.total, Some(1));
+    assert_eq!(jpegs.files[0].id, "q-jpg");
+
+    let user_images = db
+        .query_files(
+            &FileFilter {
+                file_types: vec![FileType::Image],
+                subject_id: Some("user-q".to_string()),
+                ..Default::default()
+            },
+            Sort::default(),
+            None,
+            0,
+            1,
+        )
+        .unwrap();
+    // The index counts don't cover both filters, and counting would mean
+    // reading past the page
+    assert_eq!(user_images.total, None);
+    assert_eq!(user_images.files.len(), 1);
+    assert!(user_images.next_cursor.is_some());
+
+    let none = db
+        .query_files(
+            &FileFilter {
+                file_types: vec![FileType::Document],
+                subject_id: Some("user-q".to_string()),
+                ..Default::default()
+            },
+            Sort::default(),
+            None,
+            0,
+            10,
+        )
+        .unwrap();
+    assert_eq!(none.total, Some(0));
+    assert!(none.files.is_empty());
+}
+
+#[test]
+fn test_query_files_sorting() {
+    let (_dir, db) = test_db();
+    for (i, (name, size)) in [("beta", 300), ("Alpha", 100), ("gamma", 200)]
+        .into_iter()
+        .enumerate()
+    {
+        let mut file = sample_file_at(&format!("s{i}"), &format!("s{i}.png"), i as i64);
+        file.name = Some(name.to_string());
+        file.byte_size = size;
+        db.put_file(&file).unwrap();
+    }
+    let mut unnamed = sample_file_at("s3", "s3.png", 3);
+    unnamed.name = None;
+    db.put_file(&unnamed).unwrap();
+
+    let ids = |sort: &str| -> Vec<String> {
+        db.query_files(&FileFilter::default(), sort.parse().unwrap(), None, 0, 10)
+            .unwrap()
+            .files
+            .into_iter()
+            .map(|f| f.id)
+            .collect()
+    };
+    assert_eq!(ids("created_at"), ["s0", "s1", "s2", "s3"]);
+    assert_eq!(ids("-created_at"), ["s3", "s2", "s1", "s0"]);
+    assert_eq!(ids("byte_size"), ["s1", "s2", "s0", "s3"]);
+    assert_eq!(ids("-byte_size"), ["s3", "s0", "s2", "s1"]);
+    assert_eq!(ids("name"), ["s1", "s0", "s2", "s3"]);
+
+    assert!("size".parse::<Sort>().is_err());
+}
+
+#[test]
+fn test_query_files_descending_cursor_pagination() {
+    let (_dir, db) = test_db();
+    for i in 0..5 {
+        db.put_file(&sample_file_with_subject(
+            &format!("d{i}"),
+            &format!("d{i}.png"),
+            "user-d",
+        ))
+        .unwrap();
+    }
+    let filter = FileFilter {
+        subject_id: Some("user-d".to_string()),
+        ..Default::default()
+    };
+    let sort: Sort = "-created_at".parse().unwrap();
+
+    let mut seen = Vec::new();
+    let mut after: Option<Cursor> = None;
+    loop {
+        let page = db.query_files(&filter, sort, after.as_ref(), 0, 2).unwrap();
+        assert_eq!(page.total, Some(5));
+        seen.extend(page.files.into_iter().map(|f| f.created_at));
+        match page.next_cursor {
+            Some(cursor) => after = Some(cursor),
+            None => break,
+        }
+    }
+    assert_eq!(seen.len(), 5);
+    assert!(seen.windows(2).all(|w| w[0] >= w[1]));
+}
+
+#[test]
+fn test_query_files_rich_filters() {
+    let (_dir, db) = test_db();
+    let mut png = sample_file_at("r-png", "rpng.png", 0);
+    png.name = Some("Holiday Photo".to_string());
+    png.byte_size = 500;
+    db.put_file(&png).unwrap();
+    let mut jpeg = sample_file_at("r-jpg", "rjpg.jpg", 10);
+    jpeg.mime_type = "image/jpeg".to_string();
+    jpeg.name = Some("profile".to_string());
+    jpeg.byte_size = 5_000;
+    db.put_file(&jpeg).unwrap();
+    let mut pdf = sample_file_at("r-pdf", "rpdf.pdf", 20);
+    pdf.mime_type = "application/pdf".to_string();
+    pdf.file_type = FileType::Document;
+    pdf.name = Some("photo release".to_string());
+    pdf.byte_size = 50_000;
+    db.put_file(&pdf).unwrap();
+    let mut video = sample_file_at("r-mp4", "rmp4.mp4", 30);
+    video.mime_type = "video/mp4".to_string();
+    video.file_type = FileType::Video;
+    video.name = None;
+    video.byte_size = 100_000;
+    db.put_file(&video).unwrap();
+
+    let query = |filter: FileFilter| -> Vec<String> {
+        let page = db
+            .query_files(&filter, Sort::default(), None, 0, 10)
+            .unwrap();
+        assert_eq!(page.total, Some(page.files.len() as u64));
+        page.files.into_iter().map(|f| f.id).collect()
+    };
+
+    let images = query(FileFilter {
+        mime_type: Some(MimeFilter::parse("image/*")),
+        ..Default::default()
+    });
+    assert_eq!(images, ["r-png", "r-jpg"]);
+
+    let named = query(FileFilter {
+        name_contains: Some("PHOTO".to_string()),
+        ..Default::default()
+    });
+    assert_eq!(named, ["r-png", "r-pdf"]);
+
+    let sized = query(FileFilter {
+        byte_size: RangeFilter {
+            gte: Some(1_000),
+            lt: Some(50_000),
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+    assert_eq!(sized, ["r-jpg"]);
+
+    let recent = query(FileFilter {
+        created_at: RangeFilter {
+            gt: Some(jpeg.created_at),
+            lte: Some(video.created_at),
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+    assert_eq!(recent, ["r-pdf", "r-mp4"]);
+
+    let empty_range = query(FileFilter {
+        created_at: RangeFilter {
+            gte: Some(video.created_at),
+            lt: Some(png.created_at),
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+    assert!(empty_range.is_empty());
+
+    let documents_or_videos = query(FileFilter {
+        file_types: vec![FileType::Document, FileType::Video],
+        ..Default::default()
+    });
+    assert_eq!(documents_or_videos, ["r-pdf", "r-mp4"]);
+
+    let page = db
+        .query_files(
+            &FileFilter {
+                file_types: vec![FileType::Image, FileType::Video],
+                ..Default::default()
+            },
+            Sort::default(),
+            None,
+            0,
+            1,
+        )
+        .unwrap();
+    assert_eq!(page.total, Some(3));
+}
+
+fn put_file_with_metadata(db: &Database, id: &str, metadata: serde_json::Value) {
+    let mut file = sample_file(id, &format!("{id}.png"));
+    file.metadata = Some(serde_json::from_value(metadata).unwrap());
+    db.put_file(&file).unwrap();
+}
+
+fn metadata_query(db: &Database, conditions: &[(&str, MetadataCondition)]) -> Vec<String> {
+    let filter = FileFilter {
+        metadata: conditions
+            .iter()
+            .map(|(key, condition)| MetadataFilter {
+                key: key.to_string(),
+                condition: condition.clone(),
+            })
+            .collect(),
+        ..Default::default()
+    };
+    let page = db
+        .query_files(&filter, Sort::default(), None, 0, 10)
+        .unwrap();
+    assert_eq!(page.total, Some(page.files.len() as u64));
+    let mut ids: Vec<String> = page.files.into_iter().map(|f| f.id).collect();
+    ids.sort();
+    ids
+}
+
+#[test]
+fn test_query_files_by_metadata() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = DatabaseOptions {
+        indexed_metadata_keys: vec!["campaign".to_string()],
+    };
+    let db = Database::open_with_options(dir.path().join("data"), &options).unwrap();
+    put_file_with_metadata(
+        &db,
+        "m1",
+        serde_json::json!({"campaign": "q3", "source": "import"}),
+    );
+    put_file_with_metadata(&db, "m2", serde_json::json!({"campaign": "q4", "rank": 2}));
+    put_file_with_metadata(
+        &db,
+        "m3",
+        serde_json::json!({"source": "upload", "rank": 2}),
+    );
+
+    // Indexed key
+    let q3 = MetadataCondition::Equals("q3".to_string());
+    assert_eq!(metadata_query(&db, &[("campaign", q3.clone())]), ["m1"]);
+    let either = MetadataCondition::In(vec!["q3".to_string(), "q4".to_string()]);
+    assert_eq!(metadata_query(&db, &[("campaign", either)]), ["m1", "m2"]);
+    let missing = MetadataCondition::Exists(false);
+    assert_eq!(metadata_query(&db, &[("campaign", missing)]), ["m3"]);
+
+    // Unindexed keys, including non-string values
+    let rank = MetadataCondition::Equals("2".to_string());
+    assert_eq!(metadata_query(&db, &[("rank", rank.clone())]), ["m2", "m3"]);
+    let has_source = MetadataCondition::Exists(true);
+    assert_eq!(
+        metadata_query(&db, &[("source", has_source), ("rank", rank)]),
+        ["m3"]
+    );
+
+    // The index follows updates
+    let metadata = serde_json::from_value(serde_json::json!({"campaign": "q4"})).unwrap();
+    db.update_file(
+        "m1",
+        None,
+        None,
+        None,
+        Some(Some(&metadata)),
+        None,
+        None,
+        None,
+        None,
+        Utc::now(),
+    )
+    .unwrap();
+    assert!(metadata_query(&db, &[("campaign", q3)]).is_empty());
+}
+
+#[test]
+fn test_open_rebuilds_metadata_index_when_keys_change() {
+    let dir = tempfile::tempdir().unwrap();
+    let data_dir = dir.path().join("data");
+    {
+        let db = Database::open(&data_dir).unwrap();
+        put_file_with_metadata(&db, "k1", serde_json::json!({"source": "import"}));
+        put_file_with_metadata(&db, "k2", serde_json::json!({"source": "upload"}));
+    }
+
+    let options = DatabaseOptions {
+        indexed_metadata_keys: vec!["source".to_string()],
+    };
+    let db = Database::open_with_options(&data_dir, &options).unwrap();
+    let read_txn = db.begin_read().unwrap();
+    let counts = read_txn.open_table(INDEX_COUNTS).unwrap();
+    assert_eq!(
+        counts
+            .get("metadata:default\0source")
+            .unwrap()
+            .map(|v| v.value()),
+        Some(2)
+    );
+    assert_eq!(
+        counts
+            .get("metadata:default\0source\0import")
+            .unwrap()
+            .map(|v| v.value()),
+        Some(1)
+    );
+    drop(counts);
+    drop(read_txn);
+
+    let import = MetadataCondition::Equals("import".to_string());
+    assert_eq!(metadata_query(&db, &[("source", import)]), ["k1"]);
+}
+
+fn search_ids(db: &Database, query: SearchQuery) -> Vec<String> {
+    let page = db.search_files(&query, 0, 10).unwrap();
+    assert_eq!(page.total as usize, page.hits.len());
+    page.hits.into_iter().map(|hit| hit.file.id).collect()
+}
+
+fn text_search(text: &str) -> SearchQuery {
+    SearchQuery {
+        text: text.to_string(),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_search_files() {
+    let (_dir, db) = test_db();
+    let mut hero = sample_file("hero", "marketing/hero-banner.png");
+    hero.name = Some("Beach Sale Banner".to_string());
+    hero.alt = Some("Beach at sunset".to_string());
+    db.put_file(&hero).unwrap();
+
+    let mut beach = sample_file_with_subject("beach", "photos/IMG_001.jpg", "user-s");
+    beach.name = Some("Holiday".to_string());
+    beach.alt = None;
+    beach.description = Some("A day at the beach".to_string());
+    beach.metadata =
+        Some(serde_json::from_value(serde_json::json!({"location": "Sunset Beach"})).unwrap());
+    db.put_file(&beach).unwrap();
+
+    let mut report = sample_file("report", "docs/q3-report.pdf");
+    report.name = Some("Quarterly report".to_string());
+    report.alt = None;
+    report.file_type = FileType::Document;
+    db.put_file(&report).unwrap();
+
+    // Name and alt matches rank above description and metadata matches
+    assert_eq!(search_ids(&db, text_search("beach")), ["hero", "beach"]);
+    // Every word must match
+    assert_eq!(search_ids(&db, text_search("sunset holiday")), ["beach"]);
+    // Prefixes and permalink segments
+    assert_eq!(search_ids(&db, text_search("quart")), ["report"]);
+    assert_eq!(search_ids(&db, text_search("Q3")), ["report"]);
+    assert_eq!(search_ids(&db, text_search("img_001")), ["beach"]);
+    assert!(search_ids(&db, text_search("missing")).is_empty());
+    assert!(search_ids(&db, text_search("  ")).is_empty());
+
+    let filtered = SearchQuery {
+        subject_id: Some("user-s".to_string()),
+        ..text_search("beach")
+    };
+    assert_eq!(search_ids(&db, filtered), ["beach"]);
+    let filtered = SearchQuery {
+        file_types: vec![FileType::Document],
+        ..text_search("beach")
+    };
+    assert!(search_ids(&db, filtered).is_empty());
+}
+
+#[test]
+fn test_search_index_follows_updates_and_deletes() {
+    let (_dir, db) = test_db();
+    db.put_file(&sample_file("sx", "sx.png")).unwrap();
+    assert_eq!(search_ids(&db, text_search("test file")), ["sx"]);
+
+    db.update_file(
+        "sx",
+        None,
+        None,
+        None,
+        None,
+        Some(Some("Renamed")),
+        None,
+        None,
+        None,
+        Utc::now(),
+    )
+    .unwrap();
+    assert!(search_ids(&db, text_search("file")).is_empty());
+    assert_eq!(search_ids(&db, text_search("renamed")), ["sx"]);
+
+    db.delete_file("sx").unwrap();
+    assert!(search_ids(&db, text_search("renamed")).is_empty());
+    let read_txn = db.begin_read().unwrap();
+    let counts = read_txn.open_table(INDEX_COUNTS).unwrap();
+    assert!(counts.get("search:renamed").unwrap().is_none());
+}
+
+#[test]
+fn test_query_files_by_tags() {
+    let (_dir, db) = test_db();
+    for (id, tags) in [
+        ("t1", vec!["hero"]),
+        ("t2", vec!["archived", "hero"]),
+        ("t3", vec!["archived"]),
+        ("t4", vec![]),
+    ] {
+        let mut file = sample_file(id, &format!("{id}.png"));
+        file.tags = tags.into_iter().map(String::from).collect();
+        db.put_file(&file).unwrap();
+    }
+
+    let query = |tags: &[&str], tag_match: TagMatch| -> Vec<String> {
+        let filter = FileFilter {
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            tag_match,
+            ..Default::default()
+        };
+        let page = db
+            .query_files(&filter, Sort::default(), None, 0, 10)
+            .unwrap();
+        assert_eq!(page.total, Some(page.files.len() as u64));
+        let mut ids: Vec<String> = page.files.into_iter().map(|f| f.id).collect();
+        ids.sort();
+        ids
+    };
+    assert_eq!(query(&["hero"], TagMatch::All), ["t1", "t2"]);
+    assert_eq!(query(&["hero", "archived"], TagMatch::All), ["t2"]);
+    assert_eq!(
+        query(&["hero", "archived"], TagMatch::Any),
+        ["t1", "t2", "t3"]
+    );
+    assert!(query(&["missing"], TagMatch::Any).is_empty());
+
+    assert_eq!(
+        db.list_tags(DEFAULT_NAMESPACE).unwrap(),
+        [("archived".to_string(), 2), ("hero".to_string(), 2)]
+    );
+
+    db.remove_tags("t2", &["hero".to_string()], Utc::now())
+        .unwrap();
+    db.add_tags("t4", &["new".to_string()], Utc::now()).unwrap();
+    assert_eq!(query(&["hero"], TagMatch::All), ["t1"]);
+    assert_eq!(
+        db.list_tags(DEFAULT_NAMESPACE).unwrap(),
+        [
+            ("archived".to_string(), 2),
+            ("hero".to_string(), 1),
+            ("new".to_string(), 1)
+        ]
+    );
+    assert!(!db
+        .add_tags("missing", &["x".to_string()], Utc::now())
+        .unwrap());
+}
+
+fn put_permalinks(db: &Database, permalinks: &[&str]) {
+    for (i, permalink) in permalinks.iter().enumerate() {
+        db.put_file(&sample_file(&format!("p{i}"), permalink))
+            .unwrap();
+    }
+}
+
+#[test]
+fn test_list_folder() {
+    let (_dir, db) = test_db();
+    put_permalinks(
+        &db,
+        &[
+            "docs/2023/a.pdf",
+            "docs/2024/b.pdf",
+            "docs/2024/q1/c.pdf",
+            "docs/readme.txt",
+            "docs-old/x.pdf",
+            "logo.png",
+        ],
+    );
+
+    let page = db
+        .list_folder(DEFAULT_NAMESPACE, "docs/", Some("/"), None, 10)
+        .unwrap();
+    assert_eq!(page.prefixes, ["docs/2023/", "docs/2024/"]);
+    let files: Vec<&str> = page.files.iter().map(|f| f.permalink.as_str()).collect();
+    assert_eq!(files, ["docs/readme.txt"]);
+    assert!(page.next_after.is_none());
+
+    let root = db
+        .list_folder(DEFAULT_NAMESPACE, "", Some("/"), None, 10)
+        .unwrap();
+    assert_eq!(root.prefixes, ["docs-old/", "docs/"]);
+    assert_eq!(root.files.len(), 1);
+
+    // Without a delimiter the listing is recursive
+    let all = db
+        .list_folder(DEFAULT_NAMESPACE, "docs/2024/", None, None, 10)
+        .unwrap();
+    assert!(all.prefixes.is_empty());
+    assert_eq!(all.files.len(), 2);
+}
+
+#[test]
+fn test_list_folder_pagination() {
+    let (_dir, db) = test_db();
+    put_permalinks(
+        &db,
+        &["d/a/1", "d/a/2", "d/b", "d/c/1", "d/c/2", "d/d", "d/e/1"],
+    );
+
+    let mut entries = Vec::new();
+    let mut after: Option<String> = None;
+    loop {
+        let page = db
+            .list_folder(DEFAULT_NAMESPACE, "d/", Some("/"), after.as_deref(), 2)
+            .unwrap();
+        assert!(page.files.len() + page.prefixes.len() <= 2);
+        entries.extend(page.prefixes);
+        entries.extend(page.files.into_iter().map(|f| f.permalink));
+        match page.next_after {
+            Some(next) => after = Some(next),
+            None => break,
+        }
+    }
+    entries.sort();
+    assert_eq!(entries, ["d/a/", "d/b", "d/c/", "d/d", "d/e/"]);
+}
+
+#[test]
+fn test_rename_prefix() {
+    let (_dir, db) = test_db();
+    put_permalinks(&db, &["blog/2023/a", "blog/2023/b", "blog/2024/c"]);
+
+    let plan = db
+        .plan_rename_prefix(DEFAULT_NAMESPACE, "blog/2023/", "archive/blog/2023/")
+        .unwrap();
+    assert!(plan.conflicts.is_empty());
+    assert_eq!(plan.renames.len(), 2);
+    // Planning doesn't write anything
+    assert!(db
+        .permalink_exists(DEFAULT_NAMESPACE, "blog/2023/a")
+        .unwrap());
+
+    let plan = db
+        .rename_prefix(
+            DEFAULT_NAMESPACE,
+            "blog/2023/",
+            "archive/blog/2023/",
+            Utc::now(),
+        )
+        .unwrap();
+    assert_eq!(plan.renames.len(), 2);
+    assert!(!db
+        .permalink_exists(DEFAULT_NAMESPACE, "blog/2023/a")
+        .unwrap());
+    assert_eq!(
+        db.get_file_by_permalink(DEFAULT_NAMESPACE, "archive/blog/2023/b")
+            .unwrap()
+            .unwrap()
+            .id,
+        "p1"
+    );
+    assert!(db
+        .permalink_exists(DEFAULT_NAMESPACE, "blog/2024/c")
+        .unwrap());
+    let folder = db
+        .list_folder(DEFAULT_NAMESPACE, "archive/blog/2023/", Some("/"), None, 10)
+        .unwrap();
+    assert_eq!(folder.files.len(), 2);
+}
+
+#[test]
+fn test_rename_prefix_skips_missing_records() {
+    let (_dir, db) = test_db();
+    put_permalinks(&db, &["a/x", "a/y", "a/z"]);
+    // A permalink left indexed for a record that's gone
+    let write_txn = db.begin_write().unwrap();
+    write_txn.open_table(FILES).unwrap().remove("p1").unwrap();
+    write_txn.commit().unwrap();
+
+    let renamed_at = Utc::now();
+    db.rename_prefix(DEFAULT_NAMESPACE, "a/", "b/", renamed_at)
+        .unwrap();
+    let file = db.get_file("p0").unwrap().unwrap();
+    assert_eq!(file.permalink, "b/x");
+    assert_eq!(file.updated_at, renamed_at);
+    assert_eq!(db.get_file("p2").unwrap().unwrap().permalink, "b/z");
+}
+
+#[test]
+fn test_rename_prefix_conflicts() {
+    let (_dir, db) = test_db();
+    put_permalinks(&db, &["a/x", "a/y", "b/y"]);
+
+    let plan = db
+        .rename_prefix(DEFAULT_NAMESPACE, "a/", "b/", Utc::now())
+        .unwrap();
+    assert_eq!(plan.conflicts.len(), 1);
+    assert_eq!(plan.conflicts[0].permalink, "b/y");
+    assert_eq!(plan.conflicts[0].file_id, "p2");
+    // All or nothing
+    assert!(db.permalink_exists(DEFAULT_NAMESPACE, "a/x").unwrap());
+    assert!(!db.permalink_exists(DEFAULT_NAMESPACE, "b/x").unwrap());
+
+    // Targets owned by files that are moving themselves are not conflicts
+    db.put_file(&sample_file("nested", "a/a/x")).unwrap();
+    let plan = db
+        .rename_prefix(DEFAULT_NAMESPACE, "a/", "a/a/", Utc::now())
+        .unwrap();
+    assert!(plan.conflicts.is_empty());
+    assert_eq!(plan.renames.len(), 3);
+    assert_eq!(
+        db.get_file_by_permalink(DEFAULT_NAMESPACE, "a/a/a/x")
+            .unwrap()
+            .unwrap()
+            .id,
+        "nested"
+    );
+    assert_eq!(
+        db.get_file_by_permalink(DEFAULT_NAMESPACE, "a/a/x")
+            .unwrap()
+            .unwrap()
+            .id,
+        "p0"
+    );
+    assert!(!db.permalink_exists(DEFAULT_NAMESPACE, "a/x").unwrap());
+    // A permalink that is live again no longer redirects
+    assert_eq!(
+        db.get_redirect(DEFAULT_NAMESPACE, "a/x")
+            .unwrap()
+            .as_deref(),
+        Some("p0")
+    );
+    assert_eq!(db.get_redirect(DEFAULT_NAMESPACE, "a/a/x").unwrap(), None);
+}
+
+#[test]
+fn test_permalink_redirects() {
+    let (_dir, db) = test_db();
+    db.put_file(&sample_file("r", "old.png")).unwrap();
+
+    db.update_file(
+        "r",
+        None,
+        None,
+        None,
+        None,
+        None,
+        Some("new.png"),
+        None,
+        None,
+        Utc::now(),
+    )
+    .unwrap();
+    assert_eq!(
+        db.get_redirect(DEFAULT_NAMESPACE, "old.png")
+            .unwrap()
+            .as_deref(),
+        Some("r")
+    );
+    assert!(db
+        .permalink_in_use(DEFAULT_NAMESPACE, "old.png", None)
+        .unwrap());
+    // A file may move back to one of its own former permalinks
+    assert!(!db
+        .permalink_in_use(DEFAULT_NAMESPACE, "old.png", Some("r"))
+        .unwrap());
+
+    db.update_file(
+        "r",
+        None,
+        None,
+        None,
+        None,
+        None,
+        Some("old.png"),
+        None,
+        None,
+        Utc::now(),
+    )
+    .unwrap();
+    assert_eq!(db.get_redirect(DEFAULT_NAMESPACE, "old.png").unwrap(), None);
+    assert_eq!(
+        db.list_redirects(DEFAULT_NAMESPACE, None, None, 10)
+            .unwrap(),
+        [Redirect {
+            namespace: DEFAULT_NAMESPACE.to_string(),
+            permalink: "new.png".to_string(),
+            file_id: "r".to_string(),
+        }]
+    );
+
+    // Unchanged permalinks don't redirect to themselves
+    db.update_file(
+        "r",
+        None,
+        None,
+        None,
+        None,
+        None,
+        Some("old.png"),
+        None,
+        None,
+        Utc::now(),
+    )
+    .unwrap();
+    assert_eq!(db.get_all_redirects().unwrap().len(), 1);
+
+    assert!(db.delete_redirect(DEFAULT_NAMESPACE, "new.png").unwrap());
+    assert!(!db.delete_redirect(DEFAULT_NAMESPACE, "new.png").unwrap());
+    assert!(!db
+        .permalink_in_use(DEFAULT_NAMESPACE, "new.png", None)
+        .unwrap());
+}
+
+#[test]
+fn test_list_redirects_by_file() {
+    let (_dir, db) = test_db();
+    db.put_file(&sample_file("a", "a0")).unwrap();
+    db.put_file(&sample_file("b", "b0")).unwrap();
+    for i in 1..4 {
+        db.update_file(
+            "a",
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(&format!("a{i}")),
+            None,
+            None,
+            Utc::now(),
+        )
+        .unwrap();
+    }
+    db.update_file(
+        "b",
+        None,
+        None,
+        None,
+        None,
+        None,
+        Some("b1"),
+        None,
+        None,
+        Utc::now(),
+    )
+    .unwrap();
+
+    let permalinks = |redirects: Vec<Redirect>| -> Vec<String> {
+        redirects.into_iter().map(|r| r.permalink).collect()
+    };
+    assert_eq!(
+        permalinks(
+            db.list_redirects(DEFAULT_NAMESPACE, Some("a"), None, 2)
+                .unwrap()
+        ),
+        ["a0", "a1"]
+    );
+    assert_eq!(
+        permalinks(
+            db.list_redirects(DEFAULT_NAMESPACE, Some("a"), Some("a1"), 10)
+                .unwrap()
+        ),
+        ["a2"]
+    );
+    assert_eq!(
+        permalinks(
+            db.list_redirects(DEFAULT_NAMESPACE, None, Some("a2"), 10)
+                .unwrap()
+        ),
+        ["b0"]
+    );
+
+    // Deleting a file drops its redirects
+    db.delete_file("a").unwrap();
+    assert_eq!(permalinks(db.get_all_redirects().unwrap()), ["b0"]);
+    assert_eq!(db.get_redirect(DEFAULT_NAMESPACE, "a0").unwrap(), None);
+}
+
+#[test]
+fn test_rename_prefix_conflicts_with_redirects() {
+    let (_dir, db) = test_db();
+    put_permalinks(&db, &["a/x", "b/z"]);
+    db.update_file(
+        "p1",
+        None,
+        None,
+        None,
+        None,
+        None,
+        Some("c/z"),
+        None,
+        None,
+        Utc::now(),
+    )
+    .unwrap();
+    db.update_file(
+        "p1",
+        None,
+        None,
+        None,
+        None,
+        None,
+        Some("b/x"),
+        None,
+        None,
+        Utc::now(),
+    )
+    .unwrap();
+    db.update_file(
+        "p1",
+        None,
+        None,
+        None,
+        None,
+        None,
+        Some("c/x"),
+        None,
+        None,
+        Utc::now(),
+    )
+    .unwrap();
+
+    // b/x redirects to p1, so p0 can't take it
+    let plan = db
+        .plan_rename_prefix(DEFAULT_NAMESPACE, "a/", "b/")
+        .unwrap();
+    assert_eq!(
+        plan.conflicts,
+        [PermalinkConflict {
+            permalink: "b/x".to_string(),
+            file_id: "p1".to_string(),
+        }]
+    );
+
+    // A file may take back its own former permalink
+    let plan = db
+        .rename_prefix(DEFAULT_NAMESPACE, "c/", "b/", Utc::now())
+        .unwrap();
+    assert!(plan.conflicts.is_empty());
+    assert_eq!(db.get_file("p1").unwrap().unwrap().permalink, "b/x");
+    assert_eq!(
+        db.get_redirect(DEFAULT_NAMESPACE, "c/x")
+            .unwrap()
+            .as_deref(),
+        Some("p1")
+    );
+}
+
+#[test]
+fn test_indexes_follow_updates_and_deletes() {
+    let (_dir, db) = test_db();
+    db.put_file(&sample_file_with_subject("ix", "ix.png", "user-a"))
+        .unwrap();
+
+    db.update_file(
+        "ix",
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        Some(Some("user-b")),
+        None,
+        Utc::now(),
+    )
+    .unwrap();
+    let filter = FileFilter {
+        subject_id: Some("user-a".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(
+        db.query_files(&filter, Sort::default(), None, 0, 10)
+            .unwrap()
+            .total,
+        Some(0)
+    );
+
+    db.delete_file("ix").unwrap();
+    let filter = FileFilter {
+        file_types: vec![FileType::Image],
+        ..Default::default()
+    };
+    let page = db
+        .query_files(&filter, Sort::default(), None, 0, 10)
+        .unwrap();
+    assert_eq!(page.total, Some(0));
+    assert!(page.files.is_empty());
+}
+
+#[test]
+fn test_open_rebuilds_indexes_for_legacy_database() {
+    let dir = tempfile::tempdir().unwrap();
+    let data_dir = dir.path().join("data");
+    {
+        let db = Database::open(&data_dir).unwrap();
+        db.put_file(&sample_file_with_subject("mig-a", "miga.png", "user-m"))
+            .unwrap();
+        db.put_file(&sample_file_with_subject("mig-b", "migb.png", "user-m"))
+            .unwrap();
+
+        // Simulate a database written before the secondary indexes existed
+        let legacy: redb::TableDefinition<&str, &[u8]> =
+            redb::TableDefinition::new("subject_files");
+        let write_txn = db.begin_write().unwrap();
+        {
+            let data = rmp_serde::to_vec_named(&vec!["mig-a", "mig-b"]).unwrap();
+            let mut table = write_txn.open_table(legacy).unwrap();
+            table.insert("user-m", data.as_slice()).unwrap();
+            write_txn.delete_table(META).unwrap();
+            write_txn.delete_table(SUBJECT_FILES).unwrap();
+            write_txn.delete_table(FILE_TYPE_FILES).unwrap();
+            write_txn.delete_table(INDEX_COUNTS).unwrap();
+        }
+        write_txn.commit().unwrap();
+    }
+
+    let db = Database::open(&data_dir).unwrap();
+    let files = db
+        .get_files_by_subject(DEFAULT_NAMESPACE, "user-m")
+        .unwrap();
+    assert_eq!(files.len(), 2);
+    assert_eq!(
+        db.list_files(DEFAULT_NAMESPACE, Some("image"), None)
+            .unwrap()
+            .len(),
+        2
+    );
+
+    let read_txn = db.begin_read().unwrap();
+    assert!(read_txn
+        .list_tables()
+        .unwrap()
+        .all(|t| redb::TableHandle::name(&t) != "subject_files"));
+}
+
+fn sample_version(version: u32, blob_key: &str, mime_type: &str, byte_size: u64) -> FileVersion {
+    FileVersion {
+        version,
+        blob_key: blob_key.to_string(),
+        byte_size,
+        mime_type: mime_type.to_string(),
+        sha256: Some("00".to_string()),
+        created_at: Utc::now(),
+    }
+}
+
+#[test]
+fn test_file_versions() {
+    let (_dir, db) = test_db();
+    // Files uploaded before versioning have an implicit first version
+    db.put_file(&sample_file("v", "terms.png")).unwrap();
+    let file = db.get_file("v").unwrap().unwrap();
+    assert_eq!(file.current_version().version, 1);
+    assert_eq!(file.current_version().blob_key, "v");
+
+    let pdf = sample_version(2, "blob-2", "application/pdf", 4096);
+    assert!(db.add_version("v", &pdf).unwrap());
+    let file = db.get_file("v").unwrap().unwrap();
+    assert_eq!(file.versions.len(), 2);
+    assert_eq!(file.versions[0].blob_key, "v");
+    assert_eq!(file.current_version(), pdf);
+    assert_eq!(file.byte_size, 4096);
+    assert_eq!(file.file_type, FileType::Document);
+    assert_eq!(file.permalink, "terms.png");
+
+    // Secondary indexes follow the current version
+    assert!(db
+        .list_files(DEFAULT_NAMESPACE, Some("image"), None)
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        db.list_files(DEFAULT_NAMESPACE, Some("document"), None)
+            .unwrap()
+            .len(),
+        1
+    );
+
+    // A version number can only be claimed once
+    let stale = sample_version(2, "blob-stale", "image/png", 1);
+    assert!(!db.add_version("v", &stale).unwrap());
+    assert!(!db.add_version("missing", &pdf).unwrap());
+
+    let restored_at = Utc::now();
+    assert!(db.restore_version("v", 1, restored_at).unwrap());
+    let file = db.get_file("v").unwrap().unwrap();
+    let current = file.current_version();
+    assert_eq!(current.version, 3);
+    assert_eq!(current.created_at, restored_at);
+    assert_eq!(file.updated_at, restored_at);
+    assert_eq!(current.blob_key, "v");
+    assert_eq!(file.mime_type, "image/png");
+    assert_eq!(file.blob_keys(), ["blob-2", "v"]);
+
+    assert!(!db.restore_version("v", 9, Utc::now()).unwrap());
+    assert_eq!(db.get_file("v").unwrap().unwrap().versions.len(), 3);
+}
+
+#[test]
+fn test_trash_and_restore() {
+    let (_dir, db) = test_db();
+    let mut file = sample_file_with_subject("t", "docs/terms.png", "user-t");
+    file.tags = vec!["legal".to_string()];
+    db.put_file(&file).unwrap();
+    db.put_file(&sample_file("keep", "docs/keep.png")).unwrap();
+
+    let deleted_at = Utc::now();
+    assert!(db.trash_file("t", deleted_at).unwrap());
+    assert!(!db.trash_file("t", deleted_at).unwrap());
+
+    // Hidden from listings, search, tags and folders
+    let page = db
+        .query_files(&FileFilter::default(), Sort::default(), None, 0, 10)
+        .unwrap();
+    assert_eq!(page.total, Some(1));
+    assert_eq!(page.files[0].id, "keep");
+    assert!(db
+        .get_files_by_subject(DEFAULT_NAMESPACE, "user-t")
+        .unwrap()
+        .is_empty());
+    assert!(search_ids(&db, text_search("terms")).is_empty());
+    assert!(db.list_tags(DEFAULT_NAMESPACE).unwrap().is_empty());
+    let folder = db
+        .list_folder(DEFAULT_NAMESPACE, "docs/", Some("/"), None, 10)
+        .unwrap();
+    assert_eq!(folder.files.len(), 1);
+
+    // The permalink stays reserved
+    assert!(db
+        .permalink_exists(DEFAULT_NAMESPACE, "docs/terms.png")
+        .unwrap());
+    assert_eq!(
+        db.get_file("t").unwrap().unwrap().deleted_at,
+        Some(deleted_at)
+    );
+
+    let trash = db.list_trash(DEFAULT_NAMESPACE, 0, 10).unwrap();
+    assert_eq!(trash.total, Some(1));
+    assert_eq!(trash.files[0].id, "t");
+    assert!(db
+        .get_trashed_before(deleted_at, Utc::now(), 10)
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        db.get_trashed_before(deleted_at + chrono::Duration::seconds(1), Utc::now(), 10)
+            .unwrap()
+            .len(),
+        1
+    );
+
+    assert!(db.restore_file("t").unwrap());
+    assert!(!db.restore_file("t").unwrap());
+    assert_eq!(
+        db.list_trash(DEFAULT_NAMESPACE, 0, 10).unwrap().total,
+        Some(0)
+    );
+    assert_eq!(
+        db.get_files_by_subject(DEFAULT_NAMESPACE, "user-t")
+            .unwrap()
+            .len(),
+        1
+    );
+    assert_eq!(
+        db.list_tags(DEFAULT_NAMESPACE).unwrap(),
+        [("legal".to_string(), 1)]
+    );
+
+    // Deleting a trashed file clears it from the trash index
+    db.trash_file("t", deleted_at).unwrap();
+    db.delete_file("t").unwrap();
+    assert_eq!(
+        db.list_trash(DEFAULT_NAMESPACE, 0, 10).unwrap().total,
+        Some(0)
+    );
+    assert!(!db
+        .permalink_exists(DEFAULT_NAMESPACE, "docs/terms.png")
+        .unwrap());
+}
+
+#[test]
+fn test_list_trash_newest_first() {
+    let (_dir, db) = test_db();
+    let start = Utc::now();
+    for i in 0..3 {
+        let id = format!("t{i}");
+        db.put_file(&sample_file(&id, &format!("{id}.png")))
+            .unwrap();
+        db.trash_file(&id, start + chrono::Duration::minutes(i))
+            .unwrap();
+    }
+
+    let ids = |page: file_manager::storage::query::FilePage| -> Vec<String> {
+        page.files.into_iter().map(|f| f.id).collect()
+    };
+    let first = db.list_trash(DEFAULT_NAMESPACE, 0, 2).unwrap();
+    // The total is only given once the last page is reached
+    assert_eq!(first.total, None);
+    assert_eq!(ids(first), ["t2", "t1"]);
+    let last = db.list_trash(DEFAULT_NAMESPACE, 2, 2).unwrap();
+    assert_eq!(last.total, Some(3));
+    assert_eq!(ids(last), ["t0"]);
+    assert_eq!(
+        db.list_trash(DEFAULT_NAMESPACE, 5, 2).unwrap().total,
+        Some(3)
+    );
+    // Other namespaces' trash isn't read
+    assert_eq!(db.list_trash("acme", 0, 2).unwrap().total, Some(0));
+}
+
+#[test]
+fn test_expiring_files() {
+    let (_dir, db) = test_db();
+    let now = Utc::now();
+    let mut soon = sample_file("soon", "soon.png");
+    soon.expires_at = Some(now - chrono::Duration::minutes(1));
+    db.put_file(&soon).unwrap();
+    let mut later = sample_file("later", "later.png");
+    later.expires_at = Some(now + chrono::Duration::days(1));
+    db.put_file(&later).unwrap();
+    db.put_file(&sample_file("never", "never.png")).unwrap();
+
+    let expired = db.get_expired(now, 10).unwrap();
+    assert_eq!(expired.len(), 1);
+    assert_eq!(expired[0].id, "soon");
+    assert!(expired[0].is_expired(now));
+    assert!(!later.is_expired(now));
+
+    // Trashed files still expire, and updates move the expiry
+    db.trash_file("soon", now).unwrap();
+    assert_eq!(db.get_expired(now, 10).unwrap().len(), 1);
+    db.update_file(
+        "soon",
+        None,
+        None,
+        Some(None),
+        None,
+        None,
+        None,
+        None,
+        None,
+        Utc::now(),
+    )
+    .unwrap();
+    assert!(db.get_expired(now, 10).unwrap().is_empty());
+    let tomorrow = now + chrono::Duration::days(1);
+    db.update_file(
+        "never",
+        None,
+        None,
+        Some(Some(tomorrow)),
+        None,
+        None,
+        None,
+        None,
+        None,
+        Utc::now(),
+    )
+    .unwrap();
+    assert_eq!(db.get_expired(tomorrow, 10).unwrap().len(), 2);
+
+    db.delete_file("later").unwrap();
+    let ids: Vec<String> = db
+        .get_expired(tomorrow, 10)
+        .unwrap()
+        .into_iter()
+        .map(|f| f.id)
+        .collect();
+    assert_eq!(ids, ["never"]);
+}
+
+#[test]
+fn test_reapable_files_skip_locked_ones() {
+    let (_dir, db) = test_db();
+    let now = Utc::now();
+    for i in 0..4 {
+        let mut file = sample_file(&format!("f{i}"), &format!("f{i}.png"));
+        file.expires_at = Some(now - chrono::Duration::minutes(10 - i));
+        // The two oldest are locked
+        file.legal_hold = i == 0;
+        file.retain_until = (i == 1).then(|| now + chrono::Duration::days(1));
+        db.put_file(&file).unwrap();
+        db.trash_file(&file.id, now - chrono::Duration::minutes(10 - i))
+            .unwrap();
+    }
+
+    let ids = |files: Vec<FileRecord>| -> Vec<String> { files.into_iter().map(|f| f.id).collect() };
+    assert_eq!(ids(db.get_expired(now, 2).unwrap()), ["f2", "f3"]);
+    assert_eq!(ids(db.get_trashed_before(now, now, 1).unwrap()), ["f2"]);
+
+    // Retention is checked at the time given
+    let later = now + chrono::Duration::days(2);
+    assert_eq!(ids(db.get_expired(later, 2).unwrap()), ["f1", "f2"]);
+}
+
+#[test]
+fn test_set_retention() {
+    let (_dir, db) = test_db();
+    db.put_file(&sample_file("r", "r.png")).unwrap();
+    let now = Utc::now();
+    assert!(db.get_file("r").unwrap().unwrap().lock(now).is_none());
+
+    assert!(db.set_retention("r", Some(true), None, Utc::now()).unwrap());
+    let file = db.get_file("r").unwrap().unwrap();
+    assert!(file.legal_hold);
+    assert_eq!(file.lock(now), Some(FileLock::LegalHold));
+
+    let until = now + chrono::Duration::days(30);
+    assert!(db
+        .set_retention("r", Some(false), Some(Some(until)), Utc::now())
+        .unwrap());
+    let file = db.get_file("r").unwrap().unwrap();
+    assert!(!file.legal_hold);
+    assert_eq!(file.lock(now), Some(FileLock::Retention(until)));
+    // Retention stops locking the file once it has passed
+    assert!(file.lock(until + chrono::Duration::seconds(1)).is_none());
+
+    assert!(db.set_retention("r", None, Some(None), Utc::now()).unwrap());
+    assert!(db.get_file("r").unwrap().unwrap().lock(now).is_none());
+    assert!(!db
+        .set_retention("missing", Some(true), None, Utc::now())
+        .unwrap());
+}
+
+fn sample_file_in(namespace: &str, id: &str, permalink: &str) -> FileRecord {
+    let mut file = sample_file_with_subject(id, permalink, "user-n");
+    file.namespace = namespace.to_string();
+    file.tags = vec!["shared".to_string()];
+    file
+}
+
+#[test]
+fn test_namespaces_are_isolated() {
+    let (_dir, db) = test_db();
+    db.put_file(&sample_file_in(DEFAULT_NAMESPACE, "d1", "docs/logo.png"))
+        .unwrap();
+    db.put_file(&sample_file_in("acme", "a1", "docs/logo.png"))
+        .unwrap();
+    db.put_file(&sample_file_in("acme", "a2", "docs/icon.png"))
+        .unwrap();
+
+    // The same permalink resolves per namespace
+    let by_permalink = |ns| db.get_file_by_permalink(ns, "docs/logo.png").unwrap();
+    assert_eq!(by_permalink(DEFAULT_NAMESPACE).unwrap().id, "d1");
+    assert_eq!(by_permalink("acme").unwrap().id, "a1");
+    assert!(by_permalink("other").is_none());
+
+    let ids = |files: Vec<FileRecord>| {
+        let mut ids: Vec<_> = files.into_iter().map(|f| f.id).collect();
+        ids.sort();
+        ids
+    };
+    assert_eq!(
+        ids(db.get_files_by_subject("acme", "user-n").unwrap()),
+        ["a1", "a2"]
+    );
+    assert_eq!(
+        ids(db.list_files("acme", None, None).unwrap()),
+        ["a1", "a2"]
+    );
+    assert_eq!(
+        db.list_tags(DEFAULT_NAMESPACE).unwrap(),
+        [("shared".to_string(), 1)]
+    );
+    assert_eq!(db.list_tags("acme").unwrap(), [("shared".to_string(), 2)]);
+
+    let page = db
+        .query_files(
+            &FileFilter {
+                namespace: "acme".to_string(),
+                tags: vec!["shared".to_string()],
+                ..Default::default()
+            },
+            Sort::default(),
+            None,
+            0,
+            10,
+        )
+        .unwrap();
+    assert_eq!(
+        (ids(page.files), page.total),
+        (vec!["a1".into(), "a2".into()], Some(2))
+    );
+    let page = db
+        .query_files(&FileFilter::default(), Sort::default(), None, 0, 10)
+        .unwrap();
+    assert_eq!((ids(page.files), page.total), (vec!["d1".into()], Some(1)));
+
+    let search = SearchQuery {
+        namespace: "acme".to_string(),
+        ..text_search("logo")
+    };
+    let page = db.search_files(&search, 0, 10).unwrap();
+    assert_eq!(page.hits.len(), 1);
+    assert_eq!(page.hits[0].file.id, "a1");
+
+    let folder = db.list_folder("acme", "", Some("/"), None, 10).unwrap();
+    assert_eq!(folder.prefixes, ["docs/"]);
+    let folder = db
+        .list_folder("acme", "docs/", Some("/"), None, 10)
+        .unwrap();
+    assert_eq!(ids(folder.files), ["a1", "a2"]);
+
+    // Redirects and the trash are per namespace as well
+    db.update_file(
+        "a1",
+        None,
+        None,
+        None,
+        None,
+        None,
+        Some("docs/moved.png"),
+        None,
+        None,
+        Utc::now(),
+    )
+    .unwrap();
+    assert_eq!(
+        db.get_redirect("acme", "docs/logo.png").unwrap().as_deref(),
+        Some("a1")
+    );
+    assert!(db.permalink_in_use("acme", "docs/logo.png", None).unwrap());
+    assert!(db
+        .list_redirects(DEFAULT_NAMESPACE, None, None, 10)
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        db.get_all_redirects().unwrap(),
+        [Redirect {
+            namespace: "acme".to_string(),
+            permalink: "docs/logo.png".to_string(),
+            file_id: "a1".to_string(),
+        }]
+    );
+
+    db.trash_file("a2", Utc::now()).unwrap();
+    assert_eq!(
+        db.list_trash(DEFAULT_NAMESPACE, 0, 10).unwrap().total,
+        Some(0)
+    );
+    assert_eq!(ids(db.list_trash("acme", 0, 10).unwrap().files), ["a2"]);
+}
+
+fn audit_entry(file_id: &str, actor: &str, minutes_ago: i64) -> AuditEntry {
+    AuditEntry {
+        seq: 0,
+        timestamp: Utc::now() - chrono::Duration::minutes(minutes_ago),
+        namespace: DEFAULT_NAMESPACE.to_string(),
+        file_id: Some(file_id.to_string()),
+        actor: Some(actor.to_string()),
+        request_id: None,
+        source_node: None,
+        op: WriteOp::DeleteFile {
+            id: file_id.to_string(),
+        },
+        changes: BTreeMap::new(),
+    }
+}
+
+#[test]
+fn test_list_and_prune_audit() {
+    let (_dir, db) = test_db();
+    db.append_audit(vec![
+        audit_entry("a", "alice", 30),
+        audit_entry("b", "bob", 20),
+        audit_entry("a", "bob", 10),
+    ])
+    .unwrap();
+    let mut other = audit_entry("a", "alice", 5);
+    other.namespace = "acme".to_string();
+    db.append_audit(vec![other]).unwrap();
+
+    let seqs = |entries: Vec<AuditEntry>| entries.iter().map(|e| e.seq).collect::<Vec<_>>();
+    let filter = AuditFilter {
+        namespace: DEFAULT_NAMESPACE.to_string(),
+        ..Default::default()
+    };
+    assert_eq!(seqs(db.list_audit(&filter, None, 10).unwrap()), [3, 2, 1]);
+    assert_eq!(seqs(db.list_audit(&filter, None, 2).unwrap()), [3, 2]);
+    assert_eq!(seqs(db.list_audit(&filter, Some(2), 10).unwrap()), [1]);
+
+    let by_file = AuditFilter {
+        file_id: Some("a".to_string()),
+        ..filter.clone()
+    };
+    assert_eq!(seqs(db.list_audit(&by_file, None, 10).unwrap()), [3, 1]);
+    let by_actor = AuditFilter {
+        actor: Some("bob".to_string()),
+        ..filter.clone()
+    };
+    assert_eq!(seqs(db.list_audit(&by_actor, None, 10).unwrap()), [3, 2]);
+    let by_operation = AuditFilter {
+        operation: Some("update_file".to_string()),
+        ..filter.clone()
+    };
+    assert!(db.list_audit(&by_operation, None, 10).unwrap().is_empty());
+
+    assert_eq!(
+        db.prune_audit(Utc::now() - chrono::Duration::minutes(15))
+            .unwrap(),
+        2
+    );
+    assert_eq!(seqs(db.list_audit(&filter, None, 10).unwrap()), [3]);
+    assert_eq!(seqs(db.list_audit(&by_file, None, 10).unwrap()), [3]);
+    let oldest = db.oldest_audit_timestamp().unwrap().unwrap();
+    assert!(oldest > Utc::now() - chrono::Duration::minutes(15));
+
+    // Numbering never restarts
+    db.append_audit(vec![audit_entry("c", "carol", 0)]).unwrap();
+    assert_eq!(db.audit_seq().unwrap(), 5);
+}
+
+#[test]
+fn test_diff_records() {
+    let before = sample_file("a", "a.png");
+    let mut after = before.clone();
+    after.alt = None;
+    after.tags = vec!["hero".to_string()];
+
+    let changes = diff_records(Some(&before), Some(&after)).unwrap();
+    assert_eq!(changes.keys().collect::<Vec<_>>(), ["alt", "tags"]);
+    assert_eq!(changes["alt"].before, serde_json::json!("test alt"));
+    assert!(diff_records(Some(&before), Some(&before))
+        .unwrap()
+        .is_empty());
+
+    let created = diff_records(None, Some(&before)).unwrap();
+    assert_eq!(created["id"].before, serde_json::Value::Null);
+    assert_eq!(created["id"].after, serde_json::json!("a"));
+}
+
+#[test]
+fn test_list_changes_bounds_the_scan() {
+    let (_dir, db) = test_db();
+    let event = |namespace: &str| ChangeEvent {
+        kind: ChangeKind::Created,
+        namespace: Some(namespace.to_string()),
+        file_id: Some("a".to_string()),
+        permalink: Some("a.png".to_string()),
+        previous_permalink: None,
+    };
+    for _ in 0..1200 {
+        db.append_change(Utc::now(), "create_file", vec![event("acme")])
+            .unwrap();
+    }
+    db.append_change(Utc::now(), "create_file", vec![event(DEFAULT_NAMESPACE)])
+        .unwrap();
+
+    // A quiet namespace's page stops partway, saying where to carry on
+    let page = db.list_changes(DEFAULT_NAMESPACE, 0, 10).unwrap();
+    assert!(page.changes.is_empty());
+    assert_eq!((page.next_since, page.last_seq), (1000, 1201));
+    let page = db.list_changes(DEFAULT_NAMESPACE, 1000, 10).unwrap();
+    assert_eq!(page.changes[0].seq, 1201);
+    assert_eq!(page.next_since, 1201);
+
+    // A full page resumes after its last change
+    let page = db.list_changes("acme", 0, 10).unwrap();
+    assert_eq!(page.changes.len(), 10);
+    assert_eq!(page.next_since, 10);
+}
+
+#[test]
+fn test_prune_changes() {
+    let (_dir, db) = test_db();
+    let event = ChangeEvent {
+        kind: ChangeKind::Created,
+        namespace: Some(DEFAULT_NAMESPACE.to_string()),
+        file_id: Some("a".to_string()),
+        permalink: Some("a.png".to_string()),
+        previous_permalink: None,
+    };
+    let old = Utc::now() - chrono::Duration::days(10);
+    assert_eq!(
+        db.append_change(old, "create_file", vec![event.clone()])
+            .unwrap(),
+        1
+    );
+    assert_eq!(db.append_change(old, "prune_audit", Vec::new()).unwrap(), 2);
+    assert_eq!(
+        db.append_change(Utc::now(), "update_file", vec![event])
+            .unwrap(),
+        3
+    );
+
+    assert_eq!(
+        db.prune_changes(Utc::now() - chrono::Duration::days(1))
+            .unwrap(),
+        1
+    );
+    let page = db.list_changes(DEFAULT_NAMESPACE, 1, 10).unwrap();
+    assert_eq!(page.pruned_seq, 1);
+    assert_eq!(page.last_seq, 3);
+    assert_eq!(page.changes.len(), 1);
+    assert_eq!(page.changes[0].seq, 3);
+    assert_eq!(
+        db.oldest_change_timestamp().unwrap(),
+        Some(page.changes[0].timestamp)
+    );
+}
+
+#[test]
+fn test_revision_bumps_on_every_write() {
+    let (_dir, db) = test_db();
+    let revision = |db: &Database| db.get_file("r").unwrap().unwrap().revision;
+
+    db.put_file(&sample_file("r", "docs/r.png")).unwrap();
+    assert_eq!(revision(&db), 1);
+
+    db.update_file(
+        "r",
+        Some(Some("alt")),
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        Utc::now(),
+    )
+    .unwrap();
+    assert_eq!(revision(&db), 2);
+
+    db.add_tags("r", &["hero".to_string()], Utc::now()).unwrap();
+    assert_eq!(revision(&db), 3);
+    // Writes that change nothing keep the revision
+    db.add_tags("r", &["hero".to_string()], Utc::now()).unwrap();
+    assert_eq!(revision(&db), 3);
+
+    db.add_version("r", &sample_version(2, "blob-2", "image/png", 1))
+        .unwrap();
+    db.trash_file("r", Utc::now()).unwrap();
+    db.restore_file("r").unwrap();
+    db.set_retention("r", Some(false), None, Utc::now())
+        .unwrap();
+    db.rename_prefix(DEFAULT_NAMESPACE, "docs/", "archive/", Utc::now())
+        .unwrap();
+    assert_eq!(revision(&db), 8);
+
+    // Overwriting a record continues from its revision
+    db.put_file(&sample_file("r", "docs/r.png")).unwrap();
+    assert_eq!(revision(&db), 9);
+}
+
+#[test]
+fn test_apply_batch() {
+    let (_dir, db) = test_db();
+    db.put_file(&sample_file("a", "a.png")).unwrap();
+    db.put_file(&sample_file("b", "b.png")).unwrap();
+    db.put_file(&sample_file("c", "c.png")).unwrap();
+
+    db.apply_batch(
+        &[
+            WriteOp::UpdateFile {
+                id: "a".to_string(),
+                alt: Patch::Null,
+                description: Patch::Absent,
+                expires_at: Patch::Absent,
+                metadata: Patch::Absent,
+                name: Patch::Absent,
+                permalink: Some("moved/a.png".to_string()),
+                subject_id: Patch::Absent,
+                visibility: None,
+            },
+            WriteOp::AddTags {
+                id: "b".to_string(),
+                tags: vec!["hero".to_string()],
+            },
+            WriteOp::TrashFile {
+                id: "c".to_string(),
+                deleted_at: Utc::now(),
+            },
+        ],
+        Utc::now(),
+    )
+    .unwrap();
+
+    let a = db.get_file("a").unwrap().unwrap();
+    assert_eq!(a.permalink, "moved/a.png");
+    assert_eq!(a.alt, None);
+    assert_eq!(a.revision, 2);
+    assert!(db
+        .permalink_exists(DEFAULT_NAMESPACE, "moved/a.png")
+        .unwrap());
+    assert_eq!(db.get_file("b").unwrap().unwrap().tags, vec!["hero"]);
+    assert!(db.get_file("c").unwrap().unwrap().deleted_at.is_some());
+
+    db.apply_batch(
+        &[WriteOp::DeleteFile {
+            id: "c".to_string(),
+        }],
+        Utc::now(),
+    )
+    .unwrap();
+    let files = db
+        .get_files(&["b".to_string(), "c".to_string(), "a".to_string()])
+        .unwrap();
+    let ids: Vec<_> = files
+        .iter()
+        .map(|f| f.as_ref().map(|f| f.id.as_str()))
+        .collect();
+    assert_eq!(ids, vec![Some("b"), None, Some("a")]);
+}
+
+fn sample_idempotency_record(key: &str, fingerprint: &str, minute: i64) -> IdempotencyRecord {
+    IdempotencyRecord {
+        namespace: DEFAULT_NAMESPACE.to_string(),
+        key: key.to_string(),
+        fingerprint: fingerprint.to_string(),
+        status: 200,
+        headers: vec![("content-type".to_string(), "application/json".to_string())],
+        body: format!(r#"{{"status":"success","data":"{fingerprint}"}}"#),
+        created_at: Utc::now() - chrono::Duration::minutes(minute),
+        claim: None,
+    }
+}
+
+#[test]
+fn test_idempotency_records() {
+    let (_dir, db) = test_db();
+    let first = sample_idempotency_record("retry-1", "aaa", 90);
+    assert!(db.put_idempotency_record(&first).unwrap());
+    // The first response stored for a key wins
+    assert!(!db
+        .put_idempotency_record(&sample_idempotency_record("retry-1", "bbb", 0))
+        .unwrap());
+    assert_eq!(
+        db.get_idempotency_record(DEFAULT_NAMESPACE, "retry-1")
+            .unwrap(),
+        Some(first.clone())
+    );
+    // Keys are scoped by namespace
+    assert_eq!(db.get_idempotency_record("acme", "retry-1").unwrap(), None);
+
+    let recent = sample_idempotency_record("retry-2", "ccc", 10);
+    db.put_idempotency_record(&recent).unwrap();
+    assert_eq!(
+        db.oldest_idempotency_timestamp().unwrap(),
+        Some(first.created_at)
+    );
+
+    assert_eq!(
+        db.prune_idempotency_records(Utc::now() - chrono::Duration::hours(1))
+            .unwrap(),
+        1
+    );
+    assert_eq!(db.get_idempotency_records().unwrap(), [recent]);
+}
+
+#[test]
+fn test_idempotency_claims() {
+    let (_dir, db) = test_db();
+    let claim = |id: &str, minute: i64| IdempotencyRecord {
+        status: 0,
+        headers: Vec::new(),
+        body: String::new(),
+        claim: Some(id.to_string()),
+        ..sample_idempotency_record("retry-1", "aaa", minute)
+    };
+    let first = claim("c1", 30);
+    assert!(db.claim_idempotency_key(&first).unwrap());
+    // A claim holds the key until it lapses
+    assert!(!db.claim_idempotency_key(&claim("c2", 25)).unwrap());
+    assert!(db.claim_idempotency_key(&claim("c2", 15)).unwrap());
+    // Only the holder releases it
+    assert!(!db
+        .release_idempotency_key(DEFAULT_NAMESPACE, "retry-1", "c1")
+        .unwrap());
+    assert!(db
+        .release_idempotency_key(DEFAULT_NAMESPACE, "retry-1", "c2")
+        .unwrap());
+    assert_eq!(
+        db.get_idempotency_record(DEFAULT_NAMESPACE, "retry-1")
+            .unwrap(),
+        None
+    );
+
+    // The response replaces the claim, and no claim replaces the response
+    db.claim_idempotency_key(&claim("c3", 30)).unwrap();
+    let response = sample_idempotency_record("retry-1", "aaa", 20);
+    assert!(db.put_idempotency_record(&response).unwrap());
+    assert!(!db.claim_idempotency_key(&claim("c4", 0)).unwrap());
+    assert!(!db
+        .release_idempotency_key(DEFAULT_NAMESPACE, "retry-1", "c3")
+        .unwrap());
+    assert_eq!(
+        db.get_idempotency_record(DEFAULT_NAMESPACE, "retry-1")
+            .unwrap(),
+        Some(response)
+    );
+}
+
+#[test]
+fn test_erasure_steps() {
+    let (_dir, db) = test_db();
+    let subject_file = |id: &str| {
+        let mut file = sample_file(id, &format!("{id}.png"));
+        file.subject_id = Some("user-1".to_string());
+        file
+    };
+    db.put_file(&subject_file("a")).unwrap();
+    db.put_file(&subject_file("b")).unwrap();
+    db.put_file(&subject_file("t")).unwrap();
+    db.put_file(&sample_file("other", "other.png")).unwrap();
+    db.add_version("a", &sample_version(2, "a-v2", "image/png", 10))
+        .unwrap();
+    db.trash_file("t", Utc::now()).unwrap();
+    // The same subject ID in another namespace is another subject
+    let mut elsewhere = subject_file("x");
+    elsewhere.namespace = "acme".to_string();
+    db.put_file(&elsewhere).unwrap();
+    db.trash_file("x", Utc::now()).unwrap();
+
+    let ids = |files: Vec<FileRecord>| files.into_iter().map(|f| f.id).collect::<Vec<_>>();
+    assert_eq!(
+        ids(db
+            .get_subject_files(DEFAULT_NAMESPACE, "user-1", false)
+            .unwrap()),
+        vec!["a", "b"]
+    );
+    assert_eq!(
+        ids(db
+            .get_subject_files(DEFAULT_NAMESPACE, "user-1", true)
+            .unwrap()),
+        vec!["a", "b", "t"]
+    );
+
+    let erasure = Erasure {
+        id: "e1".to_string(),
+        namespace: DEFAULT_NAMESPACE.to_string(),
+        subject_id: "user-1".to_string(),
+        include_trashed: true,
+        started_at: Utc::now(),
+        completed_at: None,
+        erased: Vec::new(),
+        retained: Vec::new(),
+        pending_content: Vec::new(),
+    };
+    assert!(db.put_erasure(&erasure).unwrap());
+    assert!(!db.put_erasure(&erasure).unwrap());
+    let other_subject = Erasure {
+        id: "e0".to_string(),
+        subject_id: "user-10".to_string(),
+        ..erasure.clone()
+    };
+    assert!(db.put_erasure(&other_subject).unwrap());
+    assert_eq!(
+        db.get_open_erasure(DEFAULT_NAMESPACE, "user-1")
+            .unwrap()
+            .map(|e| e.id),
+        Some("e1".to_string())
+    );
+
+    // Each step adds to the report; files already gone are skipped
+    let now = Utc::now();
+    assert_eq!(
+        db.erase_files("e1", &["a".to_string(), "t".to_string()], now)
+            .unwrap(),
+        2
+    );
+    assert_eq!(
+        db.erase_files("e1", &["a".to_string(), "b".to_string()], now)
+            .unwrap(),
+        1
+    );
+    assert!(db.get_file("a").unwrap().is_none());
+    assert!(!db.permalink_exists(DEFAULT_NAMESPACE, "a.png").unwrap());
+    assert!(db.get_file("other").unwrap().is_some());
+
+    assert!(db.complete_erasure("e1", &[], now).unwrap());
+    assert!(!db.complete_erasure("e1", &[], now).unwrap());
+    assert!(db
+        .get_open_erasure(DEFAULT_NAMESPACE, "user-1")
+        .unwrap()
+        .is_none());
+
+    let report = db.get_erasure("e1").unwrap().unwrap();
+    assert_eq!(report.completed_at, Some(now));
+    let erased: Vec<_> = report
+        .erased
+        .iter()
+        .map(|f| (f.id.as_str(), f.trashed, f.versions.len()))
+        .collect();
+    assert_eq!(
+        erased,
+        vec![("a", false, 2), ("t", true, 1), ("b", false, 1)]
+    );
+    assert_eq!(db.get_erasures().unwrap(), vec![other_subject, report]);
+}
@@ -0,0 +1,116 @@
+use std::sync::{Arc, Mutex};
+
+use axum::http::{HeaderMap, StatusCode};
+use axum::routing::post;
+use axum::Router;
+use chrono::Utc;
+use file_manager::storage::models::{Webhook, WebhookEvent};
+use file_manager::storage::webhooks::WebhookDelivery;
+use file_manager::webhooks::{self, MAX_ATTEMPTS};
+
+/// Requests received by a local webhook receiver
+type Received = Arc<Mutex<Vec<(HeaderMap, String)>>>;
+
+/// Start a local receiver answering every request with `status`. Returns its
+/// URL and the requests it receives.
+async fn start_receiver(status: StatusCode) -> (String, Received) {
+    let received = Received::default();
+    let log = Arc::clone(&received);
+    let app = Router::new().route(
+        "/hook",
+        post(move |headers: HeaderMap, body: String| async move {
+            log.lock().unwrap().push((headers, body));
+            status
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    (format!("http://{addr}/hook"), received)
+}
+
+fn sample_webhook(url: &str) -> Webhook {
+    Webhook {
+        id: "w".to_string(),
+        namespace: "default".to_string(),
+        url: url.to_string(),
+        secret: "s3cret".to_string(),
+        events: vec![WebhookEvent::FileCreated],
+        created_at: Utc::now(),
+    }
+}
+
+fn sample_delivery() -> WebhookDelivery {
+    WebhookDelivery {
+        id: 7,
+        webhook_id: "w".to_string(),
+        event: WebhookEvent::FileCreated,
+        payload: r#"{"event":"file.created"}"#.to_string(),
+        created_at: Utc::now(),
+        attempts: 0,
+        next_attempt_at: Utc::now(),
+        last_attempt_at: None,
+        last_error: None,
+    }
+}
+
+#[tokio::test]
+async fn test_send_signs_delivery() {
+    let (url, received) = start_receiver(StatusCode::NO_CONTENT).await;
+    let client = webhooks::http_client().unwrap();
+    let delivery = sample_delivery();
+
+    webhooks::send(&client, &sample_webhook(&url), &delivery)
+        .await
+        .unwrap();
+
+    let received = received.lock().unwrap();
+    assert_eq!(received.len(), 1);
+    let (headers, body) = &received[0];
+    let header = |name: &str| headers.get(name).unwrap().to_str().unwrap();
+    assert_eq!(body, &delivery.payload);
+    assert_eq!(header(webhooks::EVENT_HEADER), "file.created");
+    assert_eq!(header(webhooks::DELIVERY_HEADER), "7");
+    assert_eq!(header("content-type"), "application/json");
+
+    let timestamp: i64 = header(webhooks::TIMESTAMP_HEADER).parse().unwrap();
+    assert_eq!(
+        header(webhooks::SIGNATURE_HEADER),
+        webhooks::sign("s3cret", timestamp, body)
+    );
+    assert_ne!(
+        header(webhooks::SIGNATURE_HEADER),
+        webhooks::sign("other", timestamp, body)
+    );
+}
+
+#[tokio::test]
+async fn test_send_fails_on_error_status() {
+    let (url, received) = start_receiver(StatusCode::INTERNAL_SERVER_ERROR).await;
+    let client = webhooks::http_client().unwrap();
+
+    let error = webhooks::send(&client, &sample_webhook(&url), &sample_delivery())
+        .await
+        .unwrap_err();
+    assert!(error.contains("500"), "{error}");
+    assert_eq!(received.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn test_retry_backoff() {
+    let now = Utc::now();
+    let delay = |attempts| webhooks::retry_at(attempts, now).map(|t| (t - now).num_seconds());
+    assert_eq!(delay(1), Some(30));
+    assert_eq!(delay(2), Some(60));
+    assert_eq!(delay(3), Some(120));
+    assert_eq!(delay(MAX_ATTEMPTS - 1), Some(30 << (MAX_ATTEMPTS - 2)));
+    assert_eq!(delay(MAX_ATTEMPTS), None);
+}
+
+#[test]
+fn test_sign_matches_known_vector() {
+    assert_eq!(
+        webhooks::sign("key", 1_700_000_000, "body"),
+        "sha256=47b6ce0fca59474308e2921c247cb2493dce6b8101d90ac05bd0c6a37d0e046e"
+    );
+}